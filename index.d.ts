@@ -3,23 +3,637 @@
 
 /* auto-generated by NAPI-RS */
 
+export const enum HashAlgorithm {
+  Blake3 = 'Blake3',
+  /** For environments allowing only FIPS-approved algorithms, slower than Blake3. */
+  Sha256 = 'Sha256'
+}
 export interface SignatureOptions {
   minSize: number
   avgSize: number
   maxSize: number
+  /** The algorithm of the chunk hashes, Blake3 by default. */
+  hashAlgorithm?: HashAlgorithm
+  /**
+   * The FastCDC normalization level from 0 to 3, 1 by default. Higher levels cut more chunks
+   * close to `avg_size`. It's stored in the signature, so diffs and updates chunk the same way.
+   */
+  normalization?: number
+  /**
+   * Stores only the first 16 or 24 bytes of every chunk hash instead of all 32, shrinking
+   * signatures of huge files. Collisions get likelier, so results should be checked, e.g. with
+   * `verify`.
+   */
+  hashBytes?: number
+  /**
+   * Cuts the source into blocks of this size, the last one shorter, instead of with FastCDC, for
+   * protocols expecting rsync-style blocks. The chunk sizes are ignored then. Such blocks only
+   * match at the same offsets, so inserts and deletes make diffs insert everything after them.
+   */
+  fixedBlockSize?: number
+  /** Also keeps the rsync rolling checksum of every fixed-size block. */
+  weakChecksums?: boolean
+  /**
+   * Fails the signature once the source has more chunks than this, so huge sources don't take
+   * all the memory for their chunk list.
+   */
+  maxChunks?: number
+  /**
+   * Fails the signature once more than this number of bytes of the source are chunked. Sources
+   * which aren't regular files, such as pipes or devices, may never end, so they're only read
+   * with this limit set.
+   */
+  maxFileBytes?: number
+}
+export interface DiffOptions {
+  /**
+   * Keeps the index of the source chunks in a temporary file when it would take more memory than
+   * this number of bytes.
+   */
+  maxMemory?: number
+  /**
+   * Looks for source blocks of this size inside inserted regions with a rolling hash, so data
+   * shifted inside chunks is copied instead of inserted. Only `diff` has the source data to do
+   * this.
+   */
+  weakBlockSize?: number
+  /**
+   * Inserts copies shorter than this number of bytes found between two inserts, so noisy edits
+   * take fewer operations.
+   */
+  coalesceGap?: number
+  /**
+   * Moves the boundaries between operations to multiples of this number of bytes in the result,
+   * so appliers writing to block devices or with `O_DIRECT` never write a block in part. Every
+   * block with a boundary inside it is inserted whole, which grows the diff by up to a block for
+   * every operation. Copies then don't cover whole chunks, so it can't be combined with
+   * `chunk_copies`.
+   */
+  align?: number
+  /** Compresses all operations after the diff header as a single zstd stream. */
+  compress?: boolean
+  /**
+   * Finds repeated data up to 128 MiB apart when compressing, so large targets repeating data
+   * from far back compress better. Applying needs as much memory for the window, and diffs with
+   * a long window can't be applied by older versions.
+   */
+  longWindow?: boolean
+  /**
+   * Path to a zstd dictionary, see `train_dictionary`, to compress the operations with. Implies
+   * `compress`. The same dictionary must be passed to `apply`.
+   */
+  dictionary?: string
+  /**
+   * Ends the operations with a table of their kinds, positions, offsets and sizes, so readers
+   * can jump to any operation. Diffs with a table can't be applied by older versions.
+   */
+  operationTable?: boolean
+  /**
+   * Path to an insert pool shared by many diffs, created when missing. Inserted chunks are
+   * added to the pool unless it has them already and the diff copies them from there, so data
+   * new to many diffs is stored once. The same pool must be passed to `apply`.
+   */
+  insertPool?: string
+  /**
+   * Prefixes the data of every insert with a checksum, so applying verifies each insert as it's
+   * read, also from a stream, and fails at the first corrupted one instead of writing a
+   * corrupted result. Diffs with checksums can't be applied by older versions.
+   */
+  insertChecksums?: boolean
+  /**
+   * Names the source chunks copies read by their hashes instead of their offsets, so the diff is
+   * applied from a chunk store, see `export_chunks`, without the source file. Copies found by
+   * `weak_block_size` don't cover whole chunks, so the options can't be combined.
+   */
+  chunkCopies?: boolean
+  /**
+   * Waits until the diff is on the storage device before returning, so it isn't lost on a crash.
+   * Flushing the whole file takes from milliseconds to seconds, depending on the device.
+   */
+  durable?: boolean
+}
+/**
+ * The sizes of a written diff. Inserts are compressed along with the other operations in a
+ * single stream, so the compression ratio is the one of all operations.
+ */
+export interface DiffStats {
+  /** The number of bytes of the diff. */
+  diffSize: number
+  /** The number of bytes of the target inserted by the diff, before compression. */
+  uncompressedInsertBytes: number
+  /**
+   * The number of bytes of the operations after the header, inserted data included, before
+   * compression.
+   */
+  operationsSize: number
+  /**
+   * The number of bytes the operations take in the diff, the same as `operations_size` unless
+   * they're compressed.
+   */
+  compressedOperationsSize: number
+}
+export interface PullOptions {
+  /** Limits the download speed of all ranges combined, in bytes per second. Must be positive. */
+  maxBytesPerSec?: number
+  /** Copies the permissions and modification time of `source` to the pulled file. */
+  preserveMetadata?: boolean
+  /** Copies the extended attributes of `source` to the pulled file, on Linux and macOS. */
+  preserveXattrs?: boolean
+  /** Sends all requests through this proxy. `HTTP_PROXY`/`HTTPS_PROXY` are used when not set. */
+  proxy?: string
+  proxyUsername?: string
+  proxyPassword?: string
+  /** Path to a PEM bundle of additional CA certificates to trust. */
+  caCertificate?: string
+  /** Paths to a PEM client certificate and its PKCS #8 key for mutual TLS. */
+  clientCertificate?: string
+  clientKey?: string
+  /**
+   * Uses HTTP/1.1 only, for servers which misbehave on HTTP/2. By default HTTP/2 is negotiated
+   * over TLS, so all ranges share a single connection.
+   */
+  http1Only?: boolean
+  /** Uses HTTP/2 without negotiation, for plain HTTP servers known to support it. */
+  http2PriorKnowledge?: boolean
+  /** The User-Agent header sent with every request. */
+  userAgent?: string
+  /** Follows redirects, enabled by default. When disabled, a redirect fails the pull. */
+  followRedirects?: boolean
+  /** The number of redirects followed before the pull fails, 10 by default. */
+  maxRedirects?: number
+  /**
+   * Downloads the whole file when the server doesn't advertise range support, enabled by
+   * default. When disabled, such a server fails the pull.
+   */
+  rangeFallback?: boolean
+  /** Returns the plan of the pull without sending any requests or writing `dest`. */
+  dryRun?: boolean
+  /**
+   * The number of attempts of every range request failing to connect, with a 5xx or 429 status
+   * or while reading the body, 1 by default. Broken bodies are requested again from where they
+   * broke off. Retries wait for a random delay up to an exponentially growing limit.
+   */
+  maxAttempts?: number
+  /** The limit of the delay before the first retry, 100 ms by default. */
+  retryInitialDelayMs?: number
+  /** The factor the delay limit grows by after every retry, 2 by default. */
+  retryMultiplier?: number
+  /** The maximum delay limit, 10 seconds by default. */
+  retryMaxDelayMs?: number
+  /** Fails the pull instead of retrying past this time since its start. */
+  retryBudgetMs?: number
+  /**
+   * Requests up to this number of ranges at once as `multipart/byteranges`, 1 by default since
+   * servers limit it differently. Ranges missing from a response are requested one by one.
+   */
+  maxRangesPerRequest?: number
+  /**
+   * Also writes the downloaded data to this file at its offsets in the result, leaving zeros
+   * elsewhere, to inspect what the server served. The file is kept after the pull, even a
+   * failed one, and can be passed to `apply_with_local_inserts` as `inserts`.
+   */
+  insertsPath?: string
+}
+/** `length` bytes of the remote file at `offset`. */
+export interface ByteRange {
+  offset: number
+  length: number
+}
+export interface PullResult {
+  /** The `source` already matched the target signature, so nothing was downloaded. */
+  alreadyUpToDate: boolean
+  /** The plan of the pull, only returned with `dry_run`. */
+  plan?: PullPlan
+}
+export interface PullPlan {
+  /** The ranges of the remote file to download, in the order of the target file. */
+  ranges: Array<ByteRange>
+  /** The number of bytes copied from the source or already written parts of the result. */
+  copiedBytes: number
+  /** The number of bytes downloaded. */
+  insertedBytes: number
+}
+export interface ApplyOptions {
+  /** Copies the permissions and modification time of the source file to the result. */
+  preserveMetadata?: boolean
+  /**
+   * Copies the extended attributes of the source file, such as SELinux labels or quarantine
+   * flags, to the result. Only Linux and macOS have them, elsewhere nothing is copied.
+   */
+  preserveXattrs?: boolean
+  /** Path to the zstd dictionary the diff was compressed with. */
+  dictionary?: string
+  /**
+   * Path to a file where the progress is saved about every percent of the result, so an
+   * interrupted apply can be resumed. The partial result is kept on failure and the file is
+   * removed once the result is complete.
+   */
+  checkpointPath?: string
+  /** Continues the apply from `checkpoint_path`, if it was saved, instead of starting over. */
+  resume?: boolean
+  /** Path to the insert pool the diff was written with. */
+  insertPool?: string
+  /**
+   * Waits until the result is on the storage device before returning, so it isn't lost on a
+   * crash. Flushing the whole file takes from milliseconds to seconds, depending on the device.
+   */
+  durable?: boolean
+}
+export interface ApplyReport {
+  /** The number of bytes written to the result. */
+  bytesWritten: number
+  /** The number of operations copying data from the source file. */
+  copyOps: number
+  /** The number of operations inserting data from the diff. */
+  insertOps: number
+}
+/**
+ * `old_length` bytes at `offset` replaced with `new_length` bytes. Offsets of later edits
+ * account for the earlier ones.
+ */
+export interface SignatureEdit {
+  offset: number
+  oldLength: number
+  newLength: number
 }
-/** Writes calculated signature for `source` to the `dest`. */
-export function writeBinarySignature(source: string, dest: string, options?: SignatureOptions | undefined | null): void
-/** Returns calculated signature of the `source`. */
+export interface BatchOptions {
+  /** The number of paths processed at once, 1 by default. */
+  concurrency?: number
+  /**
+   * The most files open at once across the whole batch, `concurrency` by default. Paths wait for
+   * other files to be closed when there are this many open, so batches on many threads stay
+   * within the open file limit of the process.
+   */
+  maxOpenFiles?: number
+}
+/** The signature of a range of a file starting at `offset`, calculated with `signature_of_range`. */
+export interface RangeSignature {
+  offset: number
+  signature: Buffer
+}
+/** The result of one path of a batch, either `signature` or `error` is set. */
+export interface SignatureResult {
+  path: string
+  signature?: Buffer
+  error?: string
+}
+export interface ApplyProgress {
+  bytesWritten: number
+  /** The size of the result. */
+  totalBytes: number
+}
+export interface SignatureProgress {
+  bytesProcessed: number
+  /** The size of the source when the signature started. */
+  totalBytes: number
+  /** The whole percent of the source processed, 100 only once the signature is complete. */
+  percent: number
+}
+export interface Bucket {
+  /** The smallest chunk size counted in the bucket. */
+  start: number
+  /** The largest chunk size counted in the bucket. */
+  end: number
+  count: number
+}
+export interface VerifyResult {
+  matches: boolean
+  /** The offset of the first chunk which differs from the signature. */
+  firstMismatchOffset?: number
+  /** The number of chunks of the signature which weren't found at their offsets. */
+  mismatchedChunks: number
+}
+/** The result of `validate_diff`, `error` is set when the diff isn't valid. */
+export interface DiffValidation {
+  valid: boolean
+  error?: string
+  /** The size of the result declared by the diff. */
+  targetSize: number
+  copyOps: number
+  insertOps: number
+  bytesWritten: number
+}
+export const enum OperationKind {
+  /** Copies data from the source file. */
+  Copy = 'Copy',
+  /** Inserts data from the diff. */
+  Insert = 'Insert',
+  /** Copies data already written to the result. */
+  CopyOutput = 'CopyOutput',
+  /** Copies inserted data from the insert pool. */
+  CopyPool = 'CopyPool'
+}
+/** An operation of a plan from `diff_plan`. */
+export interface PlanOperation {
+  kind: OperationKind
+  /** The offset copied from the source, or the offset of the inserted data in the target. */
+  offset: number
+  size: number
+}
+/** An operation of a diff from its operation table. */
+export interface OperationEntry {
+  kind: OperationKind
+  /**
+   * The offset of the operation from the start of the operations, which follow the header in
+   * uncompressed diffs.
+   */
+  position: number
+  /** The offset copied from, or the position of the inserted data like `position`. */
+  offset: number
+  size: number
+}
+export interface Edges {
+  /** The number of bytes both files start with. */
+  prefix: number
+  /** The number of bytes both files end with, not overlapping `prefix`. */
+  suffix: number
+}
+export interface SignatureInfo {
+  version: number
+  minSize: number
+  avgSize: number
+  maxSize: number
+  hashAlgorithm: HashAlgorithm
+  fixedBlockSize?: number
+  chunkCount: number
+  /** The size of the file the signature was calculated for. */
+  fileSize: number
+  /**
+   * Whether every chunk starts where the previous one ends, which extending, updating and
+   * diffing need.
+   */
+  contiguous: boolean
+}
+export interface ChunkSizeHistogram {
+  /** Buckets of equal width covering all chunk sizes from `min` to `max`. */
+  buckets: Array<Bucket>
+  chunkCount: number
+  min: number
+  max: number
+  mean: number
+  median: number
+  /** The number of chunks cut at `min_size` and at `max_size` of the options. */
+  atMinSize: number
+  atMaxSize: number
+  /**
+   * Set when too many chunks are cut at `min_size` or `max_size`, which means the data doesn't
+   * fit `avg_size` and the signature has too many chunks or too few cut points to match, or when
+   * the whole input is only one or two chunks.
+   */
+  warning?: string
+}
+/**
+ * Writes calculated signature for `source` to the `dest`. With `durable` it waits until the
+ * signature is on the storage device before returning, so it isn't lost on a crash, which takes
+ * from milliseconds to seconds depending on the device.
+ */
+export function writeBinarySignature(source: string, dest: string, options?: SignatureOptions | undefined | null, durable?: boolean | undefined | null): void
+/**
+ * Returns calculated signature of the `source`. Identical data and options always give
+ * byte-identical signatures.
+ */
 export function signature(source: string, options?: SignatureOptions | undefined | null): Buffer
-/** Generates a diff that transforms `source` to `target`. */
-export function diff(source: string, target: string, dest: string, options?: SignatureOptions | undefined | null): void
-/** Generates a diff that transforms `source` to `target. Only source signature is required. */
-export function diffUsingSourceSignature(sourceSig: string, target: string, dest: string): void
+/**
+ * Returns calculated signature of an archive `source`, e.g. a tar file, whose members start at
+ * the ascending `member_offsets`. Every member is chunked on its own, so a changed member doesn't
+ * change the chunks of the members after it and diffs between versions of the archive stay
+ * small. Fixed-size blocks don't drift, so `fixed_block_size` isn't supported.
+ */
+export function archiveSignature(source: string, memberOffsets: Array<number>, options?: SignatureOptions | undefined | null): Buffer
+/**
+ * Returns calculated signature of the `source` like `signature`, without blocking the event loop,
+ * calling `callback` with the progress once for every whole percent of the source and once the
+ * signature is complete.
+ */
+export function signatureWithProgress(source: string, options: SignatureOptions | undefined | null, callback: (progress: SignatureProgress) => void): Promise<Buffer>
+/**
+ * Returns calculated signature of `length` bytes of the `source` starting at `offset`, as if
+ * they were a whole file, so separate processes can sign the ranges of a huge file and combine
+ * their signatures with `combine_range_signatures`. Ranges must overlap the ones before them by
+ * a few maximum chunk sizes, or with `fixed_block_size` start at multiples of the block size.
+ */
+export function signatureOfRange(source: string, offset: number, length: number, options?: SignatureOptions | undefined | null): Buffer
+/**
+ * Returns the signature of a whole file combined from the signatures of its ranges, calculated
+ * with `signature_of_range` and ordered by their offsets. It's identical to the signature of the
+ * whole file when every range overlaps the ones before it until they share a chunk boundary,
+ * otherwise combining fails.
+ */
+export function combineRangeSignatures(parts: Array<RangeSignature>): Buffer
+/**
+ * Returns calculated signatures of all `sources`. The first failure fails the whole batch unless
+ * `continueOnError` is set, then failed paths get an error in their results instead.
+ */
+export function signatures(sources: Array<string>, options?: SignatureOptions | undefined | null, continueOnError?: boolean | undefined | null, batchOptions?: BatchOptions | undefined | null): Array<SignatureResult>
+/**
+ * Returns the `signature` of a file updated for the `edits` made to it since, reading only the
+ * edited regions of the `source` holding the edited file.
+ */
+export function updateSignature(signature: Buffer, source: string, edits: Array<SignatureEdit>): Buffer
+/**
+ * Returns the `signature` of the `source` extended for the data appended to it since, reading
+ * only its last chunk and the appended data.
+ */
+export function extendSignature(signature: Buffer, source: string): Buffer
+/**
+ * Calculates the signature of the `source` and writes its chunks to the `dest` as newline
+ * delimited JSON, one `{"offset", "length", "hash"}` object per line with the hash in hex, so
+ * other tools can read them line by line.
+ */
+export function exportChunksNdjson(source: string, dest: string, options?: SignatureOptions | undefined | null): void
+/**
+ * Calculates the signature of the `source`, adds every chunk the `store` directory doesn't have
+ * yet, creating it when it's missing, and returns the signature. Diffs against the signature
+ * written with `chunk_copies` are applied from the store with `apply_from_chunk_store`.
+ */
+export function exportChunks(source: string, store: string, options?: SignatureOptions | undefined | null): Buffer
+/**
+ * Writes the chunks of the signature at `signature` to the `dest` as a `.caibx` index of casync
+ * and desync. The signature must use the `Sha256` hash algorithm, since casync names chunks by
+ * their hashes.
+ */
+export function writeCasyncIndex(signature: string, dest: string): void
+/** Checks whether the `source` still matches its stored `signature`. */
+export function verify(source: string, signature: Buffer): VerifyResult
+/** Returns the options and the size of the chunks of the `signature`, without any chunk hashes. */
+export function signatureInfo(signature: Buffer): SignatureInfo
+/**
+ * Returns the `input` signature written in format version `targetVersion`, without reading its
+ * file again. Converting to older versions fails when they can't store the options of the
+ * signature, e.g. truncated chunk hashes.
+ */
+export function convertSignature(input: Buffer, targetVersion: number): Buffer
+/**
+ * Returns the distribution of chunk sizes of the `source`, useful to tune the signature options.
+ * It warns when more than `max_clamped_fraction` of the chunks, 0.5 by default, are cut at
+ * `min_size` or `max_size`.
+ */
+export function chunkSizeHistogram(source: string, options?: SignatureOptions | undefined | null, maxClampedFraction?: number | undefined | null): ChunkSizeHistogram
+/**
+ * Trains a zstd dictionary on the `samples` files, which should be similar to the data inserted
+ * by diffs. `max_size` is 110 KiB by default.
+ */
+export function trainDictionary(samples: Array<string>, maxSize?: number | undefined | null): Buffer
+/**
+ * Returns the lengths of the data `a` and `b` start and end with, found by comparing them from
+ * both ends, far cheaper than chunking. Appends and prepends can be told from them without a
+ * diff, otherwise only the data between them differs.
+ */
+export function commonEdges(a: string, b: string): Edges
+/**
+ * Returns the hex hashes of the chunks `want_sig` needs and `have_sig` has none of, once each.
+ * A client holding the chunks of `have_sig` only needs to fetch these to build the file of
+ * `want_sig`.
+ */
+export function missingHashes(haveSig: Buffer, wantSig: Buffer): Array<string>
+/**
+ * Returns the approximate number of bytes diffs against `source_sig` take to index its chunks,
+ * which is most of their memory, so that schedulers can bound how many diffs run at once. Diffs
+ * with a lower `max_memory` keep the index in a temporary file instead.
+ */
+export function diffMemoryEstimate(sourceSig: Buffer): number
+/**
+ * Returns the size of the smallest uncompressed diff from the file of `source_sig` to the file
+ * of `target_sig` made of their chunks, without writing a diff: the new chunks are inserted once
+ * and all other chunks are copied with as few operations as possible. Diffs only copy from the
+ * first occurrence of repeated source chunks, so comparing their size to this bound shows how
+ * much the matching leaves out.
+ */
+export function optimalDiffSize(sourceSig: Buffer, targetSig: Buffer): number
+/**
+ * Returns the plan of the diff between the files of `source_sig` and `target_sig`, their
+ * operations without any inserted data, which is much smaller than a diff and cheap to cache and
+ * compare across versions. See `load_diff_plan`.
+ */
+export function diffPlan(sourceSig: Buffer, targetSig: Buffer): Buffer
+/** Returns the operations of a `plan` from `diff_plan`. */
+export function loadDiffPlan(plan: Buffer): Array<PlanOperation>
+/** Generates a diff that transforms `source` to `target` and returns its sizes. */
+export function diff(source: string, target: string, dest: string, options?: SignatureOptions | undefined | null, diffOptions?: DiffOptions | undefined | null): DiffStats
+/**
+ * Returns a diff that transforms the `source` data to the `target` data like `diff`, without
+ * any files.
+ */
+export function diffBuffers(source: Buffer, target: Buffer, options?: SignatureOptions | undefined | null, diffOptions?: DiffOptions | undefined | null): Buffer
+/**
+ * Generates a diff that transforms `source` to `target. Only source signature is required.
+ * Returns the sizes of the diff.
+ */
+export function diffUsingSourceSignature(sourceSig: string, target: string, dest: string, options?: DiffOptions | undefined | null): DiffStats
+/**
+ * Generates a diff that transforms a file with signature `source_sig` to the `target_data` with
+ * signature `target_sig`. Both signatures must be calculated with the same options, except for
+ * the hash algorithm and the hash length when the `source` file is passed too: its chunks are
+ * then hashed again like the target ones. The target chunks are read one at a time instead of
+ * loading the whole target signature, unless `insert_pool`, `coalesce_gap` or `align` is set.
+ * Returns the sizes of the diff.
+ */
+export function diffUsingBothSignatures(sourceSig: string, targetSig: string, targetData: string, dest: string, options?: DiffOptions | undefined | null, source?: string | undefined | null): DiffStats
 /**
  * Downloads the required parts of the file and builds a new file based on `target_sig` and the
- * `source`.
+ * `source`. With `dry_run` only returns the plan of the pull. When the `source` already matches
+ * `target_sig` it's copied to `dest` without any requests. With `token_provider` every request
+ * is authorized with a bearer token it resolves, and it's called again for a fresh token
+ * whenever the server answers 401, so pulls can outlive short-lived credentials.
+ */
+export function pullUsingRemoteSignature(source: string, targetSig: string, fileUri: string, dest: string, options?: PullOptions | undefined | null, tokenProvider?: (() => Promise<string>) | undefined | null): Promise<PullResult>
+/**
+ * Builds the file of `target_sig` at `dest` entirely from downloads, for when the source isn't
+ * local either: copies are downloaded from the source at `source_uri`, described by
+ * `source_sig`, and inserts from the file at `file_uri`. `dry_run` and the options preserving
+ * the metadata of the source are ignored. `token_provider` authorizes the requests like in
+ * `pull_using_remote_signature`.
  */
-export function pullUsingRemoteSignature(source: string, targetSig: string, fileUri: string, dest: string): Promise<void>
+export function pullFromRemoteSource(sourceSig: string, sourceUri: string, targetSig: string, fileUri: string, dest: string, options?: PullOptions | undefined | null, tokenProvider?: (() => Promise<string>) | undefined | null): Promise<void>
+/**
+ * Checks every operation of `diff` without applying it: the version, that copies stay within
+ * `source` when it's given and that the operations write exactly the declared size of the
+ * result. Only unreadable files are thrown, an invalid diff is reported in the result.
+ */
+export function validateDiff(diff: string, source?: string | undefined | null): DiffValidation
+/**
+ * Returns the optional features of the diff format `diff` uses, named like the `DiffOptions`
+ * using them, so a producer can be asked for diffs without the ones a consumer doesn't
+ * support, see `supported_diff_features`. Diffs using features this version can't apply are
+ * thrown with the unsupported ones listed.
+ */
+export function diffFeatures(diff: string): Array<string>
+/** Returns the names of the optional features of the diff format this version applies. */
+export function supportedDiffFeatures(): Array<string>
 /** Applies `diff` to the `a` and writes the result to `result`. */
-export function apply(diff: string, a: string, result: string): void
+export function apply(diff: string, a: string, result: string, options?: ApplyOptions | undefined | null): void
+/**
+ * Applies a `diff` written with `chunk_copies` to the chunks in the `store` directory, see
+ * `export_chunks`, and writes the result to `result`. No source file is needed, so the options
+ * preserving its metadata are ignored.
+ */
+export function applyFromChunkStore(diff: string, store: string, result: string, options?: ApplyOptions | undefined | null): void
+/** Applies the `diff` to the `source` data and returns the result, without any files. */
+export function applyBuffers(diff: Buffer, source: Buffer): Buffer
+/**
+ * Applies `diff` to the `a` and writes the result to `result` like `apply`, without blocking the
+ * event loop.
+ */
+export function applyAsync(diff: string, a: string, result: string): Promise<void>
+/**
+ * Applies `diff` to the `a` and writes the result to `result` like `apply_async`, calling
+ * `callback` with the progress about every percent of the result and once it's complete.
+ */
+export function applyWithProgress(diff: string, a: string, result: string, callback: (progress: ApplyProgress) => void): Promise<ApplyReport>
+/**
+ * Applies `diff` to the `a` and passes the result to `write` front to back, e.g. to send it as an
+ * HTTP response without writing it to disk. Every call of `write` is awaited before the next one,
+ * so resolving once a Node `Writable` has drained keeps its backpressure.
+ */
+export function applyToStream(diff: string, a: string, write: (chunk: Buffer) => Promise<void>): Promise<void>
+/** Applies `diff` to the `a`, writes the result to `result` and reports what was done. */
+export function applyWithReport(diff: string, a: string, result: string, options?: ApplyOptions | undefined | null): ApplyReport
+/**
+ * Applies `diff` to the `a` and writes the result to `result`, taking the inserted data from
+ * `inserts`. `inserts` must contain it at the offsets where it's placed in the result, for
+ * example when it was downloaded separately, and the inserts of `diff` only have their sizes.
+ */
+export function applyWithLocalInserts(diff: string, a: string, inserts: string, result: string): void
+/** Applies `diff` to the `a`, overwriting `a` with the result instead of creating a new file. */
+export function applyInPlace(diff: string, a: string): void
+/**
+ * Applies `diff` to the `source` and writes the result over the `existing` file, which already
+ * holds the data of `source` wherever the diff copies it to the same offsets, e.g. a copy of
+ * `source` made by an earlier sync. Only inserts and copies moved to other offsets are written,
+ * so small updates of large files take few writes. `existing` must not be `source`, see
+ * `apply_in_place` for that.
+ */
+export function applyPatchExisting(diff: string, source: string, existing: string): ApplyReport
+/**
+ * Applies `diff` to the `a` and writes the result to `result` like `apply`, starting from a
+ * copy-on-write clone of `a` on filesystems supporting them, such as btrfs, XFS and APFS. Only
+ * inserts and copies moved to other offsets are written then, so results mostly copied from
+ * the same offsets of `a` are fast and share their storage with it. Elsewhere, or when `a` and
+ * `result` are on different filesystems, the result is written like `apply` writes it.
+ */
+export function applyReflink(diff: string, a: string, result: string): void
+/** Applies `diffs` one after another to the `base` and writes the final result to `result`. */
+export function applyChain(diffs: Array<string>, base: string, result: string): void
+/**
+ * Merges `diff_a` and `diff_b`, both made from the file of `base_sig`, into a diff applying both
+ * of them and writes it to `dest`. Fails when both change the same region of the base. The
+ * merged diff is uncompressed and without an operation table.
+ */
+export function mergeDiffs(baseSig: Buffer, diffA: string, diffB: string, dest: string): void
+/**
+ * Returns the operations of a `diff` written with `operation_table`, without parsing them.
+ * `dictionary` is needed for diffs compressed with one.
+ */
+export function operationTable(diff: string, dictionary?: string | undefined | null): Array<OperationEntry>
+/**
+ * Splits a `diff` into a `manifest` of its operations and the `payloads` of its inserts,
+ * concatenated in order, so the small manifests and the bulky payloads can be cached and sent
+ * separately. Insert checksums and the HMAC tag aren't kept, and diffs copying from an insert
+ * pool can't be split. `dictionary` is needed for diffs compressed with one.
+ */
+export function splitDiff(diff: string, manifest: string, payloads: string, dictionary?: string | undefined | null): void
+/**
+ * Applies a `manifest` with its `payloads` from `split_diff` to the `source` and writes the
+ * result to `result`, like `apply` applies the diff they were split from.
+ */
+export function applyFromManifest(manifest: string, payloads: string, source: string, result: string): ApplyReport
@@ -310,11 +310,55 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { writeBinarySignature, signature, diff, diffUsingSourceSignature, pullUsingRemoteSignature, apply } = nativeBinding
+const { HashAlgorithm, OperationKind, writeBinarySignature, signature, archiveSignature, signatureWithProgress, signatureOfRange, combineRangeSignatures, signatures, updateSignature, extendSignature, exportChunksNdjson, exportChunks, writeCasyncIndex, verify, signatureInfo, convertSignature, chunkSizeHistogram, trainDictionary, commonEdges, missingHashes, diffMemoryEstimate, optimalDiffSize, diffPlan, loadDiffPlan, diff, diffBuffers, diffUsingSourceSignature, diffUsingBothSignatures, pullUsingRemoteSignature, pullFromRemoteSource, validateDiff, diffFeatures, supportedDiffFeatures, apply, applyFromChunkStore, applyBuffers, applyAsync, applyWithProgress, applyToStream, applyWithReport, applyWithLocalInserts, applyInPlace, applyPatchExisting, applyReflink, applyChain, mergeDiffs, operationTable, splitDiff, applyFromManifest } = nativeBinding
 
+module.exports.HashAlgorithm = HashAlgorithm
+module.exports.OperationKind = OperationKind
 module.exports.writeBinarySignature = writeBinarySignature
 module.exports.signature = signature
+module.exports.archiveSignature = archiveSignature
+module.exports.signatureWithProgress = signatureWithProgress
+module.exports.signatureOfRange = signatureOfRange
+module.exports.combineRangeSignatures = combineRangeSignatures
+module.exports.signatures = signatures
+module.exports.updateSignature = updateSignature
+module.exports.extendSignature = extendSignature
+module.exports.exportChunksNdjson = exportChunksNdjson
+module.exports.exportChunks = exportChunks
+module.exports.writeCasyncIndex = writeCasyncIndex
+module.exports.verify = verify
+module.exports.signatureInfo = signatureInfo
+module.exports.convertSignature = convertSignature
+module.exports.chunkSizeHistogram = chunkSizeHistogram
+module.exports.trainDictionary = trainDictionary
+module.exports.commonEdges = commonEdges
+module.exports.missingHashes = missingHashes
+module.exports.diffMemoryEstimate = diffMemoryEstimate
+module.exports.optimalDiffSize = optimalDiffSize
+module.exports.diffPlan = diffPlan
+module.exports.loadDiffPlan = loadDiffPlan
 module.exports.diff = diff
+module.exports.diffBuffers = diffBuffers
 module.exports.diffUsingSourceSignature = diffUsingSourceSignature
+module.exports.diffUsingBothSignatures = diffUsingBothSignatures
 module.exports.pullUsingRemoteSignature = pullUsingRemoteSignature
+module.exports.pullFromRemoteSource = pullFromRemoteSource
+module.exports.validateDiff = validateDiff
+module.exports.diffFeatures = diffFeatures
+module.exports.supportedDiffFeatures = supportedDiffFeatures
 module.exports.apply = apply
+module.exports.applyFromChunkStore = applyFromChunkStore
+module.exports.applyBuffers = applyBuffers
+module.exports.applyAsync = applyAsync
+module.exports.applyWithProgress = applyWithProgress
+module.exports.applyToStream = applyToStream
+module.exports.applyWithReport = applyWithReport
+module.exports.applyWithLocalInserts = applyWithLocalInserts
+module.exports.applyInPlace = applyInPlace
+module.exports.applyPatchExisting = applyPatchExisting
+module.exports.applyReflink = applyReflink
+module.exports.applyChain = applyChain
+module.exports.mergeDiffs = mergeDiffs
+module.exports.operationTable = operationTable
+module.exports.splitDiff = splitDiff
+module.exports.applyFromManifest = applyFromManifest
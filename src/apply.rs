@@ -1,11 +1,39 @@
-use crate::diff::Operation;
-use crate::signature::VERSION;
+use crate::auth::{self, Credentials};
+use crate::chunk_store::ChunkStore;
+use crate::diff::{
+  self, DiffOps, Operation, OperationTable, CAPABILITY_LONG_WINDOW, CHECKSUM_LEN,
+  FLAG_CAPABILITIES, FLAG_CHUNK_COPIES, FLAG_COMPRESSED, FLAG_DICTIONARY, FLAG_HMAC_SHA256,
+  FLAG_INSERT_CHECKSUMS, FLAG_INSERT_POOL, FLAG_OPERATION_TABLE, LONG_WINDOW_LOG, TABLE_ENTRY_LEN,
+  TAG_LEN, VERSION,
+};
+use crate::multipart;
+use crate::rate_limit::RateLimiter;
+use crate::reflink;
+use crate::retry::Backoff;
+use crate::signature::{usize_from, DEFAULT_MAX_SIZE};
+use crate::{ApplyReport, ByteRange, PullOptions, PullPlan};
 
-use reqwest::header::RANGE;
-use reqwest::Client;
+use anyhow::Context;
+use arrayref::array_ref;
+use filetime::FileTime;
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use napi::tokio::task::JoinError;
+use reqwest::header::{
+  HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+};
+use reqwest::redirect;
+use reqwest::{Certificate, Client, Identity, Proxy, Response, StatusCode};
 use std::error::Error;
 use std::fmt;
-use std::io::{copy, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File};
+use std::io::{self, copy, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The redirect limit when `PullOptions::max_redirects` isn't set, the same as reqwest's.
+const DEFAULT_MAX_REDIRECTS: usize = 10;
 
 #[derive(Debug)]
 struct VersionMismatch(u8);
@@ -23,11 +51,266 @@ impl Error for VersionMismatch {
   }
 }
 
-pub(crate) fn apply<R, W>(diff: &mut R, source: &mut R, dest: &mut W) -> Result<(), Box<dyn Error>>
-where
-  R: Read + Seek,
-  W: Write,
-{
+#[derive(Debug)]
+struct CopyPastEof {
+  offset: u64,
+  size: u64,
+  source_len: u64,
+}
+
+impl fmt::Display for CopyPastEof {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "copy of {} bytes at offset {} runs past the end of the source ({} bytes)",
+      self.size, self.offset, self.source_len
+    )
+  }
+}
+
+impl Error for CopyPastEof {}
+
+#[derive(Debug)]
+struct TruncatedInsert {
+  size: u64,
+  remaining: u64,
+}
+
+impl fmt::Display for TruncatedInsert {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "insert of {} bytes exceeds the {} bytes left in the diff",
+      self.size, self.remaining
+    )
+  }
+}
+
+impl Error for TruncatedInsert {}
+
+#[derive(Debug)]
+struct InsertChecksumMismatch {
+  /// The offset of the insert in the target.
+  position: u64,
+  size: u64,
+}
+
+impl fmt::Display for InsertChecksumMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "insert of {} bytes at offset {} of the target doesn't match its checksum",
+      self.size, self.position
+    )
+  }
+}
+
+impl Error for InsertChecksumMismatch {}
+
+#[derive(Debug)]
+struct TargetSizeMismatch {
+  written: u64,
+  target_size: u64,
+}
+
+impl fmt::Display for TargetSizeMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "diff produced {} bytes, but declares a target size of {}",
+      self.written, self.target_size
+    )
+  }
+}
+
+impl Error for TargetSizeMismatch {}
+
+#[derive(Debug)]
+struct CopyPastOutput {
+  offset: u64,
+  size: u64,
+  written: u64,
+}
+
+impl fmt::Display for CopyPastOutput {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "copy of {} bytes at offset {} runs past the {} bytes written to the output",
+      self.size, self.offset, self.written
+    )
+  }
+}
+
+impl Error for CopyPastOutput {}
+
+#[derive(Debug)]
+struct CopyPastPool {
+  offset: u64,
+  size: u64,
+  pool_len: u64,
+}
+
+impl fmt::Display for CopyPastPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "copy of {} bytes at offset {} runs past the end of the insert pool ({} bytes)",
+      self.size, self.offset, self.pool_len
+    )
+  }
+}
+
+impl Error for CopyPastPool {}
+
+#[derive(Debug)]
+struct MissingInsertPool;
+
+impl fmt::Display for MissingInsertPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "diff copies inserted data from an insert pool, which wasn't given"
+    )
+  }
+}
+
+impl Error for MissingInsertPool {}
+
+#[derive(Debug)]
+struct MissingChunkStore;
+
+impl fmt::Display for MissingChunkStore {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "diff copies source chunks by their hashes, which needs a chunk store"
+    )
+  }
+}
+
+impl Error for MissingChunkStore {}
+
+#[derive(Debug)]
+struct WritePastTarget {
+  size: u64,
+  written: u64,
+  target_size: u64,
+}
+
+impl fmt::Display for WritePastTarget {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "operation of {} bytes after {} bytes written runs past the target size of {}",
+      self.size, self.written, self.target_size
+    )
+  }
+}
+
+impl Error for WritePastTarget {}
+
+/// Returns the number of bytes written after an operation of `size` bytes, which may not exceed
+/// the `target_size` declared by the diff.
+fn advance(written: u64, size: u64, target_size: u64) -> Result<u64, WritePastTarget> {
+  written
+    .checked_add(size)
+    .filter(|end| *end <= target_size)
+    .ok_or(WritePastTarget {
+      size,
+      written,
+      target_size,
+    })
+}
+
+#[derive(Debug)]
+struct TooManyRedirects {
+  url: String,
+  limit: usize,
+}
+
+impl fmt::Display for TooManyRedirects {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "requesting {} exceeded the limit of {} redirects",
+      self.url, self.limit
+    )
+  }
+}
+
+impl Error for TooManyRedirects {}
+
+#[derive(Debug)]
+struct UnexpectedStatus {
+  url: String,
+  status: StatusCode,
+}
+
+impl fmt::Display for UnexpectedStatus {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "requesting {} failed with status {}",
+      self.url, self.status
+    )
+  }
+}
+
+impl Error for UnexpectedStatus {}
+
+#[derive(Debug)]
+struct RangeNotSatisfiable {
+  url: String,
+  range: String,
+  size: Option<u64>,
+}
+
+impl fmt::Display for RangeNotSatisfiable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "requested range bytes={} of {} is beyond the remote file size",
+      self.range, self.url
+    )?;
+    if let Some(size) = self.size {
+      write!(f, " of {} bytes", size)?;
+    }
+    write!(f, ", the diff is likely stale for this target")
+  }
+}
+
+impl Error for RangeNotSatisfiable {}
+
+#[derive(Debug)]
+struct RangesNotSupported {
+  url: String,
+}
+
+impl fmt::Display for RangesNotSupported {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "the server of {} doesn't support range requests",
+      self.url
+    )
+  }
+}
+
+impl Error for RangesNotSupported {}
+
+#[derive(Debug)]
+struct ZeroRateLimit;
+
+impl fmt::Display for ZeroRateLimit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "max_bytes_per_sec must be at least 1 byte per second")
+  }
+}
+
+impl Error for ZeroRateLimit {}
+
+fn check_version<R: Read>(diff: &mut R) -> Result<(), Box<dyn Error>> {
   let mut buf: [u8; 1] = [0; 1];
 
   diff.read_exact(&mut buf)?;
@@ -36,99 +319,4733 @@ where
     return Err(Box::new(VersionMismatch(buf[0])));
   }
 
-  let mut u64buf: [u8; 8] = [0; 8];
+  Ok(())
+}
 
-  loop {
-    if let Err(err) = diff.read_exact(&mut buf) {
-      if err.kind() == ErrorKind::UnexpectedEof {
-        break;
-      }
+#[derive(Debug)]
+struct NotStreamable;
 
-      return Err(Box::new(err));
-    }
+impl fmt::Display for NotStreamable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "compressed and signed diffs can't be applied from a stream"
+    )
+  }
+}
 
-    match buf[0].into() {
-      Operation::Copy => {
-        diff.read_exact(&mut u64buf)?;
-        let offset = u64::from_be_bytes(u64buf);
-        diff.read_exact(&mut u64buf)?;
-        let size = u64::from_be_bytes(u64buf);
+impl Error for NotStreamable {}
 
-        source.seek(SeekFrom::Start(offset))?;
-        let mut chunk = source.take(size);
-        copy(&mut chunk, dest)?;
-      }
-      Operation::Insert => {
-        diff.read_exact(&mut u64buf)?;
-        let size = u64::from_be_bytes(u64buf);
-        let mut chunk = diff.take(size);
-        copy(&mut chunk, dest)?;
-      }
+#[derive(Debug)]
+struct UnknownFlags(u8);
+
+impl fmt::Display for UnknownFlags {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "unknown diff header flags: {:#04x}", self.0)
+  }
+}
+
+impl Error for UnknownFlags {}
+
+#[derive(Debug)]
+struct UnsupportedCapabilities(u32);
+
+impl fmt::Display for UnsupportedCapabilities {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let features: Vec<String> = (0..32)
+      .map(|bit| 1 << bit)
+      .filter(|bit| self.0 & bit != 0)
+      .map(|bit| match capability_name(bit) {
+        Some(name) => name.to_string(),
+        None => format!("bit {}", bit.trailing_zeros()),
+      })
+      .collect();
+    write!(
+      f,
+      "diff uses features this version can't apply: {}",
+      features.join(", ")
+    )
+  }
+}
+
+impl Error for UnsupportedCapabilities {}
+
+#[derive(Debug)]
+struct MissingOperationTable;
+
+impl fmt::Display for MissingOperationTable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "diff has no operation table")
+  }
+}
+
+impl Error for MissingOperationTable {}
+
+#[derive(Debug)]
+struct MalformedOperationTable;
+
+impl fmt::Display for MalformedOperationTable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "malformed operation table")
+  }
+}
+
+impl Error for MalformedOperationTable {}
+
+#[derive(Debug)]
+struct MissingDictionary(u32);
+
+impl fmt::Display for MissingDictionary {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "diff is compressed with the dictionary {}", self.0)
+  }
+}
+
+impl Error for MissingDictionary {}
+
+#[derive(Debug)]
+struct DictionaryMismatch {
+  expected: u32,
+  actual: u32,
+}
+
+impl fmt::Display for DictionaryMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "diff is compressed with the dictionary {}, got the dictionary {}",
+      self.expected, self.actual
+    )
+  }
+}
+
+impl Error for DictionaryMismatch {}
+
+/// Where the header marks an optional feature as used.
+#[derive(Clone, Copy)]
+pub(crate) enum Mark {
+  Flag(u8),
+  /// A bit of the CAPABILITIES word, see `FLAG_CAPABILITIES`.
+  Capability(u32),
+}
+
+/// The optional features of the diff format this version applies, named like the `DiffOptions`
+/// using them, with their marks in the header.
+pub(crate) const FEATURES: [(&str, Mark); 8] = [
+  ("compress", Mark::Flag(FLAG_COMPRESSED)),
+  ("dictionary", Mark::Flag(FLAG_DICTIONARY)),
+  ("hmac", Mark::Flag(FLAG_HMAC_SHA256)),
+  ("operation_table", Mark::Flag(FLAG_OPERATION_TABLE)),
+  ("insert_pool", Mark::Flag(FLAG_INSERT_POOL)),
+  ("insert_checksums", Mark::Flag(FLAG_INSERT_CHECKSUMS)),
+  ("chunk_copies", Mark::Flag(FLAG_CHUNK_COPIES)),
+  ("long_window", Mark::Capability(CAPABILITY_LONG_WINDOW)),
+];
+
+/// The capability bits of the features this version applies.
+const CAPABILITIES: u32 = {
+  let mut capabilities = 0;
+  let mut i = 0;
+  while i < FEATURES.len() {
+    if let Mark::Capability(bit) = FEATURES[i].1 {
+      capabilities |= bit;
     }
+    i += 1;
   }
+  capabilities
+};
 
-  Ok(())
+/// Returns the name of the feature of the capability `bit`, if this version knows it.
+fn capability_name(bit: u32) -> Option<&'static str> {
+  FEATURES
+    .into_iter()
+    .find(|(_, mark)| matches!(mark, Mark::Capability(known) if *known == bit))
+    .map(|(name, _)| name)
 }
 
-/// Downloads missing diff chunks, stores them in a temporary file and uses them along with `source`
-/// to construct the new file.
-pub(crate) async fn apply_from_http<R, W>(
-  diff: Vec<(Operation, u64, u64)>,
-  uri: String,
-  source: &mut R,
-  dest: &mut W,
-) -> Result<(), Box<dyn Error>>
-where
-  R: Read + Seek,
-  W: Write,
-{
-  let remote_data = &mut tempfile::tempfile()?;
-  let mut byte_ranges = Vec::new();
+pub(crate) struct Header {
+  pub target_size: u64,
+  pub compressed: bool,
+  pub signed: bool,
+  /// The ID of the dictionary the operations are compressed with.
+  pub dictionary_id: Option<u32>,
+  /// The operations end with their table.
+  pub table: bool,
+  /// The operations copy from an insert pool.
+  pub pooled: bool,
+  /// Inserts have checksums.
+  pub checksums: bool,
+  /// Copies from the source name its chunks by their hashes.
+  pub chunk_copies: bool,
+  pub flags: u8,
+  /// The CAPABILITIES word, 0 without `FLAG_CAPABILITIES`.
+  pub capabilities: u32,
+}
 
-  for d in diff.iter() {
-    match d.0 {
-      Operation::Copy => {}
-      Operation::Insert => {
-        byte_ranges.push((d.1, d.1 + d.2 - 1));
-      }
+impl Header {
+  /// Returns the names of the optional features the diff uses, see `FEATURES`.
+  pub(crate) fn features(&self) -> Vec<&'static str> {
+    FEATURES
+      .into_iter()
+      .filter(|(_, mark)| match *mark {
+        Mark::Flag(flag) => self.flags & flag != 0,
+        Mark::Capability(bit) => self.capabilities & bit != 0,
+      })
+      .map(|(name, _)| name)
+      .collect()
+  }
+}
+
+pub(crate) fn read_header<R: Read>(diff: &mut R) -> Result<Header, Box<dyn Error>> {
+  check_version(diff)?;
+
+  let mut flags: [u8; 1] = [0; 1];
+  diff.read_exact(&mut flags)?;
+  let known = FLAG_COMPRESSED
+    | FLAG_HMAC_SHA256
+    | FLAG_DICTIONARY
+    | FLAG_OPERATION_TABLE
+    | FLAG_INSERT_POOL
+    | FLAG_INSERT_CHECKSUMS
+    | FLAG_CHUNK_COPIES
+    | FLAG_CAPABILITIES;
+  if flags[0] & !known != 0 || flags[0] & (FLAG_COMPRESSED | FLAG_DICTIONARY) == FLAG_DICTIONARY {
+    return Err(Box::new(UnknownFlags(flags[0])));
+  }
+
+  let mut u64buf: [u8; 8] = [0; 8];
+  diff.read_exact(&mut u64buf)?;
+
+  let capabilities = if flags[0] & FLAG_CAPABILITIES != 0 {
+    let mut u32buf: [u8; 4] = [0; 4];
+    diff.read_exact(&mut u32buf)?;
+    let capabilities = u32::from_be_bytes(u32buf);
+    if capabilities & !CAPABILITIES != 0 {
+      return Err(Box::new(UnsupportedCapabilities(
+        capabilities & !CAPABILITIES,
+      )));
     }
+    capabilities
+  } else {
+    0
+  };
+
+  let dictionary_id = if flags[0] & FLAG_DICTIONARY != 0 {
+    let mut u32buf: [u8; 4] = [0; 4];
+    diff.read_exact(&mut u32buf)?;
+    Some(u32::from_be_bytes(u32buf))
+  } else {
+    None
+  };
+
+  Ok(Header {
+    target_size: u64::from_be_bytes(u64buf),
+    compressed: flags[0] & FLAG_COMPRESSED != 0,
+    signed: flags[0] & FLAG_HMAC_SHA256 != 0,
+    dictionary_id,
+    table: flags[0] & FLAG_OPERATION_TABLE != 0,
+    pooled: flags[0] & FLAG_INSERT_POOL != 0,
+    checksums: flags[0] & FLAG_INSERT_CHECKSUMS != 0,
+    chunk_copies: flags[0] & FLAG_CHUNK_COPIES != 0,
+    flags: flags[0],
+    capabilities,
+  })
+}
+
+/// Fails for diffs with `FLAG_CHUNK_COPIES`, for readers of copies which need their offsets.
+fn check_copy_offsets(header: &Header) -> Result<(), MissingChunkStore> {
+  match header.chunk_copies {
+    true => Err(MissingChunkStore),
+    false => Ok(()),
   }
+}
 
-  let mut tasks = Vec::with_capacity(byte_ranges.len());
-  for (start, end) in byte_ranges {
-    let url = uri.clone();
-    let task = napi::tokio::task::spawn(async move {
-      Client::new()
-        .get(url)
-        .header(RANGE, format!("bytes={}-{}", start, end))
-        .send()
-        .await
-    });
-    tasks.push(task);
+type Checksum = [u8; CHECKSUM_LEN];
+
+/// Reads the checksum following the size of an insert of a diff with `header`, if it has them.
+fn read_checksum<R: Read>(ops: &mut R, header: &Header) -> Result<Option<Checksum>, io::Error> {
+  if !header.checksums {
+    return Ok(None);
   }
 
-  for task in tasks {
-    let mut response = task.await??;
-    while let Some(chunk) = response.chunk().await? {
-      remote_data.write_all(&chunk)?;
+  let mut checksum = [0; CHECKSUM_LEN];
+  ops.read_exact(&mut checksum)?;
+  Ok(Some(checksum))
+}
+
+/// Hashes the data of an insert, to check it against the `checksum` of the insert once it's
+/// hashed whole. Without a checksum nothing is hashed.
+struct InsertCheck {
+  hasher: Option<blake3::Hasher>,
+  checksum: Option<Checksum>,
+}
+
+impl InsertCheck {
+  fn new(checksum: Option<Checksum>) -> Self {
+    Self {
+      hasher: checksum.map(|_| blake3::Hasher::new()),
+      checksum,
     }
   }
 
-  remote_data.seek(SeekFrom::Start(0))?;
+  fn update(&mut self, data: &[u8]) {
+    if let Some(hasher) = self.hasher.as_mut() {
+      hasher.update(data);
+    }
+  }
 
-  for (op, offset, size) in diff {
-    match op {
-      Operation::Copy => {
-        source.seek(SeekFrom::Start(offset))?;
-        let mut chunk = source.take(size);
-        copy(&mut chunk, dest)?;
+  /// Checks the data hashed so far as the whole insert of `size` bytes at `position` of the
+  /// target.
+  fn verify(&self, position: u64, size: u64) -> Result<(), InsertChecksumMismatch> {
+    match (&self.hasher, self.checksum) {
+      (Some(hasher), Some(checksum))
+        if hasher.finalize().as_bytes()[..CHECKSUM_LEN] != checksum =>
+      {
+        Err(InsertChecksumMismatch { position, size })
       }
-      Operation::Insert => {
-        let mut chunk = remote_data.take(size);
-        copy(&mut chunk, dest)?;
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Hashes the data of an insert read through it, see `InsertCheck`.
+struct ChecksumReader<R> {
+  inner: R,
+  check: InsertCheck,
+}
+
+impl<R> ChecksumReader<R> {
+  fn new(inner: R, checksum: Option<Checksum>) -> Self {
+    Self {
+      inner,
+      check: InsertCheck::new(checksum),
+    }
+  }
+
+  fn verify(&self, position: u64, size: u64) -> Result<(), InsertChecksumMismatch> {
+    self.check.verify(position, size)
+  }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let len = self.inner.read(buf)?;
+    self.check.update(&buf[..len]);
+    Ok(len)
+  }
+}
+
+/// The operations of a diff following its header. Compressed operations are decompressed into a
+/// temporary file, so they can be read like the uncompressed ones.
+pub(crate) enum Operations<'a, R> {
+  Plain(&'a mut R),
+  Decompressed(File),
+}
+
+/// Returns the operations of `diff`, which is positioned right after the header, and the offset
+/// where they end. The HMAC tag of a signed diff and the operation table aren't a part of them.
+/// `dictionary` is only used when the header requires one.
+fn operations<'a, R: Read + Seek>(
+  diff: &'a mut R,
+  header: &Header,
+  dictionary: Option<&[u8]>,
+) -> Result<(Operations<'a, R>, u64), Box<dyn Error>> {
+  let start = diff.stream_position()?;
+  let mut end = diff.seek(SeekFrom::End(0))?;
+  if header.signed {
+    end = end.saturating_sub(TAG_LEN).max(start);
+  }
+  diff.seek(SeekFrom::Start(start))?;
+
+  if !header.compressed {
+    if header.table {
+      end = table_start(diff, start, end)?;
+    }
+    return Ok((Operations::Plain(diff), end));
+  }
+
+  let mut file = tempfile::tempfile()?;
+  let compressed = BufReader::new((&mut *diff).take(end - start));
+  let mut decoder = match header.dictionary_id {
+    Some(expected) => {
+      let dictionary = dictionary.ok_or(MissingDictionary(expected))?;
+      let actual = diff::dictionary_id(dictionary);
+      if expected != 0 && actual != expected {
+        return Err(Box::new(DictionaryMismatch { expected, actual }));
       }
+      zstd::stream::read::Decoder::with_dictionary(compressed, dictionary)?
     }
+    None => zstd::stream::read::Decoder::with_buffer(compressed)?,
+  };
+  if header.capabilities & CAPABILITY_LONG_WINDOW != 0 {
+    decoder.window_log_max(LONG_WINDOW_LOG)?;
   }
+  io::copy(&mut decoder, &mut file)?;
+  let mut end = file.stream_position()?;
+  if header.table {
+    end = table_start(&mut file, 0, end)?;
+  }
+  file.seek(SeekFrom::Start(0))?;
 
-  Ok(())
+  Ok((Operations::Decompressed(file), end))
+}
+
+/// Returns the offset of the operation table in `ops`, which starts at `start` and ends with the
+/// table at `end`, and seeks back to `start`.
+fn table_start<R: Read + Seek>(ops: &mut R, start: u64, end: u64) -> Result<u64, Box<dyn Error>> {
+  let count_offset = end
+    .checked_sub(8)
+    .filter(|&offset| offset >= start)
+    .ok_or(MalformedOperationTable)?;
+  ops.seek(SeekFrom::Start(count_offset))?;
+  let mut u64buf: [u8; 8] = [0; 8];
+  ops.read_exact(&mut u64buf)?;
+
+  let table_start = u64::from_be_bytes(u64buf)
+    .checked_mul(TABLE_ENTRY_LEN)
+    .and_then(|len| count_offset.checked_sub(len))
+    .filter(|&offset| offset >= start)
+    .ok_or(MalformedOperationTable)?;
+  ops.seek(SeekFrom::Start(start))?;
+
+  Ok(table_start)
+}
+
+/// Reads every operation of `diff` and returns the target size of the header, the operations
+/// without any zero-size ones, and the operations stream which the offsets of inserts point into.
+/// They're checked like `apply` checks them, except for copies from the source or the insert pool
+/// which can't be checked without them. `dictionary` is only used when the header requires one.
+pub(crate) fn read_operations<'a, R: Read + Seek>(
+  diff: &'a mut R,
+  dictionary: Option<&[u8]>,
+) -> Result<(u64, DiffOps, Operations<'a, R>), Box<dyn Error>> {
+  diff.seek(SeekFrom::Start(0))?;
+  let header = read_header(diff)?;
+  check_copy_offsets(&header)?;
+  let target_size = header.target_size;
+  let (mut ops, end) = operations(diff, &header, dictionary)?;
+
+  let mut buf: [u8; 1] = [0; 1];
+  let mut u64buf: [u8; 8] = [0; 8];
+  let mut written = 0;
+  let mut entries = Vec::new();
+  // Zero-size operations write nothing, older versions wrote one for empty targets.
+  while ops.stream_position()? < end {
+    ops.read_exact(&mut buf)?;
+
+    match Operation::try_from(buf[0])? {
+      Operation::Insert => {
+        ops.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        read_checksum(&mut ops, &header)?;
+        if size == 0 {
+          continue;
+        }
+
+        let position = ops.stream_position()?;
+        let remaining = end.saturating_sub(position);
+        if size > remaining {
+          return Err(Box::new(TruncatedInsert { size, remaining }));
+        }
+
+        ops.seek(SeekFrom::Current(size as i64))?;
+        written = advance(written, size, target_size)?;
+        entries.push((Operation::Insert, position, size));
+      }
+      op => {
+        ops.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        ops.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if op == Operation::CopyOutput && offset.checked_add(size).is_none_or(|end| end > written) {
+          return Err(Box::new(CopyPastOutput {
+            offset,
+            size,
+            written,
+          }));
+        }
+
+        written = advance(written, size, target_size)?;
+        entries.push((op, offset, size));
+      }
+    }
+  }
+
+  if written != target_size {
+    return Err(Box::new(TargetSizeMismatch {
+      written,
+      target_size,
+    }));
+  }
+
+  Ok((target_size, entries, ops))
+}
+
+/// Checks every operation of `diff` like `apply` does, without reading or writing any data, and
+/// returns the target size of the header and the report `apply` would return. Copies must stay
+/// within the `source_len` when it's known, and the operations must write exactly the target size.
+/// Copies from an insert pool can't be checked without it.
+pub(crate) fn validate<R: Read + Seek>(
+  diff: &mut R,
+  source_len: Option<u64>,
+) -> Result<(u64, ApplyReport), Box<dyn Error>> {
+  let (target_size, ops, _) = read_operations(diff, None)?;
+
+  let mut report = ApplyReport::default();
+  for (op, offset, size) in ops {
+    match op {
+      Operation::Copy => {
+        if let Some(source_len) = source_len {
+          if offset.checked_add(size).is_none_or(|end| end > source_len) {
+            return Err(Box::new(CopyPastEof {
+              offset,
+              size,
+              source_len,
+            }));
+          }
+        }
+        report.copy_ops += 1;
+      }
+      Operation::CopyOutput => report.copy_ops += 1,
+      Operation::Insert | Operation::CopyPool => report.insert_ops += 1,
+    }
+  }
+  report.bytes_written = target_size as i64;
+
+  Ok((target_size, report))
+}
+
+/// Returns the regions of the result which `diff` copies from, in order and merged where they
+/// overlap or touch, so a destination which can't be read back only needs to keep them.
+pub(crate) fn output_reads<R: Read + Seek>(
+  diff: &mut R,
+  dictionary: Option<&[u8]>,
+) -> Result<Vec<(u64, u64)>, Box<dyn Error>> {
+  diff.seek(SeekFrom::Start(0))?;
+  let header = read_header(diff)?;
+  let (mut ops, end) = operations(diff, &header, dictionary)?;
+
+  let mut buf: [u8; 1] = [0; 1];
+  let mut u64buf: [u8; 8] = [0; 8];
+  let mut reads = Vec::new();
+  while ops.stream_position()? < end {
+    ops.read_exact(&mut buf)?;
+    match Operation::try_from(buf[0])? {
+      Operation::Copy if header.chunk_copies => {
+        ops.seek(SeekFrom::Current(40))?;
+      }
+      Operation::Copy | Operation::CopyPool => {
+        ops.seek(SeekFrom::Current(16))?;
+      }
+      Operation::Insert => {
+        ops.read_exact(&mut u64buf)?;
+        read_checksum(&mut ops, &header)?;
+        ops.seek(SeekFrom::Current(u64::from_be_bytes(u64buf) as i64))?;
+      }
+      Operation::CopyOutput => {
+        ops.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        ops.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size > 0 {
+          reads.push((offset, offset.saturating_add(size)));
+        }
+      }
+    }
+  }
+
+  reads.sort_unstable();
+  let mut merged: Vec<(u64, u64)> = Vec::new();
+  for (start, end) in reads {
+    match merged.last_mut() {
+      Some((_, last_end)) if start <= *last_end => *last_end = end.max(*last_end),
+      _ => merged.push((start, end)),
+    }
+  }
+
+  Ok(
+    merged
+      .into_iter()
+      .map(|(start, end)| (start, end - start))
+      .collect(),
+  )
+}
+
+/// Returns the kind, position, offset and size of every operation from the operation table of
+/// `diff`, see `diff::serialize_operations`. Positions are from the start of the operations,
+/// which follow the header in uncompressed diffs.
+pub(crate) fn read_operation_table<R: Read + Seek>(
+  diff: &mut R,
+  dictionary: Option<&[u8]>,
+) -> Result<OperationTable, Box<dyn Error>> {
+  diff.seek(SeekFrom::Start(0))?;
+  let header = read_header(diff)?;
+  if !header.table {
+    return Err(Box::new(MissingOperationTable));
+  }
+
+  // Reads the operations with the table, to find it.
+  let (mut ops, end) = operations(
+    diff,
+    &Header {
+      table: false,
+      ..header
+    },
+    dictionary,
+  )?;
+  let start = ops.stream_position()?;
+  let table = table_start(&mut ops, start, end)?;
+  ops.seek(SeekFrom::Start(table))?;
+
+  let count = (end - 8 - table) / TABLE_ENTRY_LEN;
+  let mut entries = Vec::with_capacity(usize_from(count)?);
+  let mut entry = [0; TABLE_ENTRY_LEN as usize];
+  for _ in 0..count {
+    ops.read_exact(&mut entry)?;
+    let kind = Operation::try_from(entry[0]).map_err(|_| MalformedOperationTable)?;
+    entries.push((
+      kind,
+      u64::from_be_bytes(*array_ref![entry, 1, 8]),
+      u64::from_be_bytes(*array_ref![entry, 9, 8]),
+      u64::from_be_bytes(*array_ref![entry, 17, 8]),
+    ));
+  }
+
+  Ok(entries)
+}
+
+impl<R: Read> Read for Operations<'_, R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Operations::Plain(diff) => diff.read(buf),
+      Operations::Decompressed(file) => file.read(buf),
+    }
+  }
+}
+
+impl<R: Seek> Seek for Operations<'_, R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    match self {
+      Operations::Plain(diff) => diff.seek(pos),
+      Operations::Decompressed(file) => file.seek(pos),
+    }
+  }
+}
+
+/// A destination that can reserve space for the whole reconstructed file up front.
+pub(crate) trait Preallocate {
+  fn preallocate(&mut self, size: u64) -> Result<(), io::Error>;
+}
+
+impl Preallocate for File {
+  /// Allocates the file blocks where the platform supports it, so large files aren't fragmented
+  /// and running out of space fails early, and sets the file length to `size`.
+  fn preallocate(&mut self, size: u64) -> Result<(), io::Error> {
+    #[cfg(target_os = "linux")]
+    if size > 0 {
+      use std::os::unix::io::AsRawFd;
+
+      // Filesystems without fallocate support fall back to set_len below.
+      let len = libc::off_t::try_from(size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file size exceeds off_t"))?;
+      if unsafe { libc::fallocate(self.as_raw_fd(), 0, 0, len) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+          return Err(err);
+        }
+      }
+    }
+
+    self.set_len(size)
+  }
+}
+
+impl Preallocate for Cursor<Vec<u8>> {
+  fn preallocate(&mut self, size: u64) -> Result<(), io::Error> {
+    self
+      .get_mut()
+      .try_reserve(usize_from(size)?)
+      .map_err(|err| io::Error::new(io::ErrorKind::OutOfMemory, err))
+  }
+}
+
+/// Preallocates the result as the operations reach into it, doubling the allocation up to the
+/// target size. The target size comes from the header, so a small diff claiming a huge target
+/// can only reserve about twice what its operations actually write.
+struct Allocation {
+  allocated: u64,
+  target_size: u64,
+}
+
+impl Allocation {
+  /// Starts from the current length of `dest`, which isn't shrunk before all operations are
+  /// written, since results applied to existing files keep their unchanged data.
+  fn new<W: Seek>(dest: &mut W, target_size: u64) -> Result<Self, io::Error> {
+    let allocated = dest.seek(SeekFrom::End(0))?;
+    dest.seek(SeekFrom::Start(0))?;
+
+    Ok(Self {
+      allocated,
+      target_size,
+    })
+  }
+
+  /// Preallocates `dest` up to at least `end`, which is checked against the target size.
+  fn reserve<W: Preallocate>(&mut self, dest: &mut W, end: u64) -> Result<(), io::Error> {
+    if end > self.allocated {
+      self.allocated = end
+        .max(self.allocated.saturating_mul(2))
+        .min(self.target_size);
+      dest.preallocate(self.allocated)?;
+    }
+
+    Ok(())
+  }
+
+  /// Sets the length of `dest` to the target size once it's written whole.
+  fn finish<W: Preallocate>(self, dest: &mut W) -> Result<(), io::Error> {
+    if self.allocated != self.target_size {
+      dest.preallocate(self.target_size)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Copies `size` bytes of `file` from the offset `from` to the offset `to` through `block`. The
+/// regions may only overlap when `from` is past `to`.
+fn copy_within<F>(
+  file: &mut F,
+  from: u64,
+  to: u64,
+  size: u64,
+  block: &mut [u8],
+) -> Result<(), io::Error>
+where
+  F: Read + Write + Seek,
+{
+  let mut moved = 0;
+  while moved < size {
+    let len = (size - moved).min(block.len() as u64) as usize;
+    file.seek(SeekFrom::Start(from + moved))?;
+    file.read_exact(&mut block[..len])?;
+    file.seek(SeekFrom::Start(to + moved))?;
+    file.write_all(&block[..len])?;
+    moved += len as u64;
+  }
+
+  Ok(())
+}
+
+#[derive(Debug)]
+struct ShortInserts {
+  offset: u64,
+  size: u64,
+}
+
+impl fmt::Display for ShortInserts {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "inserts file is missing data for the insert of {} bytes at offset {}",
+      self.size, self.offset
+    )
+  }
+}
+
+impl Error for ShortInserts {}
+
+#[derive(Debug)]
+struct CheckpointMismatch {
+  diff_len: u64,
+  checkpoint_diff_len: u64,
+}
+
+impl fmt::Display for CheckpointMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "checkpoint was saved for operations of {} bytes, but the diff has {} bytes of them",
+      self.checkpoint_diff_len, self.diff_len
+    )
+  }
+}
+
+impl Error for CheckpointMismatch {}
+
+/// A sidecar file where the apply periodically records how far it got, so an interrupted apply
+/// can be resumed instead of restarted. Operations are applied in order and their sizes are
+/// known, so the position in the operations is enough to continue. All integers are big-endian:
+///
+/// DIFF LEN(u64) - the end of the operations, telling apart checkpoints of other diffs
+/// POSITION(u64) - the offset of the next operation
+/// WRITTEN(u64) - the bytes of the result written before it
+/// COPY OPS(u64), INSERT OPS(u64) - the operations applied before it
+pub(crate) struct Checkpoint<'a> {
+  pub path: &'a Path,
+  /// Continues from the saved checkpoint, if there's one, instead of starting over.
+  pub resume: bool,
+}
+
+const CHECKPOINT_LEN: usize = 40;
+
+/// Where a saved checkpoint continues from.
+struct Resumed {
+  position: u64,
+  written: u64,
+  report: ApplyReport,
+}
+
+impl Checkpoint<'_> {
+  /// Returns the saved checkpoint, or `None` when there's nothing to resume.
+  fn load(&self, diff_len: u64) -> Result<Option<Resumed>, Box<dyn Error>> {
+    if !self.resume {
+      return Ok(None);
+    }
+    let data = match fs::read(self.path) {
+      Ok(data) => data,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(Box::new(err)),
+    };
+    if data.len() != CHECKPOINT_LEN {
+      return Err(Box::new(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed checkpoint",
+      )));
+    }
+
+    let values: Vec<u64> = data
+      .chunks(8)
+      .map(|value| u64::from_be_bytes(*array_ref![value, 0, 8]))
+      .collect();
+    if values[0] != diff_len {
+      return Err(Box::new(CheckpointMismatch {
+        diff_len,
+        checkpoint_diff_len: values[0],
+      }));
+    }
+
+    Ok(Some(Resumed {
+      position: values[1],
+      written: values[2],
+      report: ApplyReport {
+        copy_ops: u32::try_from(values[3])?,
+        insert_ops: u32::try_from(values[4])?,
+        ..Default::default()
+      },
+    }))
+  }
+
+  fn save(
+    &self,
+    diff_len: u64,
+    position: u64,
+    written: u64,
+    report: &ApplyReport,
+  ) -> Result<(), io::Error> {
+    let mut data = Vec::with_capacity(CHECKPOINT_LEN);
+    for value in [
+      diff_len,
+      position,
+      written,
+      report.copy_ops.into(),
+      report.insert_ops.into(),
+    ] {
+      data.extend_from_slice(&value.to_be_bytes());
+    }
+    fs::write(self.path, data)
+  }
+}
+
+pub(crate) fn apply<D, S, W>(
+  diff: &mut D,
+  source: &mut S,
+  dest: &mut W,
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  apply_ops(diff, source, None::<&mut S>, dest, ApplyExtras::default())
+}
+
+/// What an apply may need besides the diff, the source and the destination.
+#[derive(Default)]
+pub(crate) struct ApplyExtras<'a> {
+  /// Called with the bytes written so far and the target size after operations, at most about a
+  /// hundred times. The last call reports the whole target.
+  pub progress: Option<&'a mut dyn FnMut(u64, u64)>,
+  /// The zstd dictionary the diff was compressed with.
+  pub dictionary: Option<&'a [u8]>,
+  /// Saved about every percent of the result and removed once the result is complete. The data
+  /// of the result is flushed before every save, so a resumed apply only rewrites the operations
+  /// after it.
+  pub checkpoint: Option<&'a Checkpoint<'a>>,
+  /// The insert pool the diff was written with.
+  pub pool: Option<&'a mut File>,
+  /// The chunk store copies read from when they name the source chunks by their hashes.
+  pub chunks: Option<&'a ChunkStore>,
+  /// Whether the destination already holds the data of the source at the same offsets, e.g. it's
+  /// an earlier copy of the source, so copies reading from the offsets they write to are skipped
+  /// instead of written.
+  pub existing: bool,
+}
+
+/// Applies `diff` like `apply` with the `extras`.
+pub(crate) fn apply_with<D, S, W>(
+  diff: &mut D,
+  source: &mut S,
+  dest: &mut W,
+  extras: ApplyExtras,
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  apply_ops(diff, source, None::<&mut S>, dest, extras)
+}
+
+/// Applies `diff` like `apply`, but reads the inserted data from `inserts`, at the offsets where
+/// it's placed in the target. The inserts of `diff` only have their sizes, without any data.
+pub(crate) fn apply_with_local_inserts<D, S, I, W>(
+  diff: &mut D,
+  source: &mut S,
+  inserts: &mut I,
+  dest: &mut W,
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  I: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  apply_ops(diff, source, Some(inserts), dest, ApplyExtras::default())
+}
+
+/// Applies `diff` like `apply`, calling `progress` with the bytes written so far and the target
+/// size after operations, at most about a hundred times. The last call reports the whole target.
+pub(crate) fn apply_with_progress<D, S, W>(
+  diff: &mut D,
+  source: &mut S,
+  dest: &mut W,
+  progress: &mut dyn FnMut(u64, u64),
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  apply_ops(
+    diff,
+    source,
+    None::<&mut S>,
+    dest,
+    ApplyExtras {
+      progress: Some(progress),
+      ..Default::default()
+    },
+  )
+}
+
+fn apply_ops<D, S, I, W>(
+  diff: &mut D,
+  source: &mut S,
+  mut inserts: Option<&mut I>,
+  dest: &mut W,
+  extras: ApplyExtras,
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  I: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  let source_len = source.seek(SeekFrom::End(0))?;
+  diff.seek(SeekFrom::Start(0))?;
+
+  let header = read_header(diff)?;
+  let target_size = header.target_size;
+  let ApplyExtras {
+    mut progress,
+    dictionary,
+    checkpoint,
+    mut pool,
+    chunks,
+    existing,
+  } = extras;
+  let (mut diff, diff_len) = operations(diff, &header, dictionary)?;
+  let pool_len = match pool.as_mut() {
+    Some(pool) => pool.seek(SeekFrom::End(0))?,
+    None if header.pooled => return Err(Box::new(MissingInsertPool)),
+    None => 0,
+  };
+  if chunks.is_none() {
+    check_copy_offsets(&header)?;
+  }
+
+  let mut buf: [u8; 1] = [0; 1];
+  let mut u64buf: [u8; 8] = [0; 8];
+  let mut hash: [u8; 32] = [0; 32];
+  let mut allocation = Allocation::new(dest, target_size)?;
+
+  let mut written = 0;
+  let mut report = ApplyReport::default();
+  if let Some(checkpoint) = checkpoint {
+    if let Some(resumed) = checkpoint.load(diff_len)? {
+      if resumed.position > diff_len || resumed.written > target_size {
+        return Err(Box::new(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "malformed checkpoint",
+        )));
+      }
+      diff.seek(SeekFrom::Start(resumed.position))?;
+      dest.seek(SeekFrom::Start(resumed.written))?;
+      written = resumed.written;
+      report = resumed.report;
+    }
+  }
+
+  let progress_step = (target_size / 100).max(1);
+  let mut reported = written;
+  let mut checkpointed = written;
+  let mut block = vec![0; DEFAULT_MAX_SIZE as usize];
+  #[cfg(feature = "tracing")]
+  let _span = tracing::debug_span!("apply", target_size, resumed_at = written).entered();
+
+  // Zero-size operations write nothing, older versions wrote one for empty targets.
+  while diff.stream_position()? < diff_len {
+    diff.read_exact(&mut buf)?;
+
+    match Operation::try_from(buf[0])? {
+      Operation::Copy if header.chunk_copies => {
+        diff.read_exact(&mut hash)?;
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        let hash = blake3::Hash::from_bytes(hash);
+        event!(trace, %hash, size, "copy from the chunk store");
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        let chunks = chunks.ok_or(MissingChunkStore)?;
+        let mut chunk = chunks.open(&hash, size)?.take(size);
+        written += copy(&mut chunk, dest)?;
+        report.copy_ops += 1;
+      }
+      Operation::Copy => {
+        diff.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if offset.checked_add(size).is_none_or(|end| end > source_len) {
+          return Err(Box::new(CopyPastEof {
+            offset,
+            size,
+            source_len,
+          }));
+        }
+
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        if existing && offset == written {
+          event!(trace, offset, size, "skip an unchanged copy");
+          dest.seek(SeekFrom::Start(end))?;
+          written = end;
+        } else {
+          event!(trace, offset, size, "copy");
+          source.seek(SeekFrom::Start(offset))?;
+          let mut chunk = source.take(size);
+          written += copy(&mut chunk, dest)?;
+        }
+        report.copy_ops += 1;
+      }
+      Operation::Insert => {
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        let checksum = read_checksum(&mut diff, &header)?;
+        if size == 0 {
+          continue;
+        }
+
+        event!(trace, size, "insert");
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        match inserts.as_mut() {
+          // The diff only has the sizes of the inserts, their data is all in `inserts`.
+          Some(inserts) => {
+            inserts.seek(SeekFrom::Start(written))?;
+            let mut chunk = ChecksumReader::new(inserts.take(size), checksum);
+            let copied = copy(&mut chunk, dest)?;
+            if copied != size {
+              return Err(Box::new(ShortInserts {
+                offset: written,
+                size,
+              }));
+            }
+            chunk.verify(written, size)?;
+            written += copied;
+          }
+          None => {
+            // The size may be read past the end of the operations, from the table or the tag.
+            let remaining = diff_len.saturating_sub(diff.stream_position()?);
+            if size > remaining {
+              return Err(Box::new(TruncatedInsert { size, remaining }));
+            }
+
+            let mut chunk = ChecksumReader::new(diff.by_ref().take(size), checksum);
+            copy(&mut chunk, dest)?;
+            chunk.verify(written, size)?;
+            written += size;
+          }
+        }
+        report.insert_ops += 1;
+      }
+      Operation::CopyOutput => {
+        diff.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if offset.checked_add(size).is_none_or(|end| end > written) {
+          return Err(Box::new(CopyPastOutput {
+            offset,
+            size,
+            written,
+          }));
+        }
+
+        event!(trace, offset, size, "copy from the output");
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        copy_within(dest, offset, written, size, &mut block)?;
+        written = end;
+        report.copy_ops += 1;
+      }
+      Operation::CopyPool => {
+        diff.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        let pool = pool.as_mut().ok_or(MissingInsertPool)?;
+        if offset.checked_add(size).is_none_or(|end| end > pool_len) {
+          return Err(Box::new(CopyPastPool {
+            offset,
+            size,
+            pool_len,
+          }));
+        }
+
+        event!(trace, offset, size, "copy from the insert pool");
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        pool.seek(SeekFrom::Start(offset))?;
+        let mut chunk = pool.take(size);
+        written += copy(&mut chunk, dest)?;
+        report.insert_ops += 1;
+      }
+    }
+
+    if let Some(progress) = progress.as_mut() {
+      if written - reported >= progress_step && written < target_size {
+        progress(written, target_size);
+        reported = written;
+      }
+    }
+
+    if let Some(checkpoint) = checkpoint {
+      if written - checkpointed >= progress_step && written < target_size {
+        dest.flush()?;
+        checkpoint.save(diff_len, diff.stream_position()?, written, &report)?;
+        checkpointed = written;
+      }
+    }
+  }
+
+  if written != target_size {
+    return Err(Box::new(TargetSizeMismatch {
+      written,
+      target_size,
+    }));
+  }
+  allocation.finish(dest)?;
+
+  if let Some(progress) = progress.as_mut() {
+    progress(written, target_size);
+  }
+  if let Some(checkpoint) = checkpoint {
+    dest.flush()?;
+    match fs::remove_file(checkpoint.path) {
+      Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(Box::new(err)),
+      _ => (),
+    }
+  }
+  report.bytes_written = written as i64;
+  event!(
+    debug,
+    copy_ops = report.copy_ops,
+    insert_ops = report.insert_ops,
+    bytes_written = written,
+    "applied"
+  );
+
+  Ok(report)
+}
+
+/// Applies the `diff` read front to back from a stream, e.g. an HTTP response body, as it arrives.
+///
+/// Copies are read from the `source` and inserts are copied from the stream block by block, so
+/// the diff is never buffered. Compressed and signed diffs need the whole diff to be decoded or
+/// checked first, so they're rejected.
+pub(crate) async fn apply_streaming<D, S, W>(
+  diff: &mut D,
+  source: &mut S,
+  dest: &mut W,
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  D: AsyncRead + Unpin,
+  S: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  let source_len = source.seek(SeekFrom::End(0))?;
+
+  // VERSION, FLAGS and TARGET SIZE, CAPABILITIES only follows `FLAG_CAPABILITIES` and the
+  // dictionary ID only compressed flags.
+  let mut header = vec![0; 10];
+  diff.read_exact(&mut header).await?;
+  if header[1] & (FLAG_COMPRESSED | FLAG_HMAC_SHA256) != 0 {
+    return Err(Box::new(NotStreamable));
+  }
+  if header[1] & FLAG_CAPABILITIES != 0 {
+    header.resize(14, 0);
+    diff.read_exact(&mut header[10..]).await?;
+  }
+  let header = read_header(&mut &header[..])?;
+  check_copy_offsets(&header)?;
+  let target_size = header.target_size;
+
+  let mut buf: [u8; 1] = [0; 1];
+  let mut u64buf: [u8; 8] = [0; 8];
+  let mut allocation = Allocation::new(dest, target_size)?;
+
+  let mut written = 0;
+  let mut report = ApplyReport::default();
+  let mut block = vec![0; DEFAULT_MAX_SIZE as usize];
+
+  // Zero-size operations write nothing, older versions wrote one for empty targets.
+  // The operation table after the last operation isn't needed.
+  while !(header.table && written == target_size) && diff.read(&mut buf).await? != 0 {
+    match Operation::try_from(buf[0])? {
+      Operation::Copy => {
+        diff.read_exact(&mut u64buf).await?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf).await?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if offset.checked_add(size).is_none_or(|end| end > source_len) {
+          return Err(Box::new(CopyPastEof {
+            offset,
+            size,
+            source_len,
+          }));
+        }
+
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        source.seek(SeekFrom::Start(offset))?;
+        let mut chunk = source.take(size);
+        written += copy(&mut chunk, dest)?;
+        report.copy_ops += 1;
+      }
+      Operation::Insert => {
+        diff.read_exact(&mut u64buf).await?;
+        let size = u64::from_be_bytes(u64buf);
+        let mut checksum = [0; CHECKSUM_LEN];
+        if header.checksums {
+          diff.read_exact(&mut checksum).await?;
+        }
+        if size == 0 {
+          continue;
+        }
+
+        let position = written;
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        let mut check = InsertCheck::new(header.checksums.then_some(checksum));
+        while written < end {
+          let len = (end - written).min(block.len() as u64) as usize;
+          diff.read_exact(&mut block[..len]).await?;
+          check.update(&block[..len]);
+          dest.write_all(&block[..len])?;
+          written += len as u64;
+        }
+        check.verify(position, size)?;
+        report.insert_ops += 1;
+      }
+      Operation::CopyOutput => {
+        diff.read_exact(&mut u64buf).await?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf).await?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if offset.checked_add(size).is_none_or(|end| end > written) {
+          return Err(Box::new(CopyPastOutput {
+            offset,
+            size,
+            written,
+          }));
+        }
+
+        let end = advance(written, size, target_size)?;
+        allocation.reserve(dest, end)?;
+        copy_within(dest, offset, written, size, &mut block)?;
+        written = end;
+        report.copy_ops += 1;
+      }
+      Operation::CopyPool => return Err(Box::new(MissingInsertPool)),
+    }
+  }
+
+  if written != target_size {
+    return Err(Box::new(TargetSizeMismatch {
+      written,
+      target_size,
+    }));
+  }
+  allocation.finish(dest)?;
+  report.bytes_written = written as i64;
+
+  Ok(report)
+}
+
+/// Applies `diff` to `file` in place, so no space is needed for a separate result.
+///
+/// Operations are written front to back, so when an operation is about to be written at the
+/// position `pos`, only the region `[0, pos)` of the original data has been overwritten, and
+/// copies from the output read from there. Copies reading at or after `pos` are safe: the data is
+/// moved forward in blocks, each block being read before anything past it is written. Copies
+/// reading before `pos` would see already overwritten data, so their source regions are read into
+/// an in-memory staging buffer before the first write. Diffs that reorder large parts of the file
+/// may therefore need a lot of memory.
+pub(crate) fn apply_in_place<R: Read + Seek>(
+  diff: &mut R,
+  file: &mut File,
+) -> Result<(), Box<dyn Error>> {
+  let source_len = file.seek(SeekFrom::End(0))?;
+  diff.seek(SeekFrom::Start(0))?;
+
+  let header = read_header(diff)?;
+  check_copy_offsets(&header)?;
+  let target_size = header.target_size;
+  let (mut diff, diff_len) = operations(diff, &header, None)?;
+
+  let mut buf: [u8; 1] = [0; 1];
+  let mut u64buf: [u8; 8] = [0; 8];
+
+  // (operation, offset in the source, the diff or the output, size)
+  let mut ops: Vec<(Operation, u64, u64)> = Vec::new();
+  let mut written = 0;
+  // Zero-size operations write nothing, older versions wrote one for empty targets.
+  while diff.stream_position()? < diff_len {
+    diff.read_exact(&mut buf)?;
+
+    match Operation::try_from(buf[0])? {
+      Operation::Copy => {
+        diff.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if offset.checked_add(size).is_none_or(|end| end > source_len) {
+          return Err(Box::new(CopyPastEof {
+            offset,
+            size,
+            source_len,
+          }));
+        }
+
+        ops.push((Operation::Copy, offset, size));
+        written = advance(written, size, target_size)?;
+      }
+      Operation::Insert => {
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        let checksum = read_checksum(&mut diff, &header)?;
+        if size == 0 {
+          continue;
+        }
+
+        let offset = diff.stream_position()?;
+        let remaining = diff_len.saturating_sub(offset);
+        if size > remaining {
+          return Err(Box::new(TruncatedInsert { size, remaining }));
+        }
+
+        // Inserts are checked before anything is overwritten, so a corrupted diff leaves the file
+        // as it was.
+        if checksum.is_some() {
+          let mut chunk = ChecksumReader::new(diff.by_ref().take(size), checksum);
+          io::copy(&mut chunk, &mut io::sink())?;
+          chunk.verify(written, size)?;
+        } else {
+          diff.seek(SeekFrom::Current(size as i64))?;
+        }
+        ops.push((Operation::Insert, offset, size));
+        written = advance(written, size, target_size)?;
+      }
+      Operation::CopyOutput => {
+        diff.read_exact(&mut u64buf)?;
+        let offset = u64::from_be_bytes(u64buf);
+        diff.read_exact(&mut u64buf)?;
+        let size = u64::from_be_bytes(u64buf);
+        if size == 0 {
+          continue;
+        }
+
+        if offset.checked_add(size).is_none_or(|end| end > written) {
+          return Err(Box::new(CopyPastOutput {
+            offset,
+            size,
+            written,
+          }));
+        }
+
+        ops.push((Operation::CopyOutput, offset, size));
+        written = advance(written, size, target_size)?;
+      }
+      Operation::CopyPool => return Err(Box::new(MissingInsertPool)),
+    }
+  }
+
+  if written != target_size {
+    return Err(Box::new(TargetSizeMismatch {
+      written,
+      target_size,
+    }));
+  }
+
+  // Stage the regions which are overwritten before they are copied.
+  let mut staging = Vec::new();
+  let mut pos = 0;
+  for (op, offset, size) in ops.iter() {
+    if *op == Operation::Copy && *offset < pos {
+      file.seek(SeekFrom::Start(*offset))?;
+      file.take(*size).read_to_end(&mut staging)?;
+    }
+    pos += size;
+  }
+
+  let mut block = vec![0; DEFAULT_MAX_SIZE as usize];
+  let mut staged = &staging[..];
+  let mut pos = 0;
+  for (op, offset, size) in ops {
+    match op {
+      Operation::Copy if offset < pos => {
+        let (chunk, rest) = staged.split_at(usize_from(size)?);
+        file.seek(SeekFrom::Start(pos))?;
+        file.write_all(chunk)?;
+        staged = rest;
+      }
+      Operation::Copy => {
+        if offset != pos {
+          copy_within(file, offset, pos, size, &mut block)?;
+        }
+      }
+      Operation::CopyOutput => {
+        copy_within(file, offset, pos, size, &mut block)?;
+      }
+      Operation::Insert => {
+        diff.seek(SeekFrom::Start(offset))?;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut chunk = diff.by_ref().take(size);
+        copy(&mut chunk, file)?;
+      }
+      Operation::CopyPool => unreachable!("pool copies are rejected above"),
+    }
+    pos += size;
+  }
+
+  file.set_len(target_size)?;
+
+  Ok(())
+}
+
+/// Applies `diffs` one after another starting from `base` and writes the final result to `dest`.
+/// Intermediate results are stored in temporary files which are removed once dropped.
+pub(crate) fn apply_chain<W>(
+  diffs: &mut [File],
+  base: &mut File,
+  dest: &mut W,
+) -> Result<(), Box<dyn Error>>
+where
+  W: Read + Write + Seek + Preallocate,
+{
+  for diff in diffs.iter_mut() {
+    diff.seek(SeekFrom::Start(0))?;
+    check_version(diff)?;
+  }
+
+  let Some((last, rest)) = diffs.split_last_mut() else {
+    base.seek(SeekFrom::Start(0))?;
+    copy(base, dest)?;
+    return Ok(());
+  };
+
+  let mut intermediate: Option<File> = None;
+  for diff in rest {
+    let mut next = tempfile::tempfile()?;
+    match intermediate.as_mut() {
+      Some(source) => apply(diff, source, &mut next)?,
+      None => apply(diff, base, &mut next)?,
+    };
+    intermediate = Some(next);
+  }
+
+  match intermediate.as_mut() {
+    Some(source) => apply(last, source, dest)?,
+    None => apply(last, base, dest)?,
+  };
+
+  Ok(())
+}
+
+/// A created destination file which is removed when dropped before `commit`, so a failed apply
+/// doesn't leave a partial result behind.
+pub(crate) struct Destination {
+  file: File,
+  path: PathBuf,
+  committed: bool,
+}
+
+impl Destination {
+  pub(crate) fn create<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+    let path = path.as_ref().to_path_buf();
+    // Copies from the output read it back.
+    let file = File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&path)?;
+
+    Ok(Self {
+      file,
+      path,
+      committed: false,
+    })
+  }
+
+  /// Opens a checkpointed destination, keeping what's already written when `resume` is set.
+  /// It's kept on failure too, to be resumed later.
+  pub(crate) fn resumable<P: AsRef<Path>>(path: P, resume: bool) -> Result<Self, io::Error> {
+    let path = path.as_ref().to_path_buf();
+    let file = File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(!resume)
+      .open(&path)?;
+
+    Ok(Self {
+      file,
+      path,
+      committed: true,
+    })
+  }
+
+  /// Starts the destination as a copy-on-write clone of `source`.
+  pub(crate) fn cloned<P: AsRef<Path>>(source: P, path: P) -> Result<Self, io::Error> {
+    let path = path.as_ref().to_path_buf();
+    let file = reflink::clone_file(source.as_ref(), &path)?;
+
+    Ok(Self {
+      file,
+      path,
+      committed: false,
+    })
+  }
+
+  pub(crate) fn file(&mut self) -> &mut File {
+    &mut self.file
+  }
+
+  /// Keeps the file.
+  pub(crate) fn commit(mut self) {
+    self.committed = true;
+  }
+}
+
+impl Drop for Destination {
+  fn drop(&mut self) {
+    if !self.committed {
+      let _ = fs::remove_file(&self.path);
+    }
+  }
+}
+
+/// Copies the permissions and modification time of `from` to `to`.
+pub(crate) fn copy_metadata<P: AsRef<Path>>(from: P, to: P) -> Result<(), io::Error> {
+  let metadata = fs::metadata(from)?;
+  fs::set_permissions(&to, metadata.permissions())?;
+  filetime::set_file_mtime(&to, FileTime::from_last_modification_time(&metadata))?;
+
+  Ok(())
+}
+
+fn max_redirects(options: &PullOptions) -> usize {
+  options
+    .max_redirects
+    .map_or(DEFAULT_MAX_REDIRECTS, |max| max as usize)
+}
+
+/// Builds the HTTP client used for all range requests of a pull.
+///
+/// Without an explicit proxy the `HTTP_PROXY`/`HTTPS_PROXY` environment variables are respected.
+fn build_client(options: &PullOptions) -> anyhow::Result<Client> {
+  // Encoded ranges can still be decoded, but not every server encodes them correctly, so the
+  // encoding is disabled. Responses encoded anyway are decoded by their `Content-Encoding`.
+  let mut headers = HeaderMap::new();
+  headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+  let mut builder = Client::builder().default_headers(headers);
+
+  if let Some(url) = &options.proxy {
+    let mut proxy = Proxy::all(url)?;
+    if let Some(username) = &options.proxy_username {
+      proxy = proxy.basic_auth(
+        username,
+        options.proxy_password.as_deref().unwrap_or_default(),
+      );
+    }
+    builder = builder.proxy(proxy);
+  }
+
+  if let Some(path) = &options.ca_certificate {
+    let pem = fs::read(path).with_context(|| format!("Failed to read a CA bundle {}", path))?;
+    for cert in Certificate::from_pem_bundle(&pem)
+      .with_context(|| format!("Failed to load a CA bundle {}", path))?
+    {
+      builder = builder.add_root_certificate(cert);
+    }
+  }
+
+  match (&options.client_certificate, &options.client_key) {
+    (Some(cert_path), Some(key_path)) => {
+      let cert = fs::read(cert_path)
+        .with_context(|| format!("Failed to read a client certificate {}", cert_path))?;
+      let key =
+        fs::read(key_path).with_context(|| format!("Failed to read a client key {}", key_path))?;
+      let identity = Identity::from_pkcs8_pem(&cert, &key).with_context(|| {
+        format!(
+          "Failed to load a client certificate {} with the key {}",
+          cert_path, key_path
+        )
+      })?;
+      builder = builder.identity(identity);
+    }
+    (None, None) => {}
+    _ => anyhow::bail!("Both a client certificate and a client key are required"),
+  }
+
+  match (
+    options.http1_only.unwrap_or(false),
+    options.http2_prior_knowledge.unwrap_or(false),
+  ) {
+    (true, true) => anyhow::bail!("http1Only and http2PriorKnowledge can't be used together"),
+    (true, false) => builder = builder.http1_only(),
+    (false, true) => builder = builder.http2_prior_knowledge(),
+    (false, false) => {}
+  }
+
+  if let Some(user_agent) = &options.user_agent {
+    builder = builder.user_agent(user_agent);
+  }
+  builder = builder.redirect(match options.follow_redirects {
+    Some(false) => redirect::Policy::none(),
+    _ => redirect::Policy::limited(max_redirects(options)),
+  });
+
+  Ok(builder.build()?)
+}
+
+/// Downloads missing diff chunks and uses them along with `source` to construct the new file.
+///
+/// `dest` is preallocated to the target size and every operation is written at its final offset,
+/// so downloaded ranges are stored as soon as they arrive, regardless of their order, and neither
+/// a temporary file nor an in-memory buffer of the inserts is needed at any size. The offsets
+/// are the cumulative sizes of the preceding operations, so the written ranges never overlap and
+/// the order can't change the result. Copies from the output read data which may be written
+/// later, so they run in the diff order once everything else is written.
+///
+/// With `credentials`, all requests are authorized with their bearer tokens, which are refreshed
+/// when the server rejects them.
+pub(crate) async fn apply_from_http<R>(
+  diff: Vec<(Operation, u64, u64)>,
+  uri: String,
+  source: &mut R,
+  dest: &mut File,
+  options: &PullOptions,
+  credentials: Option<Arc<Credentials>>,
+) -> Result<(), Box<dyn Error>>
+where
+  R: Read + Seek,
+{
+  let client = build_client(options)?;
+  apply_from_http_with_client(&client, diff, uri, source, dest, options, credentials).await
+}
+
+/// Like `apply_from_http`, but sends all requests with `client`, so embedders can share its
+/// connection pool and configuration. The options configuring the client, such as the proxy,
+/// TLS, redirects and the user agent, are ignored and the client's own settings are used.
+pub(crate) async fn apply_from_http_with_client<R>(
+  client: &Client,
+  diff: Vec<(Operation, u64, u64)>,
+  uri: String,
+  source: &mut R,
+  dest: &mut File,
+  options: &PullOptions,
+  credentials: Option<Arc<Credentials>>,
+) -> Result<(), Box<dyn Error>>
+where
+  R: Read + Seek,
+{
+  let source = CopySource::Local(source);
+  pull(client, diff, uri, source, dest, options, credentials).await
+}
+
+/// Like `apply_from_http`, but also downloads the copies, from the file at `source_uri`, so
+/// neither the source nor the target needs to be local. Copies are requested by ranges like the
+/// inserts, with the same batching, retries and fallback to the whole file, before them.
+pub(crate) async fn apply_from_remote_source(
+  diff: Vec<(Operation, u64, u64)>,
+  source_uri: String,
+  uri: String,
+  dest: &mut File,
+  options: &PullOptions,
+  credentials: Option<Arc<Credentials>>,
+) -> Result<(), Box<dyn Error>> {
+  let client = build_client(options)?;
+  let source = CopySource::<File>::Remote(source_uri);
+  pull(&client, diff, uri, source, dest, options, credentials).await
+}
+
+/// Where the copies of a pull are read from.
+enum CopySource<'a, R> {
+  Local(&'a mut R),
+  /// The URL of the source, which copies are downloaded from.
+  Remote(String),
+}
+
+async fn pull<R>(
+  client: &Client,
+  diff: Vec<(Operation, u64, u64)>,
+  uri: String,
+  mut source: CopySource<'_, R>,
+  dest: &mut File,
+  options: &PullOptions,
+  credentials: Option<Arc<Credentials>>,
+) -> Result<(), Box<dyn Error>>
+where
+  R: Read + Seek,
+{
+  // A range of zero bytes can't be requested.
+  let diff: Vec<_> = diff.into_iter().filter(|(_, _, size)| *size > 0).collect();
+  // Nothing could ever be downloaded at zero bytes per second.
+  if options.max_bytes_per_sec == Some(0) {
+    return Err(Box::new(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      ZeroRateLimit,
+    )));
+  }
+  let limiter = options
+    .max_bytes_per_sec
+    .map(|bytes_per_sec| RateLimiter::new(bytes_per_sec.into()));
+  let target_size = diff.iter().map(|(_, _, size)| size).sum();
+  dest.preallocate(target_size)?;
+
+  let mut kept_inserts = match &options.inserts_path {
+    Some(path) => {
+      let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+      file.set_len(target_size)?;
+      Some(file)
+    }
+    None => None,
+  };
+
+  let downloader = Downloader {
+    client,
+    options,
+    limiter,
+    backoff: Arc::new(Backoff::new(options)),
+    credentials,
+  };
+  let mut copies = Vec::new();
+  let mut inserts = Vec::new();
+  let mut output_copies = Vec::new();
+  let mut position = 0;
+
+  for (op, offset, size) in diff {
+    match op {
+      Operation::Copy => match &mut source {
+        CopySource::Local(source) => {
+          source.seek(SeekFrom::Start(offset))?;
+          dest.seek(SeekFrom::Start(position))?;
+          let mut chunk = source.take(size);
+          copy(&mut chunk, dest)?;
+        }
+        CopySource::Remote(_) => copies.push((offset, position, size)),
+      },
+      Operation::Insert => inserts.push((offset, position, size)),
+      Operation::CopyOutput => {
+        // The copied data may still be downloading.
+        output_copies.push((offset, position, size));
+      }
+      Operation::CopyPool => unreachable!("diffs of signatures copy nothing from pools"),
+    }
+
+    position += size;
+  }
+  if let CopySource::Remote(source_uri) = &source {
+    downloader.download(source_uri, copies, dest, None).await?;
+  }
+  downloader
+    .download(&uri, inserts, dest, kept_inserts.as_mut())
+    .await?;
+
+  let mut block = vec![0; DEFAULT_MAX_SIZE as usize];
+  for (offset, position, size) in output_copies {
+    copy_within(dest, offset, position, size, &mut block)?;
+  }
+
+  Ok(())
+}
+
+/// Downloads ranges of remote files as `apply_from_http` does.
+struct Downloader<'a> {
+  client: &'a Client,
+  options: &'a PullOptions,
+  limiter: Option<RateLimiter>,
+  backoff: Arc<Backoff>,
+  credentials: Option<Arc<Credentials>>,
+}
+
+impl Downloader<'_> {
+  /// Downloads the `ranges` of the file at `uri`, given as (offset, position, size), and writes
+  /// each at its position of `dest` and of `kept_inserts`. When the server doesn't support ranges,
+  /// the whole file is downloaded instead.
+  async fn download(
+    &self,
+    uri: &str,
+    ranges: Vec<(u64, u64, u64)>,
+    dest: &mut File,
+    mut kept_inserts: Option<&mut File>,
+  ) -> Result<(), Box<dyn Error>> {
+    if ranges.is_empty() {
+      return Ok(());
+    }
+    let (client, options, limiter) = (self.client, self.options, &self.limiter);
+    if !supports_ranges(client, uri, options, self.credentials.as_deref()).await? {
+      event!(
+        debug,
+        url = uri,
+        ranges = ranges.len(),
+        "ranges aren't supported, downloading the whole file"
+      );
+      return self.download_whole(uri, ranges, dest, kept_inserts).await;
+    }
+
+    let max_ranges = options.max_ranges_per_request.unwrap_or(1).max(1) as usize;
+    event!(
+      debug,
+      url = uri,
+      ranges = ranges.len(),
+      requests = ranges.len().div_ceil(max_ranges),
+      max_ranges,
+      "batching ranges into requests"
+    );
+    let mut tasks = FuturesUnordered::new();
+    for batch in ranges.chunks(max_ranges) {
+      tasks.push(request_ranges(
+        client,
+        uri,
+        batch.to_vec(),
+        Retry::default(),
+        &self.backoff,
+        &self.credentials,
+      ));
+    }
+
+    while let Some((ranges, response)) = tasks.next().await {
+      let (response, attempts) = response?.map_err(|err| err as Box<dyn Error>)?;
+      let mut response = check_range_response(response, uri, &ranges, options)?;
+      // Bodies failing midway are requested again like failed requests, from where they broke off.
+      if let [(offset, position, size)] = ranges[..] {
+        let mut received = 0;
+        let failure = loop {
+          match response.chunk().await {
+            Ok(Some(chunk)) => {
+              if let Some(limiter) = limiter {
+                limiter.acquire(chunk.len()).await;
+              }
+              write_insert(
+                dest,
+                kept_inserts.as_deref_mut(),
+                position + received,
+                &chunk,
+              )?;
+              received += chunk.len() as u64;
+            }
+            Ok(None) => break None,
+            Err(err) => break Some(err),
+          }
+        };
+        match failure {
+          None => {
+            event!(debug, url = uri, bytes = size, "downloaded a range");
+          }
+          Some(err) => {
+            let rest = (offset + received, position + received, size - received);
+            let retry = self.retry(attempts, err)?;
+            tasks.push(request_ranges(
+              client,
+              uri,
+              vec![rest],
+              retry,
+              &self.backoff,
+              &self.credentials,
+            ));
+          }
+        }
+        continue;
+      }
+
+      let header = |name| {
+        response
+          .headers()
+          .get(name)
+          .and_then(|value: &HeaderValue| value.to_str().ok())
+          .map(str::to_string)
+      };
+      let content_type = header(CONTENT_TYPE).unwrap_or_default();
+      // A single range is answered without multipart, and the whole file without a range.
+      let start = header(CONTENT_RANGE)
+        .and_then(|value| multipart::parse_content_range(&value))
+        .map_or(0, |(start, _)| start);
+      let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(err) => {
+          let retry = self.retry(attempts, err)?;
+          tasks.push(request_ranges(
+            client,
+            uri,
+            ranges,
+            retry,
+            &self.backoff,
+            &self.credentials,
+          ));
+          continue;
+        }
+      };
+      if let Some(limiter) = limiter {
+        limiter.acquire(body.len()).await;
+      }
+      event!(
+        debug,
+        url = uri,
+        ranges = ranges.len(),
+        bytes = body.len(),
+        "downloaded ranges"
+      );
+      let parts = match multipart::boundary(&content_type) {
+        Some(boundary) => multipart::parts(&body, boundary)?,
+        None => vec![(start, &body[..])],
+      };
+
+      for (offset, position, size) in ranges {
+        let data = parts.iter().find_map(|&(start, data)| {
+          let from = usize::try_from(offset.checked_sub(start)?).ok()?;
+          data.get(from..from.checked_add(usize::try_from(size).ok()?)?)
+        });
+        match data {
+          Some(data) => write_insert(dest, kept_inserts.as_deref_mut(), position, data)?,
+          // Servers may answer with fewer ranges than requested.
+          None => {
+            event!(
+              debug,
+              url = uri,
+              offset,
+              size,
+              "the range is missing from the response, requesting it alone"
+            );
+            tasks.push(request_ranges(
+              client,
+              uri,
+              vec![(offset, position, size)],
+              Retry::default(),
+              &self.backoff,
+              &self.credentials,
+            ))
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Returns when to request ranges again after `attempts` at them ended with reading the body
+  /// failing with `err`, or `err` when no attempts are left.
+  fn retry(&self, attempts: u32, err: reqwest::Error) -> Result<Retry, Box<dyn Error>> {
+    match self.backoff.delay(attempts)? {
+      Some(delay) => {
+        event!(
+          debug,
+          attempt = attempts,
+          delay_ms = delay.as_millis() as u64,
+          "retrying the range request after reading its body failed"
+        );
+        Ok(Retry { attempts, delay })
+      }
+      None => Err(err.into()),
+    }
+  }
+
+  /// Downloads the whole file at `uri` once and writes the parts of it in the `ranges`, which may
+  /// overlap when they're copies.
+  async fn download_whole(
+    &self,
+    uri: &str,
+    mut ranges: Vec<(u64, u64, u64)>,
+    dest: &mut File,
+    mut kept_inserts: Option<&mut File>,
+  ) -> Result<(), Box<dyn Error>> {
+    ranges.sort_unstable();
+    let response = auth::send(|| self.client.get(uri), self.credentials.as_deref()).await?;
+    let mut response = check_response(response, uri, self.options)?;
+    let mut offset = 0;
+    while let Some(chunk) = response.chunk().await? {
+      if let Some(limiter) = &self.limiter {
+        limiter.acquire(chunk.len()).await;
+      }
+      let chunk_end = offset + chunk.len() as u64;
+
+      // Writes the parts of the chunk which belong to ranges.
+      for &(range_offset, position, size) in ranges
+        .iter()
+        .take_while(|(range_offset, _, _)| *range_offset < chunk_end)
+      {
+        let start = range_offset.max(offset);
+        let end = (range_offset + size).min(chunk_end);
+        if start < end {
+          write_insert(
+            dest,
+            kept_inserts.as_deref_mut(),
+            position + start - range_offset,
+            &chunk[(start - offset) as usize..(end - offset) as usize],
+          )?;
+        }
+      }
+      ranges.retain(|(range_offset, _, size)| range_offset + size > chunk_end);
+
+      offset = chunk_end;
+    }
+
+    Ok(())
+  }
+}
+
+/// Writes downloaded `data` at `position` of `dest` and of `kept_inserts`.
+fn write_insert(
+  dest: &mut File,
+  kept_inserts: Option<&mut File>,
+  position: u64,
+  data: &[u8],
+) -> Result<(), io::Error> {
+  dest.seek(SeekFrom::Start(position))?;
+  dest.write_all(data)?;
+  if let Some(kept_inserts) = kept_inserts {
+    kept_inserts.seek(SeekFrom::Start(position))?;
+    kept_inserts.write_all(data)?;
+  }
+
+  Ok(())
+}
+
+/// Returns the ranges `apply_from_http` downloads for `diff` when the server supports ranges.
+pub(crate) fn pull_plan(diff: &[(Operation, u64, u64)]) -> PullPlan {
+  let mut plan = PullPlan::default();
+  // Like `pull`, which requests no ranges of zero bytes.
+  for &(op, offset, size) in diff.iter().filter(|(_, _, size)| *size > 0) {
+    match op {
+      Operation::Insert => {
+        plan.ranges.push(ByteRange {
+          offset: offset as i64,
+          length: size as i64,
+        });
+        plan.inserted_bytes += size as i64;
+      }
+      Operation::Copy | Operation::CopyOutput => plan.copied_bytes += size as i64,
+      Operation::CopyPool => unreachable!("diffs of signatures copy nothing from pools"),
+    }
+  }
+
+  plan
+}
+
+/// The response of a spawned `get_range` and the number of attempts it took.
+type RangeResponse =
+  Result<Result<(reqwest::Result<Response>, u32), Box<dyn Error + Send + Sync>>, JoinError>;
+
+/// The attempts already made at ranges and the delay before the next one.
+#[derive(Default)]
+struct Retry {
+  attempts: u32,
+  delay: Duration,
+}
+
+/// Spawns a request of the `ranges` of the file at `uri`, given as (offset, position, size), and
+/// returns them along with the response.
+fn request_ranges(
+  client: &Client,
+  uri: &str,
+  ranges: Vec<(u64, u64, u64)>,
+  retry: Retry,
+  backoff: &Arc<Backoff>,
+  credentials: &Option<Arc<Credentials>>,
+) -> impl Future<Output = (Vec<(u64, u64, u64)>, RangeResponse)> {
+  let range = range_set(&ranges);
+  let request = get_range(
+    client.clone(),
+    uri.to_string(),
+    format!("bytes={}", range),
+    retry,
+    backoff.clone(),
+    credentials.clone(),
+  );
+  #[cfg(feature = "tracing")]
+  let request = tracing::Instrument::instrument(
+    request,
+    tracing::debug_span!("range_request", url = uri, range = %range),
+  );
+  let task = napi::tokio::task::spawn(request);
+
+  task.map(move |response| (ranges, response))
+}
+
+/// Returns the `ranges`, given as (offset, position, size), in the format of the `Range` header
+/// without its unit.
+fn range_set(ranges: &[(u64, u64, u64)]) -> String {
+  ranges
+    .iter()
+    .map(|(offset, _, size)| format!("{}-{}", offset, offset + size - 1))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Requests the `range` of the file at `url` after the delay of `retry`, retrying connection
+/// failures and server errors with `backoff`. The last response is returned when no attempts are
+/// left, along with the number of attempts made, those of `retry` included.
+async fn get_range(
+  client: Client,
+  url: String,
+  range: String,
+  retry: Retry,
+  backoff: Arc<Backoff>,
+  credentials: Option<Arc<Credentials>>,
+) -> Result<(reqwest::Result<Response>, u32), Box<dyn Error + Send + Sync>> {
+  let Retry {
+    mut attempts,
+    delay,
+  } = retry;
+  if !delay.is_zero() {
+    napi::tokio::time::sleep(delay).await;
+  }
+  loop {
+    event!(debug, attempt = attempts + 1, "requesting the range");
+    #[cfg(feature = "tracing")]
+    let started = std::time::Instant::now();
+    let request = || client.get(&url).header(RANGE, &range);
+    let response = auth::send(request, credentials.as_deref()).await?;
+    attempts += 1;
+    event!(
+      debug,
+      attempt = attempts,
+      status = response
+        .as_ref()
+        .map_or(0, |response| response.status().as_u16()),
+      elapsed_ms = started.elapsed().as_millis() as u64,
+      "range request finished"
+    );
+
+    let retryable = match &response {
+      Ok(response) => {
+        response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS
+      }
+      Err(err) => !err.is_redirect(),
+    };
+    if !retryable {
+      return Ok((response, attempts));
+    }
+    match backoff.delay(attempts)? {
+      Some(delay) => {
+        event!(
+          debug,
+          attempt = attempts,
+          delay_ms = delay.as_millis() as u64,
+          "retrying the range request"
+        );
+        napi::tokio::time::sleep(delay).await
+      }
+      None => return Ok((response, attempts)),
+    }
+  }
+}
+
+/// Checks with a HEAD request whether the server advertises `Accept-Ranges: bytes`. Without it
+/// range requests may be answered with the whole file.
+async fn supports_ranges(
+  client: &Client,
+  uri: &str,
+  options: &PullOptions,
+  credentials: Option<&Credentials>,
+) -> Result<bool, Box<dyn Error>> {
+  let response = auth::send(|| client.head(uri), credentials).await?;
+  let response = check_response(response, uri, options)?;
+  let supported = response
+    .headers()
+    .get(ACCEPT_RANGES)
+    .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"bytes"));
+
+  if !supported && options.range_fallback == Some(false) {
+    return Err(Box::new(RangesNotSupported {
+      url: uri.to_string(),
+    }));
+  }
+
+  Ok(supported)
+}
+
+/// Checks the response to a request of the `ranges` like `check_response`. Ranges past the end of
+/// the file are reported with the file size, they mean the diff was made for another version of it.
+fn check_range_response(
+  response: reqwest::Result<Response>,
+  uri: &str,
+  ranges: &[(u64, u64, u64)],
+  options: &PullOptions,
+) -> Result<Response, Box<dyn Error>> {
+  if let Ok(response) = &response {
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+      // Answered with `Content-Range: bytes */<size>`.
+      let size = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes */")?.parse().ok());
+      return Err(Box::new(RangeNotSatisfiable {
+        url: uri.to_string(),
+        range: range_set(ranges),
+        size,
+      }));
+    }
+  }
+
+  check_response(response, uri, options)
+}
+
+fn check_response(
+  response: reqwest::Result<Response>,
+  uri: &str,
+  options: &PullOptions,
+) -> Result<Response, Box<dyn Error>> {
+  let response = match response {
+    Ok(response) => response,
+    Err(err) if err.is_redirect() => {
+      return Err(Box::new(TooManyRedirects {
+        url: uri.to_string(),
+        limit: max_redirects(options),
+      }))
+    }
+    Err(err) => return Err(err.into()),
+  };
+  // Also catches redirects when they aren't followed, their body isn't the requested data.
+  if !response.status().is_success() {
+    return Err(Box::new(UnexpectedStatus {
+      url: uri.to_string(),
+      status: response.status(),
+    }));
+  }
+
+  Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Operation, Preallocate};
+  use crate::diff::{serialize_copy, serialize_header, serialize_insert, write_diff_between};
+  use crate::signature::Signature;
+  use crate::test_server;
+  use crate::{ApplyReport, ByteRange, DiffOptions, PullOptions};
+
+  use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+  use std::sync::atomic::Ordering;
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn test_apply_from_http_out_of_order() {
+    let source: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+
+    // The first range is answered last.
+    let server = test_server::serve(target.clone(), |start| {
+      if start == 0 {
+        Duration::from_millis(300)
+      } else {
+        Duration::ZERO
+      }
+    });
+
+    let diff = vec![
+      (Operation::Insert, 0, 500),
+      (Operation::Copy, 100, 200),
+      (Operation::Insert, 1000, 700),
+      (Operation::Copy, 0, 50),
+      (Operation::Insert, 2900, 100),
+    ];
+    let mut expected = Vec::new();
+    expected.extend_from_slice(&target[0..500]);
+    expected.extend_from_slice(&source[100..300]);
+    expected.extend_from_slice(&target[1000..1700]);
+    expected.extend_from_slice(&source[0..50]);
+    expected.extend_from_slice(&target[2900..3000]);
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        diff,
+        server.url,
+        &mut Cursor::new(&source),
+        &mut dest,
+        &PullOptions::default(),
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, expected);
+  }
+
+  #[test]
+  fn test_apply_from_http_max_bytes_per_sec() {
+    let target: Vec<u8> = (0..15000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve(target.clone(), |_| Duration::ZERO);
+    let diff = vec![
+      (Operation::Insert, 0, 10000),
+      (Operation::Insert, 10000, 5000),
+    ];
+    let options = PullOptions {
+      max_bytes_per_sec: Some(5000),
+      ..Default::default()
+    };
+
+    let mut dest = tempfile::tempfile().unwrap();
+    let started_at = Instant::now();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        diff,
+        server.url.clone(),
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &options,
+        None,
+      ))
+      .unwrap();
+
+    // The first second worth of bytes is allowed as a burst.
+    assert!(started_at.elapsed() >= Duration::from_secs(2));
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, target);
+
+    let options = PullOptions {
+      max_bytes_per_sec: Some(0),
+      ..Default::default()
+    };
+    let err = napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![(Operation::Insert, 0, 10000)],
+        server.url,
+        &mut Cursor::new(Vec::new()),
+        &mut tempfile::tempfile().unwrap(),
+        &options,
+        None,
+      ))
+      .unwrap_err();
+    let err = err.downcast_ref::<io::Error>().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn test_apply_copy_past_source_eof() {
+    let source = vec![7u8; 100];
+    let mut diff = Vec::new();
+    serialize_header(100, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 50, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 80, 50, &mut diff).unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    let err =
+      super::apply(&mut Cursor::new(diff), &mut Cursor::new(source), &mut dest).unwrap_err();
+
+    assert_eq!(
+      err.to_string(),
+      "copy of 50 bytes at offset 80 runs past the end of the source (100 bytes)"
+    );
+  }
+
+  #[test]
+  fn test_destination_removed_on_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("result");
+
+    let mut diff = Vec::new();
+    serialize_header(100, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 50, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 80, 50, &mut diff).unwrap();
+
+    let mut dest = super::Destination::create(&path).unwrap();
+    let result = super::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(vec![7u8; 100]),
+      dest.file(),
+    );
+    assert!(result.is_err());
+    drop(dest);
+    assert!(!path.exists());
+
+    let dest = super::Destination::create(&path).unwrap();
+    dest.commit();
+    assert!(path.exists());
+  }
+
+  #[test]
+  fn test_apply_copy_output_to_destination() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("result");
+
+    let mut diff = Vec::new();
+    serialize_header(100, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 50, &mut diff).unwrap();
+    serialize_copy(Operation::CopyOutput, 0, 50, &mut diff).unwrap();
+
+    let mut dest = super::Destination::create(&path).unwrap();
+    super::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new((0..100).collect::<Vec<u8>>()),
+      dest.file(),
+    )
+    .unwrap();
+    dest.commit();
+
+    let mut expected: Vec<u8> = (0..50).collect();
+    expected.extend(0..50);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+  }
+
+  /// A result failing writes past `budget` bytes, like an apply interrupted midway.
+  struct Interrupted {
+    inner: Cursor<Vec<u8>>,
+    budget: usize,
+  }
+
+  impl Read for Interrupted {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      self.inner.read(buf)
+    }
+  }
+
+  impl Write for Interrupted {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      if buf.len() > self.budget {
+        return Err(std::io::Error::other("interrupted"));
+      }
+      self.budget -= buf.len();
+      self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Seek for Interrupted {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+      self.inner.seek(pos)
+    }
+  }
+
+  impl Preallocate for Interrupted {
+    fn preallocate(&mut self, size: u64) -> std::io::Result<()> {
+      self.inner.preallocate(size)
+    }
+  }
+
+  /// A result recording the offsets and lengths of all writes.
+  struct Recorded {
+    inner: Cursor<Vec<u8>>,
+    writes: Vec<(u64, usize)>,
+  }
+
+  impl Read for Recorded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      self.inner.read(buf)
+    }
+  }
+
+  impl Write for Recorded {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      let position = self.inner.position();
+      let written = self.inner.write(buf)?;
+      self.writes.push((position, written));
+      Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Seek for Recorded {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+      self.inner.seek(pos)
+    }
+  }
+
+  impl Preallocate for Recorded {
+    fn preallocate(&mut self, size: u64) -> std::io::Result<()> {
+      self.inner.preallocate(size)
+    }
+  }
+
+  /// A file recording the sizes it's preallocated to.
+  struct Preallocated {
+    inner: std::fs::File,
+    sizes: Vec<u64>,
+  }
+
+  impl Read for Preallocated {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      self.inner.read(buf)
+    }
+  }
+
+  impl Write for Preallocated {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      self.inner.flush()
+    }
+  }
+
+  impl Seek for Preallocated {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+      self.inner.seek(pos)
+    }
+  }
+
+  impl Preallocate for Preallocated {
+    fn preallocate(&mut self, size: u64) -> std::io::Result<()> {
+      self.sizes.push(size);
+      self.inner.preallocate(size)
+    }
+  }
+
+  #[test]
+  fn test_apply_to_existing() {
+    let source: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target[500000..500100].copy_from_slice(&[0; 100]);
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+
+    let apply = |existing| {
+      let mut dest = Recorded {
+        inner: Cursor::new(source.clone()),
+        writes: Vec::new(),
+      };
+      let extras = super::ApplyExtras {
+        existing,
+        ..Default::default()
+      };
+      let report = super::apply_with(
+        &mut Cursor::new(&diff),
+        &mut Cursor::new(&source),
+        &mut dest,
+        extras,
+      )
+      .unwrap();
+      assert_eq!(report.bytes_written, target.len() as i64);
+      assert_eq!(dest.inner.get_ref(), &target);
+      dest.writes
+    };
+
+    // Only the chunks around the changed region are written.
+    let writes = apply(true);
+    let written: usize = writes.iter().map(|(_, len)| len).sum();
+    assert!(written > 0 && written <= 2 * 16384);
+    for (offset, len) in writes {
+      assert!(offset + 16384 >= 500000 && offset + len as u64 <= 500100 + 16384);
+    }
+    let written: usize = apply(false).iter().map(|(_, len)| len).sum();
+    assert_eq!(written, target.len());
+  }
+
+  #[test]
+  fn test_apply_resumes_from_checkpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint");
+    let source: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    let inserted: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+
+    let mut diff = Vec::new();
+    let mut expected = Vec::new();
+    serialize_header(100000, &mut diff).unwrap();
+    for i in 0..50 {
+      serialize_copy(Operation::Copy, i * 2000, 1000, &mut diff).unwrap();
+      serialize_insert(
+        0,
+        1000,
+        &mut Cursor::new(&inserted),
+        &mut diff,
+        &mut Vec::new(),
+      )
+      .unwrap();
+      expected.extend_from_slice(&source[(i * 2000) as usize..][..1000]);
+      expected.extend_from_slice(&inserted);
+    }
+
+    let checkpoint = super::Checkpoint {
+      path: &path,
+      resume: true,
+    };
+    let mut dest = Interrupted {
+      inner: Cursor::new(Vec::new()),
+      budget: 55500,
+    };
+    let extras = || super::ApplyExtras {
+      checkpoint: Some(&checkpoint),
+      ..Default::default()
+    };
+    assert!(super::apply_with(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+      extras(),
+    )
+    .is_err());
+    assert!(path.exists());
+
+    let mut dest = Interrupted {
+      inner: Cursor::new(dest.inner.into_inner()),
+      budget: usize::MAX,
+    };
+    let report = super::apply_with(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+      extras(),
+    )
+    .unwrap();
+    // Only the operations after the checkpoint are applied again.
+    assert_eq!(usize::MAX - dest.budget, 45000);
+    assert_eq!(dest.inner.into_inner(), expected);
+    assert_eq!(
+      report,
+      ApplyReport {
+        bytes_written: 100000,
+        copy_ops: 50,
+        insert_ops: 50,
+      }
+    );
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn test_apply_skips_zero_size_operations() {
+    let source: Vec<u8> = (0..100).collect();
+    let mut diff = Vec::new();
+    serialize_header(150, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 1000, 0, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 100, &mut diff).unwrap();
+    serialize_insert(
+      0,
+      0,
+      &mut Cursor::new(Vec::new()),
+      &mut diff,
+      &mut Vec::new(),
+    )
+    .unwrap();
+    serialize_copy(Operation::CopyOutput, 500, 0, &mut diff).unwrap();
+    serialize_copy(Operation::CopyOutput, 0, 50, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 0, &mut diff).unwrap();
+    let expected = [&source[..], &source[..50]].concat();
+
+    let mut dest = Cursor::new(Vec::new());
+    let report = super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), expected);
+    assert_eq!(
+      report,
+      ApplyReport {
+        bytes_written: 150,
+        copy_ops: 2,
+        insert_ops: 0,
+      }
+    );
+
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&source).unwrap();
+    super::apply_in_place(&mut Cursor::new(&diff), &mut file).unwrap();
+    let mut result = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut result).unwrap();
+    assert_eq!(result, expected);
+  }
+
+  #[test]
+  fn test_apply_truncated_insert() {
+    let target = vec![9u8; 100];
+    let mut diff = Vec::new();
+    serialize_header(100, &mut diff).unwrap();
+    serialize_insert(
+      0,
+      100,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &mut Vec::new(),
+    )
+    .unwrap();
+    diff.truncate(diff.len() - 10);
+
+    let mut dest = Cursor::new(Vec::new());
+    let err = super::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(Vec::new()),
+      &mut dest,
+    )
+    .unwrap_err();
+
+    assert_eq!(
+      err.to_string(),
+      "insert of 100 bytes exceeds the 90 bytes left in the diff"
+    );
+  }
+
+  #[test]
+  fn test_apply_copy_output() {
+    let source: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let block: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let mut target = block.clone();
+    target.extend_from_slice(&source);
+    target.extend_from_slice(&block);
+
+    let mut diff = Vec::new();
+    serialize_header(target.len() as u64, &mut diff).unwrap();
+    serialize_insert(
+      0,
+      3000,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &mut Vec::new(),
+    )
+    .unwrap();
+    serialize_copy(Operation::Copy, 0, 10000, &mut diff).unwrap();
+    serialize_copy(Operation::CopyOutput, 0, 3000, &mut diff).unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    super::apply(&mut Cursor::new(diff), &mut Cursor::new(source), &mut dest).unwrap();
+    assert_eq!(dest.into_inner(), target);
+  }
+
+  #[test]
+  fn test_apply_huge_target_size() {
+    let mut diff = Vec::new();
+    serialize_header(u64::MAX, &mut diff).unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    let result = super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(Vec::new()),
+      &mut dest,
+    );
+    assert!(result.is_err());
+
+    let mut dest = tempfile::tempfile().unwrap();
+    let result = super::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(Vec::new()),
+      &mut dest,
+    );
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_apply_writes_past_target_size() {
+    use std::io::Write;
+
+    let source: Vec<u8> = (0..100).map(|_| rand::random::<u8>()).collect();
+    // Copies of the output doubling it would overflow the written size without the check.
+    let mut diff = Vec::new();
+    serialize_header(1000, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 100, &mut diff).unwrap();
+    let mut size = 100;
+    for _ in 0..64 {
+      serialize_copy(Operation::CopyOutput, 0, size, &mut diff).unwrap();
+      size = size.wrapping_mul(2);
+    }
+
+    let mut dest = Cursor::new(Vec::new());
+    let error = super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "operation of 800 bytes after 800 bytes written runs past the target size of 1000"
+    );
+
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&source).unwrap();
+    let error = super::apply_in_place(&mut Cursor::new(diff), &mut file).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "operation of 800 bytes after 800 bytes written runs past the target size of 1000"
+    );
+  }
+
+  #[test]
+  fn test_apply_preallocates_destination() {
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target[50000..50100].copy_from_slice(&[0u8; 100]);
+
+    let source_sig = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let target_sig = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      &source_sig,
+      &target_sig,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+
+    let mut dest = Preallocated {
+      inner: tempfile::tempfile().unwrap(),
+      sizes: Vec::new(),
+    };
+    super::apply(&mut Cursor::new(diff), &mut Cursor::new(source), &mut dest).unwrap();
+
+    // Preallocated in growing steps up to the target size as the result is written.
+    assert_eq!(dest.sizes.last(), Some(&(target.len() as u64)));
+    assert!(dest.sizes.windows(2).all(|sizes| sizes[0] < sizes[1]));
+    let mut file = dest.inner;
+    assert_eq!(file.metadata().unwrap().len(), target.len() as u64);
+    #[cfg(target_os = "linux")]
+    {
+      use std::os::unix::fs::MetadataExt;
+      assert!(file.metadata().unwrap().blocks() * 512 >= target.len() as u64);
+    }
+
+    let mut result = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut result).unwrap();
+    assert_eq!(result, target);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_copy_metadata() {
+    use filetime::FileTime;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let source = tempfile::NamedTempFile::new().unwrap();
+    let dest = tempfile::NamedTempFile::new().unwrap();
+    fs::set_permissions(source.path(), fs::Permissions::from_mode(0o640)).unwrap();
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(source.path(), mtime).unwrap();
+
+    super::copy_metadata(source.path(), dest.path()).unwrap();
+
+    let metadata = fs::metadata(dest.path()).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+  }
+
+  #[test]
+  fn test_apply_empty_files() {
+    let data: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+
+    for (source, target) in [
+      (Vec::new(), data.clone()),
+      (data.clone(), Vec::new()),
+      (Vec::new(), Vec::new()),
+    ] {
+      let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+      let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+      let mut diff = Vec::new();
+      write_diff_between(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions::default(),
+      )
+      .unwrap();
+
+      let mut dest = Cursor::new(Vec::new());
+      super::apply(&mut Cursor::new(diff), &mut Cursor::new(source), &mut dest).unwrap();
+      assert_eq!(dest.into_inner(), target);
+    }
+  }
+
+  #[test]
+  fn test_apply_compressed() {
+    let words = ["alpha ", "beta ", "gamma ", "delta "];
+    let text = |len: usize| -> Vec<u8> {
+      (0..len)
+        .flat_map(|_| words[rand::random::<usize>() % words.len()].bytes())
+        .collect()
+    };
+    let source = text(20000);
+    let mut target = source.clone();
+    target.splice(30000..30000, text(5000));
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let write_diff = |compress| {
+      let mut diff = Vec::new();
+      write_diff_between(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions {
+          compress: Some(compress),
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      diff
+    };
+    let plain = write_diff(false);
+    let compressed = write_diff(true);
+    assert!(compressed.len() < plain.len() / 2);
+
+    let mut dest = Cursor::new(Vec::new());
+    super::apply(
+      &mut Cursor::new(compressed.clone()),
+      &mut Cursor::new(source.clone()),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&source).unwrap();
+    super::apply_in_place(&mut Cursor::new(compressed), &mut file).unwrap();
+    let mut result = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut result).unwrap();
+    assert_eq!(result, target);
+  }
+
+  #[test]
+  fn test_apply_compressed_with_dictionary() {
+    let names = ["alice", "bob", "carol", "dave", "erin", "frank"];
+    let record = || {
+      let name = names[rand::random::<usize>() % names.len()];
+      format!(
+        "{{\"id\": {}, \"name\": \"{}\", \"email\": \"{}@example.com\", \"active\": {}}}\n",
+        rand::random::<u16>(),
+        name,
+        name,
+        rand::random::<bool>()
+      )
+    };
+    let records =
+      |count: usize| -> Vec<u8> { (0..count).flat_map(|_| record().into_bytes()).collect() };
+
+    let dir = tempfile::tempdir().unwrap();
+    let samples: Vec<_> = (0..64)
+      .map(|i| {
+        let path = dir.path().join(format!("sample{}", i));
+        std::fs::write(&path, records(20)).unwrap();
+        path
+      })
+      .collect();
+    let dictionary = crate::diff::train_dictionary(&samples, 4096).unwrap();
+    let dictionary_path = dir.path().join("dictionary");
+    std::fs::write(&dictionary_path, &dictionary).unwrap();
+
+    let source = records(50);
+    let mut target = source.clone();
+    target.splice(2000..2000, records(10));
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 256, 1024, 4096).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 256, 1024, 4096).unwrap();
+    let write_diff = |dictionary: Option<String>| {
+      let mut diff = Vec::new();
+      write_diff_between(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions {
+          compress: Some(true),
+          dictionary,
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      diff
+    };
+    let compressed = write_diff(None);
+    let with_dictionary = write_diff(Some(dictionary_path.to_str().unwrap().to_string()));
+    assert!(
+      with_dictionary.len() < compressed.len(),
+      "{} bytes with the dictionary, {} without",
+      with_dictionary.len(),
+      compressed.len()
+    );
+
+    let mut dest = Cursor::new(Vec::new());
+    super::apply_with(
+      &mut Cursor::new(with_dictionary.clone()),
+      &mut Cursor::new(source.clone()),
+      &mut dest,
+      super::ApplyExtras {
+        dictionary: Some(&dictionary),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+
+    let err = super::apply(
+      &mut Cursor::new(with_dictionary),
+      &mut Cursor::new(source),
+      &mut Cursor::new(Vec::new()),
+    )
+    .unwrap_err();
+    assert!(err
+      .to_string()
+      .starts_with("diff is compressed with the dictionary"));
+  }
+
+  #[test]
+  fn test_apply_with_progress() {
+    let source: Vec<u8> = (0..200000).map(|_| rand::random::<u8>()).collect();
+    // Interleave the source with new data, so there are many operations.
+    let target: Vec<u8> = source
+      .chunks(20000)
+      .flat_map(|part| {
+        let mut data = part.to_vec();
+        data.extend((0..10000).map(|_| rand::random::<u8>()));
+        data
+      })
+      .collect();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+
+    let mut calls = Vec::new();
+    let mut dest = Cursor::new(Vec::new());
+    super::apply_with_progress(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(source),
+      &mut dest,
+      &mut |written, total| calls.push((written, total)),
+    )
+    .unwrap();
+
+    assert_eq!(dest.into_inner(), target);
+    assert!(
+      calls.len() > 10 && calls.len() <= 101,
+      "{} calls",
+      calls.len()
+    );
+    assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert!(calls.iter().all(|&(_, total)| total == 300000));
+    assert_eq!(calls.last(), Some(&(300000, 300000)));
+  }
+
+  #[test]
+  fn test_apply_report() {
+    let source = vec![1u8; 100];
+    let target = vec![2u8; 30];
+    let mut diff = Vec::new();
+    serialize_header(100, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 40, &mut diff).unwrap();
+    serialize_insert(0, 30, &mut Cursor::new(&target), &mut diff, &mut Vec::new()).unwrap();
+    serialize_copy(Operation::Copy, 70, 30, &mut diff).unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    let report = super::apply(&mut Cursor::new(diff), &mut Cursor::new(source), &mut dest).unwrap();
+
+    assert_eq!(
+      report,
+      ApplyReport {
+        bytes_written: 100,
+        copy_ops: 2,
+        insert_ops: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn test_apply_chain() {
+    use std::io::Write;
+
+    let v1: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut v2 = v1.clone();
+    v2[10000..10100].copy_from_slice(&[0u8; 100]);
+    let mut v3 = v2.clone();
+    v3.extend_from_slice(&[1u8; 5000]);
+
+    let diff_files = |a: &[u8], b: &[u8]| {
+      let a_sig = Signature::calculate(&mut Cursor::new(a), 1024, 4096, 16384).unwrap();
+      let b_sig = Signature::calculate(&mut Cursor::new(b), 1024, 4096, 16384).unwrap();
+      let mut diff = tempfile::tempfile().unwrap();
+      write_diff_between(
+        &a_sig,
+        &b_sig,
+        &mut Cursor::new(b),
+        &mut diff,
+        &DiffOptions::default(),
+      )
+      .unwrap();
+      diff
+    };
+    let mut diffs = vec![diff_files(&v1, &v2), diff_files(&v2, &v3)];
+    let mut base = tempfile::tempfile().unwrap();
+    base.write_all(&v1).unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    super::apply_chain(&mut diffs, &mut base, &mut dest).unwrap();
+    assert_eq!(dest.into_inner(), v3);
+  }
+
+  fn apply_in_place(source: &[u8], diff: Vec<u8>) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(source).unwrap();
+    super::apply_in_place(&mut Cursor::new(diff), &mut file).unwrap();
+
+    let mut result = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut result).unwrap();
+    result
+  }
+
+  #[test]
+  fn test_apply_in_place() {
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.drain(1000..2000);
+    target[50000..50100].copy_from_slice(&[0u8; 100]);
+    target.extend_from_slice(&[1u8; 5000]);
+
+    let source_sig = Signature::calculate(&mut Cursor::new(&source), 256, 1024, 4096).unwrap();
+    let target_sig = Signature::calculate(&mut Cursor::new(&target), 256, 1024, 4096).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      &source_sig,
+      &target_sig,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(apply_in_place(&source, diff), target);
+  }
+
+  #[test]
+  fn test_apply_in_place_overlapping() {
+    let source: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    // The second copy reads the first half, which is overwritten by the first copy.
+    let mut diff = Vec::new();
+    serialize_header(100000, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 50000, 50000, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 50000, &mut diff).unwrap();
+
+    let mut target = source[50000..].to_vec();
+    target.extend_from_slice(&source[..50000]);
+
+    assert_eq!(apply_in_place(&source, diff), target);
+  }
+
+  #[test]
+  fn test_apply_from_http_proxy() {
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let proxy = test_server::serve(target.clone(), |_| Duration::ZERO);
+    let options = PullOptions {
+      proxy: Some(proxy.url.clone()),
+      proxy_username: Some("user".to_string()),
+      proxy_password: Some("secret".to_string()),
+      ..Default::default()
+    };
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![(Operation::Insert, 0, 3000)],
+        "http://fastcdc-diff.invalid/target.bin".to_string(),
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &options,
+        None,
+      ))
+      .unwrap();
+
+    let requests = proxy.requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].starts_with("HEAD http://fastcdc-diff.invalid/target.bin HTTP/1.1"));
+    assert!(requests[1].starts_with("GET http://fastcdc-diff.invalid/target.bin HTTP/1.1"));
+    // base64("user:secret")
+    assert!(requests[1]
+      .to_ascii_lowercase()
+      .contains("proxy-authorization: basic dxnlcjpzzwnyzxq="));
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, target);
+  }
+
+  #[test]
+  fn test_apply_from_http2_single_connection() {
+    let target: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve_h2(target.clone());
+
+    // Every other 100 bytes are inserted, so each insert is a separate range.
+    let diff: Vec<_> = (0..50).map(|i| (Operation::Insert, i * 200, 100)).collect();
+    let expected: Vec<u8> = (0..50)
+      .flat_map(|i| target[i * 200..i * 200 + 100].to_vec())
+      .collect();
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        diff,
+        server.url,
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &PullOptions {
+          http2_prior_knowledge: Some(true),
+          ..Default::default()
+        },
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, expected);
+    // A HEAD request checking range support and a request for every insert.
+    assert_eq!(server.requests.lock().unwrap().len(), 51);
+    assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn test_apply_from_http_matches_sequential_apply() {
+    let source: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let target: Vec<u8> = (0..20000).map(|_| rand::random::<u8>()).collect();
+    // The first range is answered last.
+    let server = test_server::serve(target.clone(), |start| {
+      if start == 0 {
+        Duration::from_millis(300)
+      } else {
+        Duration::ZERO
+      }
+    });
+
+    // Overlapping source ranges and a copy from the output of another one.
+    let ops = vec![
+      (Operation::Insert, 0, 3000),
+      (Operation::Copy, 100, 5000),
+      (Operation::Copy, 2000, 5000),
+      (Operation::CopyOutput, 1000, 4000),
+      (Operation::CopyOutput, 14000, 2000),
+      (Operation::Insert, 19000, 1000),
+    ];
+
+    let mut diff = Vec::new();
+    serialize_header(ops.iter().map(|op| op.2).sum(), &mut diff).unwrap();
+    for &(op, offset, size) in ops.iter() {
+      match op {
+        Operation::Insert => serialize_insert(
+          offset,
+          size,
+          &mut Cursor::new(&target),
+          &mut diff,
+          &mut Vec::new(),
+        )
+        .unwrap(),
+        _ => serialize_copy(op, offset, size, &mut diff).unwrap(),
+      }
+    }
+    let mut expected = Cursor::new(Vec::new());
+    super::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(source.clone()),
+      &mut expected,
+    )
+    .unwrap();
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        ops,
+        server.url,
+        &mut Cursor::new(&source),
+        &mut dest,
+        &PullOptions::default(),
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, expected.into_inner());
+  }
+
+  #[test]
+  fn test_apply_from_http_user_agent() {
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve(target.clone(), |_| Duration::ZERO);
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![(Operation::Insert, 0, 3000)],
+        server.url,
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &PullOptions {
+          user_agent: Some("updater/1.0".to_string()),
+          ..Default::default()
+        },
+        None,
+      ))
+      .unwrap();
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[0]
+      .to_ascii_lowercase()
+      .contains("user-agent: updater/1.0\r\n"));
+  }
+
+  #[test]
+  fn test_apply_from_http_with_client() {
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve(target.clone(), |_| Duration::ZERO);
+    let mut headers = HeaderMap::new();
+    headers.insert("x-embedder", HeaderValue::from_static("shared"));
+    let client = reqwest::Client::builder()
+      .default_headers(headers)
+      .build()
+      .unwrap();
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http_with_client(
+        &client,
+        vec![
+          (Operation::Insert, 0, 1000),
+          (Operation::Insert, 2000, 1000),
+        ],
+        server.url,
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &PullOptions {
+          user_agent: Some("ignored/1.0".to_string()),
+          ..Default::default()
+        },
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, [&target[..1000], &target[2000..]].concat());
+
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    for request in requests.iter() {
+      let request = request.to_ascii_lowercase();
+      assert!(request.contains("x-embedder: shared\r\n"));
+      assert!(!request.contains("ignored/1.0"));
+    }
+  }
+
+  #[test]
+  fn test_apply_from_http_redirect_loop() {
+    let server = test_server::serve_with(|_| test_server::Response {
+      status: "302 Found",
+      headers: vec![("Location", "/target.bin".to_string())],
+      body: Vec::new(),
+    });
+    let pull = |options: PullOptions| {
+      napi::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(super::apply_from_http(
+          vec![(Operation::Insert, 0, 3000)],
+          format!("{}/target.bin", server.url),
+          &mut Cursor::new(Vec::new()),
+          &mut tempfile::tempfile().unwrap(),
+          &options,
+          None,
+        ))
+        .unwrap_err()
+        .to_string()
+    };
+
+    let err = pull(PullOptions {
+      max_redirects: Some(3),
+      ..Default::default()
+    });
+    assert_eq!(
+      err,
+      format!(
+        "requesting {}/target.bin exceeded the limit of 3 redirects",
+        server.url
+      )
+    );
+    assert_eq!(server.requests.lock().unwrap().len(), 4);
+
+    let err = pull(PullOptions {
+      follow_redirects: Some(false),
+      ..Default::default()
+    });
+    assert_eq!(
+      err,
+      format!(
+        "requesting {}/target.bin failed with status 302 Found",
+        server.url
+      )
+    );
+  }
+
+  #[test]
+  fn test_apply_from_http_range_not_satisfiable() {
+    let data: Vec<u8> = (0..2000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve_with(move |request| match request.range {
+      Some((_, end)) if end >= data.len() as u64 => test_server::Response {
+        status: "416 Range Not Satisfiable",
+        headers: vec![("Content-Range", format!("bytes */{}", data.len()))],
+        body: Vec::new(),
+      },
+      _ => test_server::range_response(&data, request),
+    });
+
+    let err = napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![
+          (Operation::Insert, 0, 1000),
+          (Operation::Insert, 1500, 1000),
+        ],
+        format!("{}/target.bin", server.url),
+        &mut Cursor::new(Vec::new()),
+        &mut tempfile::tempfile().unwrap(),
+        &PullOptions::default(),
+        None,
+      ))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      format!(
+        "requested range bytes=1500-2499 of {}/target.bin is beyond the remote file size of 2000 \
+         bytes, the diff is likely stale for this target",
+        server.url
+      )
+    );
+  }
+
+  #[test]
+  fn test_apply_from_http_refreshes_tokens() {
+    use crate::auth::Credentials;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    let source: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    let target: Vec<u8> = (0..30000).map(|_| rand::random::<u8>()).collect();
+    let data = target.clone();
+    // The first token expires after two requests.
+    let requests = AtomicUsize::new(0);
+    let server = test_server::serve_with(move |request| {
+      let n = requests.fetch_add(1, Ordering::SeqCst);
+      match request.authorization.as_deref() {
+        Some("Bearer token-1") => test_server::range_response(&data, request),
+        Some("Bearer token-0") if n < 2 => test_server::range_response(&data, request),
+        _ => test_server::Response {
+          status: "401 Unauthorized",
+          headers: Vec::new(),
+          body: Vec::new(),
+        },
+      }
+    });
+
+    let provided = Arc::new(AtomicUsize::new(0));
+    let counter = provided.clone();
+    let credentials = Credentials::new(move || {
+      let n = counter.fetch_add(1, Ordering::SeqCst);
+      async move { Ok(format!("token-{}", n)) }
+    });
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![
+          (Operation::Insert, 0, 10000),
+          (Operation::Copy, 0, 1000),
+          (Operation::Insert, 11000, 10000),
+          (Operation::Insert, 25000, 5000),
+        ],
+        server.url.clone(),
+        &mut Cursor::new(&source),
+        &mut dest,
+        &PullOptions {
+          max_ranges_per_request: Some(1),
+          ..Default::default()
+        },
+        Some(Arc::new(credentials)),
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    let expected = [
+      &target[..10000],
+      &source[..],
+      &target[11000..21000],
+      &target[25000..],
+    ]
+    .concat();
+    assert!(result == expected);
+    assert_eq!(provided.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn test_apply_from_http_without_ranges() {
+    let source: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    let target: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let data = target.clone();
+    let server = test_server::serve_with(move |_| test_server::Response {
+      status: "200 OK",
+      headers: Vec::new(),
+      body: data.clone(),
+    });
+    let diff = vec![
+      (Operation::Insert, 0, 500),
+      (Operation::Copy, 100, 200),
+      (Operation::Insert, 700, 100000),
+      (Operation::Insert, 200000, 30),
+    ];
+    let pull = |options: PullOptions| {
+      let mut dest = tempfile::tempfile().unwrap();
+      napi::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(super::apply_from_http(
+          diff.clone(),
+          server.url.clone(),
+          &mut Cursor::new(&source),
+          &mut dest,
+          &options,
+          None,
+        ))
+        .map(|_| {
+          let mut result = Vec::new();
+          dest.seek(SeekFrom::Start(0)).unwrap();
+          dest.read_to_end(&mut result).unwrap();
+          result
+        })
+        .map_err(|err| err.to_string())
+    };
+
+    let mut expected = Vec::new();
+    expected.extend_from_slice(&target[0..500]);
+    expected.extend_from_slice(&source[100..300]);
+    expected.extend_from_slice(&target[700..100700]);
+    expected.extend_from_slice(&target[200000..200030]);
+    assert_eq!(pull(PullOptions::default()), Ok(expected));
+
+    let err = pull(PullOptions {
+      range_fallback: Some(false),
+      ..Default::default()
+    });
+    assert_eq!(
+      err,
+      Err(format!(
+        "the server of {} doesn't support range requests",
+        server.url
+      ))
+    );
+  }
+
+  #[test]
+  fn test_apply_from_http_custom_ca() {
+    use std::io::Write;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_pem = cert.cert.pem();
+    let key_pem = cert.key_pair.serialize_pem();
+    let identity =
+      native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve_tls(target.clone(), identity);
+    let uri = server.url.replace("127.0.0.1", "localhost");
+    let runtime = napi::tokio::runtime::Runtime::new().unwrap();
+
+    let mut dest = tempfile::tempfile().unwrap();
+    let err = runtime
+      .block_on(super::apply_from_http(
+        vec![(Operation::Insert, 0, 3000)],
+        uri.clone(),
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &PullOptions::default(),
+        None,
+      ))
+      .unwrap_err();
+    assert!(err.to_string().starts_with("error sending request"));
+
+    let mut ca = tempfile::NamedTempFile::new().unwrap();
+    ca.write_all(cert_pem.as_bytes()).unwrap();
+    let mut key = tempfile::NamedTempFile::new().unwrap();
+    key.write_all(key_pem.as_bytes()).unwrap();
+    let options = PullOptions {
+      ca_certificate: Some(ca.path().to_string_lossy().into_owned()),
+      client_certificate: Some(ca.path().to_string_lossy().into_owned()),
+      client_key: Some(key.path().to_string_lossy().into_owned()),
+      ..Default::default()
+    };
+
+    runtime
+      .block_on(super::apply_from_http(
+        vec![(Operation::Insert, 0, 3000)],
+        uri,
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &options,
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, target);
+  }
+
+  #[test]
+  fn test_apply_with_local_inserts() {
+    use std::io::Write;
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target[50000..50100].copy_from_slice(&[0u8; 100]);
+    target.extend_from_slice(&[1u8; 5000]);
+
+    let source_sig = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let target_sig = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+
+    // Only the inserted ranges of the target are present in the inserts file, and the diff has
+    // none of their data.
+    let mut diff = Vec::new();
+    serialize_header(target.len() as u64, &mut diff).unwrap();
+    let mut inserts = tempfile::tempfile().unwrap();
+    inserts.set_len(target.len() as u64).unwrap();
+    for (op, offset, size) in crate::diff::diff_signatures(&source_sig, &target_sig) {
+      if op == Operation::Insert {
+        diff.push(Operation::Insert.into());
+        diff.extend_from_slice(&size.to_be_bytes());
+        inserts.seek(SeekFrom::Start(offset)).unwrap();
+        inserts
+          .write_all(&target[offset as usize..(offset + size) as usize])
+          .unwrap();
+      } else {
+        serialize_copy(op, offset, size, &mut diff).unwrap();
+      }
+    }
+    let mut diff = Cursor::new(diff);
+    let mut source_file = tempfile::tempfile().unwrap();
+    source_file.write_all(&source).unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    super::apply_with_local_inserts(&mut diff, &mut source_file, &mut inserts, &mut dest).unwrap();
+    assert_eq!(dest.into_inner(), target);
+  }
+
+  #[test]
+  fn test_pull_plan_matches_requests() {
+    let source: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let mut target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    target.extend_from_slice(&source[2000..6000]);
+    target.extend((0..500).map(|_| rand::random::<u8>()));
+    target.extend_from_slice(&source[..2000]);
+    let server = test_server::serve(target.clone(), |_| Duration::ZERO);
+
+    let source_signature = Signature::calculate(&mut Cursor::new(&source), 256, 512, 1024).unwrap();
+    let target_signature = Signature::calculate(&mut Cursor::new(&target), 256, 512, 1024).unwrap();
+    let diff = crate::diff::diff_signatures(&source_signature, &target_signature);
+    let plan = super::pull_plan(&diff);
+    assert_eq!(plan.copied_bytes + plan.inserted_bytes, target.len() as i64);
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        diff,
+        server.url,
+        &mut Cursor::new(&source),
+        &mut dest,
+        &PullOptions::default(),
+        None,
+      ))
+      .unwrap();
+
+    let mut ranges: Vec<ByteRange> = server
+      .requests
+      .lock()
+      .unwrap()
+      .iter()
+      .filter_map(|request| {
+        let range = request
+          .to_ascii_lowercase()
+          .split("\r\n")
+          .find_map(|line| line.strip_prefix("range: bytes=").map(str::to_string))?;
+        let (start, end) = range.split_once('-').unwrap();
+        let (start, end): (i64, i64) = (start.parse().unwrap(), end.parse().unwrap());
+        Some(ByteRange {
+          offset: start,
+          length: end - start + 1,
+        })
+      })
+      .collect();
+    ranges.sort_by_key(|range| range.offset);
+    assert!(!ranges.is_empty());
+    assert_eq!(ranges, plan.ranges);
+    assert_eq!(
+      ranges.iter().map(|range| range.length).sum::<i64>(),
+      plan.inserted_bytes
+    );
+  }
+
+  #[test]
+  fn test_apply_from_http_encoded_ranges() {
+    use flate2::write::GzEncoder;
+
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let data = target.clone();
+    // Gzips every range even though the client asks not to.
+    let server = test_server::serve_with(move |request| {
+      let mut response = test_server::range_response(&data, request);
+      if request.range.is_some() {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&response.body).unwrap();
+        response.body = encoder.finish().unwrap();
+        response
+          .headers
+          .push(("Content-Encoding", "gzip".to_string()));
+      }
+      response
+    });
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![
+          (Operation::Insert, 0, 1000),
+          (Operation::Insert, 2000, 1000),
+        ],
+        server.url,
+        &mut Cursor::new(Vec::new()),
+        &mut dest,
+        &PullOptions::default(),
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    let mut expected = target[..1000].to_vec();
+    expected.extend_from_slice(&target[2000..]);
+    assert_eq!(result, expected);
+
+    let requests = server.requests.lock().unwrap();
+    assert!(requests.iter().all(|request| request
+      .to_ascii_lowercase()
+      .contains("accept-encoding: identity\r\n")));
+  }
+
+  #[test]
+  fn test_apply_from_http_retries() {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    // Every range fails twice before it's served.
+    let flaky_server = || {
+      let data = target.clone();
+      let failures = Mutex::new(HashMap::new());
+      test_server::serve_with(move |request| match request.range {
+        Some((start, _)) if *failures.lock().unwrap().entry(start).or_insert(0) < 2 => {
+          *failures.lock().unwrap().get_mut(&start).unwrap() += 1;
+          test_server::Response {
+            status: "503 Service Unavailable",
+            headers: Vec::new(),
+            body: Vec::new(),
+          }
+        }
+        _ => test_server::range_response(&data, request),
+      })
+    };
+    let pull = |server: &test_server::TestServer, max_attempts| {
+      let mut dest = tempfile::tempfile().unwrap();
+      napi::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(super::apply_from_http(
+          vec![
+            (Operation::Insert, 0, 1000),
+            (Operation::Insert, 2000, 1000),
+          ],
+          server.url.clone(),
+          &mut Cursor::new(Vec::new()),
+          &mut dest,
+          &PullOptions {
+            max_attempts: Some(max_attempts),
+            retry_initial_delay_ms: Some(10),
+            ..Default::default()
+          },
+          None,
+        ))
+        .map(|_| dest)
+    };
+
+    let err = pull(&flaky_server(), 2).unwrap_err();
+    assert!(err.to_string().contains("503"), "{}", err);
+
+    let server = flaky_server();
+    let mut dest = pull(&server, 3).unwrap();
+    // A HEAD request and three attempts of every range.
+    assert_eq!(server.requests.lock().unwrap().len(), 7);
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    let mut expected = target[..1000].to_vec();
+    expected.extend_from_slice(&target[2000..]);
+    assert_eq!(result, expected);
+  }
+
+  #[test]
+  fn test_apply_from_http_retries_broken_bodies() {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    let target: Vec<u8> = (0..30000).map(|_| rand::random::<u8>()).collect();
+    // The first response for every range breaks off after half of its body, the rest of it is
+    // requested from elsewhere than the start of a range.
+    let flaky_server = || {
+      let data = target.clone();
+      let broken = Mutex::new(HashSet::new());
+      test_server::serve_with(move |request| {
+        let mut response = match request.ranges.len() {
+          0 | 1 => test_server::range_response(&data, request),
+          _ => test_server::multipart_response(&data, &request.ranges),
+        };
+        if let Some(&(start, _)) = request.ranges.first() {
+          if start % 10000 == 0 && broken.lock().unwrap().insert(start) {
+            let len = response.body.len();
+            response.headers.push(("Content-Length", len.to_string()));
+            response.body.truncate(len / 2);
+          }
+        }
+        response
+      })
+    };
+    let pull = |server: &test_server::TestServer, max_attempts, max_ranges_per_request| {
+      let mut dest = tempfile::tempfile().unwrap();
+      napi::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(super::apply_from_http(
+          vec![
+            (Operation::Insert, 0, 10000),
+            (Operation::Insert, 20000, 10000),
+          ],
+          server.url.clone(),
+          &mut Cursor::new(Vec::new()),
+          &mut dest,
+          &PullOptions {
+            max_attempts: Some(max_attempts),
+            max_ranges_per_request: Some(max_ranges_per_request),
+            retry_initial_delay_ms: Some(10),
+            ..Default::default()
+          },
+          None,
+        ))
+        .map(|_| dest)
+    };
+
+    assert!(pull(&flaky_server(), 1, 1).is_err());
+
+    let mut expected = target[..10000].to_vec();
+    expected.extend_from_slice(&target[20000..]);
+    for max_ranges_per_request in [1, 2] {
+      let server = flaky_server();
+      let mut dest = pull(&server, 2, max_ranges_per_request).unwrap();
+      let mut result = Vec::new();
+      dest.seek(SeekFrom::Start(0)).unwrap();
+      dest.read_to_end(&mut result).unwrap();
+      assert_eq!(result, expected);
+    }
+  }
+
+  #[test]
+  fn test_apply_from_http_retry_budget() {
+    let server = test_server::serve_with(|request| match request.range {
+      Some(_) => test_server::Response {
+        status: "503 Service Unavailable",
+        headers: Vec::new(),
+        body: Vec::new(),
+      },
+      None => test_server::range_response(&[0; 1000], request),
+    });
+
+    let started_at = Instant::now();
+    let err = napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        vec![(Operation::Insert, 0, 500), (Operation::Insert, 500, 500)],
+        server.url,
+        &mut Cursor::new(Vec::new()),
+        &mut tempfile::tempfile().unwrap(),
+        &PullOptions {
+          max_attempts: Some(1000),
+          retry_initial_delay_ms: Some(20),
+          retry_multiplier: Some(1.5),
+          retry_max_delay_ms: Some(100),
+          retry_budget_ms: Some(300),
+          ..Default::default()
+        },
+        None,
+      ))
+      .unwrap_err();
+
+    assert_eq!(err.to_string(), "retries exceeded the budget of 300 ms");
+    // The pull fails before a retry would end past the budget, not after all attempts.
+    assert!(started_at.elapsed() < Duration::from_millis(500));
+    assert!(server.requests.lock().unwrap().len() > 3);
+  }
+
+  #[test]
+  fn test_apply_in_memory_diff_to_file() {
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.drain(1000..2000);
+    target.extend((0..5000).map(|_| rand::random::<u8>()));
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+
+    let mut source_file = tempfile::tempfile().unwrap();
+    source_file.write_all(&source).unwrap();
+    let mut dest = Cursor::new(Vec::new());
+    super::apply(&mut Cursor::new(diff), &mut source_file, &mut dest).unwrap();
+    assert_eq!(dest.into_inner(), target);
+  }
+
+  #[test]
+  fn test_apply_from_http_multipart_ranges() {
+    let target: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let data = target.clone();
+    // Answers with at most two ranges of every request.
+    let server = test_server::serve_with(move |request| match request.ranges.len() {
+      0 | 1 => test_server::range_response(&data, request),
+      _ => test_server::multipart_response(&data, &request.ranges[..2]),
+    });
+    let diff: Vec<_> = (0..5)
+      .flat_map(|i| {
+        [
+          (Operation::Insert, i * 2000, 1000),
+          (Operation::Copy, i * 100, 100),
+        ]
+      })
+      .collect();
+    let mut expected = Vec::new();
+    let source: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    for i in 0..5 {
+      expected.extend_from_slice(&target[i * 2000..i * 2000 + 1000]);
+      expected.extend_from_slice(&source[i * 100..i * 100 + 100]);
+    }
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        diff,
+        server.url,
+        &mut Cursor::new(&source),
+        &mut dest,
+        &PullOptions {
+          max_ranges_per_request: Some(3),
+          ..Default::default()
+        },
+        None,
+      ))
+      .unwrap();
+
+    let mut result = Vec::new();
+    dest.seek(SeekFrom::Start(0)).unwrap();
+    dest.read_to_end(&mut result).unwrap();
+    assert_eq!(result, expected);
+
+    let requests = server.requests.lock().unwrap();
+    let mut ranges: Vec<_> = requests
+      .iter()
+      .filter_map(|request| {
+        request.split("\r\n").find_map(|line| {
+          line
+            .to_ascii_lowercase()
+            .strip_prefix("range: ")
+            .map(str::to_string)
+        })
+      })
+      .collect();
+    ranges.sort();
+    // Two batches, the third range of the first one requested again on its own.
+    assert_eq!(
+      ranges,
+      vec![
+        "bytes=0-999,2000-2999,4000-4999",
+        "bytes=4000-4999",
+        "bytes=6000-6999,8000-8999",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_apply_from_http_keeps_inserts() {
+    let source: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    let target: Vec<u8> = (0..5000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve(target.clone(), |_| Duration::ZERO);
+
+    // The first two ranges share a multipart response, the last one is streamed.
+    let diff = vec![
+      (Operation::Insert, 0, 500),
+      (Operation::Copy, 100, 200),
+      (Operation::Insert, 1000, 700),
+      (Operation::Insert, 4000, 1000),
+    ];
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("inserts");
+    let options = PullOptions {
+      inserts_path: Some(path.to_str().unwrap().to_string()),
+      max_ranges_per_request: Some(2),
+      ..Default::default()
+    };
+
+    let mut dest = tempfile::tempfile().unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_from_http(
+        diff,
+        server.url,
+        &mut Cursor::new(&source),
+        &mut dest,
+        &options,
+        None,
+      ))
+      .unwrap();
+
+    let mut expected = target[0..500].to_vec();
+    expected.extend_from_slice(&[0; 200]);
+    expected.extend_from_slice(&target[1000..1700]);
+    expected.extend_from_slice(&target[4000..5000]);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+  }
+
+  #[test]
+  fn test_operation_table() {
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(20000..20100, (0..300).map(|_| rand::random::<u8>()));
+    target.splice(70000..70000, (0..5000).map(|_| rand::random::<u8>()));
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let write_diff = |compress| {
+      let mut diff = Vec::new();
+      write_diff_between(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions {
+          compress: Some(compress),
+          operation_table: Some(true),
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      diff
+    };
+    let diff = write_diff(false);
+    let table = super::read_operation_table(&mut Cursor::new(&diff), None).unwrap();
+
+    // The operations follow the 10 bytes of the header.
+    let mut sequential = Vec::new();
+    let mut ops = Cursor::new(&diff[10..]);
+    let mut u64buf = [0; 8];
+    for _ in 0..table.len() {
+      let position = ops.position();
+      let mut kind = [0; 1];
+      ops.read_exact(&mut kind).unwrap();
+      let kind = Operation::try_from(kind[0]).unwrap();
+      let offset = if kind == Operation::Insert {
+        position + 9
+      } else {
+        ops.read_exact(&mut u64buf).unwrap();
+        u64::from_be_bytes(u64buf)
+      };
+      ops.read_exact(&mut u64buf).unwrap();
+      let size = u64::from_be_bytes(u64buf);
+      if kind == Operation::Insert {
+        ops.seek(SeekFrom::Current(size as i64)).unwrap();
+      }
+      sequential.push((kind, position, offset, size));
+    }
+    assert_eq!(table, sequential);
+    assert!(table.iter().any(|(kind, ..)| *kind == Operation::Insert));
+
+    // Jumping to the data of the last insert without parsing the operations before it.
+    let (_, position, offset, size) = *table
+      .iter()
+      .rfind(|(kind, ..)| *kind == Operation::Insert)
+      .unwrap();
+    let written: u64 = table
+      .iter()
+      .take_while(|(_, other, ..)| *other != position)
+      .map(|(.., size)| size)
+      .sum();
+    assert_eq!(diff[10 + position as usize], u8::from(Operation::Insert));
+    assert_eq!(
+      &diff[10 + offset as usize..][..size as usize],
+      &target[written as usize..][..size as usize]
+    );
+
+    for diff in [diff.clone(), write_diff(true)] {
+      let compressed_table = super::read_operation_table(&mut Cursor::new(&diff), None).unwrap();
+      assert_eq!(compressed_table, table);
+
+      let mut dest = Cursor::new(Vec::new());
+      super::apply(&mut Cursor::new(diff), &mut Cursor::new(&source), &mut dest).unwrap();
+      assert_eq!(dest.into_inner(), target);
+    }
+
+    let mut dest = Cursor::new(Vec::new());
+    futures::executor::block_on(super::apply_streaming(
+      &mut &diff[..],
+      &mut Cursor::new(&source),
+      &mut dest,
+    ))
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+  }
+
+  #[test]
+  fn test_insert_checksums() {
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let source = random(60000);
+    let mut target = source.clone();
+    target.splice(40000..40000, random(3000));
+    target.splice(10000..10000, random(3000));
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let write_diff = |insert_checksums| {
+      let mut diff = Vec::new();
+      write_diff_between(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions {
+          operation_table: Some(true),
+          insert_checksums: Some(insert_checksums),
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      diff
+    };
+    let diff = write_diff(true);
+    let table = super::read_operation_table(&mut Cursor::new(&diff), None).unwrap();
+    let inserts: Vec<_> = table
+      .iter()
+      .enumerate()
+      .filter(|(_, (op, _, _, _))| *op == Operation::Insert)
+      .map(|(i, &(_, _, offset, size))| {
+        let written: u64 = table[..i].iter().map(|(_, _, _, size)| size).sum();
+        (written, offset, size)
+      })
+      .collect();
+    assert_eq!(inserts.len(), 2);
+    assert_eq!(
+      write_diff(false).len(),
+      diff.len() - 2 * super::CHECKSUM_LEN
+    );
+    for &(written, offset, size) in inserts.iter() {
+      assert_eq!(
+        &diff[10 + offset as usize..][..size as usize],
+        &target[written as usize..][..size as usize]
+      );
+    }
+
+    let mut dest = Cursor::new(Vec::new());
+    super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+
+    // The second insert is corrupted, which fails at that insert.
+    let (written, offset, size) = inserts[1];
+    let mut corrupted = diff.clone();
+    corrupted[10 + offset as usize + 100] ^= 1;
+    let expected = format!(
+      "insert of {} bytes at offset {} of the target doesn't match its checksum",
+      size, written
+    );
+
+    let mut dest = Cursor::new(Vec::new());
+    let err = super::apply(
+      &mut Cursor::new(&corrupted),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), expected);
+    assert_eq!(
+      &dest.get_ref()[..written as usize],
+      &target[..written as usize]
+    );
+
+    let err = futures::executor::block_on(super::apply_streaming(
+      &mut &corrupted[..],
+      &mut Cursor::new(&source),
+      &mut Cursor::new(Vec::new()),
+    ))
+    .unwrap_err();
+    assert_eq!(err.to_string(), expected);
+
+    // Nothing is overwritten in place.
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&source).unwrap();
+    let err = super::apply_in_place(&mut Cursor::new(&corrupted), &mut file).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut data).unwrap();
+    assert_eq!(data, source);
+  }
+
+  #[test]
+  fn test_apply_malformed_diffs() {
+    let source: Vec<u8> = (0..30000).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target[10000..12000].copy_from_slice(&[7; 2000]);
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+
+    for options in [
+      DiffOptions::default(),
+      DiffOptions {
+        operation_table: Some(true),
+        ..Default::default()
+      },
+    ] {
+      let mut diff = Vec::new();
+      write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, &options).unwrap();
+
+      for _ in 0..2000 {
+        let mut malformed = diff.clone();
+        // The header is corrupted too, results are only preallocated as they're written.
+        for _ in 0..1 + rand::random::<usize>() % 4 {
+          let index = rand::random::<usize>() % malformed.len();
+          malformed[index] = rand::random();
+        }
+        if rand::random::<bool>() {
+          malformed.truncate(rand::random::<usize>() % malformed.len());
+        }
+
+        let mut dest = Cursor::new(Vec::new());
+        let _ = super::apply(
+          &mut Cursor::new(&malformed),
+          &mut Cursor::new(&source),
+          &mut dest,
+        );
+        let _ = super::read_operation_table(&mut Cursor::new(&malformed), None);
+        let _ = super::validate(&mut Cursor::new(&malformed), Some(source.len() as u64));
+        let mut dest = Cursor::new(Vec::new());
+        let _ = futures::executor::block_on(super::apply_streaming(
+          &mut &malformed[..],
+          &mut Cursor::new(&source),
+          &mut dest,
+        ));
+      }
+    }
+
+    let mut diff = Vec::new();
+    serialize_header(100, &mut diff).unwrap();
+    diff.push(9);
+    let err = super::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(&source),
+      &mut Cursor::new(Vec::new()),
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "unknown operation 9");
+
+    // A header claiming a terabyte doesn't reserve it for a diff writing a few bytes.
+    let mut diff = Vec::new();
+    serialize_header(1 << 40, &mut diff).unwrap();
+    serialize_copy(Operation::Copy, 0, 100, &mut diff).unwrap();
+    let mut dest = tempfile::tempfile().unwrap();
+    let err = super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap_err();
+    assert!(err.is::<super::TargetSizeMismatch>(), "{}", err);
+    assert_eq!(dest.metadata().unwrap().len(), 100);
+    let mut dest = tempfile::tempfile().unwrap();
+    let err = futures::executor::block_on(super::apply_streaming(
+      &mut &diff[..],
+      &mut Cursor::new(&source),
+      &mut dest,
+    ))
+    .unwrap_err();
+    assert!(err.is::<super::TargetSizeMismatch>(), "{}", err);
+    assert_eq!(dest.metadata().unwrap().len(), 100);
+  }
+
+  #[test]
+  fn test_validate() {
+    let source: Vec<u8> = (0..30000).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target[10000..12000].copy_from_slice(&[7; 2000]);
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+
+    let mut dest = Cursor::new(Vec::new());
+    let report = super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    for source_len in [None, Some(source.len() as u64)] {
+      let (target_size, validated) = super::validate(&mut Cursor::new(&diff), source_len).unwrap();
+      assert_eq!(target_size, target.len() as u64);
+      assert_eq!(validated, report);
+    }
+
+    let mut mismatched = diff.clone();
+    mismatched[0] = super::VERSION + 1;
+    let err = super::validate(&mut Cursor::new(&mismatched), None).unwrap_err();
+    assert!(err.to_string().starts_with("version mismatch: got"));
+
+    // Only a known source is checked for copies past its end.
+    let mut past_eof = Vec::new();
+    serialize_header(100, &mut past_eof).unwrap();
+    serialize_copy(Operation::Copy, 29950, 100, &mut past_eof).unwrap();
+    let err = super::validate(&mut Cursor::new(&past_eof), Some(30000)).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "copy of 100 bytes at offset 29950 runs past the end of the source (30000 bytes)"
+    );
+    assert!(super::validate(&mut Cursor::new(&past_eof), None).is_ok());
+
+    // The operations write less than the declared size.
+    let mut short = Vec::new();
+    serialize_header(200, &mut short).unwrap();
+    serialize_copy(Operation::Copy, 0, 100, &mut short).unwrap();
+    assert!(super::validate(&mut Cursor::new(&short), Some(30000)).is_err());
+  }
+
+  #[test]
+  fn test_apply_from_remote_source() {
+    let source: Vec<u8> = (0..60000).map(|_| rand::random::<u8>()).collect();
+    let inserted: Vec<u8> = (0..5000).map(|_| rand::random::<u8>()).collect();
+    // Reorders the source and copies a part of it twice.
+    let target = [
+      &source[30000..],
+      &inserted[..],
+      &source[..30000],
+      &source[..20000],
+    ]
+    .concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let diff = crate::diff::diff_signatures(&sig1, &sig2);
+    assert!(diff.iter().any(|(op, _, _)| *op == Operation::Copy));
+
+    let data = source.clone();
+    let sources = [
+      test_server::serve(source.clone(), |_| Duration::ZERO),
+      // Without range support the whole source is downloaded once.
+      test_server::serve_with(move |_| test_server::Response {
+        status: "200 OK",
+        headers: Vec::new(),
+        body: data.clone(),
+      }),
+    ];
+    for source_server in sources {
+      let target_server = test_server::serve(target.clone(), |_| Duration::ZERO);
+      let mut dest = tempfile::tempfile().unwrap();
+      napi::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(super::apply_from_remote_source(
+          diff.clone(),
+          source_server.url.clone(),
+          target_server.url.clone(),
+          &mut dest,
+          &PullOptions {
+            max_ranges_per_request: Some(4),
+            ..Default::default()
+          },
+          None,
+        ))
+        .unwrap();
+
+      let mut result = Vec::new();
+      dest.seek(SeekFrom::Start(0)).unwrap();
+      dest.read_to_end(&mut result).unwrap();
+      assert_eq!(result, target);
+
+      // Copies aren't requested from the target.
+      let requested: u64 = target_server
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|request| {
+          request
+            .to_ascii_lowercase()
+            .split_once("range: bytes=")?
+            .1
+            .lines()
+            .next()
+            .map(str::to_string)
+        })
+        .flat_map(|ranges| {
+          ranges
+            .split(',')
+            .map(|range| {
+              let (start, end) = range.split_once('-').unwrap();
+              end.parse::<u64>().unwrap() - start.parse::<u64>().unwrap() + 1
+            })
+            .collect::<Vec<_>>()
+        })
+        .sum();
+      let inserts: u64 = diff
+        .iter()
+        .filter(|(op, _, _)| *op == Operation::Insert)
+        .map(|(_, _, size)| size)
+        .sum();
+      assert_eq!(requested, inserts);
+      assert!(!source_server.requests.lock().unwrap().is_empty());
+    }
+  }
+
+  #[cfg(feature = "tracing")]
+  #[test]
+  fn test_pull_tracing() {
+    use std::fmt::Write as _;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    /// Collects every event as its fields separated by spaces.
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+      fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        write!(self.0, "{}={:?} ", field.name(), value).unwrap();
+      }
+    }
+
+    impl tracing::Subscriber for Collector {
+      fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+      }
+
+      fn new_span(&self, _span: &Attributes) -> Id {
+        Id::from_u64(1)
+      }
+
+      fn record(&self, _span: &Id, _values: &Record) {}
+
+      fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+      fn event(&self, event: &Event) {
+        let mut fields = Fields(String::new());
+        event.record(&mut fields);
+        self.0.lock().unwrap().push(fields.0);
+      }
+
+      fn enter(&self, _span: &Id) {}
+
+      fn exit(&self, _span: &Id) {}
+    }
+
+    let target: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let server = test_server::serve(target.clone(), |_| Duration::ZERO);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let diff = vec![
+      (Operation::Insert, 0, 1000),
+      (Operation::CopyOutput, 0, 1000),
+      (Operation::Insert, 2000, 1000),
+    ];
+
+    // A single thread, so the tasks of the range requests see the subscriber as well.
+    let runtime = napi::tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .unwrap();
+    let mut dest = tempfile::tempfile().unwrap();
+    tracing::subscriber::with_default(Collector(events.clone()), || {
+      runtime
+        .block_on(super::apply_from_http(
+          diff,
+          server.url.clone(),
+          &mut Cursor::new(Vec::new()),
+          &mut dest,
+          &PullOptions::default(),
+          None,
+        ))
+        .unwrap();
+    });
+
+    let events = events.lock().unwrap();
+    let count = |message: &str| {
+      events
+        .iter()
+        .filter(|event| event.contains(&format!("message={} ", message)))
+        .count()
+    };
+    assert_eq!(count("batching ranges into requests"), 1);
+    assert!(events
+      .iter()
+      .any(|event| event.contains("ranges=2 requests=2 max_ranges=1")));
+    assert_eq!(count("requesting the range"), 2);
+    assert_eq!(count("range request finished"), 2);
+    assert!(events.iter().any(|event| event.contains("status=206")));
+    assert_eq!(count("downloaded a range"), 2);
+    assert!(events.iter().any(|event| event.contains("bytes=1000")));
+  }
+
+  #[test]
+  fn test_diff_capabilities() {
+    use crate::diff::{serialize_header_with_flags, CAPABILITY_LONG_WINDOW, FLAG_CAPABILITIES};
+
+    let source: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    let with_capabilities = |capabilities: u32| {
+      let mut diff = Vec::new();
+      serialize_header_with_flags(500, FLAG_CAPABILITIES, &mut diff).unwrap();
+      diff.extend_from_slice(&capabilities.to_be_bytes());
+      serialize_copy(Operation::Copy, 100, 500, &mut diff).unwrap();
+      diff
+    };
+
+    // Diffs without further features apply, also from a stream.
+    let diff = with_capabilities(0);
+    let mut dest = Cursor::new(Vec::new());
+    super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), &source[100..600]);
+    let mut dest = Cursor::new(Vec::new());
+    futures::executor::block_on(super::apply_streaming(
+      &mut &diff[..],
+      &mut Cursor::new(&source),
+      &mut dest,
+    ))
+    .unwrap();
+    assert_eq!(dest.into_inner(), &source[100..600]);
+    let header = super::read_header(&mut &diff[..]).unwrap();
+    assert!(header.features().is_empty());
+
+    let diff = with_capabilities(1 << 3 | 1 << 17);
+    let mut dest = Cursor::new(Vec::new());
+    let err = super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "diff uses features this version can't apply: bit 3, bit 17"
+    );
+    let err = futures::executor::block_on(super::apply_streaming(
+      &mut &diff[..],
+      &mut Cursor::new(&source),
+      &mut Cursor::new(Vec::new()),
+    ))
+    .unwrap_err();
+    assert!(err.to_string().ends_with("apply: bit 3, bit 17"));
+    // Known features are named, a newer version may still drop one.
+    assert_eq!(
+      super::UnsupportedCapabilities(CAPABILITY_LONG_WINDOW | 1 << 17).to_string(),
+      "diff uses features this version can't apply: long_window, bit 17"
+    );
+
+    let sig = Signature::calculate(&mut Cursor::new(&source), 64, 256, 1024).unwrap();
+    let options = DiffOptions {
+      compress: Some(true),
+      operation_table: Some(true),
+      insert_checksums: Some(true),
+      ..Default::default()
+    };
+    let mut diff = Vec::new();
+    write_diff_between(&sig, &sig, &mut Cursor::new(&source), &mut diff, &options).unwrap();
+    let header = super::read_header(&mut &diff[..]).unwrap();
+    assert_eq!(
+      header.features(),
+      ["compress", "operation_table", "insert_checksums"]
+    );
+
+    let options = DiffOptions {
+      compress: Some(true),
+      long_window: Some(true),
+      ..Default::default()
+    };
+    let mut diff = Vec::new();
+    write_diff_between(&sig, &sig, &mut Cursor::new(&source), &mut diff, &options).unwrap();
+    let header = super::read_header(&mut &diff[..]).unwrap();
+    assert_eq!(header.features(), ["compress", "long_window"]);
+    let mut dest = Cursor::new(Vec::new());
+    super::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), source);
+  }
 }
@@ -0,0 +1,126 @@
+use futures::future::BoxFuture;
+use futures::lock::Mutex;
+use futures::prelude::*;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+type Provide = dyn Fn() -> BoxFuture<'static, Result<String, String>> + Send + Sync;
+
+#[derive(Debug)]
+pub(crate) struct TokenUnavailable(String);
+
+impl fmt::Display for TokenUnavailable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "getting an authorization token failed: {}", self.0)
+  }
+}
+
+impl Error for TokenUnavailable {}
+
+/// Bearer tokens shared by all download tasks of a pull. Tokens come from `provide`, first for
+/// the first request and then whenever the server rejects the current one, e.g. once short-lived
+/// credentials expire. Concurrent tasks rejected with the same token wait for a single refresh.
+pub(crate) struct Credentials {
+  provide: Box<Provide>,
+  token: Mutex<Option<Arc<str>>>,
+}
+
+impl Credentials {
+  pub(crate) fn new<F, T>(provide: F) -> Self
+  where
+    F: Fn() -> T + Send + Sync + 'static,
+    T: Future<Output = Result<String, String>> + Send + 'static,
+  {
+    Self {
+      provide: Box::new(move || provide().boxed()),
+      token: Mutex::new(None),
+    }
+  }
+
+  /// Returns the current token, getting the first one when there's none yet.
+  async fn token(&self) -> Result<Arc<str>, TokenUnavailable> {
+    let mut token = self.token.lock().await;
+    match &*token {
+      Some(current) => Ok(current.clone()),
+      None => {
+        let fresh: Arc<str> = (self.provide)().await.map_err(TokenUnavailable)?.into();
+        *token = Some(fresh.clone());
+        Ok(fresh)
+      }
+    }
+  }
+
+  /// Replaces the `rejected` token with a fresh one, unless another task already has.
+  async fn refresh(&self, rejected: &str) -> Result<Arc<str>, TokenUnavailable> {
+    let mut token = self.token.lock().await;
+    if let Some(current) = token.as_ref().filter(|current| &***current != rejected) {
+      return Ok(current.clone());
+    }
+    let fresh: Arc<str> = (self.provide)().await.map_err(TokenUnavailable)?.into();
+    *token = Some(fresh.clone());
+
+    Ok(fresh)
+  }
+}
+
+/// Sends the request built by `request` with the token of `credentials`, if any. A request
+/// rejected with 401 is sent once more with a fresh token, its response is returned either way.
+pub(crate) async fn send<F>(
+  request: F,
+  credentials: Option<&Credentials>,
+) -> Result<reqwest::Result<Response>, TokenUnavailable>
+where
+  F: Fn() -> RequestBuilder,
+{
+  let Some(credentials) = credentials else {
+    return Ok(request().send().await);
+  };
+
+  let token = credentials.token().await?;
+  let response = request().bearer_auth(&token).send().await;
+  match &response {
+    Ok(rejected) if rejected.status() == StatusCode::UNAUTHORIZED => {
+      event!(debug, "the token was rejected, refreshing it");
+      let token = credentials.refresh(&token).await?;
+      Ok(request().bearer_auth(&token).send().await)
+    }
+    _ => Ok(response),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Credentials;
+
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  #[test]
+  fn test_refresh_once() {
+    let provided = Arc::new(AtomicUsize::new(0));
+    let counter = provided.clone();
+    let credentials = Credentials::new(move || {
+      let n = counter.fetch_add(1, Ordering::SeqCst);
+      async move { Ok(format!("token-{}", n)) }
+    });
+
+    futures::executor::block_on(async {
+      assert_eq!(&*credentials.token().await.unwrap(), "token-0");
+      assert_eq!(&*credentials.token().await.unwrap(), "token-0");
+      // Tasks rejected with the same token share the refreshed one.
+      assert_eq!(&*credentials.refresh("token-0").await.unwrap(), "token-1");
+      assert_eq!(&*credentials.refresh("token-0").await.unwrap(), "token-1");
+      assert_eq!(&*credentials.refresh("token-1").await.unwrap(), "token-2");
+    });
+    assert_eq!(provided.load(Ordering::SeqCst), 3);
+
+    let failing = Credentials::new(|| async { Err("expired session".to_string()) });
+    let err = futures::executor::block_on(failing.token()).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "getting an authorization token failed: expired session"
+    );
+  }
+}
@@ -0,0 +1,164 @@
+//! `.caibx` indexes of casync and desync, the tools of systemd keeping content-defined chunks in
+//! chunk stores.
+//!
+//! An index is a `CaFormatIndex` header followed by a table of chunk records, see `caformat.h` of
+//! casync. All integers are little-endian:
+//!
+//! INDEX HEADER:
+//!   SIZE(u64) - 48, the size of the header
+//!   TYPE(u64) - `CA_FORMAT_INDEX`
+//!   FEATURE FLAGS(u64) - `CA_FORMAT_SHA512_256` when chunks are named by their SHA-512/256
+//!   CHUNK SIZE MIN(u64), CHUNK SIZE AVG(u64), CHUNK SIZE MAX(u64)
+//! TABLE HEADER:
+//!   SIZE(u64) - `u64::MAX`, the table size is only known from its tail
+//!   TYPE(u64) - `CA_FORMAT_TABLE`
+//! for every chunk:
+//!   OFFSET(u64) - the offset of the end of the chunk
+//!   ID([u8; 32]) - the hash of the chunk data
+//! TABLE TAIL:
+//!   ZERO FILL(u64), ZERO FILL(u64)
+//!   INDEX OFFSET(u64) - 48, the offset of the table
+//!   SIZE(u64) - the size of the table including its header and tail
+//!   MARKER(u64) - `CA_FORMAT_TABLE_TAIL_MARKER`
+
+use crate::signature::Signature;
+use crate::HashAlgorithm;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+const CA_FORMAT_INDEX: u64 = 0x96824d9c7b129ff9;
+const CA_FORMAT_TABLE: u64 = 0xe75b9e112f17417d;
+const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f050e5549ecd1;
+const INDEX_HEADER_SIZE: u64 = 48;
+const TABLE_HEADER_SIZE: u64 = 16;
+const TABLE_ITEM_SIZE: u64 = 40;
+const TABLE_TAIL_SIZE: u64 = 40;
+
+/// The default chunk sizes of casync, signatures calculated with them split files like
+/// `casync make` does only in size, their cut points are found by FastCDC instead of buzhash.
+pub const CASYNC_MIN_SIZE: u32 = 16 * 1024;
+pub const CASYNC_AVG_SIZE: u32 = 64 * 1024;
+pub const CASYNC_MAX_SIZE: u32 = 256 * 1024;
+
+#[derive(Debug)]
+struct UnsupportedHashAlgorithm(HashAlgorithm, u8);
+
+impl fmt::Display for UnsupportedHashAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "casync names chunks by whole SHA-512/256 or SHA-256 hashes, but the signature uses {:?} \
+       truncated to {} bytes",
+      self.0, self.1
+    )
+  }
+}
+
+impl Error for UnsupportedHashAlgorithm {}
+
+/// Writes the chunks of the `signature` as a `.caibx` index. Chunk IDs are the chunk hashes and
+/// casync names chunks by their SHA-512/256 unless `CA_FORMAT_SHA512_256` is cleared, then by
+/// their SHA-256, so only SHA-256 signatures with whole hashes can be written.
+pub(crate) fn write_index<W: Write>(signature: &Signature, dest: &mut W) -> Result<(), io::Error> {
+  if signature.hash_algorithm != HashAlgorithm::Sha256 || signature.hash_bytes != 32 {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      UnsupportedHashAlgorithm(signature.hash_algorithm, signature.hash_bytes),
+    ));
+  }
+
+  for value in [
+    INDEX_HEADER_SIZE,
+    CA_FORMAT_INDEX,
+    0,
+    signature.min_size.into(),
+    signature.avg_size.into(),
+    signature.max_size.into(),
+    u64::MAX,
+    CA_FORMAT_TABLE,
+  ] {
+    dest.write_all(&value.to_le_bytes())?;
+  }
+
+  for chunk in signature.chunks.iter() {
+    dest.write_all(&(chunk.offset + chunk.length as u64).to_le_bytes())?;
+    dest.write_all(chunk.hash.as_bytes())?;
+  }
+
+  let table_size =
+    TABLE_HEADER_SIZE + signature.chunks.len() as u64 * TABLE_ITEM_SIZE + TABLE_TAIL_SIZE;
+  for value in [
+    0,
+    0,
+    INDEX_HEADER_SIZE,
+    table_size,
+    CA_FORMAT_TABLE_TAIL_MARKER,
+  ] {
+    dest.write_all(&value.to_le_bytes())?;
+  }
+
+  dest.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::signature::Signature;
+  use crate::HashAlgorithm;
+
+  use arrayref::array_ref;
+  use sha2::{Digest, Sha256};
+  use std::io::Cursor;
+
+  #[test]
+  fn test_write_index() {
+    let data: Vec<u8> = (0..1005000).map(|_| rand::random::<u8>()).collect();
+    let signature = Signature::calculate_with(
+      &mut Cursor::new(&data),
+      super::CASYNC_MIN_SIZE,
+      super::CASYNC_AVG_SIZE,
+      super::CASYNC_MAX_SIZE,
+      HashAlgorithm::Sha256,
+      crate::signature::DEFAULT_NORMALIZATION,
+      crate::signature::DEFAULT_HASH_BYTES,
+    )
+    .unwrap();
+    let mut index = Vec::new();
+    super::write_index(&signature, &mut index).unwrap();
+
+    let u64_at = |offset: usize| u64::from_le_bytes(*array_ref![index, offset, 8]);
+    let count = signature.chunks.len();
+    assert_eq!(index.len(), 48 + 16 + count * 40 + 40);
+
+    assert_eq!(u64_at(0), 48);
+    assert_eq!(u64_at(8), super::CA_FORMAT_INDEX);
+    assert_eq!(u64_at(16), 0);
+    assert_eq!(
+      (u64_at(24), u64_at(32), u64_at(40)),
+      (16 * 1024, 64 * 1024, 256 * 1024)
+    );
+    assert_eq!(u64_at(48), u64::MAX);
+    assert_eq!(u64_at(56), super::CA_FORMAT_TABLE);
+
+    // Chunk IDs are the SHA-256 of the data between the end offsets.
+    let mut start = 0;
+    for i in 0..count {
+      let item = 64 + i * 40;
+      let end = u64_at(item) as usize;
+      let id: [u8; 32] = Sha256::digest(&data[start..end]).into();
+      assert_eq!(&index[item + 8..item + 40], &id);
+      start = end;
+    }
+    assert_eq!(start, data.len());
+
+    let tail = 64 + count * 40;
+    assert_eq!((u64_at(tail), u64_at(tail + 8)), (0, 0));
+    assert_eq!(u64_at(tail + 16), 48);
+    assert_eq!(u64_at(tail + 24), (16 + count * 40 + 40) as u64);
+    assert_eq!(u64_at(tail + 32), super::CA_FORMAT_TABLE_TAIL_MARKER);
+
+    let blake3 = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+    assert!(super::write_index(&blake3, &mut Vec::new()).is_err());
+  }
+}
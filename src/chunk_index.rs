@@ -0,0 +1,97 @@
+use crate::signature::Chunk;
+
+use arrayref::array_ref;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+/// Size of a `DiskIndex` record: HASH([u8; 32]), LENGTH(u64) and OFFSET(u64).
+const RECORD_SIZE: u64 = 48;
+
+/// Chunks are looked up by their hash and length, like `Chunk::eq` compares them, so a chunk
+/// with the same hash but another length is never copied in its place.
+pub(crate) type ChunkKey = (blake3::Hash, usize);
+
+pub(crate) fn key(chunk: &Chunk) -> ChunkKey {
+  (chunk.hash, chunk.length)
+}
+
+/// An index of the source chunks used to find the target chunks in the source.
+pub(crate) trait ChunkIndex {
+  /// Returns the offset of the first source chunk equal to the `chunk`.
+  fn offset_of(&mut self, chunk: &Chunk) -> Result<Option<u64>, io::Error>;
+}
+
+impl<S: BuildHasher> ChunkIndex for HashMap<ChunkKey, &Chunk, S> {
+  fn offset_of(&mut self, chunk: &Chunk) -> Result<Option<u64>, io::Error> {
+    Ok(self.get(&key(chunk)).map(|chunk| chunk.offset))
+  }
+}
+
+/// Returns the approximate number of bytes an in-memory index of `chunks` takes.
+pub(crate) fn memory_estimate(chunks: usize) -> u64 {
+  // Every entry has a control byte, and the table is at most 7/8 full.
+  let entry = std::mem::size_of::<(ChunkKey, &Chunk)>() + 1;
+  (chunks * entry * 8 / 7) as u64
+}
+
+/// Returns the bytes `DiskIndex` records are sorted by.
+fn sort_key(hash: &blake3::Hash, length: u64) -> [u8; 40] {
+  let mut sort_key = [0u8; 40];
+  sort_key[..32].copy_from_slice(hash.as_bytes());
+  sort_key[32..].copy_from_slice(&length.to_be_bytes());
+  sort_key
+}
+
+/// A chunk index stored in a temporary file as records sorted by hash and length, looked up with a
+/// binary search. It is much slower than the in-memory index but only needs memory while being
+/// built.
+pub(crate) struct DiskIndex {
+  file: File,
+  records: u64,
+}
+
+impl DiskIndex {
+  pub(crate) fn build(chunks: &[Chunk]) -> Result<Self, io::Error> {
+    let mut sorted: Vec<&Chunk> = chunks.iter().collect();
+    // The sort is stable, so the first equal chunk is kept like in the in-memory index.
+    sorted.sort_by_key(|chunk| sort_key(&chunk.hash, chunk.length as u64));
+    sorted.dedup_by(|a, b| key(a) == key(b));
+
+    let mut file = BufWriter::new(tempfile::tempfile()?);
+    for chunk in sorted.iter() {
+      file.write_all(&sort_key(&chunk.hash, chunk.length as u64))?;
+      file.write_all(chunk.offset.to_be_bytes().as_ref())?;
+    }
+
+    Ok(Self {
+      file: file.into_inner().map_err(|err| err.into_error())?,
+      records: sorted.len() as u64,
+    })
+  }
+}
+
+impl ChunkIndex for DiskIndex {
+  fn offset_of(&mut self, chunk: &Chunk) -> Result<Option<u64>, io::Error> {
+    let wanted = sort_key(&chunk.hash, chunk.length as u64);
+    let mut record = [0u8; RECORD_SIZE as usize];
+    let (mut low, mut high) = (0, self.records);
+
+    while low < high {
+      let middle = low + (high - low) / 2;
+      self.file.seek(SeekFrom::Start(middle * RECORD_SIZE))?;
+      self.file.read_exact(&mut record)?;
+
+      match array_ref![record, 0, 40].cmp(&wanted) {
+        std::cmp::Ordering::Less => low = middle + 1,
+        std::cmp::Ordering::Greater => high = middle,
+        std::cmp::Ordering::Equal => {
+          return Ok(Some(u64::from_be_bytes(*array_ref![record, 40, 8])));
+        }
+      }
+    }
+
+    Ok(None)
+  }
+}
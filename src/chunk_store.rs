@@ -0,0 +1,190 @@
+//! A directory of content-defined chunks named by their hashes, for appliers keeping sources as
+//! chunks instead of whole files. Diffs written with `chunk_copies` name the source chunks they
+//! copy by hash, so they're applied from a store without the source file. Every chunk is a file
+//! named by the hex of the 32 bytes of its hash in the signature, with truncated hashes padded
+//! with zeros like in signatures. Chunks are only ever added, so diffs written earlier stay valid.
+
+use crate::signature::Signature;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+struct MissingChunk(blake3::Hash);
+
+impl fmt::Display for MissingChunk {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "chunk {} isn't in the chunk store", self.0)
+  }
+}
+
+impl Error for MissingChunk {}
+
+#[derive(Debug)]
+struct ChunkSizeMismatch {
+  hash: blake3::Hash,
+  size: u64,
+  stored: u64,
+}
+
+impl fmt::Display for ChunkSizeMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "chunk {} of the chunk store has {} bytes, but the diff copies {}",
+      self.hash, self.stored, self.size
+    )
+  }
+}
+
+impl Error for ChunkSizeMismatch {}
+
+pub(crate) struct ChunkStore {
+  dir: PathBuf,
+}
+
+impl ChunkStore {
+  pub(crate) fn new<P: AsRef<Path>>(dir: P) -> Self {
+    Self {
+      dir: dir.as_ref().to_path_buf(),
+    }
+  }
+
+  fn path(&self, hash: &blake3::Hash) -> PathBuf {
+    self.dir.join(hash.to_hex().as_str())
+  }
+
+  /// Adds every chunk of `signature`, read from its file `source`, which the store doesn't have
+  /// yet, and returns the number of added chunks. The directory is created when it's missing.
+  pub(crate) fn export<R: Read + Seek>(
+    &self,
+    signature: &Signature,
+    source: &mut R,
+  ) -> Result<usize, io::Error> {
+    fs::create_dir_all(&self.dir)?;
+
+    let mut buf = vec![0; signature.max_size as usize];
+    let mut added = 0;
+    for chunk in signature.chunks.iter() {
+      let path = self.path(&chunk.hash);
+      if path.exists() {
+        continue;
+      }
+
+      buf.resize(chunk.length, 0);
+      source.seek(SeekFrom::Start(chunk.offset))?;
+      source.read_exact(&mut buf)?;
+      // Renamed into place once complete, so an interrupted export leaves no partial chunk.
+      let partial = path.with_extension("partial");
+      fs::write(&partial, &buf)?;
+      fs::rename(&partial, &path)?;
+      added += 1;
+    }
+
+    Ok(added)
+  }
+
+  /// Opens the chunk with `hash`, which must have `size` bytes.
+  pub(crate) fn open(&self, hash: &blake3::Hash, size: u64) -> Result<File, Box<dyn Error>> {
+    let file = match File::open(self.path(hash)) {
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {
+        return Err(Box::new(MissingChunk(*hash)))
+      }
+      file => file?,
+    };
+    let stored = file.metadata()?.len();
+    if stored != size {
+      return Err(Box::new(ChunkSizeMismatch {
+        hash: *hash,
+        size,
+        stored,
+      }));
+    }
+
+    Ok(file)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::ChunkStore;
+  use crate::apply::{self, ApplyExtras};
+  use crate::diff::write_diff_between;
+  use crate::signature::Signature;
+  use crate::DiffOptions;
+
+  use std::fs;
+  use std::io::Cursor;
+
+  #[test]
+  fn test_apply_from_chunk_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ChunkStore::new(dir.path().join("store"));
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+
+    let source = random(200000);
+    let target = [&source[100000..], &random(5000), &source[..100000]].concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    assert_eq!(
+      store.export(&sig1, &mut Cursor::new(&source)).unwrap(),
+      sig1.chunks.len()
+    );
+    assert_eq!(store.export(&sig1, &mut Cursor::new(&source)).unwrap(), 0);
+
+    let options = DiffOptions {
+      chunk_copies: Some(true),
+      ..Default::default()
+    };
+    let mut diff = Vec::new();
+    write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, &options).unwrap();
+
+    // Only the store has the source data.
+    let mut dest = Cursor::new(Vec::new());
+    let report = apply::apply_with(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(Vec::new()),
+      &mut dest,
+      ApplyExtras {
+        chunks: Some(&store),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+    assert!(report.copy_ops as usize > sig1.chunks.len() / 2);
+
+    let mut dest = Cursor::new(Vec::new());
+    let err = apply::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "diff copies source chunks by their hashes, which needs a chunk store"
+    );
+
+    let missing = sig1.chunks[sig1.chunks.len() / 4].hash;
+    fs::remove_file(dir.path().join("store").join(missing.to_hex().as_str())).unwrap();
+    let mut dest = Cursor::new(Vec::new());
+    let err = apply::apply_with(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(Vec::new()),
+      &mut dest,
+      ApplyExtras {
+        chunks: Some(&store),
+        ..Default::default()
+      },
+    )
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      format!("chunk {} isn't in the chunk store", missing)
+    );
+  }
+}
@@ -1,24 +1,54 @@
-use crate::signature::{Chunk, Signature};
+use crate::chunk_index::{self, ChunkIndex, ChunkKey, DiskIndex};
+use crate::insert_pool::InsertPool;
+use crate::signature::{Chunk, Signature, SignatureReader, DEFAULT_MAX_SIZE};
+use crate::weak_match;
+use crate::{DiffOptions, DiffStats, HashAlgorithm};
 
-use std::collections::HashMap;
+use arrayref::array_ref;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::{self, copy, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
 
 /// Operation is an operation for applying the diff.
 /// `Operation::Insert` is for inserting new data that is not present in the source file.
 /// `Operation::Copy` is for copying existing data from the source file.
-#[derive(Debug, PartialEq)]
+/// `Operation::CopyOutput` is for copying data already written to the output, used when the target
+/// repeats data that is not present in the source file.
+/// `Operation::CopyPool` is for copying inserted data from an insert pool shared by many diffs.
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) enum Operation {
   Copy,
   Insert,
+  CopyOutput,
+  CopyPool,
 }
 
-impl From<u8> for Operation {
-  fn from(num: u8) -> Self {
+#[derive(Debug)]
+pub(crate) struct UnknownOperation(u8);
+
+impl fmt::Display for UnknownOperation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "unknown operation {}", self.0)
+  }
+}
+
+impl Error for UnknownOperation {}
+
+impl TryFrom<u8> for Operation {
+  type Error = UnknownOperation;
+
+  fn try_from(num: u8) -> Result<Self, Self::Error> {
     match num {
-      0 => Operation::Copy,
-      1 => Operation::Insert,
-      _ => unimplemented!(),
+      0 => Ok(Operation::Copy),
+      1 => Ok(Operation::Insert),
+      2 => Ok(Operation::CopyOutput),
+      3 => Ok(Operation::CopyPool),
+      _ => Err(UnknownOperation(num)),
     }
   }
 }
@@ -28,153 +58,1409 @@ impl From<Operation> for u8 {
     match op {
       Operation::Copy => 0,
       Operation::Insert => 1,
+      Operation::CopyOutput => 2,
+      Operation::CopyPool => 3,
     }
   }
 }
 
+#[derive(Debug)]
+struct ChunkingMismatch {
+  a: (u32, u32, u32),
+  b: (u32, u32, u32),
+}
+
+impl fmt::Display for ChunkingMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signatures are calculated with different chunk sizes: {}/{}/{} and {}/{}/{}",
+      self.a.0, self.a.1, self.a.2, self.b.0, self.b.1, self.b.2
+    )
+  }
+}
+
+impl Error for ChunkingMismatch {}
+
+#[derive(Debug)]
+struct MissingSourceData {
+  a: (HashAlgorithm, u8),
+  b: (HashAlgorithm, u8),
+}
+
+impl fmt::Display for MissingSourceData {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signatures are hashed differently: {:?} in {} bytes and {:?} in {} bytes, re-hashing the \
+      source chunks needs the source file",
+      self.a.0, self.a.1, self.b.0, self.b.1
+    )
+  }
+}
+
+impl Error for MissingSourceData {}
+
+#[derive(Debug)]
+struct HashAlgorithmMismatch(HashAlgorithm, HashAlgorithm);
+
+impl fmt::Display for HashAlgorithmMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signatures are calculated with different hash algorithms: {:?} and {:?}",
+      self.0, self.1
+    )
+  }
+}
+
+impl Error for HashAlgorithmMismatch {}
+
+#[derive(Debug)]
+struct HashBytesMismatch(u8, u8);
+
+impl fmt::Display for HashBytesMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signatures store chunk hashes of different lengths: {} and {} bytes",
+      self.0, self.1
+    )
+  }
+}
+
+impl Error for HashBytesMismatch {}
+
+#[derive(Debug)]
+struct UnalignedCopy {
+  offset: u64,
+  size: u64,
+}
+
+impl fmt::Display for UnalignedCopy {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "copy of {} bytes at offset {} of the source doesn't cover whole chunks, so it can't name \
+      them by their hashes",
+      self.size, self.offset
+    )
+  }
+}
+
+impl Error for UnalignedCopy {}
+
+#[derive(Debug)]
+struct NotStreamable(&'static str);
+
+impl fmt::Display for NotStreamable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "`{}` needs the whole target signature, which streaming diffs don't load",
+      self.0
+    )
+  }
+}
+
+impl Error for NotStreamable {}
+
+#[derive(Debug)]
+struct PlanVersionMismatch(u8);
+
+impl fmt::Display for PlanVersionMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "plan version mismatch: got {}, want {}",
+      self.0, PLAN_VERSION
+    )
+  }
+}
+
+impl Error for PlanVersionMismatch {}
+
+#[derive(Debug)]
+struct TruncatedPlan {
+  count: u64,
+  available: u64,
+}
+
+impl fmt::Display for TruncatedPlan {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "plan is truncated, it has room for {} of its {} operations",
+      self.available, self.count
+    )
+  }
+}
+
+impl Error for TruncatedPlan {}
+
+#[derive(Debug)]
+struct BlockingMismatch(Option<u32>, Option<u32>);
+
+impl fmt::Display for BlockingMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let blocking = |block_size: Option<u32>| match block_size {
+      Some(block_size) => format!("fixed {}-byte blocks", block_size),
+      None => "content-defined chunks".to_string(),
+    };
+    write!(
+      f,
+      "signatures are calculated with different blocking: {} and {}",
+      blocking(self.0),
+      blocking(self.1)
+    )
+  }
+}
+
+impl Error for BlockingMismatch {}
+
+#[derive(Debug)]
+struct EmptyWeakBlocks;
+
+impl fmt::Display for EmptyWeakBlocks {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "weak_block_size must be at least 1 byte")
+  }
+}
+
+impl Error for EmptyWeakBlocks {}
+
+/// Checks that the chunks of `a` and `b` are comparable, which needs the same blocking, chunk
+/// sizes and hash algorithm.
+pub(crate) fn check_chunking(a: &Signature, b: &Signature) -> Result<(), Box<dyn Error>> {
+  check_hash_algorithms(a, b)?;
+
+  if a.fixed_block_size.is_some() != b.fixed_block_size.is_some() {
+    return Err(Box::new(BlockingMismatch(
+      a.fixed_block_size,
+      b.fixed_block_size,
+    )));
+  }
+
+  let sizes = |sig: &Signature| (sig.min_size, sig.avg_size, sig.max_size);
+  if sizes(a) != sizes(b) {
+    return Err(Box::new(ChunkingMismatch {
+      a: sizes(a),
+      b: sizes(b),
+    }));
+  }
+
+  Ok(())
+}
+
+/// Diff operations with their offsets and sizes.
+pub(crate) type DiffOps = Vec<(Operation, u64, u64)>;
+
+/// Operations with their positions, offsets and sizes from an operation table.
+pub(crate) type OperationTable = Vec<(Operation, u64, u64, u64)>;
+
+/// The version of the diff format. It was the signature version until signatures started to
+/// store the normalization level.
+pub(crate) const VERSION: u8 = 3;
+/// Set in the header flags when everything after the header is a single zstd stream.
+pub(crate) const FLAG_COMPRESSED: u8 = 1;
+/// Set in the header flags when the diff ends with a `TAG_LEN` bytes long HMAC-SHA256 of
+/// everything before it.
+pub(crate) const FLAG_HMAC_SHA256: u8 = 2;
+pub(crate) const TAG_LEN: u64 = 32;
+/// Set along with `FLAG_COMPRESSED` when the zstd stream is compressed with a dictionary. The
+/// header then ends with the ID of the dictionary.
+pub(crate) const FLAG_DICTIONARY: u8 = 4;
+/// Set in the header flags when the operations end with a table of all of them, so readers can
+/// jump to any operation without parsing the ones before it.
+pub(crate) const FLAG_OPERATION_TABLE: u8 = 8;
+/// Set in the header flags when the operations copy inserted data from an insert pool, see
+/// `insert_pool`.
+pub(crate) const FLAG_INSERT_POOL: u8 = 16;
+/// Set in the header flags when every insert has a checksum of its data.
+pub(crate) const FLAG_INSERT_CHECKSUMS: u8 = 32;
+/// Set in the header flags when copies from the source name its chunks by their hashes instead
+/// of offsets, see `chunk_store`.
+pub(crate) const FLAG_CHUNK_COPIES: u8 = 64;
+/// Set in the header flags when the header has a CAPABILITIES word, with a bit for every feature
+/// added after the flags ran out. Readers reject diffs with bits they don't know.
+pub(crate) const FLAG_CAPABILITIES: u8 = 128;
+/// Set in the CAPABILITIES word when the zstd stream uses long distance matching over a window of
+/// 2^`LONG_WINDOW_LOG` bytes, which zstd doesn't decode unless it's allowed to.
+pub(crate) const CAPABILITY_LONG_WINDOW: u32 = 1;
+pub(crate) const LONG_WINDOW_LOG: u32 = 27;
+/// The number of bytes of the Blake3 hash of the inserted data kept as its checksum.
+pub(crate) const CHECKSUM_LEN: usize = 8;
+/// KIND(u8), POSITION(u64), OFFSET(u64) and SIZE(u64) of an operation table entry.
+pub(crate) const TABLE_ENTRY_LEN: u64 = 25;
+/// The version of the plan format written by `write_plan`:
+///
+/// VERSION(u8), COUNT(u64), then for every operation:
+///   KIND(u8), OFFSET(u64) and SIZE(u64) - inserts are at their offsets in the target
+pub(crate) const PLAN_VERSION: u8 = 1;
+/// KIND(u8), OFFSET(u64) and SIZE(u64) of a plan entry.
+const PLAN_ENTRY_LEN: usize = 17;
+/// The default dictionary size of the zstd CLI.
+pub(crate) const DEFAULT_DICTIONARY_SIZE: u32 = 110 * 1024;
+
+/// Returns `a` with its chunks hashed like the ones of `b` when they're hashed with a different
+/// algorithm or truncated differently, reading them from `a_data`, the file of `a`, so old
+/// signatures can be diffed against ones hashed differently. Without `a_data` that's an error.
+pub(crate) fn hash_like<R: Read + Seek>(
+  a: Signature,
+  b: &Signature,
+  a_data: Option<&mut R>,
+) -> Result<Signature, Box<dyn Error>> {
+  if a.hash_algorithm == b.hash_algorithm && a.hash_bytes == b.hash_bytes {
+    return Ok(a);
+  }
+
+  match a_data {
+    Some(a_data) => Ok(a.rehash(a_data, b.hash_algorithm, b.hash_bytes)?),
+    None => Err(Box::new(MissingSourceData {
+      a: (a.hash_algorithm, a.hash_bytes),
+      b: (b.hash_algorithm, b.hash_bytes),
+    })),
+  }
+}
+
+/// Chunks of different sizes may still match, but hashes of different algorithms or lengths
+/// never do.
+fn check_hash_algorithms(a: &Signature, b: &Signature) -> Result<(), Box<dyn Error>> {
+  if a.hash_algorithm != b.hash_algorithm {
+    return Err(Box::new(HashAlgorithmMismatch(
+      a.hash_algorithm,
+      b.hash_algorithm,
+    )));
+  }
+  if a.hash_bytes != b.hash_bytes {
+    return Err(Box::new(HashBytesMismatch(a.hash_bytes, b.hash_bytes)));
+  }
+
+  Ok(())
+}
+
 /// Generate simple diff format:
 ///
 /// VERSION(u8) - a diff file version for compatibility checking
-/// OPERATION(u8) - 0/1, 0 means copy, 1 means insert
+/// FLAGS(u8) - `FLAG_COMPRESSED` when the operations below are compressed with zstd,
+///   `FLAG_HMAC_SHA256` when the diff ends with an HMAC tag, `FLAG_DICTIONARY` when the zstd
+///   stream needs a dictionary, `FLAG_OPERATION_TABLE` when the operations end with a table,
+///   `FLAG_INSERT_POOL` when they copy from an insert pool, `FLAG_INSERT_CHECKSUMS` when inserts
+///   have checksums, `FLAG_CHUNK_COPIES` when copies from A name its chunks, `FLAG_CAPABILITIES`
+///   when CAPABILITIES follows
+/// TARGET SIZE(u64) - size of the file produced by the diff
+/// CAPABILITIES(u32) - only with `FLAG_CAPABILITIES`, a bit for every further feature used,
+///   `CAPABILITY_LONG_WINDOW` when the zstd stream needs a long window
+/// DICTIONARY ID(u32) - only with `FLAG_DICTIONARY`, 0 for dictionaries without an ID
+/// OPERATION(u8) - 0/1/2/3, 0 means copy, 1 means insert, 2 means copy from the output, 3 means
+///   copy from the insert pool
 /// DATA:
 ///   for 0:
 ///     START OFFSET(u64) - offset of the file A to copy from
 ///     SIZE(u64) - size of a chunk to copy from A
+///   for 0 with `FLAG_CHUNK_COPIES`, once for every chunk:
+///     HASH([u8; 32]) - hash of the chunk of A in its signature
+///     SIZE(u64) - size of the chunk
 ///   for 1:
-///     SIZE(usize) - the number of bytes
+///     SIZE(u64) - the number of bytes
+///     CHECKSUM([u8; CHECKSUM_LEN]) - only with `FLAG_INSERT_CHECKSUMS`, the start of the Blake3
+///       hash of BYTES
 ///     BYTES([u8]) - the raw binary data from file B to be insterted
+///   for 2:
+///     START OFFSET(u64) - offset of the already written output to copy from
+///     SIZE(u64) - size of a chunk to copy from the output
+///   for 3:
+///     START OFFSET(u64) - offset of the insert pool to copy from
+///     SIZE(u64) - size of the data to copy from the pool
 pub(crate) fn write_diff_between<R, W>(
   a: &Signature,
   b: &Signature,
   b_data: &mut R,
   dest: &mut W,
-) -> Result<(), Box<dyn Error>>
+  options: &DiffOptions,
+) -> Result<DiffStats, Box<dyn Error>>
 where
   R: Read + Seek,
   W: Write,
 {
-  // Write the tool version
-  dest.write_all(&[a.version])?;
+  check_hash_algorithms(a, b)?;
+  let max_memory = options.max_memory.map(u64::from);
+  let mut diff = diff_signatures_bounded(a, b, max_memory)?;
+
+  if let Some(gap) = options.coalesce_gap {
+    diff = coalesce_small_copies(diff, gap as u64);
+  }
+  if let Some(align) = options.align {
+    diff = align_operations(diff, align as u64);
+  }
+
+  write_operations(diff, a, b, b_data, dest, options, 0)
+}
+
+/// Returns the option which needs the whole target signature in memory, if any is set.
+pub(crate) fn needs_whole_target(options: &DiffOptions) -> Option<&'static str> {
+  if options.insert_pool.is_some() {
+    Some("insert_pool")
+  } else if options.coalesce_gap.is_some() {
+    Some("coalesce_gap")
+  } else if options.align.is_some() {
+    Some("align")
+  } else {
+    None
+  }
+}
+
+/// Like `write_diff_between`, but reads the chunks of the target signature `b` one at a time and
+/// writes the operations as soon as they're merged, so only the index of the source chunks is kept
+/// in memory, along with the entries of the operation table when there's one. The diff is the same
+/// as `write_diff_between` writes. Options which need the whole target signature are an error,
+/// see `needs_whole_target`.
+pub(crate) fn write_diff_streaming<R, W>(
+  a: &Signature,
+  b: SignatureReader,
+  b_data: &mut R,
+  dest: &mut W,
+  options: &DiffOptions,
+) -> Result<DiffStats, Box<dyn Error>>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  check_hash_algorithms(a, b.header())?;
+  if let Some(option) = needs_whole_target(options) {
+    return Err(Box::new(NotStreamable(option)));
+  }
+  // Inserts are read at the offsets of the chunks of `b`, which must cover its file.
+  let chunks = b.contiguous();
+  let target_size = b.file_size()?;
+
+  match options.max_memory.map(u64::from) {
+    Some(max_memory) if chunk_index::memory_estimate(a.chunks.len()) > max_memory => {
+      let mut index = DiskIndex::build(&a.chunks)?;
+      let diff = ChunkOps::<_, _, ChunkHasher>::new(&mut index, chunks);
+      write_operations_with_size(diff, a, b.header(), target_size, b_data, dest, options, 0)
+    }
+    _ => {
+      let mut index = source_index::<ChunkHasher>(a);
+      let diff = ChunkOps::<_, _, ChunkHasher>::new(&mut index, chunks);
+      write_operations_with_size(diff, a, b.header(), target_size, b_data, dest, options, 0)
+    }
+  }
+}
+
+/// Like `write_diff_between`, but also takes the data of `a`, which is needed to look for data
+/// shifted inside chunks when `options.weak_block_size` is set.
+pub(crate) fn write_diff_between_files<R, W>(
+  a: &Signature,
+  b: &Signature,
+  a_data: &mut R,
+  b_data: &mut R,
+  dest: &mut W,
+  options: &DiffOptions,
+) -> Result<DiffStats, Box<dyn Error>>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  let diff = operations_between_files(a, b, a_data, b_data, options)?;
+
+  write_operations(diff, a, b, b_data, dest, options, 0)
+}
+
+/// Returns the operations transforming the file `a_data` with signature `a` to the file `b_data`
+/// with signature `b`.
+pub(crate) fn operations_between_files<R: Read + Seek>(
+  a: &Signature,
+  b: &Signature,
+  a_data: &mut R,
+  b_data: &mut R,
+  options: &DiffOptions,
+) -> Result<DiffOps, Box<dyn Error>> {
+  check_hash_algorithms(a, b)?;
+  let max_memory = options.max_memory.map(u64::from);
+  let mut diff = diff_signatures_bounded(a, b, max_memory)?;
+
+  if let Some(block_size) = options.weak_block_size {
+    // Empty blocks never advance through the source, so they're rejected like fixed blocks.
+    if block_size == 0 {
+      return Err(Box::new(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        EmptyWeakBlocks,
+      )));
+    }
+    diff = weak_match::refine(diff, a_data, b_data, block_size as usize)?;
+  }
+  if let Some(gap) = options.coalesce_gap {
+    diff = coalesce_small_copies(diff, gap as u64);
+  }
+  if let Some(align) = options.align {
+    diff = align_operations(diff, align as u64);
+  }
+
+  Ok(diff)
+}
+
+fn header_flags(options: &DiffOptions) -> u8 {
+  let compression = if options.dictionary.is_some() {
+    FLAG_COMPRESSED | FLAG_DICTIONARY
+  } else if options.compress.unwrap_or(false) {
+    FLAG_COMPRESSED
+  } else {
+    0
+  };
+
+  let mut flags = compression;
+  if options.operation_table.unwrap_or(false) {
+    flags |= FLAG_OPERATION_TABLE;
+  }
+  if options.insert_pool.is_some() {
+    flags |= FLAG_INSERT_POOL;
+  }
+  if options.insert_checksums.unwrap_or(false) {
+    flags |= FLAG_INSERT_CHECKSUMS;
+  }
+  if options.chunk_copies.unwrap_or(false) {
+    flags |= FLAG_CHUNK_COPIES;
+  }
+  if header_capabilities(options) != 0 {
+    flags |= FLAG_CAPABILITIES;
+  }
+
+  flags
+}
+
+fn header_capabilities(options: &DiffOptions) -> u32 {
+  let compressed = options.dictionary.is_some() || options.compress.unwrap_or(false);
+  match compressed && options.long_window.unwrap_or(false) {
+    true => CAPABILITY_LONG_WINDOW,
+    false => 0,
+  }
+}
+
+/// Makes the `encoder` match across `LONG_WINDOW_LOG` windows when `capabilities` say so.
+fn set_window<W: Write>(
+  encoder: &mut zstd::Encoder<W>,
+  capabilities: u32,
+) -> Result<(), io::Error> {
+  if capabilities & CAPABILITY_LONG_WINDOW != 0 {
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(LONG_WINDOW_LOG)?;
+  }
+
+  Ok(())
+}
+
+/// Returns the ID zstd stores in the dictionary, or 0 for raw content dictionaries.
+pub(crate) fn dictionary_id(dictionary: &[u8]) -> u32 {
+  zstd::zstd_safe::get_dict_id_from_dict(dictionary).map_or(0, NonZeroU32::get)
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes on the `samples` files.
+pub(crate) fn train_dictionary<P: AsRef<Path>>(
+  samples: &[P],
+  max_size: usize,
+) -> Result<Vec<u8>, io::Error> {
+  zstd::dict::from_files(samples, max_size)
+}
+
+/// Serializes the header with the flags of `options` and `extra_flags`, and the diff operations
+/// which transform the file with signature `a` to the file with signature `b`. Returns the sizes
+/// of the written diff.
+pub(crate) fn write_operations<R, W>(
+  diff: Vec<(Operation, u64, u64)>,
+  a: &Signature,
+  b: &Signature,
+  b_data: &mut R,
+  dest: &mut W,
+  options: &DiffOptions,
+  extra_flags: u8,
+) -> Result<DiffStats, Box<dyn Error>>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  // Inserts are read at the offsets of the chunks of `b`, which must cover its file.
+  b.check_contiguous()?;
+  let diff = diff.into_iter().map(Ok);
+  write_operations_with_size(
+    diff,
+    a,
+    b,
+    b.file_size(),
+    b_data,
+    dest,
+    options,
+    extra_flags,
+  )
+}
+
+/// Serializes the header of a diff producing `target_size` bytes and the operations of `diff`,
+/// which may fail while it's read. Only `b` and its options are used to serialize inserts, its
+/// chunks only with an insert pool.
+#[allow(clippy::too_many_arguments)]
+fn write_operations_with_size<D, R, W>(
+  diff: D,
+  a: &Signature,
+  b: &Signature,
+  target_size: u64,
+  b_data: &mut R,
+  dest: &mut W,
+  options: &DiffOptions,
+  extra_flags: u8,
+) -> Result<DiffStats, Box<dyn Error>>
+where
+  D: Iterator<Item = Result<(Operation, u64, u64), io::Error>>,
+  R: Read + Seek,
+  W: Write,
+{
+  let mut dest = CountingWriter {
+    inner: dest,
+    count: 0,
+  };
+  let flags = header_flags(options) | extra_flags;
+  let capabilities = header_capabilities(options);
+  serialize_header_with_flags(target_size, flags, &mut dest)?;
+  if flags & FLAG_CAPABILITIES != 0 {
+    dest.write_all(&capabilities.to_be_bytes())?;
+  }
+  let table = flags & FLAG_OPERATION_TABLE != 0;
+  let checksums = flags & FLAG_INSERT_CHECKSUMS != 0;
+  let chunks = (flags & FLAG_CHUNK_COPIES != 0).then_some(a);
+  let mut pool = match &options.insert_pool {
+    Some(path) => Some(InsertPool::open(path)?),
+    None => None,
+  };
+  let mut header_len = dest.count;
+  let mut stats;
+
+  if let Some(path) = &options.dictionary {
+    let dictionary = fs::read(path)?;
+    dest.write_all(&dictionary_id(&dictionary).to_be_bytes())?;
+    header_len = dest.count;
+    let mut encoder = zstd::Encoder::with_dictionary(&mut dest, 0, &dictionary)?;
+    set_window(&mut encoder, capabilities)?;
+    stats = serialize_operations(
+      diff,
+      b,
+      b_data,
+      &mut encoder,
+      table,
+      checksums,
+      chunks,
+      pool.as_mut(),
+    )?;
+    encoder.finish()?;
+  } else if flags & FLAG_COMPRESSED != 0 {
+    let mut encoder = zstd::Encoder::new(&mut dest, 0)?;
+    set_window(&mut encoder, capabilities)?;
+    stats = serialize_operations(
+      diff,
+      b,
+      b_data,
+      &mut encoder,
+      table,
+      checksums,
+      chunks,
+      pool.as_mut(),
+    )?;
+    encoder.finish()?;
+  } else {
+    stats = serialize_operations(
+      diff,
+      b,
+      b_data,
+      &mut dest,
+      table,
+      checksums,
+      chunks,
+      pool.as_mut(),
+    )?;
+  }
+  stats.compressed_operations_size = (dest.count - header_len) as i64;
+  stats.diff_size = dest.count as i64;
+
+  Ok(stats)
+}
+
+/// Passes everything written through to `inner`, counting the written bytes.
+struct CountingWriter<'a, W> {
+  inner: &'a mut W,
+  count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.count += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Serializes the operations, followed by their table when `table` is set:
+///
+/// for every operation:
+///   KIND(u8) - 0/1/2/3 like OPERATION
+///   POSITION(u64) - the offset of the operation from the start of the operations
+///   OFFSET(u64) - START OFFSET of copies, the offset of BYTES from the start of the operations
+///     for inserts
+///   SIZE(u64)
+/// COUNT(u64) - the number of operations
+///
+/// Positions are in the decompressed operations of compressed diffs. Inserts have checksums when
+/// `checksums` is set. With the source signature in `chunks`, copies from the source name its
+/// chunks by their hashes. With a `pool`, inserted data is added to it and copied from there
+/// instead. Returns the stats with the sizes before compression.
+#[allow(clippy::too_many_arguments)]
+fn serialize_operations<D, R, W>(
+  diff: D,
+  b: &Signature,
+  b_data: &mut R,
+  dest: &mut W,
+  table: bool,
+  checksums: bool,
+  chunks: Option<&Signature>,
+  mut pool: Option<&mut InsertPool>,
+) -> Result<DiffStats, Box<dyn Error>>
+where
+  D: Iterator<Item = Result<(Operation, u64, u64), io::Error>>,
+  R: Read + Seek,
+  W: Write,
+{
+  // Inserts are made of whole chunks, so a buffer of the maximum chunk size is shared by all of
+  // them.
+  let mut buf = vec![0; b.max_size as usize];
+  let mut entries = Vec::with_capacity(if table { diff.size_hint().0 } else { 0 });
+  let mut position = 0;
+  let mut inserted = 0;
 
   // Write the operations
-  for (op, offset, size) in diff_signatures(a, b) {
+  for op in diff {
+    let (op, offset, size) = op?;
     match op {
-      Operation::Copy => {
-        serialize_copy(offset, size, dest)?;
+      Operation::Copy if chunks.is_some() => {
+        for chunk in copied_chunks(chunks.unwrap(), offset, size)? {
+          serialize_chunk_copy(chunk, dest)?;
+          if table {
+            entries.push((op, position, chunk.offset, chunk.length as u64));
+          }
+          position += 41;
+        }
+      }
+      Operation::Copy | Operation::CopyOutput | Operation::CopyPool => {
+        serialize_copy(op, offset, size, dest)?;
+        if table {
+          entries.push((op, position, offset, size));
+        }
+        position += 17;
+      }
+      Operation::Insert if pool.is_some() => {
+        let pool = pool.as_mut().unwrap();
+        for (pool_offset, size) in pool_insert(offset, size, b, b_data, pool, &mut buf)? {
+          serialize_copy(Operation::CopyPool, pool_offset, size, dest)?;
+          if table {
+            entries.push((Operation::CopyPool, position, pool_offset, size));
+          }
+          position += 17;
+        }
       }
       Operation::Insert => {
-        serialize_insert(offset, size, b_data, dest)?;
+        let header_len = if checksums {
+          serialize_insert_with_checksum(offset, size, b_data, dest, &mut buf)?;
+          9 + CHECKSUM_LEN as u64
+        } else {
+          serialize_insert(offset, size, b_data, dest, &mut buf)?;
+          9
+        };
+        if table {
+          entries.push((op, position, position + header_len, size));
+        }
+        position += header_len + size;
+        inserted += size;
       }
     }
   }
 
-  Ok(())
+  if table {
+    for (op, position, offset, size) in entries.iter() {
+      dest.write_all(&[(*op).into()])?;
+      for value in [position, offset, size] {
+        dest.write_all(&value.to_be_bytes())?;
+      }
+    }
+    dest.write_all(&(entries.len() as u64).to_be_bytes())?;
+    position += entries.len() as u64 * TABLE_ENTRY_LEN + 8;
+  }
+
+  Ok(DiffStats {
+    uncompressed_insert_bytes: inserted as i64,
+    operations_size: position as i64,
+    ..Default::default()
+  })
+}
+
+/// Returns the chunks of `a` read by the copy of `size` bytes at `offset`, which must cover them
+/// whole.
+fn copied_chunks(a: &Signature, offset: u64, size: u64) -> Result<&[Chunk], UnalignedCopy> {
+  let first = a.chunks.partition_point(|chunk| chunk.offset < offset);
+  let mut last = first;
+  let mut end = offset;
+  while end < offset + size {
+    match a.chunks.get(last) {
+      Some(chunk) if chunk.offset == end => {
+        end += chunk.length as u64;
+        last += 1;
+      }
+      _ => return Err(UnalignedCopy { offset, size }),
+    }
+  }
+  if end != offset + size {
+    return Err(UnalignedCopy { offset, size });
+  }
+
+  Ok(&a.chunks[first..last])
+}
+
+/// Adds the inserted `size` bytes at `offset` of `b_data` to the `pool` chunk by chunk and returns
+/// the ranges of the pool holding them. Inserts refined by weak matches don't start or end at
+/// chunk boundaries, so their first and last chunks are pooled in part.
+fn pool_insert<R: Read + Seek>(
+  offset: u64,
+  size: u64,
+  b: &Signature,
+  b_data: &mut R,
+  pool: &mut InsertPool,
+  buf: &mut [u8],
+) -> Result<Vec<(u64, u64)>, io::Error> {
+  let end = offset + size;
+  let chunk_end = |chunk: &Chunk| chunk.offset + chunk.length as u64;
+  let mut chunks = b.chunks[b.chunks.partition_point(|chunk| chunk_end(chunk) <= offset)..].iter();
+  let mut boundary = chunks.next().map_or(u64::MAX, chunk_end);
+
+  let mut ranges: Vec<(u64, u64)> = Vec::new();
+  let mut start = offset;
+  while start < end {
+    let piece_end = boundary.min(end).min(start + buf.len() as u64);
+    let data = &mut buf[..(piece_end - start) as usize];
+    b_data.seek(SeekFrom::Start(start))?;
+    b_data.read_exact(data)?;
+
+    let pool_offset = pool.offset_of(data)?;
+    match ranges.last_mut() {
+      Some((last, len)) if *last + *len == pool_offset => *len += data.len() as u64,
+      _ => ranges.push((pool_offset, data.len() as u64)),
+    }
+
+    start = piece_end;
+    if start == boundary {
+      boundary = chunks.next().map_or(u64::MAX, chunk_end);
+    }
+  }
+
+  Ok(ranges)
+}
+
+/// The keys of the chunk index are Blake3 hashes, which are already uniformly distributed, so a
+/// fast hasher is used unless the `fast-hash` feature is disabled.
+#[cfg(feature = "fast-hash")]
+type ChunkHasher = rustc_hash::FxBuildHasher;
+#[cfg(not(feature = "fast-hash"))]
+type ChunkHasher = std::collections::hash_map::RandomState;
+
+/// Returns a vector with tuples: (Operation, offset, size).
+/// For `Operation::Insert` and `Operation::CopyOutput` offset and size refer to the target file.
+/// For `Operation::Copy` offset and size refer to the source file.
+pub(crate) fn diff_signatures<'a>(
+  a: &'a Signature,
+  b: &'a Signature,
+) -> Vec<(Operation, u64, u64)> {
+  diff_signatures_with_hasher::<ChunkHasher>(a, b)
+}
+
+/// Like `diff_signatures`, but keeps the index of the source chunks in a temporary file when it
+/// would take more than `max_memory` bytes in memory.
+pub(crate) fn diff_signatures_bounded(
+  a: &Signature,
+  b: &Signature,
+  max_memory: Option<u64>,
+) -> Result<Vec<(Operation, u64, u64)>, io::Error> {
+  match max_memory {
+    Some(max_memory) if chunk_index::memory_estimate(a.chunks.len()) > max_memory => {
+      let mut index = DiskIndex::build(&a.chunks)?;
+      diff_with_index::<_, ChunkHasher>(&mut index, b)
+    }
+    _ => Ok(diff_signatures(a, b)),
+  }
+}
+
+fn diff_signatures_with_hasher<'a, S>(
+  a: &'a Signature,
+  b: &'a Signature,
+) -> Vec<(Operation, u64, u64)>
+where
+  S: BuildHasher + Default,
+{
+  let mut original_chunks = source_index::<S>(a);
+
+  diff_with_index::<_, S>(&mut original_chunks, b).expect("in-memory index lookups can't fail")
+}
+
+/// Returns the index of the chunks of `a` by their keys, which keeps the first of repeated ones.
+fn source_index<S>(a: &Signature) -> HashMap<ChunkKey, &Chunk, S>
+where
+  S: BuildHasher + Default,
+{
+  let mut original_chunks: HashMap<ChunkKey, &Chunk, S> =
+    HashMap::with_capacity_and_hasher(a.chunks.len(), S::default());
+  for chunk in a.chunks.iter() {
+    original_chunks
+      .entry(chunk_index::key(chunk))
+      .or_insert(chunk);
+  }
+
+  original_chunks
+}
+
+fn diff_with_index<I, S>(
+  original_chunks: &mut I,
+  b: &Signature,
+) -> Result<Vec<(Operation, u64, u64)>, io::Error>
+where
+  I: ChunkIndex,
+  S: BuildHasher + Default,
+{
+  ChunkOps::<_, _, S>::new(original_chunks, b.chunks.iter().cloned().map(Ok)).collect()
+}
+
+/// Looks up the target `chunks` in the index of the source chunks one at a time and yields the
+/// operations producing them, merging the ones of consecutive chunks.
+struct ChunkOps<'a, I, C, S> {
+  original_chunks: &'a mut I,
+  chunks: C,
+  // Chunks inserted so far, which later occurrences can copy from the output.
+  inserted_chunks: HashMap<ChunkKey, u64, S>,
+  current_op: Operation,
+  current_offset: u64,
+  current_length: u64,
+}
+
+impl<'a, I, C, S> ChunkOps<'a, I, C, S>
+where
+  S: BuildHasher + Default,
+{
+  fn new(original_chunks: &'a mut I, chunks: C) -> Self {
+    Self {
+      original_chunks,
+      chunks,
+      inserted_chunks: HashMap::default(),
+      current_op: Operation::Copy,
+      current_offset: 0,
+      current_length: 0,
+    }
+  }
+}
+
+impl<I, C, S> Iterator for ChunkOps<'_, I, C, S>
+where
+  I: ChunkIndex,
+  C: Iterator<Item = Result<Chunk, io::Error>>,
+  S: BuildHasher,
+{
+  type Item = Result<(Operation, u64, u64), io::Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    for new_chunk in self.chunks.by_ref() {
+      let new_chunk = match new_chunk {
+        Ok(chunk) => chunk,
+        Err(err) => return Some(Err(err)),
+      };
+      let (op, offset) = match self.original_chunks.offset_of(&new_chunk) {
+        Ok(Some(offset)) => (Operation::Copy, offset),
+        Ok(None) => match self.inserted_chunks.get(&chunk_index::key(&new_chunk)) {
+          Some(&offset) => (Operation::CopyOutput, offset),
+          None => {
+            self
+              .inserted_chunks
+              .insert(chunk_index::key(&new_chunk), new_chunk.offset);
+            (Operation::Insert, new_chunk.offset)
+          }
+        },
+        Err(err) => return Some(Err(err)),
+      };
+
+      if op == self.current_op && self.current_offset + self.current_length == offset {
+        self.current_length += new_chunk.length as u64;
+      } else {
+        let done = (self.current_op, self.current_offset, self.current_length);
+        self.current_op = op;
+        self.current_offset = offset;
+        self.current_length = new_chunk.length as u64;
+        if done.2 > 0 {
+          return Some(Ok(done));
+        }
+      }
+    }
+
+    // An empty target has no operations.
+    let last = (self.current_op, self.current_offset, self.current_length);
+    self.current_length = 0;
+    (last.2 > 0).then_some(Ok(last))
+  }
+}
+
+/// Turns copies shorter than `gap` bytes between two inserts into a part of one larger insert.
+/// Each operation costs framing bytes, so this makes diffs of noisy edits smaller.
+pub(crate) fn coalesce_small_copies(
+  diff: Vec<(Operation, u64, u64)>,
+  gap: u64,
+) -> Vec<(Operation, u64, u64)> {
+  let mut coalesced: Vec<(Operation, u64, u64)> = Vec::with_capacity(diff.len());
+  let mut ops = diff.into_iter().peekable();
+
+  while let Some(op) = ops.next() {
+    let (kind, _, size) = op;
+    let between_inserts = matches!(coalesced.last(), Some((Operation::Insert, _, _)))
+      && matches!(ops.peek(), Some((Operation::Insert, _, _)));
+
+    if kind != Operation::Insert && size < gap && between_inserts {
+      let (_, _, next_size) = ops.next().unwrap();
+      coalesced.last_mut().unwrap().2 += size + next_size;
+    } else {
+      coalesced.push(op);
+    }
+  }
+
+  coalesced
+}
+
+/// Moves the boundaries between operations to multiples of `align` bytes in the target, except
+/// for the end of the last one. Every block of `align` bytes with a boundary inside it is
+/// inserted whole, and the operations around it are shortened to the blocks they cover whole.
+pub(crate) fn align_operations(
+  diff: Vec<(Operation, u64, u64)>,
+  align: u64,
+) -> Vec<(Operation, u64, u64)> {
+  if align <= 1 {
+    return diff;
+  }
+
+  let target_size: u64 = diff.iter().map(|(_, _, size)| size).sum();
+  let mut aligned: Vec<(Operation, u64, u64)> = Vec::with_capacity(diff.len());
+  let mut push = |op: Operation, offset: u64, size: u64| match aligned.last_mut() {
+    Some((last, last_offset, last_size)) if *last == op && *last_offset + *last_size == offset => {
+      *last_size += size
+    }
+    _ => aligned.push((op, offset, size)),
+  };
+
+  // The end of the target the aligned operations produce.
+  let mut written = 0;
+  let mut start = 0;
+  for (op, offset, size) in diff {
+    let end = start + size;
+    let block_start = end - end % align;
+    let piece_start = start.max(written);
+    let piece_end = if end == target_size { end } else { block_start };
+    if piece_start < piece_end {
+      // Inserts are at their offsets in the target like the pieces.
+      push(op, offset + piece_start - start, piece_end - piece_start);
+      written = piece_end;
+    }
+
+    if end % align != 0 && end != target_size {
+      let block_end = (block_start + align).min(target_size);
+      let insert_start = written.max(block_start);
+      if insert_start < block_end {
+        push(Operation::Insert, insert_start, block_end - insert_start);
+        written = block_end;
+      }
+    }
+    start = end;
+  }
+
+  aligned
+}
+
+const EDGE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Returns the lengths of the common prefix and suffix of `a` and `b`, compared block by block
+/// from both ends. The suffix doesn't overlap the prefix, so together they never exceed the
+/// shorter of the two.
+pub(crate) fn common_edges<A, B>(a: &mut A, b: &mut B) -> Result<(u64, u64), io::Error>
+where
+  A: Read + Seek,
+  B: Read + Seek,
+{
+  let a_len = a.seek(SeekFrom::End(0))?;
+  let b_len = b.seek(SeekFrom::End(0))?;
+  let len = a_len.min(b_len);
+  let mut a_block = vec![0; EDGE_BLOCK_SIZE];
+  let mut b_block = vec![0; EDGE_BLOCK_SIZE];
+
+  a.seek(SeekFrom::Start(0))?;
+  b.seek(SeekFrom::Start(0))?;
+  let mut prefix = 0;
+  while prefix < len {
+    let size = (len - prefix).min(EDGE_BLOCK_SIZE as u64) as usize;
+    a.read_exact(&mut a_block[..size])?;
+    b.read_exact(&mut b_block[..size])?;
+    let same = common_len(a_block[..size].iter(), b_block[..size].iter());
+    prefix += same as u64;
+    if same < size {
+      break;
+    }
+  }
+
+  let mut suffix = 0;
+  while prefix + suffix < len {
+    let size = (len - prefix - suffix).min(EDGE_BLOCK_SIZE as u64) as usize;
+    a.seek(SeekFrom::Start(a_len - suffix - size as u64))?;
+    b.seek(SeekFrom::Start(b_len - suffix - size as u64))?;
+    a.read_exact(&mut a_block[..size])?;
+    b.read_exact(&mut b_block[..size])?;
+    let same = common_len(a_block[..size].iter().rev(), b_block[..size].iter().rev());
+    suffix += same as u64;
+    if same < size {
+      break;
+    }
+  }
+
+  Ok((prefix, suffix))
+}
+
+fn common_len<'a>(a: impl Iterator<Item = &'a u8>, b: impl Iterator<Item = &'a u8>) -> usize {
+  a.zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Returns the hashes of the chunks of `want` which `have` has none of, once each and in the
+/// order of `want`. Only the hashes are kept, the chunks are read one at a time.
+pub(crate) fn missing_hashes(
+  have: SignatureReader,
+  want: SignatureReader,
+) -> Result<Vec<blake3::Hash>, Box<dyn Error>> {
+  check_chunking(have.header(), want.header())?;
+
+  let mut seen = HashSet::with_capacity(have.len());
+  for chunk in have {
+    seen.insert(chunk?.hash);
+  }
+  let mut missing = Vec::new();
+  for chunk in want {
+    let hash = chunk?.hash;
+    if seen.insert(hash) {
+      missing.push(hash);
+    }
+  }
+
+  Ok(missing)
 }
 
-/// Returns a vector with tuples: (Operation, offset, size).
-/// For `Operation::Insert` offset and size refer to the target file.
-/// For `Operation::Copy` offset and size refer to the source file.
-pub(crate) fn diff_signatures<'a>(
-  a: &'a Signature,
-  b: &'a Signature,
-) -> Vec<(Operation, u64, u64)> {
-  let mut original_chunks: HashMap<blake3::Hash, &Chunk> = HashMap::with_capacity(a.chunks.len());
+/// Returns the size of the smallest diff without compression or other options which transforms
+/// the file of `a` to the file of `b` using their chunks: every chunk of `b` which is neither in
+/// `a` nor earlier in `b` is inserted, and all others are copied with as few operations as
+/// possible, from any of their occurrences. `diff_signatures` only copies from the first
+/// occurrence of every chunk, so its diffs may be larger.
+pub(crate) fn optimal_size(a: &Signature, b: &Signature) -> Result<u64, Box<dyn Error>> {
+  check_hash_algorithms(a, b)?;
+  let at_offset: HashMap<u64, ChunkKey> = a
+    .chunks
+    .iter()
+    .map(|chunk| (chunk.offset, chunk_index::key(chunk)))
+    .collect();
+  let mut offsets: HashMap<ChunkKey, Vec<u64>> = HashMap::new();
   for chunk in a.chunks.iter() {
-    original_chunks.entry(chunk.hash).or_insert(chunk);
-  }
-
-  let mut diff: Vec<(Operation, u64, u64)> = Vec::new();
-  let mut current_op: Operation = Operation::Copy;
-  let mut current_length = 0;
-  let mut current_offset = 0;
-  for new_chunk in b.chunks.iter() {
-    match original_chunks.get(&new_chunk.hash) {
-      Some(&chunk) => match current_op {
-        Operation::Copy => {
-          if current_offset + current_length == chunk.offset {
-            current_length += chunk.length as u64;
-          } else {
-            if current_length > 0 {
-              diff.push((Operation::Copy, current_offset, current_length));
-            }
-            current_offset = chunk.offset;
-            current_length = chunk.length as u64;
-          }
-        }
-        Operation::Insert => {
-          if current_length > 0 {
-            diff.push((Operation::Insert, current_offset, current_length));
-            current_offset = chunk.offset;
-          }
-          current_length = chunk.length as u64;
-          current_op = Operation::Copy;
-        }
-      },
-      None => match current_op {
-        Operation::Insert => {
-          if current_offset + current_length == new_chunk.offset {
-            current_length += new_chunk.length as u64;
-          } else {
-            if current_length > 0 {
-              diff.push((Operation::Insert, current_offset, current_length));
-            }
-            current_offset = new_chunk.offset;
-            current_length = new_chunk.length as u64;
-          }
-        }
-        Operation::Copy => {
-          if current_length > 0 {
-            diff.push((Operation::Copy, current_offset, current_length));
-            current_offset = new_chunk.offset;
-          }
-          current_length = new_chunk.length as u64;
-          current_op = Operation::Insert;
-        }
-      },
+    offsets
+      .entry(chunk_index::key(chunk))
+      .or_default()
+      .push(chunk.offset);
+  }
+  // The indexes of the chunks of `b` written so far.
+  let mut written: HashMap<ChunkKey, Vec<usize>> = HashMap::new();
+
+  let chunks = &b.chunks;
+  let key = |i: usize| chunk_index::key(&chunks[i]);
+  // VERSION, FLAGS and TARGET SIZE.
+  let mut size = 10;
+  let mut inserting = false;
+  let mut i = 0;
+  while i < chunks.len() {
+    // A run of chunks can be copied at once when it follows each other in the source or in the
+    // output written before it. The shorter parts of such a run can be too, so copying the
+    // longest run every time takes the fewest copies.
+    let remaining = chunks.len() - i;
+    let mut run = 0;
+    for &offset in offsets.get(&key(i)).into_iter().flatten() {
+      let (mut len, mut next) = (0, offset);
+      while len < remaining && at_offset.get(&next) == Some(&key(i + len)) {
+        next += chunks[i + len].length as u64;
+        len += 1;
+      }
+      run = run.max(len);
+      if run == remaining {
+        break;
+      }
+    }
+    for &start in written.get(&key(i)).into_iter().flatten() {
+      // Later chunks have less output before `i` to copy.
+      if i - start <= run {
+        break;
+      }
+      let mut len = 0;
+      while len < remaining && start + len < i && key(start + len) == key(i + len) {
+        len += 1;
+      }
+      run = run.max(len);
+      if run == remaining {
+        break;
+      }
+    }
+
+    if run == 0 {
+      // SIZE(u64) of the first inserted chunk and the data of all of them.
+      if !inserting {
+        size += 9;
+      }
+      inserting = true;
+      size += chunks[i].length as u64;
+      run = 1;
+    } else {
+      // START OFFSET(u64) and SIZE(u64).
+      size += 17;
+      inserting = false;
     }
+    for j in i..i + run {
+      written.entry(key(j)).or_default().push(j);
+    }
+    i += run;
   }
-  diff.push((current_op, current_offset, current_length));
 
-  diff
+  Ok(size)
+}
+
+#[cfg(test)]
+pub(crate) fn serialize_header<W: Write>(target_size: u64, dest: &mut W) -> Result<(), io::Error> {
+  serialize_header_with_flags(target_size, 0, dest)
+}
+
+pub(crate) fn serialize_header_with_flags<W: Write>(
+  target_size: u64,
+  flags: u8,
+  dest: &mut W,
+) -> Result<(), io::Error> {
+  // Write the tool version
+  dest.write_all(&[VERSION])?;
+  dest.write_all(&[flags])?;
+  dest.write_all(target_size.to_be_bytes().as_ref())?;
+
+  Ok(())
 }
 
+/// Writes an insert operation with `size` bytes of `source` starting at `offset`. The data is
+/// copied through `buf`, which is reused between calls to avoid allocating on every insert.
 pub(crate) fn serialize_insert<R, W>(
   offset: u64,
   size: u64,
   source: &mut R,
   dest: &mut W,
+  buf: &mut Vec<u8>,
+) -> Result<(), io::Error>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  dest.write_all(&[Operation::Insert.into()])?;
+  dest.write_all(size.to_be_bytes().as_ref())?;
+
+  copy_through(offset, size, source, buf, |data| dest.write_all(data))
+}
+
+/// Like `serialize_insert`, but also writes the checksum of the data before it, which takes
+/// reading the data twice.
+pub(crate) fn serialize_insert_with_checksum<R, W>(
+  offset: u64,
+  size: u64,
+  source: &mut R,
+  dest: &mut W,
+  buf: &mut Vec<u8>,
 ) -> Result<(), io::Error>
 where
   R: Read + Seek,
   W: Write,
 {
+  let mut hasher = blake3::Hasher::new();
+  copy_through(offset, size, source, buf, |data| {
+    hasher.update(data);
+    Ok(())
+  })?;
+
   dest.write_all(&[Operation::Insert.into()])?;
   dest.write_all(size.to_be_bytes().as_ref())?;
+  dest.write_all(&hasher.finalize().as_bytes()[..CHECKSUM_LEN])?;
+
+  copy_through(offset, size, source, buf, |data| dest.write_all(data))
+}
+
+/// Passes `size` bytes of `source` starting at `offset` to `f` through `buf`.
+fn copy_through<R, F>(
+  offset: u64,
+  size: u64,
+  source: &mut R,
+  buf: &mut Vec<u8>,
+  mut f: F,
+) -> Result<(), io::Error>
+where
+  R: Read + Seek,
+  F: FnMut(&[u8]) -> Result<(), io::Error>,
+{
+  if buf.is_empty() {
+    buf.resize(DEFAULT_MAX_SIZE as usize, 0);
+  }
 
   source.seek(SeekFrom::Start(offset))?;
-  let mut chunk = source.take(size);
-  copy(&mut chunk, dest)?;
+  let mut remaining = size;
+  while remaining > 0 {
+    let len = remaining.min(buf.len() as u64) as usize;
+    source.read_exact(&mut buf[..len])?;
+    f(&buf[..len])?;
+    remaining -= len as u64;
+  }
 
   Ok(())
 }
 
+/// Writes a copy operation, `op` is either `Operation::Copy` or `Operation::CopyOutput`.
 pub(crate) fn serialize_copy<W: Write>(
+  op: Operation,
   offset: u64,
   size: u64,
   dest: &mut W,
 ) -> Result<(), Box<dyn Error>> {
-  dest.write_all(&[Operation::Copy.into()])?;
+  dest.write_all(&[op.into()])?;
   dest.write_all(offset.to_be_bytes().as_ref())?;
   dest.write_all(size.to_be_bytes().as_ref())?;
 
   Ok(())
 }
 
+/// Writes a copy of the source `chunk` named by its hash.
+fn serialize_chunk_copy<W: Write>(chunk: &Chunk, dest: &mut W) -> Result<(), io::Error> {
+  dest.write_all(&[Operation::Copy.into()])?;
+  dest.write_all(chunk.hash.as_bytes())?;
+  dest.write_all((chunk.length as u64).to_be_bytes().as_ref())?;
+
+  Ok(())
+}
+
+/// Writes the operations of `diff_signatures` without any inserted data, so plans of many versions
+/// can be cached and compared cheaply.
+pub(crate) fn write_plan<W: Write>(
+  ops: &[(Operation, u64, u64)],
+  dest: &mut W,
+) -> Result<(), io::Error> {
+  dest.write_all(&[PLAN_VERSION])?;
+  dest.write_all((ops.len() as u64).to_be_bytes().as_ref())?;
+  for &(op, offset, size) in ops {
+    dest.write_all(&[op.into()])?;
+    dest.write_all(offset.to_be_bytes().as_ref())?;
+    dest.write_all(size.to_be_bytes().as_ref())?;
+  }
+
+  Ok(())
+}
+
+/// Loads a plan written by `write_plan`. Like signatures, plans may come from elsewhere, so the
+/// count is checked against the bytes that follow before anything is allocated.
+pub(crate) fn load_plan(data: &[u8]) -> Result<DiffOps, Box<dyn Error>> {
+  let truncated = || io::Error::from(io::ErrorKind::UnexpectedEof);
+  let (&version, rest) = data.split_first().ok_or_else(truncated)?;
+  if version != PLAN_VERSION {
+    return Err(Box::new(PlanVersionMismatch(version)));
+  }
+  let count = rest.get(..8).ok_or_else(truncated)?;
+  let count = u64::from_be_bytes(*array_ref![count, 0, 8]);
+  let entries = &rest[8..];
+  let available = (entries.len() / PLAN_ENTRY_LEN) as u64;
+  if count > available {
+    return Err(Box::new(TruncatedPlan { count, available }));
+  }
+
+  entries
+    .chunks_exact(PLAN_ENTRY_LEN)
+    .take(count as usize)
+    .map(|entry| {
+      Ok((
+        Operation::try_from(entry[0])?,
+        u64::from_be_bytes(*array_ref![entry, 1, 8]),
+        u64::from_be_bytes(*array_ref![entry, 9, 8]),
+      ))
+    })
+    .collect()
+}
+
 #[cfg(test)]
 mod tests {
   use super::Chunk;
   use super::Operation;
   use super::Signature;
+  use super::SignatureReader;
+
+  #[test]
+  fn test_optimal_size() {
+    use crate::{DiffOptions, HashAlgorithm};
+    use std::io::Cursor;
+
+    let blocks: Vec<Vec<u8>> = (0..5)
+      .map(|_| (0..1024).map(|_| rand::random::<u8>()).collect())
+      .collect();
+    let [a, b, c, d, x] = [0, 1, 2, 3, 4].map(|i| &blocks[i][..]);
+    let sizes = |source: &[&[u8]], target: &[&[u8]]| {
+      let source = source.concat();
+      let target = target.concat();
+      let signature = |data: &[u8]| {
+        Signature::calculate_fixed(
+          &mut Cursor::new(data),
+          1024,
+          HashAlgorithm::Blake3,
+          32,
+          false,
+        )
+        .unwrap()
+      };
+      let (sig1, sig2) = (signature(&source), signature(&target));
+      let mut diff = Vec::new();
+      super::write_diff_between(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions::default(),
+      )
+      .unwrap();
+      (
+        super::optimal_size(&sig1, &sig2).unwrap(),
+        diff.len() as u64,
+      )
+    };
+
+    // The header, copies of C and D at once, of A and of B, one insert of X and a copy of X from
+    // the output.
+    let optimal = 10 + 3 * 17 + (9 + 1024) + 17;
+    assert_eq!(
+      sizes(&[a, b, c, d], &[c, d, a, x, x, b]),
+      (optimal, optimal)
+    );
+    // The second A is followed by C, so both are copied at once.
+    assert_eq!(sizes(&[a, b, a, c], &[a, c]), (10 + 17, 10 + 2 * 17));
+    assert_eq!(sizes(&[a], &[]), (10, 10));
+    // The second X is copied from the first, the last two from the first two.
+    assert_eq!(
+      sizes(&[], &[x, x, x, x]),
+      (10 + 9 + 1024 + 2 * 17, 10 + 9 + 1024 + 3 * 17)
+    );
+  }
 
   #[test]
   fn test_diff_signatures() {
@@ -200,6 +1486,12 @@ mod tests {
       min_size: 1024,
       avg_size: 1024,
       max_size: 2048,
+      hash_algorithm: crate::HashAlgorithm::Blake3,
+      normalization: crate::signature::DEFAULT_NORMALIZATION,
+      hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
       chunks: chunks1,
     };
 
@@ -240,6 +1532,12 @@ mod tests {
       min_size: 1024,
       avg_size: 1024,
       max_size: 2048,
+      hash_algorithm: crate::HashAlgorithm::Blake3,
+      normalization: crate::signature::DEFAULT_NORMALIZATION,
+      hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
       chunks: chunks2,
     };
 
@@ -255,4 +1553,693 @@ mod tests {
       ]
     )
   }
+
+  #[test]
+  fn test_diff_signatures_hashers() {
+    use std::collections::hash_map::RandomState;
+
+    // Every 7th chunk of the second signature is changed.
+    let chunks = |changed: bool| -> Vec<Chunk> {
+      (0..100_000u64)
+        .map(|i| {
+          let mut data = i.to_be_bytes().to_vec();
+          if changed && i % 7 == 0 {
+            data.push(0);
+          }
+
+          Chunk {
+            hash: blake3::hash(&data),
+            offset: i * 16,
+            length: 16,
+          }
+        })
+        .collect()
+    };
+    let signature = |chunks| Signature {
+      version: 0,
+      min_size: 16,
+      avg_size: 16,
+      max_size: 16,
+      hash_algorithm: crate::HashAlgorithm::Blake3,
+      normalization: crate::signature::DEFAULT_NORMALIZATION,
+      hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
+      chunks,
+    };
+    let sig1 = signature(chunks(false));
+    let sig2 = signature(chunks(true));
+
+    let fast = super::diff_signatures(&sig1, &sig2);
+    let default = super::diff_signatures_with_hasher::<RandomState>(&sig1, &sig2);
+    assert_eq!(fast, default);
+  }
+
+  #[test]
+  fn test_serialize_insert_reuses_buffer() {
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let mut source = Cursor::new(&data);
+    let mut buf = vec![0; 64];
+    let mut serialized = Vec::new();
+    let mut expected = Vec::new();
+
+    for (offset, size) in [(0, 10), (100, 64), (500, 1000), (9990, 10)] {
+      super::serialize_insert(offset, size, &mut source, &mut serialized, &mut buf).unwrap();
+
+      expected.push(Operation::Insert.into());
+      expected.extend_from_slice(&size.to_be_bytes());
+      expected.extend_from_slice(&data[offset as usize..(offset + size) as usize]);
+    }
+
+    assert_eq!(serialized, expected);
+    assert_eq!(buf.capacity(), 64);
+  }
+
+  #[test]
+  fn test_diff_signatures_copies_repeated_output() {
+    let chunk = |hash: u8, offset: u64, length: usize| Chunk {
+      hash: [hash; 32].into(),
+      offset,
+      length,
+    };
+    let signature = |chunks| Signature {
+      version: 0,
+      min_size: 16,
+      avg_size: 16,
+      max_size: 64,
+      hash_algorithm: crate::HashAlgorithm::Blake3,
+      normalization: crate::signature::DEFAULT_NORMALIZATION,
+      hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
+      chunks,
+    };
+    let sig1 = signature(vec![chunk(1, 0, 16), chunk(2, 16, 16)]);
+    // Chunks 3 and 4 are not in the source and are repeated.
+    let sig2 = signature(vec![
+      chunk(1, 0, 16),
+      chunk(3, 16, 32),
+      chunk(4, 48, 16),
+      chunk(2, 64, 16),
+      chunk(3, 80, 32),
+      chunk(4, 112, 16),
+      chunk(3, 128, 32),
+    ]);
+
+    let res = super::diff_signatures(&sig1, &sig2);
+    assert_eq!(
+      res,
+      vec![
+        (Operation::Copy, 0, 16),
+        (Operation::Insert, 16, 48),
+        (Operation::Copy, 16, 16),
+        (Operation::CopyOutput, 16, 48),
+        (Operation::CopyOutput, 16, 32),
+      ]
+    )
+  }
+
+  #[test]
+  fn test_diff_copies_repeated_novel_block() {
+    use crate::DiffOptions;
+    use std::io::Cursor;
+
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let source = random(60000);
+    let novel = random(40000);
+    let target = [&source[..30000], &novel, &source[30000..], &novel].concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+
+    // The second occurrence of the block is copied back from where it was first inserted.
+    let ops = super::diff_signatures(&sig1, &sig2);
+    let novel_at = 30000..30000 + novel.len() as u64;
+    let copied_back: u64 = ops
+      .iter()
+      .filter(|(op, offset, _)| *op == Operation::CopyOutput && novel_at.contains(offset))
+      .map(|(_, _, size)| size)
+      .sum();
+    assert!(copied_back > novel.len() as u64 - 2 * 16384);
+
+    let mut diff = Vec::new();
+    super::write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+    assert!(diff.len() < novel.len() + 2 * 16384 + 1000);
+
+    let mut result = Cursor::new(Vec::new());
+    crate::apply::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(&source),
+      &mut result,
+    )
+    .unwrap();
+    assert_eq!(result.into_inner(), target);
+  }
+
+  #[test]
+  fn test_diff_signatures_same_hash_other_length() {
+    let chunk = |hash: u8, offset: u64, length: usize| Chunk {
+      hash: [hash; 32].into(),
+      offset,
+      length,
+    };
+    let signature = |chunks| Signature {
+      version: 0,
+      min_size: 16,
+      avg_size: 16,
+      max_size: 64,
+      hash_algorithm: crate::HashAlgorithm::Blake3,
+      normalization: crate::signature::DEFAULT_NORMALIZATION,
+      hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
+      chunks,
+    };
+    let sig1 = signature(vec![chunk(1, 0, 16), chunk(2, 16, 16)]);
+    // Chunk 2 collides with a longer chunk, which is inserted and then copied from the output.
+    let sig2 = signature(vec![
+      chunk(2, 0, 32),
+      chunk(1, 32, 16),
+      chunk(2, 48, 32),
+      chunk(2, 80, 16),
+    ]);
+
+    let expected = vec![
+      (Operation::Insert, 0, 32),
+      (Operation::Copy, 0, 16),
+      (Operation::CopyOutput, 0, 32),
+      (Operation::Copy, 16, 16),
+    ];
+    assert_eq!(super::diff_signatures(&sig1, &sig2), expected);
+    assert_eq!(
+      super::diff_signatures_bounded(&sig1, &sig2, Some(0)).unwrap(),
+      expected
+    );
+  }
+
+  #[test]
+  fn test_diff_signatures_bounded() {
+    use std::io::Cursor;
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.drain(1000..2000);
+    target[50000..50100].copy_from_slice(&[0u8; 100]);
+    target.extend_from_slice(&source[..20000]);
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 256, 1024, 4096).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 256, 1024, 4096).unwrap();
+
+    let in_memory = super::diff_signatures(&sig1, &sig2);
+    let on_disk = super::diff_signatures_bounded(&sig1, &sig2, Some(0)).unwrap();
+    assert_eq!(in_memory, on_disk);
+    assert_eq!(
+      super::diff_signatures_bounded(&sig1, &sig2, Some(u64::MAX)).unwrap(),
+      in_memory
+    );
+  }
+
+  #[test]
+  fn test_weak_matching_shifted_data() {
+    use crate::DiffOptions;
+    use std::io::{self, Cursor};
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = vec![42u8];
+    target.extend_from_slice(&source);
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+
+    let diff_size = |weak_block_size| {
+      let mut diff = Vec::new();
+      super::write_diff_between_files(
+        &sig1,
+        &sig2,
+        &mut Cursor::new(&source),
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions {
+          weak_block_size,
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      diff.len()
+    };
+
+    let plain = diff_size(None);
+    let weak = diff_size(Some(256));
+    assert!(weak < plain);
+    // One inserted byte plus at most one partial block at the end of the first chunk.
+    assert!(weak < 512, "diff is {} bytes", weak);
+
+    let err = super::operations_between_files(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&source),
+      &mut Cursor::new(&target),
+      &DiffOptions {
+        weak_block_size: Some(0),
+        ..Default::default()
+      },
+    )
+    .unwrap_err();
+    let err = err.downcast_ref::<io::Error>().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn test_coalesce_small_copies() {
+    use std::io::Cursor;
+
+    let source: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    for i in (0..target.len()).step_by(1000) {
+      target[i] = target[i].wrapping_add(1);
+    }
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 64, 256, 1024).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 64, 256, 1024).unwrap();
+
+    let diff = super::diff_signatures(&sig1, &sig2);
+    let coalesced = super::coalesce_small_copies(diff.clone(), 1024);
+
+    assert!(coalesced.len() < diff.len() / 10);
+    let size = |ops: &[(Operation, u64, u64)]| ops.iter().map(|op| op.2).sum::<u64>();
+    assert_eq!(size(&coalesced), size(&diff));
+    assert_eq!(size(&coalesced), target.len() as u64);
+  }
+
+  #[test]
+  fn test_align_operations() {
+    use crate::apply;
+    use crate::DiffOptions;
+    use std::io::Cursor;
+
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let source = random(200000);
+    let novel = random(3000);
+    let target = [
+      &source[1000..60000],
+      &novel,
+      &source[90000..150000],
+      &novel,
+      &source[..700],
+    ]
+    .concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 256, 1024, 4096).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 256, 1024, 4096).unwrap();
+
+    let diff = super::diff_signatures(&sig1, &sig2);
+    for align in [512, 4096, 16384] {
+      let aligned = super::align_operations(diff.clone(), align);
+      let mut position = 0;
+      for (_, _, size) in aligned.iter() {
+        assert_eq!(position % align, 0);
+        position += size;
+      }
+      assert_eq!(position, target.len() as u64);
+      assert!(aligned.iter().any(|(op, _, _)| *op == Operation::Copy));
+
+      let options = DiffOptions {
+        align: Some(align as u32),
+        ..Default::default()
+      };
+      let mut diff = Vec::new();
+      super::write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, &options)
+        .unwrap();
+      let mut dest = Cursor::new(Vec::new());
+      apply::apply(
+        &mut Cursor::new(&diff),
+        &mut Cursor::new(&source),
+        &mut dest,
+      )
+      .unwrap();
+      assert_eq!(dest.into_inner(), target);
+    }
+    assert_eq!(super::align_operations(diff.clone(), 1), diff);
+  }
+
+  #[test]
+  fn test_diff_signatures_empty() {
+    use std::io::Cursor;
+
+    let empty = Signature::calculate(&mut Cursor::new(Vec::new()), 1024, 4096, 16384).unwrap();
+    let data: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+
+    let diff = super::diff_signatures(&empty, &sig);
+    assert!(diff.iter().all(|op| op.0 == Operation::Insert));
+    assert_eq!(diff.iter().map(|op| op.2).sum::<u64>(), 10000);
+    assert_eq!(super::diff_signatures(&sig, &empty), vec![]);
+    assert_eq!(super::diff_signatures(&empty, &empty), vec![]);
+  }
+
+  #[test]
+  fn test_diff_with_truncated_hashes() {
+    use crate::signature::DEFAULT_NORMALIZATION;
+    use crate::{DiffOptions, HashAlgorithm};
+    use std::io::Cursor;
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..300).map(|_| rand::random::<u8>()));
+
+    let calculate = |data: &[u8], hash_bytes| {
+      let sig = Signature::calculate_with(
+        &mut Cursor::new(data),
+        1024,
+        4096,
+        16384,
+        HashAlgorithm::Blake3,
+        DEFAULT_NORMALIZATION,
+        hash_bytes,
+      )
+      .unwrap();
+      let mut serialized = Vec::new();
+      sig.write(&mut serialized).unwrap();
+      (Signature::load(&serialized).unwrap(), serialized.len())
+    };
+    let (full, full_len) = calculate(&source, 32);
+    let (sig1, short_len) = calculate(&source, 16);
+    let (sig2, _) = calculate(&target, 16);
+    assert_eq!(sig1.hash_bytes, 16);
+    assert_eq!(full_len - short_len, full.chunks.len() * 16);
+
+    let mut diff = Vec::new();
+    super::write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+    assert!(diff.len() < 40000);
+
+    let mut result = Cursor::new(Vec::new());
+    crate::apply::apply(
+      &mut Cursor::new(diff),
+      &mut Cursor::new(&source),
+      &mut result,
+    )
+    .unwrap();
+    assert_eq!(result.into_inner(), target);
+
+    let err = super::write_diff_between(
+      &full,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut Vec::new(),
+      &DiffOptions::default(),
+    )
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "signatures store chunk hashes of different lengths: 32 and 16 bytes"
+    );
+    assert!(Signature::calculate_with(
+      &mut Cursor::new(&source),
+      1024,
+      4096,
+      16384,
+      HashAlgorithm::Blake3,
+      DEFAULT_NORMALIZATION,
+      20,
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn test_common_edges() {
+    use std::io::Cursor;
+
+    let prefix: Vec<u8> = (0..1 << 20).map(|_| rand::random::<u8>()).collect();
+    let suffix: Vec<u8> = (0..1 << 20).map(|_| rand::random::<u8>()).collect();
+    let a = [&prefix[..], &[1; 1000], &suffix[..]].concat();
+    let b = [&prefix[..], &[2; 3000], &suffix[..]].concat();
+
+    let edges =
+      |a: &[u8], b: &[u8]| super::common_edges(&mut Cursor::new(a), &mut Cursor::new(b)).unwrap();
+    assert_eq!(edges(&a, &b), (1 << 20, 1 << 20));
+    assert_eq!(edges(&b, &a), (1 << 20, 1 << 20));
+    assert_eq!(edges(&a, &a), (a.len() as u64, 0));
+    assert_eq!(edges(&prefix, &a), (1 << 20, 0));
+    assert_eq!(edges(&suffix, &a), (0, 1 << 20));
+    assert_eq!(edges(&[], &a), (0, 0));
+  }
+
+  #[test]
+  fn test_diff_signatures_zero_length_chunks() {
+    let signature = |chunks| Signature {
+      version: 0,
+      min_size: 16,
+      avg_size: 16,
+      max_size: 16,
+      hash_algorithm: crate::HashAlgorithm::Blake3,
+      normalization: crate::signature::DEFAULT_NORMALIZATION,
+      hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
+      chunks,
+    };
+    let chunk = |hash: u8, offset, length| Chunk {
+      hash: [hash; 32].into(),
+      offset,
+      length,
+    };
+
+    let a = signature(vec![chunk(1, 0, 100)]);
+    let b = signature(vec![chunk(1, 0, 100), chunk(2, 100, 0)]);
+    assert_eq!(
+      super::diff_signatures(&a, &b),
+      vec![(Operation::Copy, 0, 100)]
+    );
+
+    let b = signature(vec![chunk(2, 0, 0), chunk(1, 0, 100), chunk(3, 100, 0)]);
+    assert_eq!(
+      super::diff_signatures(&a, &b),
+      vec![(Operation::Copy, 0, 100)]
+    );
+    assert_eq!(
+      super::diff_signatures(&a, &signature(vec![chunk(2, 0, 0)])),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn test_missing_hashes() {
+    let chunk = |hash: u8, offset: u64| Chunk {
+      hash: [hash; 32].into(),
+      offset,
+      length: 16,
+    };
+    let signature = |chunks| {
+      let mut serialized = Vec::new();
+      Signature {
+        version: 0,
+        min_size: 1024,
+        avg_size: 4096,
+        max_size: 16384,
+        hash_algorithm: crate::HashAlgorithm::Blake3,
+        normalization: crate::signature::DEFAULT_NORMALIZATION,
+        hash_bytes: crate::signature::DEFAULT_HASH_BYTES,
+        fixed_block_size: None,
+        weak_checksums: None,
+        boundaries: Vec::new(),
+        chunks,
+      }
+      .write(&mut serialized)
+      .unwrap();
+      serialized
+    };
+    let missing = |have: &[u8], want: &[u8]| {
+      let (have, want) = (SignatureReader::new(have), SignatureReader::new(want));
+      super::missing_hashes(have.unwrap(), want.unwrap()).unwrap()
+    };
+    let have = signature(vec![chunk(1, 0), chunk(2, 16), chunk(3, 32)]);
+    // Two novel chunks, one of them repeated, and a known one moved.
+    let want = signature(vec![
+      chunk(3, 0),
+      chunk(7, 16),
+      chunk(1, 32),
+      chunk(8, 48),
+      chunk(7, 64),
+    ]);
+
+    assert_eq!(
+      missing(&have, &want),
+      vec![blake3::Hash::from([7u8; 32]), blake3::Hash::from([8u8; 32])]
+    );
+    assert_eq!(missing(&want, &have), vec![blake3::Hash::from([2u8; 32])]);
+    assert!(missing(&have, &have).is_empty());
+  }
+
+  #[test]
+  fn test_plan() {
+    use std::io::Cursor;
+
+    let source: Vec<u8> = (0..60000).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target[20000..23000].fill(7);
+    target.extend((0..5000).map(|_| rand::random::<u8>()));
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let ops = super::diff_signatures(&sig1, &sig2);
+
+    let mut plan = Vec::new();
+    super::write_plan(&ops, &mut plan).unwrap();
+    assert_eq!(plan.len(), 9 + ops.len() * super::PLAN_ENTRY_LEN);
+    assert_eq!(super::load_plan(&plan).unwrap(), ops);
+
+    let mut mismatched = plan.clone();
+    mismatched[0] = super::PLAN_VERSION + 1;
+    assert!(super::load_plan(&mismatched)
+      .unwrap_err()
+      .to_string()
+      .starts_with("plan version mismatch"));
+    for len in 0..plan.len() {
+      assert!(super::load_plan(&plan[..len]).is_err(), "{}", len);
+    }
+  }
+
+  #[test]
+  fn test_write_diff_streaming() {
+    use super::{write_diff_between, write_diff_streaming};
+    use crate::DiffOptions;
+    use std::io::Cursor;
+
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let source = random(300000);
+    let novel = random(20000);
+    let target = [
+      &source[150000..],
+      &novel,
+      &source[..100000],
+      &novel,
+      &random(500),
+    ]
+    .concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut serialized = Vec::new();
+    sig2.write(&mut serialized).unwrap();
+
+    let streamed = |serialized: &[u8], options: &DiffOptions| {
+      let reader = SignatureReader::new(serialized).unwrap();
+      let mut diff = Vec::new();
+      write_diff_streaming(&sig1, reader, &mut Cursor::new(&target), &mut diff, options)?;
+      Ok::<_, Box<dyn std::error::Error>>(diff)
+    };
+    for options in [
+      DiffOptions::default(),
+      DiffOptions {
+        max_memory: Some(0),
+        ..Default::default()
+      },
+      DiffOptions {
+        compress: Some(true),
+        operation_table: Some(true),
+        insert_checksums: Some(true),
+        ..Default::default()
+      },
+      DiffOptions {
+        chunk_copies: Some(true),
+        ..Default::default()
+      },
+    ] {
+      let mut diff = Vec::new();
+      write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, &options).unwrap();
+      assert_eq!(streamed(&serialized, &options).unwrap(), diff);
+    }
+
+    let options = DiffOptions {
+      coalesce_gap: Some(100),
+      ..Default::default()
+    };
+    assert_eq!(
+      streamed(&serialized, &options).unwrap_err().to_string(),
+      "`coalesce_gap` needs the whole target signature, which streaming diffs don't load"
+    );
+
+    // Gaps are only found once the chunks after them are read.
+    let mut gapped = sig2;
+    gapped.chunks.remove(gapped.chunks.len() / 2);
+    serialized.clear();
+    gapped.write(&mut serialized).unwrap();
+    assert!(streamed(&serialized, &DiffOptions::default())
+      .unwrap_err()
+      .to_string()
+      .starts_with("signature chunks aren't contiguous"));
+
+    // An empty target has no operations.
+    let empty = Signature::calculate(&mut Cursor::new(&[]), 1024, 4096, 16384).unwrap();
+    serialized.clear();
+    empty.write(&mut serialized).unwrap();
+    assert_eq!(
+      streamed(&serialized, &DiffOptions::default())
+        .unwrap()
+        .len(),
+      10
+    );
+  }
+
+  #[test]
+  fn test_diff_stats() {
+    use super::write_diff_between;
+    use crate::DiffOptions;
+    use std::io::Cursor;
+
+    let source: Vec<u8> = (0..200000).map(|_| rand::random::<u8>()).collect();
+    // Doesn't repeat whole chunks, which would be copied from the output.
+    let text: Vec<u8> = (0..10000)
+      .flat_map(|i| format!("inserted line number {}\n", i).into_bytes())
+      .collect();
+    let target = [&source[..100000], &text, &source[100000..]].concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+
+    let write = |options: &DiffOptions| {
+      let mut diff = Vec::new();
+      let stats =
+        write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, options).unwrap();
+      assert_eq!(stats.diff_size, diff.len() as i64);
+      stats
+    };
+
+    let plain = write(&DiffOptions::default());
+    assert!(plain.uncompressed_insert_bytes >= text.len() as i64);
+    assert!(plain.uncompressed_insert_bytes < text.len() as i64 + 3 * 16384);
+    assert_eq!(plain.operations_size, plain.compressed_operations_size);
+    assert_eq!(plain.diff_size, 10 + plain.operations_size);
+
+    let compressed = write(&DiffOptions {
+      compress: Some(true),
+      operation_table: Some(true),
+      ..Default::default()
+    });
+    assert_eq!(
+      compressed.uncompressed_insert_bytes,
+      plain.uncompressed_insert_bytes
+    );
+    assert!(compressed.operations_size > plain.operations_size);
+    assert!(compressed.compressed_operations_size * 10 < compressed.uncompressed_insert_bytes);
+    assert_eq!(
+      compressed.diff_size,
+      10 + compressed.compressed_operations_size
+    );
+  }
 }
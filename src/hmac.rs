@@ -0,0 +1,167 @@
+use crate::apply::read_header;
+use crate::diff::{self, FLAG_HMAC_SHA256, TAG_LEN};
+use crate::signature::Signature;
+use crate::DiffOptions;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, copy, Read, Seek, SeekFrom, Write};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+struct NotSigned;
+
+impl fmt::Display for NotSigned {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "the diff isn't signed")
+  }
+}
+
+impl Error for NotSigned {}
+
+#[derive(Debug)]
+struct InvalidTag;
+
+impl fmt::Display for InvalidTag {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "the diff HMAC doesn't match, the diff was modified or the key is wrong"
+    )
+  }
+}
+
+impl Error for InvalidTag {}
+
+/// Passes everything written through to `inner`, updating the HMAC with it.
+struct SigningWriter<'a, W> {
+  inner: &'a mut W,
+  mac: HmacSha256,
+}
+
+impl<W: Write> Write for SigningWriter<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.mac.update(&buf[..written]);
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Like `diff::write_diff_between_files`, but appends an HMAC-SHA256 of the whole diff computed
+/// with `key` while the diff is written.
+pub(crate) fn write_signed_diff_between_files<R, W>(
+  a: &Signature,
+  b: &Signature,
+  a_data: &mut R,
+  b_data: &mut R,
+  dest: &mut W,
+  options: &DiffOptions,
+  key: &[u8],
+) -> Result<(), Box<dyn Error>>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  let ops = diff::operations_between_files(a, b, a_data, b_data, options)?;
+  let mut writer = SigningWriter {
+    inner: dest,
+    mac: HmacSha256::new_from_slice(key)?,
+  };
+  diff::write_operations(ops, a, b, b_data, &mut writer, options, FLAG_HMAC_SHA256)?;
+
+  let tag = writer.mac.finalize().into_bytes();
+  dest.write_all(&tag)?;
+  dest.flush()?;
+
+  Ok(())
+}
+
+/// Checks the HMAC tag of the signed `diff` using `key`, reading the whole diff.
+pub(crate) fn verify<R: Read + Seek>(diff: &mut R, key: &[u8]) -> Result<(), Box<dyn Error>> {
+  diff.seek(SeekFrom::Start(0))?;
+  if !read_header(diff)?.signed {
+    return Err(Box::new(NotSigned));
+  }
+
+  let len = diff.seek(SeekFrom::End(0))?;
+  let Some(signed_len) = len.checked_sub(TAG_LEN) else {
+    return Err(Box::new(InvalidTag));
+  };
+  diff.seek(SeekFrom::Start(0))?;
+
+  let mut writer = SigningWriter {
+    inner: &mut io::sink(),
+    mac: HmacSha256::new_from_slice(key)?,
+  };
+  copy(&mut diff.take(signed_len), &mut writer)?;
+  let mac = writer.mac;
+
+  let mut tag = vec![0; TAG_LEN as usize];
+  diff.read_exact(&mut tag)?;
+  mac.verify_slice(&tag).map_err(|_| InvalidTag)?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::diff::{FLAG_HMAC_SHA256, TAG_LEN};
+  use crate::signature::Signature;
+  use crate::DiffOptions;
+
+  use std::io::Cursor;
+
+  #[test]
+  fn test_verify_tampered_diff() {
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..300).map(|_| rand::random::<u8>()));
+
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    super::write_signed_diff_between_files(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&source),
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+      b"secret",
+    )
+    .unwrap();
+    assert_eq!(diff[1], FLAG_HMAC_SHA256);
+
+    super::verify(&mut Cursor::new(&diff), b"secret").unwrap();
+    let mut dest = Cursor::new(Vec::new());
+    crate::apply::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+
+    let err = super::verify(&mut Cursor::new(&diff), b"other").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the diff HMAC doesn't match, the diff was modified or the key is wrong"
+    );
+
+    // The first operation is a copy, flip a bit of its offset.
+    assert_eq!(diff[10], 0);
+    let mut tampered = diff.clone();
+    tampered[18] ^= 1;
+    assert!(super::verify(&mut Cursor::new(&tampered), b"secret").is_err());
+
+    let truncated = &diff[..diff.len() - TAG_LEN as usize - 1];
+    assert!(super::verify(&mut Cursor::new(truncated), b"secret").is_err());
+  }
+}
@@ -0,0 +1,175 @@
+//! A content-addressed file of inserted data shared by many diffs, so data new to all of their
+//! sources is stored once. Diffs written with a pool copy inserted chunks from it instead of
+//! embedding them, and the same pool is needed to apply them. Entries are only ever appended, so
+//! diffs written earlier stay valid:
+//!
+//! for every chunk:
+//!   HASH([u8; 32]) - the Blake3 hash of the data
+//!   SIZE(u64) - the number of bytes
+//!   DATA([u8]) - the chunk data, which diffs refer to by its offset in the pool
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+struct TruncatedPool(u64);
+
+impl fmt::Display for TruncatedPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "insert pool is truncated in the entry at offset {}",
+      self.0
+    )
+  }
+}
+
+impl Error for TruncatedPool {}
+
+pub(crate) struct InsertPool {
+  file: File,
+  /// The offsets of the data of all entries by their hashes.
+  entries: HashMap<blake3::Hash, u64>,
+  len: u64,
+}
+
+impl InsertPool {
+  /// Opens the pool at `path`, creating an empty one when there's none.
+  pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+    let mut file = File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut entries = HashMap::new();
+    let mut reader = BufReader::new(&mut file);
+    let mut head = [0; 40];
+    let mut offset = 0;
+    while offset < len {
+      if len - offset < head.len() as u64 {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          TruncatedPool(offset),
+        ));
+      }
+      reader.read_exact(&mut head)?;
+      let hash = blake3::Hash::from_bytes(*arrayref::array_ref![head, 0, 32]);
+      let size = u64::from_be_bytes(*arrayref::array_ref![head, 32, 8]);
+      let data = offset + head.len() as u64;
+      if size > len - data {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          TruncatedPool(offset),
+        ));
+      }
+
+      entries.insert(hash, data);
+      reader.seek_relative(size as i64)?;
+      offset = data + size;
+    }
+
+    Ok(Self { file, entries, len })
+  }
+
+  /// Returns the offset of `data` in the pool, appending it when it's not there yet.
+  pub(crate) fn offset_of(&mut self, data: &[u8]) -> Result<u64, io::Error> {
+    let hash = blake3::hash(data);
+    if let Some(&offset) = self.entries.get(&hash) {
+      return Ok(offset);
+    }
+
+    self.file.seek(SeekFrom::Start(self.len))?;
+    self.file.write_all(hash.as_bytes())?;
+    self.file.write_all(&(data.len() as u64).to_be_bytes())?;
+    self.file.write_all(data)?;
+    let offset = self.len + 40;
+    self.len = offset + data.len() as u64;
+    self.entries.insert(hash, offset);
+
+    Ok(offset)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::apply::{self, ApplyExtras};
+  use crate::diff::write_diff_between;
+  use crate::signature::Signature;
+  use crate::DiffOptions;
+
+  use std::fs::{self, File};
+  use std::io::Cursor;
+
+  #[test]
+  fn test_diffs_share_insert_pool() {
+    let dir = tempfile::tempdir().unwrap();
+    let pool_path = dir.path().join("pool");
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+
+    // Both targets gained the same header, new to both of their sources.
+    let header = random(20000);
+    let sources = [random(50000), random(70000)];
+    let options = DiffOptions {
+      insert_pool: Some(pool_path.to_string_lossy().into_owned()),
+      ..Default::default()
+    };
+
+    let mut diffs = Vec::new();
+    let mut pool_sizes = Vec::new();
+    let mut first_chunk_len = 0;
+    for source in sources.iter() {
+      let target = [&header[..], &source[..]].concat();
+      let sig1 = Signature::calculate(&mut Cursor::new(source), 1024, 4096, 16384).unwrap();
+      let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+      // The first chunk is within the header, which is longer than the maximum chunk size.
+      first_chunk_len = sig2.chunks[0].length;
+      let mut diff = Vec::new();
+      write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, &options).unwrap();
+
+      // The header is copied from the pool instead of being inserted.
+      assert!(diff.len() < 5000);
+      pool_sizes.push(fs::metadata(&pool_path).unwrap().len());
+      diffs.push((diff, target));
+    }
+
+    // The header is stored once, only the chunks at its end, cut differently, may be stored twice.
+    let pool = fs::read(&pool_path).unwrap();
+    let first = &header[..first_chunk_len];
+    assert_eq!(
+      pool
+        .windows(first.len())
+        .filter(|window| window == &first)
+        .count(),
+      1
+    );
+    assert!(pool_sizes[0] >= header.len() as u64);
+    assert!(pool_sizes[1] - pool_sizes[0] < 2 * 16384 + 2 * 40);
+
+    for ((diff, target), source) in diffs.into_iter().zip(sources.iter()) {
+      let mut dest = Cursor::new(Vec::new());
+      let mut pool = File::open(&pool_path).unwrap();
+      apply::apply_with(
+        &mut Cursor::new(&diff),
+        &mut Cursor::new(source),
+        &mut dest,
+        ApplyExtras {
+          pool: Some(&mut pool),
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      assert_eq!(dest.into_inner(), target);
+
+      let mut dest = Cursor::new(Vec::new());
+      assert!(apply::apply(&mut Cursor::new(&diff), &mut Cursor::new(source), &mut dest).is_err());
+    }
+  }
+}
@@ -1,25 +1,89 @@
 #![deny(clippy::all)]
 
+// First, so its macros can be used by the modules below.
+#[macro_use]
+mod trace;
 mod apply;
+mod auth;
+mod casync;
+mod chunk_index;
+mod chunk_store;
 mod diff;
+#[cfg(feature = "hmac")]
+mod hmac;
+mod insert_pool;
+mod manifest;
+mod merge;
+mod multipart;
+mod open_files;
+mod rate_limit;
+mod reflink;
+mod retry;
 mod signature;
+mod stream;
+#[cfg(test)]
+mod test_server;
+mod weak_match;
+mod xattr;
+
+pub use stream::{apply_streaming, apply_to_writer, stream_apply, stream_diff};
 
 use anyhow::Context;
 use futures::prelude::*;
 use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
 use std::default::Default;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
-use crate::signature::Signature;
+use crate::open_files::OpenFileLimit;
+use crate::signature::{Signature, SignatureReader, SignatureUpdater};
 
 #[macro_use]
 extern crate napi_derive;
 
+#[napi(string_enum)]
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HashAlgorithm {
+  #[default]
+  Blake3,
+  /// For environments allowing only FIPS-approved algorithms, slower than Blake3.
+  Sha256,
+}
+
 #[napi(object)]
+#[derive(Clone)]
 pub struct SignatureOptions {
   pub min_size: u32,
   pub avg_size: u32,
   pub max_size: u32,
+  /// The algorithm of the chunk hashes, Blake3 by default.
+  pub hash_algorithm: Option<HashAlgorithm>,
+  /// The FastCDC normalization level from 0 to 3, 1 by default. Higher levels cut more chunks
+  /// close to `avg_size`. It's stored in the signature, so diffs and updates chunk the same way.
+  pub normalization: Option<u32>,
+  /// Stores only the first 16 or 24 bytes of every chunk hash instead of all 32, shrinking
+  /// signatures of huge files. Collisions get likelier, so results should be checked, e.g. with
+  /// `verify`.
+  pub hash_bytes: Option<u32>,
+  /// Cuts the source into blocks of this size, the last one shorter, instead of with FastCDC, for
+  /// protocols expecting rsync-style blocks. The chunk sizes are ignored then. Such blocks only
+  /// match at the same offsets, so inserts and deletes make diffs insert everything after them.
+  pub fixed_block_size: Option<u32>,
+  /// Also keeps the rsync rolling checksum of every fixed-size block.
+  pub weak_checksums: Option<bool>,
+  /// Fails the signature once the source has more chunks than this, so huge sources don't take
+  /// all the memory for their chunk list.
+  pub max_chunks: Option<u32>,
+  /// Fails the signature once more than this number of bytes of the source are chunked. Sources
+  /// which aren't regular files, such as pipes or devices, may never end, so they're only read
+  /// with this limit set.
+  pub max_file_bytes: Option<i64>,
 }
 
 impl Default for SignatureOptions {
@@ -28,48 +92,620 @@ impl Default for SignatureOptions {
       min_size: signature::DEFAULT_MIN_SIZE,
       avg_size: signature::DEFAULT_AVG_SIZE,
       max_size: signature::DEFAULT_MAX_SIZE,
+      hash_algorithm: None,
+      normalization: None,
+      hash_bytes: None,
+      fixed_block_size: None,
+      weak_checksums: None,
+      max_chunks: None,
+      max_file_bytes: None,
+    }
+  }
+}
+
+impl SignatureOptions {
+  /// Returns a builder starting from the default options.
+  pub fn builder() -> SignatureOptionsBuilder {
+    SignatureOptionsBuilder::default()
+  }
+
+  /// Returns the options for chunks of `avg_size` on average, with the minimum and maximum sizes
+  /// four times smaller and larger like the defaults.
+  pub fn from_avg(avg_size: u32) -> Result<Self> {
+    Self::builder()
+      .min_size(avg_size / 4)
+      .avg_size(avg_size)
+      .max_size(avg_size.saturating_mul(4))
+      .build()
+  }
+
+  /// Returns the options of signatures for `write_casync_index`, with SHA-256 chunk hashes and the
+  /// default chunk sizes of casync.
+  pub fn casync() -> Self {
+    SignatureOptions {
+      min_size: casync::CASYNC_MIN_SIZE,
+      avg_size: casync::CASYNC_AVG_SIZE,
+      max_size: casync::CASYNC_MAX_SIZE,
+      hash_algorithm: Some(HashAlgorithm::Sha256),
+      normalization: None,
+      hash_bytes: None,
+      fixed_block_size: None,
+      weak_checksums: None,
+      max_chunks: None,
+      max_file_bytes: None,
+    }
+  }
+
+  fn normalization_level(&self) -> u8 {
+    self
+      .normalization
+      .map_or(signature::DEFAULT_NORMALIZATION, |level| {
+        level.min(u8::MAX.into()) as u8
+      })
+  }
+
+  fn hash_byte_count(&self) -> u8 {
+    self
+      .hash_bytes
+      .map_or(signature::DEFAULT_HASH_BYTES, |bytes| {
+        bytes.min(u8::MAX.into()) as u8
+      })
+  }
+
+  fn limits(&self) -> signature::Limits {
+    signature::Limits {
+      max_chunks: self.max_chunks.map(|max| max as usize),
+      max_bytes: self.max_file_bytes.map(|max| max.max(0) as u64),
+    }
+  }
+
+  /// Opens the `source` to calculate its signature. Only regular files surely end, others are
+  /// refused unless `max_file_bytes` is set.
+  fn open_source(&self, source: &str) -> Result<File> {
+    let file = open_file(source)?;
+    let metadata = file
+      .metadata()
+      .with_context(|| format!("Failed to read the metadata of {}", source))
+      .map_err(anyhow_to_js_error)?;
+    if !metadata.is_file() && self.max_file_bytes.is_none() {
+      return Err(Error::from_reason(format!(
+        "{} isn't a regular file and may never end, set max_file_bytes to read it",
+        source
+      )));
+    }
+
+    Ok(file)
+  }
+
+  /// Calculates the signature of the `source` with these options.
+  fn calculate(&self, source: &mut impl io::Read) -> std::result::Result<Signature, io::Error> {
+    let (signature, _) = match self.fixed_block_size {
+      Some(block_size) => Signature::calculate_blocks_from(
+        source,
+        block_size,
+        self.hash_algorithm.unwrap_or_default(),
+        self.hash_byte_count(),
+        self.weak_checksums.unwrap_or(false),
+        self.limits(),
+      )?,
+      None => Signature::calculate_from(
+        source,
+        self.min_size,
+        self.avg_size,
+        self.max_size,
+        self.hash_algorithm.unwrap_or_default(),
+        self.normalization_level(),
+        self.hash_byte_count(),
+        self.limits(),
+      )?,
+    };
+
+    Ok(signature)
+  }
+}
+
+/// Builds `SignatureOptions` checked against the bounds of FastCDC, so invalid options fail once
+/// instead of on every signature calculated with them.
+#[derive(Default)]
+pub struct SignatureOptionsBuilder {
+  options: SignatureOptions,
+}
+
+impl SignatureOptionsBuilder {
+  pub fn min_size(mut self, min_size: u32) -> Self {
+    self.options.min_size = min_size;
+    self
+  }
+
+  pub fn avg_size(mut self, avg_size: u32) -> Self {
+    self.options.avg_size = avg_size;
+    self
+  }
+
+  pub fn max_size(mut self, max_size: u32) -> Self {
+    self.options.max_size = max_size;
+    self
+  }
+
+  pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+    self.options.hash_algorithm = Some(hash_algorithm);
+    self
+  }
+
+  pub fn normalization(mut self, level: u32) -> Self {
+    self.options.normalization = Some(level);
+    self
+  }
+
+  pub fn hash_bytes(mut self, hash_bytes: u32) -> Self {
+    self.options.hash_bytes = Some(hash_bytes);
+    self
+  }
+
+  pub fn fixed_block_size(mut self, block_size: u32) -> Self {
+    self.options.fixed_block_size = Some(block_size);
+    self
+  }
+
+  pub fn weak_checksums(mut self, weak_checksums: bool) -> Self {
+    self.options.weak_checksums = Some(weak_checksums);
+    self
+  }
+
+  pub fn max_chunks(mut self, max_chunks: u32) -> Self {
+    self.options.max_chunks = Some(max_chunks);
+    self
+  }
+
+  pub fn max_file_bytes(mut self, max_file_bytes: i64) -> Self {
+    self.options.max_file_bytes = Some(max_file_bytes);
+    self
+  }
+
+  pub fn build(self) -> Result<SignatureOptions> {
+    let options = self.options;
+    if let Some(block_size) = options.fixed_block_size {
+      signature::check_block_size(block_size, std::io::ErrorKind::InvalidInput)
+        .map_err(to_js_error)?;
+    }
+    signature::check_chunk_sizes(options.min_size, options.avg_size, options.max_size)
+      .map_err(to_js_error)?;
+    signature::check_chunk_size_order(options.min_size, options.avg_size, options.max_size)
+      .map_err(to_js_error)?;
+    signature::normalization(options.normalization_level()).map_err(to_js_error)?;
+    signature::check_hash_bytes(options.hash_byte_count(), std::io::ErrorKind::InvalidInput)
+      .map_err(to_js_error)?;
+
+    Ok(options)
+  }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct DiffOptions {
+  /// Keeps the index of the source chunks in a temporary file when it would take more memory than
+  /// this number of bytes.
+  pub max_memory: Option<u32>,
+  /// Looks for source blocks of this size inside inserted regions with a rolling hash, so data
+  /// shifted inside chunks is copied instead of inserted. Only `diff` has the source data to do
+  /// this.
+  pub weak_block_size: Option<u32>,
+  /// Inserts copies shorter than this number of bytes found between two inserts, so noisy edits
+  /// take fewer operations.
+  pub coalesce_gap: Option<u32>,
+  /// Moves the boundaries between operations to multiples of this number of bytes in the result,
+  /// so appliers writing to block devices or with `O_DIRECT` never write a block in part. Every
+  /// block with a boundary inside it is inserted whole, which grows the diff by up to a block for
+  /// every operation. Copies then don't cover whole chunks, so it can't be combined with
+  /// `chunk_copies`.
+  pub align: Option<u32>,
+  /// Compresses all operations after the diff header as a single zstd stream.
+  pub compress: Option<bool>,
+  /// Finds repeated data up to 128 MiB apart when compressing, so large targets repeating data
+  /// from far back compress better. Applying needs as much memory for the window, and diffs with
+  /// a long window can't be applied by older versions.
+  pub long_window: Option<bool>,
+  /// Path to a zstd dictionary, see `train_dictionary`, to compress the operations with. Implies
+  /// `compress`. The same dictionary must be passed to `apply`.
+  pub dictionary: Option<String>,
+  /// Ends the operations with a table of their kinds, positions, offsets and sizes, so readers
+  /// can jump to any operation. Diffs with a table can't be applied by older versions.
+  pub operation_table: Option<bool>,
+  /// Path to an insert pool shared by many diffs, created when missing. Inserted chunks are
+  /// added to the pool unless it has them already and the diff copies them from there, so data
+  /// new to many diffs is stored once. The same pool must be passed to `apply`.
+  pub insert_pool: Option<String>,
+  /// Prefixes the data of every insert with a checksum, so applying verifies each insert as it's
+  /// read, also from a stream, and fails at the first corrupted one instead of writing a
+  /// corrupted result. Diffs with checksums can't be applied by older versions.
+  pub insert_checksums: Option<bool>,
+  /// Names the source chunks copies read by their hashes instead of their offsets, so the diff is
+  /// applied from a chunk store, see `export_chunks`, without the source file. Copies found by
+  /// `weak_block_size` don't cover whole chunks, so the options can't be combined.
+  pub chunk_copies: Option<bool>,
+  /// Waits until the diff is on the storage device before returning, so it isn't lost on a crash.
+  /// Flushing the whole file takes from milliseconds to seconds, depending on the device.
+  pub durable: Option<bool>,
+}
+
+/// The sizes of a written diff. Inserts are compressed along with the other operations in a
+/// single stream, so the compression ratio is the one of all operations.
+#[napi(object)]
+#[derive(Debug, Default, PartialEq)]
+pub struct DiffStats {
+  /// The number of bytes of the diff.
+  pub diff_size: i64,
+  /// The number of bytes of the target inserted by the diff, before compression.
+  pub uncompressed_insert_bytes: i64,
+  /// The number of bytes of the operations after the header, inserted data included, before
+  /// compression.
+  pub operations_size: i64,
+  /// The number of bytes the operations take in the diff, the same as `operations_size` unless
+  /// they're compressed.
+  pub compressed_operations_size: i64,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct PullOptions {
+  /// Limits the download speed of all ranges combined, in bytes per second. Must be positive.
+  pub max_bytes_per_sec: Option<u32>,
+  /// Copies the permissions and modification time of `source` to the pulled file.
+  pub preserve_metadata: Option<bool>,
+  /// Copies the extended attributes of `source` to the pulled file, on Linux and macOS.
+  pub preserve_xattrs: Option<bool>,
+  /// Sends all requests through this proxy. `HTTP_PROXY`/`HTTPS_PROXY` are used when not set.
+  pub proxy: Option<String>,
+  pub proxy_username: Option<String>,
+  pub proxy_password: Option<String>,
+  /// Path to a PEM bundle of additional CA certificates to trust.
+  pub ca_certificate: Option<String>,
+  /// Paths to a PEM client certificate and its PKCS #8 key for mutual TLS.
+  pub client_certificate: Option<String>,
+  pub client_key: Option<String>,
+  /// Uses HTTP/1.1 only, for servers which misbehave on HTTP/2. By default HTTP/2 is negotiated
+  /// over TLS, so all ranges share a single connection.
+  pub http1_only: Option<bool>,
+  /// Uses HTTP/2 without negotiation, for plain HTTP servers known to support it.
+  pub http2_prior_knowledge: Option<bool>,
+  /// The User-Agent header sent with every request.
+  pub user_agent: Option<String>,
+  /// Follows redirects, enabled by default. When disabled, a redirect fails the pull.
+  pub follow_redirects: Option<bool>,
+  /// The number of redirects followed before the pull fails, 10 by default.
+  pub max_redirects: Option<u32>,
+  /// Downloads the whole file when the server doesn't advertise range support, enabled by
+  /// default. When disabled, such a server fails the pull.
+  pub range_fallback: Option<bool>,
+  /// Returns the plan of the pull without sending any requests or writing `dest`.
+  pub dry_run: Option<bool>,
+  /// The number of attempts of every range request failing to connect, with a 5xx or 429 status
+  /// or while reading the body, 1 by default. Broken bodies are requested again from where they
+  /// broke off. Retries wait for a random delay up to an exponentially growing limit.
+  pub max_attempts: Option<u32>,
+  /// The limit of the delay before the first retry, 100 ms by default.
+  pub retry_initial_delay_ms: Option<u32>,
+  /// The factor the delay limit grows by after every retry, 2 by default.
+  pub retry_multiplier: Option<f64>,
+  /// The maximum delay limit, 10 seconds by default.
+  pub retry_max_delay_ms: Option<u32>,
+  /// Fails the pull instead of retrying past this time since its start.
+  pub retry_budget_ms: Option<u32>,
+  /// Requests up to this number of ranges at once as `multipart/byteranges`, 1 by default since
+  /// servers limit it differently. Ranges missing from a response are requested one by one.
+  pub max_ranges_per_request: Option<u32>,
+  /// Also writes the downloaded data to this file at its offsets in the result, leaving zeros
+  /// elsewhere, to inspect what the server served. The file is kept after the pull, even a
+  /// failed one, and can be passed to `apply_with_local_inserts` as `inserts`.
+  pub inserts_path: Option<String>,
+}
+
+/// `length` bytes of the remote file at `offset`.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct ByteRange {
+  pub offset: i64,
+  pub length: i64,
+}
+
+#[napi(object)]
+#[derive(Debug, Default, PartialEq)]
+pub struct PullResult {
+  /// The `source` already matched the target signature, so nothing was downloaded.
+  pub already_up_to_date: bool,
+  /// The plan of the pull, only returned with `dry_run`.
+  pub plan: Option<PullPlan>,
+}
+
+#[napi(object)]
+#[derive(Debug, Default, PartialEq)]
+pub struct PullPlan {
+  /// The ranges of the remote file to download, in the order of the target file.
+  pub ranges: Vec<ByteRange>,
+  /// The number of bytes copied from the source or already written parts of the result.
+  pub copied_bytes: i64,
+  /// The number of bytes downloaded.
+  pub inserted_bytes: i64,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ApplyOptions {
+  /// Copies the permissions and modification time of the source file to the result.
+  pub preserve_metadata: Option<bool>,
+  /// Copies the extended attributes of the source file, such as SELinux labels or quarantine
+  /// flags, to the result. Only Linux and macOS have them, elsewhere nothing is copied.
+  pub preserve_xattrs: Option<bool>,
+  /// Path to the zstd dictionary the diff was compressed with.
+  pub dictionary: Option<String>,
+  /// Path to a file where the progress is saved about every percent of the result, so an
+  /// interrupted apply can be resumed. The partial result is kept on failure and the file is
+  /// removed once the result is complete.
+  pub checkpoint_path: Option<String>,
+  /// Continues the apply from `checkpoint_path`, if it was saved, instead of starting over.
+  pub resume: Option<bool>,
+  /// Path to the insert pool the diff was written with.
+  pub insert_pool: Option<String>,
+  /// Waits until the result is on the storage device before returning, so it isn't lost on a
+  /// crash. Flushing the whole file takes from milliseconds to seconds, depending on the device.
+  pub durable: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Debug, Default, PartialEq)]
+pub struct ApplyReport {
+  /// The number of bytes written to the result.
+  pub bytes_written: i64,
+  /// The number of operations copying data from the source file.
+  pub copy_ops: u32,
+  /// The number of operations inserting data from the diff.
+  pub insert_ops: u32,
+}
+
+/// `old_length` bytes at `offset` replaced with `new_length` bytes. Offsets of later edits
+/// account for the earlier ones.
+#[napi(object)]
+pub struct SignatureEdit {
+  pub offset: i64,
+  pub old_length: i64,
+  pub new_length: i64,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct BatchOptions {
+  /// The number of paths processed at once, 1 by default.
+  pub concurrency: Option<u32>,
+  /// The most files open at once across the whole batch, `concurrency` by default. Paths wait for
+  /// other files to be closed when there are this many open, so batches on many threads stay
+  /// within the open file limit of the process.
+  pub max_open_files: Option<u32>,
+}
+
+/// The signature of a range of a file starting at `offset`, calculated with `signature_of_range`.
+#[napi(object)]
+pub struct RangeSignature {
+  pub offset: i64,
+  pub signature: Buffer,
+}
+
+/// The result of one path of a batch, either `signature` or `error` is set.
+#[napi(object)]
+pub struct SignatureResult {
+  pub path: String,
+  pub signature: Option<Buffer>,
+  pub error: Option<String>,
+}
+
+#[napi(object)]
+pub struct ApplyProgress {
+  pub bytes_written: i64,
+  /// The size of the result.
+  pub total_bytes: i64,
+}
+
+#[napi(object)]
+pub struct SignatureProgress {
+  pub bytes_processed: i64,
+  /// The size of the source when the signature started.
+  pub total_bytes: i64,
+  /// The whole percent of the source processed, 100 only once the signature is complete.
+  pub percent: u32,
+}
+
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct Bucket {
+  /// The smallest chunk size counted in the bucket.
+  pub start: u32,
+  /// The largest chunk size counted in the bucket.
+  pub end: u32,
+  pub count: u32,
+}
+
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct VerifyResult {
+  pub matches: bool,
+  /// The offset of the first chunk which differs from the signature.
+  pub first_mismatch_offset: Option<i64>,
+  /// The number of chunks of the signature which weren't found at their offsets.
+  pub mismatched_chunks: u32,
+}
+
+/// The result of `validate_diff`, `error` is set when the diff isn't valid.
+#[napi(object)]
+#[derive(Debug, Default, PartialEq)]
+pub struct DiffValidation {
+  pub valid: bool,
+  pub error: Option<String>,
+  /// The size of the result declared by the diff.
+  pub target_size: i64,
+  pub copy_ops: u32,
+  pub insert_ops: u32,
+  pub bytes_written: i64,
+}
+
+#[napi(string_enum)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum OperationKind {
+  /// Copies data from the source file.
+  Copy,
+  /// Inserts data from the diff.
+  Insert,
+  /// Copies data already written to the result.
+  CopyOutput,
+  /// Copies inserted data from the insert pool.
+  CopyPool,
+}
+
+impl From<diff::Operation> for OperationKind {
+  fn from(op: diff::Operation) -> Self {
+    match op {
+      diff::Operation::Copy => OperationKind::Copy,
+      diff::Operation::Insert => OperationKind::Insert,
+      diff::Operation::CopyOutput => OperationKind::CopyOutput,
+      diff::Operation::CopyPool => OperationKind::CopyPool,
     }
   }
 }
 
-/// Writes calculated signature for `source` to the `dest`.
+/// An operation of a plan from `diff_plan`.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct PlanOperation {
+  pub kind: OperationKind,
+  /// The offset copied from the source, or the offset of the inserted data in the target.
+  pub offset: i64,
+  pub size: i64,
+}
+
+/// An operation of a diff from its operation table.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct OperationEntry {
+  pub kind: OperationKind,
+  /// The offset of the operation from the start of the operations, which follow the header in
+  /// uncompressed diffs.
+  pub position: i64,
+  /// The offset copied from, or the position of the inserted data like `position`.
+  pub offset: i64,
+  pub size: i64,
+}
+
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct Edges {
+  /// The number of bytes both files start with.
+  pub prefix: i64,
+  /// The number of bytes both files end with, not overlapping `prefix`.
+  pub suffix: i64,
+}
+
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct SignatureInfo {
+  pub version: u32,
+  pub min_size: u32,
+  pub avg_size: u32,
+  pub max_size: u32,
+  pub hash_algorithm: HashAlgorithm,
+  pub fixed_block_size: Option<u32>,
+  pub chunk_count: u32,
+  /// The size of the file the signature was calculated for.
+  pub file_size: i64,
+  /// Whether every chunk starts where the previous one ends, which extending, updating and
+  /// diffing need.
+  pub contiguous: bool,
+}
+
+#[napi(object)]
+#[derive(Debug)]
+pub struct ChunkSizeHistogram {
+  /// Buckets of equal width covering all chunk sizes from `min` to `max`.
+  pub buckets: Vec<Bucket>,
+  pub chunk_count: u32,
+  pub min: u32,
+  pub max: u32,
+  pub mean: f64,
+  pub median: f64,
+  /// The number of chunks cut at `min_size` and at `max_size` of the options.
+  pub at_min_size: u32,
+  pub at_max_size: u32,
+  /// Set when too many chunks are cut at `min_size` or `max_size`, which means the data doesn't
+  /// fit `avg_size` and the signature has too many chunks or too few cut points to match, or when
+  /// the whole input is only one or two chunks.
+  pub warning: Option<String>,
+}
+
+/// Writes calculated signature for `source` to the `dest`. With `durable` it waits until the
+/// signature is on the storage device before returning, so it isn't lost on a crash, which takes
+/// from milliseconds to seconds depending on the device.
 #[napi]
 pub fn write_binary_signature(
   source: String,
   dest: String,
   options: Option<SignatureOptions>,
+  durable: Option<bool>,
 ) -> Result<()> {
-  let mut source_file = open_file(&source)?;
-  let mut dest = create_file(&dest)?;
   let options = options.unwrap_or_default();
+  let mut source_file = options.open_source(&source)?;
+  let mut dest_file = create_file(&dest)?;
 
-  let signature = Signature::calculate(
-    &mut source_file,
-    options.min_size,
-    options.avg_size,
-    options.max_size,
-  )
-  .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
-  .map_err(anyhow_to_js_error)?;
+  let signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
   signature
-    .write(&mut dest)
+    .write(&mut dest_file)
     .context("Failed to write the signature to the file")
     .map_err(anyhow_to_js_error)?;
+  sync_durable(&dest_file, durable, &dest)?;
 
   Ok(())
 }
 
-/// Returns calculated signature of the `source`.
+/// Returns calculated signature of the `source`. Identical data and options always give
+/// byte-identical signatures.
 #[napi]
 pub fn signature(source: String, options: Option<SignatureOptions>) -> Result<Buffer> {
+  Ok(signature_data(&source, options)?.into())
+}
+
+/// Returns calculated signature of an archive `source`, e.g. a tar file, whose members start at
+/// the ascending `member_offsets`. Every member is chunked on its own, so a changed member doesn't
+/// change the chunks of the members after it and diffs between versions of the archive stay
+/// small. Fixed-size blocks don't drift, so `fixed_block_size` isn't supported.
+#[napi]
+pub fn archive_signature(
+  source: String,
+  member_offsets: Vec<i64>,
+  options: Option<SignatureOptions>,
+) -> Result<Buffer> {
   let options = options.unwrap_or_default();
+  if options.fixed_block_size.is_some() {
+    return Err(Error::from_reason(
+      "Archive members can't be cut into fixed-size blocks",
+    ));
+  }
+  let boundaries = member_offsets
+    .into_iter()
+    .map(|offset| {
+      u64::try_from(offset)
+        .map_err(|_| Error::from_reason(format!("Invalid member offset {}", offset)))
+    })
+    .collect::<Result<Vec<_>>>()?;
 
-  let mut source_file = open_file(&source)?;
-  let signature = Signature::calculate(
+  let mut source_file = options.open_source(&source)?;
+  let signature = Signature::calculate_members(
     &mut source_file,
+    &boundaries,
     options.min_size,
     options.avg_size,
     options.max_size,
+    options.hash_algorithm.unwrap_or_default(),
+    options.normalization_level(),
+    options.hash_byte_count(),
   )
   .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
   .map_err(anyhow_to_js_error)?;
@@ -80,142 +716,2234 @@ pub fn signature(source: String, options: Option<SignatureOptions>) -> Result<Bu
   Ok(dest.into())
 }
 
-/// Generates a diff that transforms `source` to `target`.
-#[napi]
-pub fn diff(
+/// Returns calculated signature of the `source` like `signature`, without blocking the event loop,
+/// calling `callback` with the progress once for every whole percent of the source and once the
+/// signature is complete.
+#[napi(
+  ts_args_type = "source: string, options: SignatureOptions | undefined | null, \
+                  callback: (progress: SignatureProgress) => void"
+)]
+pub async fn signature_with_progress(
   source: String,
-  target: String,
-  dest: String,
   options: Option<SignatureOptions>,
-) -> Result<()> {
-  let options = options.unwrap_or_default();
-
-  let mut source_file = open_file(&source)?;
-  let source_signature = Signature::calculate(
-    &mut source_file,
-    options.min_size,
-    options.avg_size,
-    options.max_size,
-  )
-  .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
-  .map_err(anyhow_to_js_error)?;
+  callback: ThreadsafeFunction<SignatureProgress, ErrorStrategy::Fatal>,
+) -> Result<Buffer> {
+  let data = napi::tokio::task::spawn_blocking(move || {
+    let options = options.unwrap_or_default();
+    let mut source_file = options.open_source(&source)?;
+    let total = source_file.metadata()?.len();
 
-  let mut target_file = open_file(&target)?;
-  let target_signature = Signature::calculate(
-    &mut target_file,
-    options.min_size,
-    options.avg_size,
-    options.max_size,
-  )
-  .with_context(|| format!("Failed to calculate the sugnagure for {}", &target))
-  .map_err(anyhow_to_js_error)?;
+    let mut progress = |read: u64, percent| {
+      callback.call(
+        SignatureProgress {
+          bytes_processed: read as i64,
+          total_bytes: total as i64,
+          percent,
+        },
+        ThreadsafeFunctionCallMode::NonBlocking,
+      );
+    };
+    let mut reader = signature::ProgressReader::new(&mut source_file, total, &mut progress);
+    let signature = options
+      .calculate(&mut reader)
+      .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+      .map_err(anyhow_to_js_error)?;
+    reader.finish();
 
-  let mut dest_file = create_file(&dest)?;
+    let mut dest = Vec::new();
+    signature.write(&mut dest).map_err(to_js_error)?;
 
-  diff::write_diff_between(
-    &source_signature,
-    &target_signature,
-    &mut target_file,
-    &mut dest_file,
-  )
-  .map_err(box_to_js_error)?;
+    Ok::<_, Error>(dest)
+  })
+  .await
+  .map_err(to_js_error)??;
 
-  Ok(())
+  Ok(data.into())
 }
 
-/// Generates a diff that transforms `source` to `target. Only source signature is required.
-#[napi]
-pub fn diff_using_source_signature(source_sig: String, target: String, dest: String) -> Result<()> {
-  let sig_data = fs::read(source_sig).map_err(to_js_error)?;
-  let source_signature = Signature::load(&sig_data);
-
-  let mut target_file = open_file(&target)?;
-  let target_signature = Signature::calculate(
-    &mut target_file,
-    source_signature.min_size,
-    source_signature.avg_size,
-    source_signature.max_size,
-  )
-  .with_context(|| format!("Failed to calculate the sugnagure for {}", &target))
-  .map_err(anyhow_to_js_error)?;
+fn signature_data(source: &str, options: Option<SignatureOptions>) -> Result<Vec<u8>> {
+  let options = options.unwrap_or_default();
 
-  let mut dest_file = create_file(&dest)?;
+  let mut source_file = options.open_source(source)?;
+  let signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
 
-  diff::write_diff_between(
-    &source_signature,
-    &target_signature,
-    &mut target_file,
-    &mut dest_file,
-  )
-  .map_err(box_to_js_error)?;
+  let mut dest = Vec::new();
+  signature.write(&mut dest).map_err(to_js_error)?;
 
-  Ok(())
+  Ok(dest)
 }
 
-/// Downloads the required parts of the file and builds a new file based on `target_sig` and the
-/// `source`.
+/// Returns calculated signature of `length` bytes of the `source` starting at `offset`, as if
+/// they were a whole file, so separate processes can sign the ranges of a huge file and combine
+/// their signatures with `combine_range_signatures`. Ranges must overlap the ones before them by
+/// a few maximum chunk sizes, or with `fixed_block_size` start at multiples of the block size.
 #[napi]
-pub async fn pull_using_remote_signature(
+pub fn signature_of_range(
   source: String,
-  target_sig: String,
-  file_uri: String,
-  dest: String,
-) -> Result<()> {
-  let sig_data = fs::read(target_sig).map_err(to_js_error)?;
-  let target_signature = Signature::load(&sig_data);
+  offset: i64,
+  length: i64,
+  options: Option<SignatureOptions>,
+) -> Result<Buffer> {
+  let offset = u64::try_from(offset)
+    .map_err(|_| Error::from_reason(format!("Invalid range offset {}", offset)))?;
+  let length = u64::try_from(length)
+    .map_err(|_| Error::from_reason(format!("Invalid range length {}", length)))?;
 
-  let mut source_file = open_file(&source)?;
-  let source_signature = Signature::calculate(
-    &mut source_file,
-    target_signature.min_size,
-    target_signature.avg_size,
-    target_signature.max_size,
-  )
-  .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
-  .map_err(anyhow_to_js_error)?;
+  Ok(signature_of_range_data(&source, offset, length, options)?.into())
+}
 
-  let sig_diff = diff::diff_signatures(&source_signature, &target_signature);
+fn signature_of_range_data(
+  source: &str,
+  offset: u64,
+  length: u64,
+  options: Option<SignatureOptions>,
+) -> Result<Vec<u8>> {
+  let options = options.unwrap_or_default();
 
-  let mut dest_file = create_file(&dest)?;
-  apply::apply_from_http(sig_diff, file_uri, &mut source_file, &mut dest_file)
-    .map_err(box_to_js_error)
-    .await?;
+  let mut source_file = options.open_source(source)?;
+  source_file
+    .seek(io::SeekFrom::Start(offset))
+    .map_err(to_js_error)?;
+  let signature = options
+    .calculate(&mut source_file.take(length))
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
 
-  Ok(())
+  let mut dest = Vec::new();
+  signature.write(&mut dest).map_err(to_js_error)?;
+
+  Ok(dest)
 }
 
-/// Applies `diff` to the `a` and writes the result to `result`.
+/// Returns the signature of a whole file combined from the signatures of its ranges, calculated
+/// with `signature_of_range` and ordered by their offsets. It's identical to the signature of the
+/// whole file when every range overlaps the ones before it until they share a chunk boundary,
+/// otherwise combining fails.
 #[napi]
-pub fn apply(diff: String, a: String, result: String) -> Result<()> {
-  let mut diff_file = open_file(&diff)?;
-  let mut target_file = open_file(&a)?;
-  let mut res_file = File::create(result).map_err(to_js_error)?;
+pub fn combine_range_signatures(parts: Vec<RangeSignature>) -> Result<Buffer> {
+  let parts = parts
+    .iter()
+    .map(|part| {
+      let offset = u64::try_from(part.offset)
+        .map_err(|_| Error::from_reason(format!("Invalid range offset {}", part.offset)))?;
+      Ok((offset, &part.signature[..]))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  Ok(combine_range_signatures_data(parts)?.into())
+}
 
-  apply::apply(&mut diff_file, &mut target_file, &mut res_file).map_err(box_to_js_error)?;
+fn combine_range_signatures_data(parts: Vec<(u64, &[u8])>) -> Result<Vec<u8>> {
+  let parts = parts
+    .into_iter()
+    .map(|(offset, data)| Ok((offset, Signature::load(data).map_err(to_js_error)?)))
+    .collect::<Result<Vec<_>>>()?;
+  let signature = Signature::combine_ranges(parts).map_err(to_js_error)?;
 
-  Ok(())
+  let mut dest = Vec::new();
+  signature.write(&mut dest).map_err(to_js_error)?;
+
+  Ok(dest)
 }
 
-fn open_file(path: &str) -> Result<File> {
-  File::open(path)
-    .with_context(|| format!("Failed to open a file {}", path))
-    .map_err(anyhow_to_js_error)
+/// Returns calculated signatures of all `sources`. The first failure fails the whole batch unless
+/// `continue_on_error` is set, then failed paths get an error in their results instead.
+#[napi]
+pub fn signatures(
+  sources: Vec<String>,
+  options: Option<SignatureOptions>,
+  continue_on_error: Option<bool>,
+  batch_options: Option<BatchOptions>,
+) -> Result<Vec<SignatureResult>> {
+  let results = batch(
+    sources,
+    continue_on_error.unwrap_or(false),
+    &batch_options.unwrap_or_default(),
+    |path| signature_data(path, options.clone()),
+  )?;
+
+  Ok(
+    results
+      .into_iter()
+      .map(|(path, result)| match result {
+        Ok(signature) => SignatureResult {
+          path,
+          signature: Some(signature.into()),
+          error: None,
+        },
+        Err(error) => SignatureResult {
+          path,
+          signature: None,
+          error: Some(error),
+        },
+      })
+      .collect(),
+  )
 }
 
-fn create_file(path: &str) -> Result<File> {
-  File::create(path)
-    .with_context(|| format!("Failed to create a file {}", path))
-    .map_err(anyhow_to_js_error)
+/// Runs `f` for every path on `options.concurrency` threads, with one file open for every call
+/// counted against `options.max_open_files`. Errors are returned per path with
+/// `continue_on_error`, otherwise the batch stops and the first one in the order of the paths is
+/// returned.
+fn batch<T, F>(
+  paths: Vec<String>,
+  continue_on_error: bool,
+  options: &BatchOptions,
+  f: F,
+) -> Result<Vec<(String, std::result::Result<T, String>)>>
+where
+  T: Send,
+  F: Fn(&str) -> Result<T> + Sync,
+{
+  let concurrency = (options.concurrency.unwrap_or(1).max(1) as usize).min(paths.len().max(1));
+  let limit = OpenFileLimit::new(
+    options
+      .max_open_files
+      .map_or(concurrency, |max_open_files| max_open_files as usize),
+  );
+  let next = AtomicUsize::new(0);
+  let failed = AtomicBool::new(false);
+
+  // napi errors can't be sent between threads, so they're taken apart and put together again.
+  let mut results = thread::scope(|scope| {
+    let workers: Vec<_> = (0..concurrency)
+      .map(|_| {
+        scope.spawn(|| {
+          let mut results = Vec::new();
+          loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            if index >= paths.len() || failed.load(Ordering::Relaxed) {
+              return results;
+            }
+
+            let _files = limit.acquire(1);
+            let result = f(&paths[index]).map_err(|err| (err.status, err.reason));
+            if result.is_err() && !continue_on_error {
+              failed.store(true, Ordering::Relaxed);
+            }
+            results.push((index, result));
+          }
+        })
+      })
+      .collect();
+
+    workers
+      .into_iter()
+      .flat_map(|worker| worker.join().unwrap())
+      .collect::<Vec<_>>()
+  });
+  results.sort_unstable_by_key(|(index, _)| *index);
+
+  // Paths skipped after a failure have no results, but then only the error is returned.
+  let mut paths: Vec<_> = paths.into_iter().map(Some).collect();
+  results
+    .into_iter()
+    .map(|(index, result)| (paths[index].take().unwrap(), result))
+    .map(|(path, result)| match result {
+      Ok(value) => Ok((path, Ok(value))),
+      Err((_, reason)) if continue_on_error => Ok((path, Err(reason))),
+      Err((status, reason)) => Err(Error::new(status, reason)),
+    })
+    .collect()
 }
 
-fn to_js_error(e: impl std::error::Error) -> Error {
-  Error::from_reason(e.to_string())
+/// Returns the `signature` of a file updated for the `edits` made to it since, reading only the
+/// edited regions of the `source` holding the edited file.
+#[napi]
+pub fn update_signature(
+  signature: Buffer,
+  source: String,
+  edits: Vec<SignatureEdit>,
+) -> Result<Buffer> {
+  Ok(update_signature_data(&signature, &source, &edits)?.into())
 }
 
-fn anyhow_to_js_error(e: anyhow::Error) -> Error {
-  Error::from_reason(e.to_string())
+fn update_signature_data(
+  signature: &[u8],
+  source: &str,
+  edits: &[SignatureEdit],
+) -> Result<Vec<u8>> {
+  let mut source_file = open_file(source)?;
+  let mut updater = SignatureUpdater::new(Signature::load(signature).map_err(to_js_error)?);
+  for edit in edits {
+    let offset = u64::try_from(edit.offset)
+      .map_err(|_| Error::from_reason(format!("Invalid edit offset {}", edit.offset)))?;
+    let length = |length: i64| {
+      u64::try_from(length)
+        .map_err(|_| Error::from_reason(format!("Invalid edit length {}", length)))
+    };
+    updater
+      .update(
+        &mut source_file,
+        offset,
+        length(edit.old_length)?,
+        length(edit.new_length)?,
+      )
+      .with_context(|| format!("Failed to update the signature for {}", &source))
+      .map_err(anyhow_to_js_error)?;
+  }
+
+  let mut dest = Vec::new();
+  updater.finish().write(&mut dest).map_err(to_js_error)?;
+
+  Ok(dest)
 }
 
-fn box_to_js_error(e: Box<dyn std::error::Error>) -> Error {
+/// Returns the `signature` of the `source` extended for the data appended to it since, reading
+/// only its last chunk and the appended data.
+#[napi]
+pub fn extend_signature(signature: Buffer, source: String) -> Result<Buffer> {
+  let mut source_file = open_file(&source)?;
+  let mut signature = Signature::load(&signature).map_err(to_js_error)?;
+  signature
+    .extend(&mut source_file)
+    .with_context(|| format!("Failed to extend the signature for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest = Vec::new();
+  signature.write(&mut dest).map_err(to_js_error)?;
+
+  Ok(dest.into())
+}
+
+/// Calculates the signature of the `source` and writes its chunks to the `dest` as newline
+/// delimited JSON, one `{"offset", "length", "hash"}` object per line with the hash in hex, so
+/// other tools can read them line by line.
+#[napi]
+pub fn export_chunks_ndjson(
+  source: String,
+  dest: String,
+  options: Option<SignatureOptions>,
+) -> Result<()> {
+  let options = options.unwrap_or_default();
+  let mut source_file = options.open_source(&source)?;
+  let signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest = BufWriter::new(create_file(&dest)?);
+  signature
+    .write_ndjson(&mut dest)
+    .context("Failed to write the chunks to the file")
+    .map_err(anyhow_to_js_error)?;
+
+  Ok(())
+}
+
+/// Calculates the signature of the `source`, adds every chunk the `store` directory doesn't have
+/// yet, creating it when it's missing, and returns the signature. Diffs against the signature
+/// written with `chunk_copies` are applied from the store with `apply_from_chunk_store`.
+#[napi]
+pub fn export_chunks(
+  source: String,
+  store: String,
+  options: Option<SignatureOptions>,
+) -> Result<Buffer> {
+  Ok(export_chunks_data(&source, &store, options)?.into())
+}
+
+fn export_chunks_data(
+  source: &str,
+  store: &str,
+  options: Option<SignatureOptions>,
+) -> Result<Vec<u8>> {
+  let options = options.unwrap_or_default();
+  let mut source_file = options.open_source(source)?;
+  let signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+  chunk_store::ChunkStore::new(store)
+    .export(&signature, &mut source_file)
+    .with_context(|| format!("Failed to export the chunks to {}", &store))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest = Vec::new();
+  signature.write(&mut dest).map_err(to_js_error)?;
+
+  Ok(dest)
+}
+
+/// Writes the chunks of the signature at `signature` to the `dest` as a `.caibx` index of casync
+/// and desync. The signature must use the `Sha256` hash algorithm, since casync names chunks by
+/// their hashes.
+#[napi]
+pub fn write_casync_index(signature: String, dest: String) -> Result<()> {
+  let sig_data = fs::read(&signature)
+    .with_context(|| format!("Failed to read the signature {}", &signature))
+    .map_err(anyhow_to_js_error)?;
+  let signature = Signature::load(&sig_data).map_err(to_js_error)?;
+  let mut dest = create_file(&dest)?;
+  casync::write_index(&signature, &mut dest).map_err(to_js_error)?;
+
+  Ok(())
+}
+
+/// Checks whether the `source` still matches its stored `signature`.
+#[napi]
+pub fn verify(source: String, signature: Buffer) -> Result<VerifyResult> {
+  verify_data(&source, &signature)
+}
+
+fn verify_data(source: &str, signature: &[u8]) -> Result<VerifyResult> {
+  let expected = Signature::load(signature).map_err(to_js_error)?;
+  let mut source_file = open_file(source)?;
+  let actual = expected
+    .calculate_like(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", source))
+    .map_err(anyhow_to_js_error)?;
+
+  Ok(expected.verify(&actual))
+}
+
+/// Returns the options and the size of the chunks of the `signature`, without any chunk hashes.
+#[napi]
+pub fn signature_info(signature: Buffer) -> Result<SignatureInfo> {
+  let signature = Signature::load(&signature).map_err(to_js_error)?;
+
+  Ok(SignatureInfo {
+    version: signature.version.into(),
+    min_size: signature.min_size,
+    avg_size: signature.avg_size,
+    max_size: signature.max_size,
+    hash_algorithm: signature.hash_algorithm,
+    fixed_block_size: signature.fixed_block_size,
+    chunk_count: signature.chunks.len() as u32,
+    file_size: signature.file_size() as i64,
+    contiguous: signature.is_contiguous(),
+  })
+}
+
+/// Returns the `input` signature written in format version `target_version`, without reading its
+/// file again. Converting to older versions fails when they can't store the options of the
+/// signature, e.g. truncated chunk hashes.
+#[napi]
+pub fn convert_signature(input: Buffer, target_version: u32) -> Result<Buffer> {
+  let mut signature = Signature::load(&input).map_err(to_js_error)?;
+  signature.convert(target_version).map_err(to_js_error)?;
+
+  let mut dest = Vec::new();
+  signature.write(&mut dest).map_err(to_js_error)?;
+
+  Ok(dest.into())
+}
+
+/// Returns the distribution of chunk sizes of the `source`, useful to tune the signature options.
+/// It warns when more than `max_clamped_fraction` of the chunks, 0.5 by default, are cut at
+/// `min_size` or `max_size`.
+#[napi]
+pub fn chunk_size_histogram(
+  source: String,
+  options: Option<SignatureOptions>,
+  max_clamped_fraction: Option<f64>,
+) -> Result<ChunkSizeHistogram> {
+  let options = options.unwrap_or_default();
+
+  let mut source_file = options.open_source(&source)?;
+  let signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+
+  Ok(
+    signature.chunk_size_histogram(
+      max_clamped_fraction.unwrap_or(signature::DEFAULT_MAX_CLAMPED_FRACTION),
+    ),
+  )
+}
+
+/// Trains a zstd dictionary on the `samples` files, which should be similar to the data inserted
+/// by diffs. `max_size` is 110 KiB by default.
+#[napi]
+pub fn train_dictionary(samples: Vec<String>, max_size: Option<u32>) -> Result<Buffer> {
+  let max_size = max_size.unwrap_or(diff::DEFAULT_DICTIONARY_SIZE);
+  let dictionary = diff::train_dictionary(&samples, max_size as usize)
+    .context("Failed to train a dictionary")
+    .map_err(anyhow_to_js_error)?;
+
+  Ok(dictionary.into())
+}
+
+/// Returns the lengths of the data `a` and `b` start and end with, found by comparing them from
+/// both ends, far cheaper than chunking. Appends and prepends can be told from them without a
+/// diff, otherwise only the data between them differs.
+#[napi]
+pub fn common_edges(a: String, b: String) -> Result<Edges> {
+  let mut a_file = open_file(&a)?;
+  let mut b_file = open_file(&b)?;
+  let (prefix, suffix) = diff::common_edges(&mut a_file, &mut b_file)
+    .with_context(|| format!("Failed to compare {} and {}", &a, &b))
+    .map_err(anyhow_to_js_error)?;
+
+  Ok(Edges {
+    prefix: prefix as i64,
+    suffix: suffix as i64,
+  })
+}
+
+/// Returns the hex hashes of the chunks `want_sig` needs and `have_sig` has none of, once each.
+/// A client holding the chunks of `have_sig` only needs to fetch these to build the file of
+/// `want_sig`.
+#[napi]
+pub fn missing_hashes(have_sig: Buffer, want_sig: Buffer) -> Result<Vec<String>> {
+  let have = SignatureReader::new(&have_sig).map_err(to_js_error)?;
+  let want = SignatureReader::new(&want_sig).map_err(to_js_error)?;
+  let missing = diff::missing_hashes(have, want).map_err(box_to_js_error)?;
+
+  Ok(
+    missing
+      .iter()
+      .map(|hash| hash.to_hex().to_string())
+      .collect(),
+  )
+}
+
+/// Returns the approximate number of bytes diffs against `source_sig` take to index its chunks,
+/// which is most of their memory, so that schedulers can bound how many diffs run at once. Diffs
+/// with a lower `max_memory` keep the index in a temporary file instead.
+#[napi]
+pub fn diff_memory_estimate(source_sig: Buffer) -> Result<i64> {
+  Ok(memory_estimate_data(&source_sig)? as i64)
+}
+
+fn memory_estimate_data(source_sig: &[u8]) -> Result<u64> {
+  let source = SignatureReader::new(source_sig).map_err(to_js_error)?;
+
+  Ok(chunk_index::memory_estimate(source.len()))
+}
+
+/// Returns the size of the smallest uncompressed diff from the file of `source_sig` to the file
+/// of `target_sig` made of their chunks, without writing a diff: the new chunks are inserted once
+/// and all other chunks are copied with as few operations as possible. Diffs only copy from the
+/// first occurrence of repeated source chunks, so comparing their size to this bound shows how
+/// much the matching leaves out.
+#[napi]
+pub fn optimal_diff_size(source_sig: Buffer, target_sig: Buffer) -> Result<i64> {
+  let source_signature = Signature::load(&source_sig).map_err(to_js_error)?;
+  let target_signature = Signature::load(&target_sig).map_err(to_js_error)?;
+  let size = diff::optimal_size(&source_signature, &target_signature).map_err(box_to_js_error)?;
+
+  Ok(size as i64)
+}
+
+/// Returns the plan of the diff between the files of `source_sig` and `target_sig`, their
+/// operations without any inserted data, which is much smaller than a diff and cheap to cache and
+/// compare across versions. See `load_diff_plan`.
+#[napi]
+pub fn diff_plan(source_sig: Buffer, target_sig: Buffer) -> Result<Buffer> {
+  let source_signature = Signature::load(&source_sig).map_err(to_js_error)?;
+  let target_signature = Signature::load(&target_sig).map_err(to_js_error)?;
+  diff::check_chunking(&source_signature, &target_signature).map_err(box_to_js_error)?;
+
+  let sig_diff = diff::diff_signatures(&source_signature, &target_signature);
+  let mut plan = Vec::new();
+  diff::write_plan(&sig_diff, &mut plan).map_err(to_js_error)?;
+
+  Ok(plan.into())
+}
+
+/// Returns the operations of a `plan` from `diff_plan`.
+#[napi]
+pub fn load_diff_plan(plan: Buffer) -> Result<Vec<PlanOperation>> {
+  let ops = diff::load_plan(&plan).map_err(box_to_js_error)?;
+
+  Ok(
+    ops
+      .into_iter()
+      .map(|(op, offset, size)| PlanOperation {
+        kind: op.into(),
+        offset: offset as i64,
+        size: size as i64,
+      })
+      .collect(),
+  )
+}
+
+/// Generates a diff that transforms `source` to `target` and returns its sizes.
+#[napi]
+pub fn diff(
+  source: String,
+  target: String,
+  dest: String,
+  options: Option<SignatureOptions>,
+  diff_options: Option<DiffOptions>,
+) -> Result<DiffStats> {
+  let options = options.unwrap_or_default();
+  let diff_options = diff_options.unwrap_or_default();
+
+  let mut source_file = options.open_source(&source)?;
+  let source_signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut target_file = options.open_source(&target)?;
+  let target_signature = options
+    .calculate(&mut target_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &target))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest_file = create_file(&dest)?;
+
+  let stats = diff::write_diff_between_files(
+    &source_signature,
+    &target_signature,
+    &mut source_file,
+    &mut target_file,
+    &mut dest_file,
+    &diff_options,
+  )
+  .map_err(box_to_js_error)?;
+  sync_durable(&dest_file, diff_options.durable, &dest)?;
+
+  Ok(stats)
+}
+
+/// Returns a diff that transforms the `source` data to the `target` data like `diff`, without
+/// any files.
+#[napi]
+pub fn diff_buffers(
+  source: Buffer,
+  target: Buffer,
+  options: Option<SignatureOptions>,
+  diff_options: Option<DiffOptions>,
+) -> Result<Buffer> {
+  Ok(diff_data(&source, &target, options, diff_options)?.into())
+}
+
+fn diff_data(
+  source: &[u8],
+  target: &[u8],
+  options: Option<SignatureOptions>,
+  diff_options: Option<DiffOptions>,
+) -> Result<Vec<u8>> {
+  let options = options.unwrap_or_default();
+  let diff_options = diff_options.unwrap_or_default();
+
+  let mut source_data = io::Cursor::new(source);
+  let source_signature = options
+    .calculate(&mut source_data)
+    .context("Failed to calculate the sugnagure for the source")
+    .map_err(anyhow_to_js_error)?;
+
+  let mut target_data = io::Cursor::new(target);
+  let target_signature = options
+    .calculate(&mut target_data)
+    .context("Failed to calculate the sugnagure for the target")
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest = Vec::new();
+  diff::write_diff_between_files(
+    &source_signature,
+    &target_signature,
+    &mut source_data,
+    &mut target_data,
+    &mut dest,
+    &diff_options,
+  )
+  .map_err(box_to_js_error)?;
+
+  Ok(dest)
+}
+
+/// Generates a diff that transforms `source` to `target. Only source signature is required.
+/// Returns the sizes of the diff.
+#[napi]
+pub fn diff_using_source_signature(
+  source_sig: String,
+  target: String,
+  dest: String,
+  options: Option<DiffOptions>,
+) -> Result<DiffStats> {
+  let options = options.unwrap_or_default();
+  let sig_data = fs::read(source_sig).map_err(to_js_error)?;
+  let source_signature = Signature::load(&sig_data).map_err(to_js_error)?;
+
+  let mut target_file = open_file(&target)?;
+  let target_signature = source_signature
+    .calculate_like(&mut target_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &target))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest_file = create_file(&dest)?;
+
+  let stats = diff::write_diff_between(
+    &source_signature,
+    &target_signature,
+    &mut target_file,
+    &mut dest_file,
+    &options,
+  )
+  .map_err(box_to_js_error)?;
+  sync_durable(&dest_file, options.durable, &dest)?;
+
+  Ok(stats)
+}
+
+/// Generates a diff like `diff` and appends an HMAC-SHA256 of it computed with `key`, so
+/// `apply_verified_hmac` can check the diff wasn't modified.
+#[cfg(feature = "hmac")]
+#[napi]
+pub fn diff_signed(
+  key: Buffer,
+  source: String,
+  target: String,
+  dest: String,
+  options: Option<SignatureOptions>,
+  diff_options: Option<DiffOptions>,
+) -> Result<()> {
+  let options = options.unwrap_or_default();
+  let diff_options = diff_options.unwrap_or_default();
+
+  let mut source_file = options.open_source(&source)?;
+  let source_signature = options
+    .calculate(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut target_file = options.open_source(&target)?;
+  let target_signature = options
+    .calculate(&mut target_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &target))
+    .map_err(anyhow_to_js_error)?;
+
+  let mut dest_file = create_file(&dest)?;
+
+  hmac::write_signed_diff_between_files(
+    &source_signature,
+    &target_signature,
+    &mut source_file,
+    &mut target_file,
+    &mut dest_file,
+    &diff_options,
+    &key,
+  )
+  .map_err(box_to_js_error)?;
+  sync_durable(&dest_file, diff_options.durable, &dest)?;
+
+  Ok(())
+}
+
+/// Generates a diff that transforms a file with signature `source_sig` to the `target_data` with
+/// signature `target_sig`. Both signatures must be calculated with the same options, except for
+/// the hash algorithm and the hash length when the `source` file is passed too: its chunks are
+/// then hashed again like the target ones. The target chunks are read one at a time instead of
+/// loading the whole target signature, unless `insert_pool`, `coalesce_gap` or `align` is set.
+/// Returns the sizes of the diff.
+#[napi]
+pub fn diff_using_both_signatures(
+  source_sig: String,
+  target_sig: String,
+  target_data: String,
+  dest: String,
+  options: Option<DiffOptions>,
+  source: Option<String>,
+) -> Result<DiffStats> {
+  let options = options.unwrap_or_default();
+  let sig_data = fs::read(source_sig).map_err(to_js_error)?;
+  let source_signature = Signature::load(&sig_data).map_err(to_js_error)?;
+  let sig_data = fs::read(target_sig).map_err(to_js_error)?;
+  let target_reader = SignatureReader::new(&sig_data).map_err(to_js_error)?;
+  let mut source_file = source.as_deref().map(open_file).transpose()?;
+  let source_signature = diff::hash_like(
+    source_signature,
+    target_reader.header(),
+    source_file.as_mut(),
+  )
+  .map_err(box_to_js_error)?;
+  diff::check_chunking(&source_signature, target_reader.header()).map_err(box_to_js_error)?;
+
+  let mut target_file = open_file(&target_data)?;
+  let mut dest_file = create_file(&dest)?;
+
+  let stats = if diff::needs_whole_target(&options).is_some() {
+    let target_signature = Signature::load(&sig_data).map_err(to_js_error)?;
+    diff::write_diff_between(
+      &source_signature,
+      &target_signature,
+      &mut target_file,
+      &mut dest_file,
+      &options,
+    )
+  } else {
+    diff::write_diff_streaming(
+      &source_signature,
+      target_reader,
+      &mut target_file,
+      &mut dest_file,
+      &options,
+    )
+  }
+  .map_err(box_to_js_error)?;
+  sync_durable(&dest_file, options.durable, &dest)?;
+
+  Ok(stats)
+}
+
+/// Downloads the required parts of the file and builds a new file based on `target_sig` and the
+/// `source`. With `dry_run` only returns the plan of the pull. When the `source` already matches
+/// `target_sig` it's copied to `dest` without any requests. With `token_provider` every request
+/// is authorized with a bearer token it resolves, and it's called again for a fresh token
+/// whenever the server answers 401, so pulls can outlive short-lived credentials.
+#[napi]
+pub async fn pull_using_remote_signature(
+  source: String,
+  target_sig: String,
+  file_uri: String,
+  dest: String,
+  options: Option<PullOptions>,
+  token_provider: Option<ThreadsafeFunction<(), ErrorStrategy::Fatal>>,
+) -> Result<PullResult> {
+  let credentials = token_provider.map(credentials);
+  pull_with_credentials(source, target_sig, file_uri, dest, options, credentials).await
+}
+
+/// `pull_using_remote_signature` with the credentials already resolved, as the thread-safe
+/// functions of `token_provider` only exist inside Node.
+async fn pull_with_credentials(
+  source: String,
+  target_sig: String,
+  file_uri: String,
+  dest: String,
+  options: Option<PullOptions>,
+  credentials: Option<Arc<auth::Credentials>>,
+) -> Result<PullResult> {
+  let options = options.unwrap_or_default();
+  let sig_data = fs::read(target_sig).map_err(to_js_error)?;
+  let target_signature = Signature::load(&sig_data).map_err(to_js_error)?;
+
+  let mut source_file = open_file(&source)?;
+  let source_signature = target_signature
+    .calculate_like(&mut source_file)
+    .with_context(|| format!("Failed to calculate the sugnagure for {}", &source))
+    .map_err(anyhow_to_js_error)?;
+
+  let already_up_to_date = source_signature.chunks == target_signature.chunks;
+  if options.dry_run.unwrap_or(false) {
+    let sig_diff = diff::diff_signatures(&source_signature, &target_signature);
+    return Ok(PullResult {
+      already_up_to_date,
+      plan: Some(apply::pull_plan(&sig_diff)),
+    });
+  }
+
+  if already_up_to_date {
+    if source != dest {
+      fs::copy(&source, &dest)
+        .with_context(|| format!("Failed to copy {} to {}", &source, &dest))
+        .map_err(anyhow_to_js_error)?;
+    }
+  } else {
+    let sig_diff = diff::diff_signatures(&source_signature, &target_signature);
+    let mut dest_file = create_file(&dest)?;
+    apply::apply_from_http(
+      sig_diff,
+      file_uri,
+      &mut source_file,
+      &mut dest_file,
+      &options,
+      credentials,
+    )
+    .map_err(box_to_js_error)
+    .await?;
+  }
+
+  // Before the permissions, which may make the file read-only.
+  if options.preserve_xattrs.unwrap_or(false) {
+    xattr::copy_xattrs(&source, &dest)
+      .with_context(|| {
+        format!(
+          "Failed to copy the extended attributes of {} to {}",
+          &source, &dest
+        )
+      })
+      .map_err(anyhow_to_js_error)?;
+  }
+  if options.preserve_metadata.unwrap_or(false) {
+    apply::copy_metadata(&source, &dest)
+      .with_context(|| format!("Failed to copy the metadata of {} to {}", &source, &dest))
+      .map_err(anyhow_to_js_error)?;
+  }
+
+  Ok(PullResult {
+    already_up_to_date,
+    plan: None,
+  })
+}
+
+/// Builds the file of `target_sig` at `dest` entirely from downloads, for when the source isn't
+/// local either: copies are downloaded from the source at `source_uri`, described by
+/// `source_sig`, and inserts from the file at `file_uri`. `dry_run` and the options preserving
+/// the metadata of the source are ignored. `token_provider` authorizes the requests like in
+/// `pull_using_remote_signature`.
+#[napi]
+pub async fn pull_from_remote_source(
+  source_sig: String,
+  source_uri: String,
+  target_sig: String,
+  file_uri: String,
+  dest: String,
+  options: Option<PullOptions>,
+  token_provider: Option<ThreadsafeFunction<(), ErrorStrategy::Fatal>>,
+) -> Result<()> {
+  let options = options.unwrap_or_default();
+  let sig_data = fs::read(source_sig).map_err(to_js_error)?;
+  let source_signature = Signature::load(&sig_data).map_err(to_js_error)?;
+  let sig_data = fs::read(target_sig).map_err(to_js_error)?;
+  let target_signature = Signature::load(&sig_data).map_err(to_js_error)?;
+  diff::check_chunking(&source_signature, &target_signature).map_err(box_to_js_error)?;
+
+  let sig_diff = diff::diff_signatures(&source_signature, &target_signature);
+  let mut dest_file = create_file(&dest)?;
+  apply::apply_from_remote_source(
+    sig_diff,
+    source_uri,
+    file_uri,
+    &mut dest_file,
+    &options,
+    token_provider.map(credentials),
+  )
+  .map_err(box_to_js_error)
+  .await?;
+
+  Ok(())
+}
+
+/// Gets the bearer tokens of pulls from a JavaScript `token_provider` returning a promise of one.
+fn credentials(
+  token_provider: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+) -> Arc<auth::Credentials> {
+  Arc::new(auth::Credentials::new(move || {
+    let token_provider = token_provider.clone();
+    async move {
+      let token: Promise<String> = token_provider
+        .call_async(())
+        .await
+        .map_err(|err| err.reason)?;
+      token.await.map_err(|err| err.reason)
+    }
+  }))
+}
+
+/// Checks every operation of `diff` without applying it: the version, that copies stay within
+/// `source` when it's given and that the operations write exactly the declared size of the
+/// result. Only unreadable files are thrown, an invalid diff is reported in the result.
+#[napi]
+pub fn validate_diff(diff: String, source: Option<String>) -> Result<DiffValidation> {
+  let mut diff_file = BufReader::new(open_file(&diff)?);
+  let source_len = match source {
+    Some(source) => Some(open_file(&source)?.metadata()?.len()),
+    None => None,
+  };
+
+  Ok(match apply::validate(&mut diff_file, source_len) {
+    Ok((target_size, report)) => DiffValidation {
+      valid: true,
+      error: None,
+      target_size: target_size as i64,
+      copy_ops: report.copy_ops,
+      insert_ops: report.insert_ops,
+      bytes_written: report.bytes_written,
+    },
+    Err(err) => DiffValidation {
+      error: Some(err.to_string()),
+      ..Default::default()
+    },
+  })
+}
+
+/// Returns the optional features of the diff format `diff` uses, named like the `DiffOptions`
+/// using them, so a producer can be asked for diffs without the ones a consumer doesn't
+/// support, see `supported_diff_features`. Diffs using features this version can't apply are
+/// thrown with the unsupported ones listed.
+#[napi]
+pub fn diff_features(diff: String) -> Result<Vec<String>> {
+  let mut diff_file = open_file(&diff)?;
+  let header = apply::read_header(&mut diff_file).map_err(box_to_js_error)?;
+
+  Ok(header.features().into_iter().map(String::from).collect())
+}
+
+/// Returns the names of the optional features of the diff format this version applies.
+#[napi]
+pub fn supported_diff_features() -> Vec<String> {
+  apply::FEATURES
+    .into_iter()
+    .map(|(name, _)| name.to_string())
+    .collect()
+}
+
+/// Applies `diff` to the `a` and writes the result to `result`.
+#[napi]
+pub fn apply(diff: String, a: String, result: String, options: Option<ApplyOptions>) -> Result<()> {
+  apply_files(diff, a, result, options)?;
+
+  Ok(())
+}
+
+/// Applies a `diff` written with `chunk_copies` to the chunks in the `store` directory, see
+/// `export_chunks`, and writes the result to `result`. No source file is needed, so the options
+/// preserving its metadata are ignored.
+#[napi]
+pub fn apply_from_chunk_store(
+  diff: String,
+  store: String,
+  result: String,
+  options: Option<ApplyOptions>,
+) -> Result<()> {
+  let store = chunk_store::ChunkStore::new(&store);
+  let options = options.unwrap_or_default();
+  apply_into(
+    &mut open_file(&diff)?,
+    &mut Cursor::new(Vec::new()),
+    Some(&store),
+    &result,
+    &options,
+  )?;
+
+  Ok(())
+}
+
+/// Applies the `diff` to the `source` data and returns the result, without any files.
+#[napi]
+pub fn apply_buffers(diff: Buffer, source: Buffer) -> Result<Buffer> {
+  Ok(apply_data(&diff, &source)?.into())
+}
+
+fn apply_data(diff: &[u8], source: &[u8]) -> Result<Vec<u8>> {
+  let mut result = io::Cursor::new(Vec::new());
+  apply::apply(
+    &mut io::Cursor::new(diff),
+    &mut io::Cursor::new(source),
+    &mut result,
+  )
+  .map_err(box_to_js_error)?;
+
+  Ok(result.into_inner())
+}
+
+/// Checks the HMAC of the signed `diff` with `key` and only then applies it like `apply`.
+#[cfg(feature = "hmac")]
+#[napi]
+pub fn apply_verified_hmac(
+  key: Buffer,
+  diff: String,
+  a: String,
+  result: String,
+  options: Option<ApplyOptions>,
+) -> Result<()> {
+  // Checked and applied from a private copy, so the diff can't be replaced in between.
+  let mut diff_file = open_file(&diff)?;
+  let mut verified = tempfile::tempfile()
+    .and_then(|mut verified| io::copy(&mut diff_file, &mut verified).map(|_| verified))
+    .with_context(|| format!("Failed to copy the diff {}", &diff))
+    .map_err(anyhow_to_js_error)?;
+  hmac::verify(&mut verified, &key).map_err(box_to_js_error)?;
+  apply_diff_file(&mut verified, a, result, options)?;
+
+  Ok(())
+}
+
+/// Applies `diff` to the `a` and writes the result to `result` like `apply`, without blocking the
+/// event loop.
+#[napi]
+pub async fn apply_async(diff: String, a: String, result: String) -> Result<()> {
+  napi::tokio::task::spawn_blocking(move || apply_files(diff, a, result, None))
+    .await
+    .map_err(to_js_error)??;
+
+  Ok(())
+}
+
+/// Applies `diff` to the `a` and writes the result to `result` like `apply_async`, calling
+/// `callback` with the progress about every percent of the result and once it's complete.
+#[napi(ts_args_type = "diff: string, a: string, result: string, \
+                  callback: (progress: ApplyProgress) => void")]
+pub async fn apply_with_progress(
+  diff: String,
+  a: String,
+  result: String,
+  callback: ThreadsafeFunction<ApplyProgress, ErrorStrategy::Fatal>,
+) -> Result<ApplyReport> {
+  napi::tokio::task::spawn_blocking(move || {
+    let mut diff_file = open_file(&diff)?;
+    let mut target_file = open_file(&a)?;
+    let mut res_file = create_destination(&result)?;
+
+    let report = apply::apply_with_progress(
+      &mut diff_file,
+      &mut target_file,
+      res_file.file(),
+      &mut |written, total| {
+        callback.call(
+          ApplyProgress {
+            bytes_written: written as i64,
+            total_bytes: total as i64,
+          },
+          ThreadsafeFunctionCallMode::NonBlocking,
+        );
+      },
+    )
+    .map_err(box_to_js_error)?;
+    res_file.commit();
+
+    Ok(report)
+  })
+  .await
+  .map_err(to_js_error)?
+}
+
+/// Applies `diff` to the `a` and passes the result to `write` front to back, e.g. to send it as an
+/// HTTP response without writing it to disk. Every call of `write` is awaited before the next one,
+/// so resolving once a Node `Writable` has drained keeps its backpressure.
+#[napi(ts_args_type = "diff: string, a: string, write: (chunk: Buffer) => Promise<void>")]
+pub async fn apply_to_stream(
+  diff: String,
+  a: String,
+  write: ThreadsafeFunction<Buffer, ErrorStrategy::Fatal>,
+) -> Result<()> {
+  let handle = napi::tokio::runtime::Handle::current();
+  napi::tokio::task::spawn_blocking(move || {
+    let mut diff_file = open_file(&diff)?;
+    let mut target_file = open_file(&a)?;
+    let mut writer = BufWriter::with_capacity(JS_WRITE_SIZE, JsWriter { write, handle });
+
+    stream::apply_to_writer(&mut diff_file, &mut target_file, &mut writer)
+      .map_err(box_to_js_error)?;
+
+    Ok(())
+  })
+  .await
+  .map_err(to_js_error)?
+}
+
+/// The size of the chunks passed to JavaScript by `apply_to_stream`, the default high water mark
+/// of Node streams.
+const JS_WRITE_SIZE: usize = 64 * 1024;
+
+/// Passes the data to a JavaScript `write` function and waits for the promise it returns.
+struct JsWriter {
+  write: ThreadsafeFunction<Buffer, ErrorStrategy::Fatal>,
+  handle: napi::tokio::runtime::Handle,
+}
+
+impl Write for JsWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self
+      .handle
+      .block_on(async {
+        let written: Promise<()> = self.write.call_async(buf.to_vec().into()).await?;
+        written.await
+      })
+      .map_err(|err| io::Error::other(err.reason))?;
+
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Applies `diff` to the `a`, writes the result to `result` and reports what was done.
+#[napi]
+pub fn apply_with_report(
+  diff: String,
+  a: String,
+  result: String,
+  options: Option<ApplyOptions>,
+) -> Result<ApplyReport> {
+  apply_files(diff, a, result, options)
+}
+
+/// Applies `diff` to the `a` and writes the result to `result`, taking the inserted data from
+/// `inserts`. `inserts` must contain it at the offsets where it's placed in the result, for
+/// example when it was downloaded separately, and the inserts of `diff` only have their sizes.
+#[napi]
+pub fn apply_with_local_inserts(
+  diff: String,
+  a: String,
+  inserts: String,
+  result: String,
+) -> Result<()> {
+  let mut diff_file = open_file(&diff)?;
+  let mut target_file = open_file(&a)?;
+  let mut inserts_file = open_file(&inserts)?;
+  let mut res_file = create_destination(&result)?;
+
+  apply::apply_with_local_inserts(
+    &mut diff_file,
+    &mut target_file,
+    &mut inserts_file,
+    res_file.file(),
+  )
+  .map_err(box_to_js_error)?;
+  res_file.commit();
+
+  Ok(())
+}
+
+/// Applies `diff` to the `a`, overwriting `a` with the result instead of creating a new file.
+#[napi]
+pub fn apply_in_place(diff: String, a: String) -> Result<()> {
+  let mut diff_file = open_file(&diff)?;
+  let mut file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open(&a)
+    .with_context(|| format!("Failed to open a file {}", &a))
+    .map_err(anyhow_to_js_error)?;
+
+  apply::apply_in_place(&mut diff_file, &mut file).map_err(box_to_js_error)?;
+
+  Ok(())
+}
+
+/// Applies `diff` to the `source` and writes the result over the `existing` file, which already
+/// holds the data of `source` wherever the diff copies it to the same offsets, e.g. a copy of
+/// `source` made by an earlier sync. Only inserts and copies moved to other offsets are written,
+/// so small updates of large files take few writes. `existing` must not be `source`, see
+/// `apply_in_place` for that.
+#[napi]
+pub fn apply_patch_existing(diff: String, source: String, existing: String) -> Result<ApplyReport> {
+  let mut diff_file = open_file(&diff)?;
+  let mut source_file = open_file(&source)?;
+  let mut file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open(&existing)
+    .with_context(|| format!("Failed to open a file {}", &existing))
+    .map_err(anyhow_to_js_error)?;
+
+  apply::apply_with(
+    &mut diff_file,
+    &mut source_file,
+    &mut file,
+    apply::ApplyExtras {
+      existing: true,
+      ..Default::default()
+    },
+  )
+  .map_err(box_to_js_error)
+}
+
+/// Applies `diff` to the `a` and writes the result to `result` like `apply`, starting from a
+/// copy-on-write clone of `a` on filesystems supporting them, such as btrfs, XFS and APFS. Only
+/// inserts and copies moved to other offsets are written then, so results mostly copied from
+/// the same offsets of `a` are fast and share their storage with it. Elsewhere, or when `a` and
+/// `result` are on different filesystems, the result is written like `apply` writes it.
+#[napi]
+pub fn apply_reflink(diff: String, a: String, result: String) -> Result<()> {
+  let mut res_file = match apply::Destination::cloned(&a, &result) {
+    Ok(res_file) => res_file,
+    Err(err) if reflink::is_unsupported(&err) => {
+      apply_files(diff, a, result, None)?;
+      return Ok(());
+    }
+    Err(err) => {
+      return Err(anyhow_to_js_error(
+        anyhow::Error::new(err).context(format!("Failed to clone {} to {}", &a, &result)),
+      ))
+    }
+  };
+
+  let mut diff_file = open_file(&diff)?;
+  apply::apply_in_place(&mut diff_file, res_file.file()).map_err(box_to_js_error)?;
+  res_file.commit();
+
+  Ok(())
+}
+
+/// Applies `diffs` one after another to the `base` and writes the final result to `result`.
+#[napi]
+pub fn apply_chain(diffs: Vec<String>, base: String, result: String) -> Result<()> {
+  let mut diff_files = diffs
+    .iter()
+    .map(|diff| open_file(diff))
+    .collect::<Result<Vec<_>>>()?;
+  let mut base_file = open_file(&base)?;
+  let mut res_file = create_destination(&result)?;
+
+  apply::apply_chain(&mut diff_files, &mut base_file, res_file.file()).map_err(box_to_js_error)?;
+  res_file.commit();
+
+  Ok(())
+}
+
+/// Merges `diff_a` and `diff_b`, both made from the file of `base_sig`, into a diff applying both
+/// of them and writes it to `dest`. Fails when both change the same region of the base. The
+/// merged diff is uncompressed and without an operation table.
+#[napi]
+pub fn merge_diffs(base_sig: Buffer, diff_a: String, diff_b: String, dest: String) -> Result<()> {
+  let base_signature = Signature::load(&base_sig).map_err(to_js_error)?;
+  let mut a_file = open_file(&diff_a)?;
+  let mut b_file = open_file(&diff_b)?;
+  let mut dest_file = create_destination(&dest)?;
+
+  merge::merge_diffs(
+    &base_signature,
+    &mut BufReader::new(&mut a_file),
+    &mut BufReader::new(&mut b_file),
+    &mut BufWriter::new(dest_file.file()),
+  )
+  .map_err(box_to_js_error)?;
+  dest_file.commit();
+
+  Ok(())
+}
+
+/// Returns the operations of a `diff` written with `operation_table`, without parsing them.
+/// `dictionary` is needed for diffs compressed with one.
+#[napi]
+pub fn operation_table(diff: String, dictionary: Option<String>) -> Result<Vec<OperationEntry>> {
+  let mut diff_file = open_file(&diff)?;
+  let dictionary = match &dictionary {
+    Some(path) => Some(
+      fs::read(path)
+        .with_context(|| format!("Failed to read the dictionary {}", path))
+        .map_err(anyhow_to_js_error)?,
+    ),
+    None => None,
+  };
+
+  let table =
+    apply::read_operation_table(&mut diff_file, dictionary.as_deref()).map_err(box_to_js_error)?;
+
+  Ok(
+    table
+      .into_iter()
+      .map(|(op, position, offset, size)| OperationEntry {
+        kind: op.into(),
+        position: position as i64,
+        offset: offset as i64,
+        size: size as i64,
+      })
+      .collect(),
+  )
+}
+
+/// Splits a `diff` into a `manifest` of its operations and the `payloads` of its inserts,
+/// concatenated in order, so the small manifests and the bulky payloads can be cached and sent
+/// separately. Insert checksums and the HMAC tag aren't kept, and diffs copying from an insert
+/// pool can't be split. `dictionary` is needed for diffs compressed with one.
+#[napi]
+pub fn split_diff(
+  diff: String,
+  manifest: String,
+  payloads: String,
+  dictionary: Option<String>,
+) -> Result<()> {
+  let mut diff_file = open_file(&diff)?;
+  let dictionary = match &dictionary {
+    Some(path) => Some(
+      fs::read(path)
+        .with_context(|| format!("Failed to read the dictionary {}", path))
+        .map_err(anyhow_to_js_error)?,
+    ),
+    None => None,
+  };
+  let mut manifest_file = create_destination(&manifest)?;
+  let mut payloads_file = create_destination(&payloads)?;
+
+  manifest::split_diff(
+    &mut BufReader::new(&mut diff_file),
+    &mut BufWriter::new(manifest_file.file()),
+    &mut BufWriter::new(payloads_file.file()),
+    dictionary.as_deref(),
+  )
+  .map_err(box_to_js_error)?;
+  manifest_file.commit();
+  payloads_file.commit();
+
+  Ok(())
+}
+
+/// Applies a `manifest` with its `payloads` from `split_diff` to the `source` and writes the
+/// result to `result`, like `apply` applies the diff they were split from.
+#[napi]
+pub fn apply_from_manifest(
+  manifest: String,
+  payloads: String,
+  source: String,
+  result: String,
+) -> Result<ApplyReport> {
+  let manifest = fs::read(&manifest)
+    .with_context(|| format!("Failed to read the manifest {}", &manifest))
+    .map_err(anyhow_to_js_error)?;
+  let mut payloads_file = open_file(&payloads)?;
+  let mut source_file = open_file(&source)?;
+  let mut res_file = create_destination(&result)?;
+
+  let report = manifest::apply_from_manifest(
+    &manifest,
+    &mut BufReader::new(&mut payloads_file),
+    &mut source_file,
+    res_file.file(),
+  )
+  .map_err(box_to_js_error)?;
+  res_file.commit();
+
+  Ok(report)
+}
+
+fn apply_files(
+  diff: String,
+  a: String,
+  result: String,
+  options: Option<ApplyOptions>,
+) -> Result<ApplyReport> {
+  let mut diff_file = open_file(&diff)?;
+  apply_diff_file(&mut diff_file, a, result, options)
+}
+
+/// Like `apply_files`, but reads the diff from the already open `diff_file`.
+fn apply_diff_file(
+  diff_file: &mut File,
+  a: String,
+  result: String,
+  options: Option<ApplyOptions>,
+) -> Result<ApplyReport> {
+  let options = options.unwrap_or_default();
+  let mut target_file = open_file(&a)?;
+  let report = apply_into(diff_file, &mut target_file, None, &result, &options)?;
+
+  // Before the permissions, which may make the file read-only.
+  if options.preserve_xattrs.unwrap_or(false) {
+    xattr::copy_xattrs(&a, &result)
+      .with_context(|| {
+        format!(
+          "Failed to copy the extended attributes of {} to {}",
+          &a, &result
+        )
+      })
+      .map_err(anyhow_to_js_error)?;
+  }
+  if options.preserve_metadata.unwrap_or(false) {
+    apply::copy_metadata(&a, &result)
+      .with_context(|| format!("Failed to copy the metadata of {} to {}", &a, &result))
+      .map_err(anyhow_to_js_error)?;
+  }
+
+  Ok(report)
+}
+
+/// Applies the `diff_file` to the `source` data, reading copies which name source chunks from
+/// `chunks`, and writes the result to `result`.
+fn apply_into<S: Read + Seek>(
+  diff_file: &mut File,
+  source: &mut S,
+  chunks: Option<&chunk_store::ChunkStore>,
+  result: &str,
+  options: &ApplyOptions,
+) -> Result<ApplyReport> {
+  let mut res_file = match &options.checkpoint_path {
+    Some(_) => apply::Destination::resumable(result, options.resume.unwrap_or(false))
+      .with_context(|| format!("Failed to open a file {}", result))
+      .map_err(anyhow_to_js_error)?,
+    None => create_destination(result)?,
+  };
+
+  let dictionary = match &options.dictionary {
+    Some(path) => Some(
+      fs::read(path)
+        .with_context(|| format!("Failed to read the dictionary {}", path))
+        .map_err(anyhow_to_js_error)?,
+    ),
+    None => None,
+  };
+
+  let mut pool = match &options.insert_pool {
+    Some(path) => Some(open_file(path)?),
+    None => None,
+  };
+  let checkpoint = options
+    .checkpoint_path
+    .as_ref()
+    .map(|path| apply::Checkpoint {
+      path: Path::new(path),
+      resume: options.resume.unwrap_or(false),
+    });
+
+  let report = apply::apply_with(
+    diff_file,
+    source,
+    res_file.file(),
+    apply::ApplyExtras {
+      dictionary: dictionary.as_deref(),
+      checkpoint: checkpoint.as_ref(),
+      pool: pool.as_mut(),
+      chunks,
+      ..Default::default()
+    },
+  )
+  .map_err(box_to_js_error)?;
+  sync_durable(res_file.file(), options.durable, result)?;
+  res_file.commit();
+
+  Ok(report)
+}
+
+fn open_file(path: &str) -> Result<File> {
+  File::open(path)
+    .with_context(|| format!("Failed to open a file {}", path))
+    .map_err(anyhow_to_js_error)
+}
+
+fn create_file(path: &str) -> Result<File> {
+  // Pulls copy data already written to the result, so it's read back.
+  File::options()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .open(path)
+    .with_context(|| format!("Failed to create a file {}", path))
+    .map_err(anyhow_to_js_error)
+}
+
+fn create_destination(path: &str) -> Result<apply::Destination> {
+  apply::Destination::create(path)
+    .with_context(|| format!("Failed to create a file {}", path))
+    .map_err(anyhow_to_js_error)
+}
+
+/// Files whose written data can be flushed to the storage device.
+trait SyncAll {
+  fn sync_all(&self) -> io::Result<()>;
+}
+
+impl SyncAll for File {
+  fn sync_all(&self) -> io::Result<()> {
+    File::sync_all(self)
+  }
+}
+
+/// Waits until the data written to the `file` at `path` is on the storage device when `durable`
+/// is set.
+fn sync_durable(file: &impl SyncAll, durable: Option<bool>, path: &str) -> Result<()> {
+  if durable.unwrap_or(false) {
+    file
+      .sync_all()
+      .with_context(|| format!("Failed to sync a file {}", path))
+      .map_err(anyhow_to_js_error)?;
+  }
+
+  Ok(())
+}
+
+fn to_js_error(e: impl std::error::Error) -> Error {
   Error::from_reason(e.to_string())
 }
+
+fn anyhow_to_js_error(e: anyhow::Error) -> Error {
+  // Includes the causes, e.g. which limit a signature exceeded.
+  Error::from_reason(format!("{:#}", e))
+}
+
+fn box_to_js_error(e: Box<dyn std::error::Error>) -> Error {
+  // Errors like TLS handshake failures are only described by their sources.
+  let mut reason = e.to_string();
+  let mut source = e.source();
+  while let Some(err) = source {
+    reason.push_str(": ");
+    reason.push_str(&err.to_string());
+    source = err.source();
+  }
+
+  Error::from_reason(reason)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn test_diff_using_both_signatures() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..300).map(|_| rand::random::<u8>()));
+    fs::write(path("source"), &source).unwrap();
+    fs::write(path("target"), &target).unwrap();
+
+    super::write_binary_signature(path("source"), path("source.sig"), None, None).unwrap();
+    super::write_binary_signature(path("target"), path("target.sig"), None, None).unwrap();
+    super::diff(
+      path("source"),
+      path("target"),
+      path("full.diff"),
+      None,
+      None,
+    )
+    .unwrap();
+    super::diff_using_both_signatures(
+      path("source.sig"),
+      path("target.sig"),
+      path("target"),
+      path("sigs.diff"),
+      None,
+      None,
+    )
+    .unwrap();
+
+    assert_eq!(
+      fs::read(path("sigs.diff")).unwrap(),
+      fs::read(path("full.diff")).unwrap()
+    );
+
+    super::write_binary_signature(
+      path("target"),
+      path("other.sig"),
+      Some(super::SignatureOptions {
+        min_size: 1024,
+        avg_size: 4096,
+        max_size: 16384,
+        ..Default::default()
+      }),
+      None,
+    )
+    .unwrap();
+    let err = super::diff_using_both_signatures(
+      path("source.sig"),
+      path("other.sig"),
+      path("target"),
+      path("other.diff"),
+      None,
+      None,
+    )
+    .unwrap_err();
+    assert_eq!(
+      err.reason,
+      "signatures are calculated with different chunk sizes: 4096/16384/65535 and 1024/4096/16384"
+    );
+  }
+
+  #[test]
+  fn test_diff_rehashing_source_signature() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..300).map(|_| rand::random::<u8>()));
+    fs::write(path("source"), &source).unwrap();
+    fs::write(path("target"), &target).unwrap();
+
+    let sha256 = super::SignatureOptions {
+      hash_algorithm: Some(super::HashAlgorithm::Sha256),
+      ..Default::default()
+    };
+    super::write_binary_signature(path("source"), path("source.sig"), Some(sha256), None).unwrap();
+    super::write_binary_signature(path("target"), path("target.sig"), None, None).unwrap();
+    super::diff(
+      path("source"),
+      path("target"),
+      path("full.diff"),
+      None,
+      None,
+    )
+    .unwrap();
+
+    let err = super::diff_using_both_signatures(
+      path("source.sig"),
+      path("target.sig"),
+      path("target"),
+      path("sigs.diff"),
+      None,
+      None,
+    )
+    .unwrap_err();
+    assert_eq!(
+      err.reason,
+      "signatures are hashed differently: Sha256 in 32 bytes and Blake3 in 32 bytes, re-hashing \
+      the source chunks needs the source file"
+    );
+
+    super::diff_using_both_signatures(
+      path("source.sig"),
+      path("target.sig"),
+      path("target"),
+      path("sigs.diff"),
+      None,
+      Some(path("source")),
+    )
+    .unwrap();
+    assert_eq!(
+      fs::read(path("sigs.diff")).unwrap(),
+      fs::read(path("full.diff")).unwrap()
+    );
+
+    super::apply(path("sigs.diff"), path("source"), path("result"), None).unwrap();
+    assert_eq!(fs::read(path("result")).unwrap(), target);
+  }
+
+  #[test]
+  fn test_apply_async() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..300).map(|_| rand::random::<u8>()));
+    fs::write(path("source"), &source).unwrap();
+    fs::write(path("target"), &target).unwrap();
+
+    super::diff(path("source"), path("target"), path("diff"), None, None).unwrap();
+    super::apply(path("diff"), path("source"), path("sync"), None).unwrap();
+    napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_async(
+        path("diff"),
+        path("source"),
+        path("async"),
+      ))
+      .unwrap();
+
+    assert_eq!(
+      fs::read(path("async")).unwrap(),
+      fs::read(path("sync")).unwrap()
+    );
+    assert_eq!(fs::read(path("async")).unwrap(), target);
+
+    let err = napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::apply_async(
+        path("missing"),
+        path("source"),
+        path("async"),
+      ))
+      .unwrap_err();
+    assert!(err.reason.starts_with("Failed to open a file"));
+  }
+
+  #[test]
+  fn test_batch_continue_on_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    fs::write(path("a"), vec![1u8; 10000]).unwrap();
+    fs::write(path("c"), vec![2u8; 10000]).unwrap();
+    let sources = vec![path("a"), path("missing"), path("c")];
+    let signature = |path: &str| super::signature_data(path, None);
+
+    let options = super::BatchOptions::default();
+    let err = super::batch(sources.clone(), false, &options, signature).unwrap_err();
+    assert!(err.reason.starts_with("Failed to open a file"));
+
+    let results = super::batch(sources, true, &options, signature).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+      results[0].1,
+      signature(&path("a")).map_err(|err| err.reason)
+    );
+    assert_eq!(
+      results[2].1,
+      signature(&path("c")).map_err(|err| err.reason)
+    );
+    assert_eq!(
+      results[1],
+      (
+        path("missing"),
+        Err(format!(
+          "Failed to open a file {}: No such file or directory (os error 2)",
+          path("missing")
+        ))
+      )
+    );
+  }
+
+  #[test]
+  fn test_batch_max_open_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let sources: Vec<_> = (0..64u8)
+      .map(|i| {
+        let path = dir.path().join(i.to_string());
+        fs::write(&path, vec![i; 10000]).unwrap();
+        path.to_str().unwrap().to_string()
+      })
+      .collect();
+    let options = super::BatchOptions {
+      concurrency: Some(16),
+      max_open_files: Some(2),
+    };
+
+    let open = AtomicUsize::new(0);
+    let most_open = AtomicUsize::new(0);
+    let results = super::batch(sources.clone(), false, &options, |path| {
+      most_open.fetch_max(open.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+      let signature = super::signature_data(path, None);
+      open.fetch_sub(1, Ordering::SeqCst);
+      signature
+    })
+    .unwrap();
+
+    assert!(most_open.load(Ordering::SeqCst) <= 2);
+    assert_eq!(results.len(), sources.len());
+    for ((path, result), source) in results.into_iter().zip(sources) {
+      assert_eq!(path, source);
+      assert_eq!(
+        result,
+        super::signature_data(&source, None).map_err(|err| err.reason)
+      );
+    }
+  }
+
+  #[test]
+  fn test_verify() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file").to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    fs::write(&path, &data).unwrap();
+    let signature = super::signature_data(&path, None).unwrap();
+
+    assert!(super::verify_data(&path, &signature).unwrap().matches);
+
+    let mut changed = data.clone();
+    changed[70000] ^= 0xff;
+    fs::write(&path, &changed).unwrap();
+    let result = super::verify_data(&path, &signature).unwrap();
+    assert!(!result.matches);
+    assert!(result.mismatched_chunks >= 1);
+    assert!(result.first_mismatch_offset.unwrap() <= 70000);
+
+    fs::write(&path, &data[..50000]).unwrap();
+    let result = super::verify_data(&path, &signature).unwrap();
+    assert!(!result.matches);
+    assert!(result.first_mismatch_offset.unwrap() <= 50000);
+  }
+
+  #[test]
+  fn test_pull_already_up_to_date() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    fs::write(path("source"), &data).unwrap();
+    fs::write(
+      path("target.sig"),
+      super::signature_data(&path("source"), None).unwrap(),
+    )
+    .unwrap();
+
+    // The file is never requested.
+    let result = napi::tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(super::pull_with_credentials(
+        path("source"),
+        path("target.sig"),
+        "http://fastcdc-diff.invalid/target.bin".to_string(),
+        path("dest"),
+        None,
+        None,
+      ))
+      .unwrap();
+
+    assert_eq!(
+      result,
+      super::PullResult {
+        already_up_to_date: true,
+        plan: None,
+      }
+    );
+    assert_eq!(fs::read(path("dest")).unwrap(), data);
+  }
+
+  #[test]
+  fn test_pull_empty_files() {
+    use crate::test_server;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    fs::write(path("empty"), b"").unwrap();
+    fs::write(path("data"), &data).unwrap();
+    for name in ["empty", "data"] {
+      let signature = super::signature_data(&path(name), None).unwrap();
+      fs::write(path(&format!("{}.sig", name)), signature).unwrap();
+    }
+    let pull_with = |source: &str, target: &str, url: String, options| {
+      napi::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(super::pull_with_credentials(
+          path(source),
+          path(&format!("{}.sig", target)),
+          url,
+          path("dest"),
+          options,
+          None,
+        ))
+        .unwrap()
+    };
+    let pull = |source: &str, target: &str, url: String| pull_with(source, target, url, None);
+    let plan = |source: &str, target: &str| {
+      let options = super::PullOptions {
+        dry_run: Some(true),
+        ..Default::default()
+      };
+      let url = "http://fastcdc-diff.invalid/target.bin".to_string();
+      let plan = pull_with(source, target, url, Some(options)).plan.unwrap();
+      let ranges: Vec<_> = plan.ranges.iter().map(|r| (r.offset, r.length)).collect();
+      (ranges, plan.copied_bytes, plan.inserted_bytes)
+    };
+    assert_eq!(plan("empty", "data"), (vec![(0, 100000)], 0, 100000));
+    assert_eq!(plan("data", "empty"), (vec![], 0, 0));
+
+    // The whole target is downloaded without any empty ranges.
+    let server = test_server::serve(data.clone(), |_| Duration::ZERO);
+    let result = pull("empty", "data", server.url.clone());
+    assert!(!result.already_up_to_date);
+    assert_eq!(fs::read(path("dest")).unwrap(), data);
+    let requests = server.requests.lock().unwrap();
+    // Requests without ranges only probe for their support.
+    let ranges: Vec<String> = requests
+      .iter()
+      .filter_map(|request| {
+        request.lines().find_map(|line| {
+          line
+            .to_ascii_lowercase()
+            .strip_prefix("range: bytes=")
+            .map(String::from)
+        })
+      })
+      .collect();
+    assert!(!ranges.is_empty());
+    for ranges in ranges {
+      for range in ranges.trim_end().split(',') {
+        let (start, end) = range.split_once('-').unwrap();
+        assert!(
+          start.parse::<u64>().unwrap() <= end.parse::<u64>().unwrap(),
+          "{}",
+          range
+        );
+      }
+    }
+    drop(requests);
+
+    // Nothing is requested for an empty target.
+    fs::write(path("dest"), b"stale").unwrap();
+    let result = pull(
+      "data",
+      "empty",
+      "http://fastcdc-diff.invalid/empty.bin".to_string(),
+    );
+    assert!(!result.already_up_to_date);
+    assert_eq!(fs::read(path("dest")).unwrap(), b"");
+
+    let result = pull(
+      "empty",
+      "empty",
+      "http://fastcdc-diff.invalid/empty.bin".to_string(),
+    );
+    assert!(result.already_up_to_date);
+    assert_eq!(fs::read(path("dest")).unwrap(), b"");
+  }
+
+  #[test]
+  fn test_signature_options_builder() {
+    use super::{HashAlgorithm, SignatureOptions};
+
+    let options = SignatureOptions::builder()
+      .min_size(1024)
+      .avg_size(4096)
+      .max_size(16384)
+      .hash_algorithm(HashAlgorithm::Sha256)
+      .normalization(2)
+      .build()
+      .unwrap();
+    assert_eq!(
+      (options.min_size, options.avg_size, options.max_size),
+      (1024, 4096, 16384)
+    );
+    assert_eq!(options.hash_algorithm, Some(HashAlgorithm::Sha256));
+    assert_eq!(options.normalization, Some(2));
+    assert!(SignatureOptions::builder().build().is_ok());
+
+    let options = SignatureOptions::from_avg(1 << 20).unwrap();
+    assert_eq!(
+      (options.min_size, options.avg_size, options.max_size),
+      (1 << 18, 1 << 20, 1 << 22)
+    );
+    assert!(SignatureOptions::from_avg(16).is_err());
+
+    let invalid = [
+      SignatureOptions::builder().min_size(16),
+      SignatureOptions::builder().avg_size(16),
+      SignatureOptions::builder().max_size(u32::MAX),
+      SignatureOptions::builder().min_size(8192).avg_size(4096),
+      SignatureOptions::builder().avg_size(65536).max_size(32768),
+      SignatureOptions::builder().normalization(4),
+      SignatureOptions::builder().hash_bytes(20),
+    ];
+    for builder in invalid {
+      assert!(builder.build().is_err());
+    }
+  }
+
+  #[test]
+  fn test_durable_outputs() {
+    use super::{sync_durable, SyncAll};
+    use std::cell::Cell;
+    use std::io;
+
+    struct Synced(Cell<usize>);
+    impl SyncAll for Synced {
+      fn sync_all(&self) -> io::Result<()> {
+        self.0.set(self.0.get() + 1);
+        Ok(())
+      }
+    }
+
+    let file = Synced(Cell::new(0));
+    sync_durable(&file, None, "file").unwrap();
+    sync_durable(&file, Some(false), "file").unwrap();
+    assert_eq!(file.0.get(), 0);
+    sync_durable(&file, Some(true), "file").unwrap();
+    assert_eq!(file.0.get(), 1);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    fs::write(path("source"), &data).unwrap();
+    fs::write(path("target"), &data[1000..]).unwrap();
+
+    super::write_binary_signature(path("source"), path("source.sig"), None, Some(true)).unwrap();
+    super::diff(
+      path("source"),
+      path("target"),
+      path("diff"),
+      None,
+      Some(super::DiffOptions {
+        durable: Some(true),
+        ..Default::default()
+      }),
+    )
+    .unwrap();
+    super::apply(
+      path("diff"),
+      path("source"),
+      path("result"),
+      Some(super::ApplyOptions {
+        durable: Some(true),
+        ..Default::default()
+      }),
+    )
+    .unwrap();
+    assert_eq!(fs::read(path("result")).unwrap(), &data[1000..]);
+  }
+
+  #[test]
+  fn test_apply_reflink() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    let source: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let novel: Vec<u8> = (0..5000).map(|_| rand::random::<u8>()).collect();
+    let target = [
+      &source[..100000],
+      &novel,
+      &source[100000..200000],
+      &source[250000..],
+    ]
+    .concat();
+    fs::write(path("source"), &source).unwrap();
+    fs::write(path("target"), &target).unwrap();
+    fs::write(path("result"), b"replaced").unwrap();
+    super::diff(path("source"), path("target"), path("diff"), None, None).unwrap();
+
+    // Cloned where the filesystem supports it, written like `apply` writes it elsewhere.
+    super::apply_reflink(path("diff"), path("source"), path("result")).unwrap();
+    assert_eq!(fs::read(path("result")).unwrap(), target);
+    assert_eq!(fs::read(path("source")).unwrap(), source);
+
+    assert!(super::apply_reflink(path("missing"), path("source"), path("failed")).is_err());
+    assert!(!dir.path().join("failed").exists());
+  }
+
+  #[test]
+  fn test_signature_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("source").to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    fs::write(&path, &data).unwrap();
+
+    let options = super::SignatureOptions::builder()
+      .max_chunks(10)
+      .build()
+      .unwrap();
+    let err = super::signature_data(&path, Some(options)).unwrap_err();
+    assert!(
+      err.reason.ends_with("the source has more than 10 chunks"),
+      "{}",
+      err.reason
+    );
+
+    #[cfg(unix)]
+    {
+      let err = super::signature_data("/dev/zero", None).unwrap_err();
+      assert_eq!(
+        err.reason,
+        "/dev/zero isn't a regular file and may never end, set max_file_bytes to read it"
+      );
+      let options = super::SignatureOptions::builder()
+        .max_file_bytes(100000)
+        .build()
+        .unwrap();
+      let err = super::signature_data("/dev/zero", Some(options)).unwrap_err();
+      assert!(
+        err
+          .reason
+          .ends_with("the source is larger than 100000 bytes"),
+        "{}",
+        err.reason
+      );
+    }
+  }
+
+  #[test]
+  fn test_apply_from_chunk_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+    let source: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(150000..150100, (0..3000).map(|_| rand::random::<u8>()));
+    fs::write(path("source"), &source).unwrap();
+    fs::write(path("target"), &target).unwrap();
+
+    super::export_chunks_data(&path("source"), &path("store"), None).unwrap();
+    let diff_options = || super::DiffOptions {
+      chunk_copies: Some(true),
+      ..Default::default()
+    };
+    super::diff(
+      path("source"),
+      path("target"),
+      path("diff"),
+      None,
+      Some(diff_options()),
+    )
+    .unwrap();
+    fs::remove_file(path("source")).unwrap();
+
+    super::apply_from_chunk_store(path("diff"), path("store"), path("result"), None).unwrap();
+    assert!(fs::read(path("result")).unwrap() == target);
+
+    // Weak matches copy parts of chunks.
+    fs::write(path("source"), &source).unwrap();
+    let err = super::diff(
+      path("source"),
+      path("target"),
+      path("weak.diff"),
+      None,
+      Some(super::DiffOptions {
+        weak_block_size: Some(64),
+        ..diff_options()
+      }),
+    )
+    .unwrap_err();
+    assert!(
+      err.reason.contains("doesn't cover whole chunks"),
+      "{}",
+      err.reason
+    );
+  }
+
+  #[test]
+  fn test_diff_memory_estimate() {
+    let dir = tempfile::tempdir().unwrap();
+    let estimate = |chunks: usize| {
+      let path = dir.path().join("source").to_str().unwrap().to_string();
+      fs::write(&path, vec![1u8; chunks * 4096]).unwrap();
+      let options = super::SignatureOptions::builder()
+        .fixed_block_size(4096)
+        .build()
+        .unwrap();
+      let signature = super::signature_data(&path, Some(options)).unwrap();
+      super::memory_estimate_data(&signature).unwrap()
+    };
+
+    let estimate_70 = estimate(70);
+    assert!(estimate_70 > 70 * 32);
+    assert_eq!(estimate(140), 2 * estimate_70);
+    assert_eq!(estimate(700), 10 * estimate_70);
+    assert_eq!(estimate(0), 0);
+
+    assert!(super::memory_estimate_data(&[1, 2, 3]).is_err());
+  }
+
+  #[test]
+  fn test_diff_buffers() {
+    // Large enough that the chunks around the edits, up to the maximum size, stay a small part.
+    let source: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    let novel: Vec<u8> = (0..3000).map(|_| rand::random::<u8>()).collect();
+    let target = [&source[250000..], &novel, &source[..250000]].concat();
+
+    let diff = super::diff_data(&source, &target, None, None).unwrap();
+    assert!(diff.len() < target.len() / 2);
+    assert_eq!(super::apply_data(&diff, &source).unwrap(), target);
+
+    assert!(super::apply_data(&[1, 2, 3], &[]).is_err());
+  }
+
+  #[test]
+  fn test_combine_range_signatures() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("source").to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    fs::write(&path, &data).unwrap();
+
+    let ranges = [(0, 400000), (300000, 800000), (700000, 1000000)];
+    let signatures = ranges
+      .iter()
+      .map(|&(offset, end)| {
+        let signature = super::signature_of_range_data(&path, offset, end - offset, None).unwrap();
+        (offset, signature)
+      })
+      .collect::<Vec<_>>();
+    let combined = super::combine_range_signatures_data(
+      signatures
+        .iter()
+        .map(|(offset, signature)| (*offset, &signature[..]))
+        .collect(),
+    )
+    .unwrap();
+    assert_eq!(combined, super::signature_data(&path, None).unwrap());
+
+    let err = super::combine_range_signatures_data(Vec::new()).unwrap_err();
+    assert_eq!(err.reason, "there are no range signatures to combine");
+  }
+
+  #[test]
+  fn test_update_signature() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("source").to_str().unwrap().to_string();
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    fs::write(&path, &data).unwrap();
+    let signature = super::signature_data(&path, None).unwrap();
+
+    let mut edited = data.clone();
+    edited.splice(50000..50100, [7u8; 300]);
+    fs::write(&path, &edited).unwrap();
+    let edit = |offset, old_length, new_length| super::SignatureEdit {
+      offset,
+      old_length,
+      new_length,
+    };
+    let updated = super::update_signature_data(&signature, &path, &[edit(50000, 100, 300)]);
+    assert_eq!(
+      updated.unwrap(),
+      super::signature_data(&path, None).unwrap()
+    );
+
+    let err = super::update_signature_data(&signature, &path, &[edit(-1, 100, 300)]).unwrap_err();
+    assert_eq!(err.reason, "Invalid edit offset -1");
+    let err =
+      super::update_signature_data(&signature, &path, &[edit(50000, 100, -300)]).unwrap_err();
+    assert_eq!(err.reason, "Invalid edit length -300");
+  }
+}
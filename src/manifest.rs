@@ -0,0 +1,172 @@
+//! Splitting a diff into a manifest of its operations and a blob of its inserted data, so the
+//! small manifests and the bulky payloads can be stored, cached and sent separately. Manifests
+//! are plans, see `write_plan`, whose inserts are at their offsets in the payloads instead of the
+//! target. Payloads are the inserted data of all inserts, concatenated in order.
+
+use crate::apply::{self, Preallocate};
+use crate::diff::{
+  load_plan, serialize_copy, serialize_header_with_flags, serialize_insert, write_plan, Operation,
+};
+use crate::ApplyReport;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+#[derive(Debug)]
+struct Pooled;
+
+impl fmt::Display for Pooled {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "diffs copying from an insert pool can't be split")
+  }
+}
+
+impl Error for Pooled {}
+
+#[derive(Debug)]
+struct TargetTooLarge;
+
+impl fmt::Display for TargetTooLarge {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "the operations of the manifest write more than 2^64 bytes"
+    )
+  }
+}
+
+impl Error for TargetTooLarge {}
+
+/// Writes the operations of `diff` to `manifest` and their inserted data to `payloads`.
+/// `dictionary` is only used when the diff is compressed with one. Insert checksums and the HMAC
+/// tag aren't kept, the manifest and the payloads are checked by whatever stores them.
+pub(crate) fn split_diff<D, M, P>(
+  diff: &mut D,
+  manifest: &mut M,
+  payloads: &mut P,
+  dictionary: Option<&[u8]>,
+) -> Result<(), Box<dyn Error>>
+where
+  D: Read + Seek,
+  M: Write,
+  P: Write,
+{
+  let (_, mut ops, mut data) = apply::read_operations(diff, dictionary)?;
+
+  let mut written = 0;
+  for (op, offset, size) in ops.iter_mut() {
+    match op {
+      Operation::CopyPool => return Err(Box::new(Pooled)),
+      Operation::Insert => {
+        data.seek(SeekFrom::Start(*offset))?;
+        io::copy(&mut (&mut data).take(*size), payloads)?;
+        *offset = written;
+        written += *size;
+      }
+      Operation::Copy | Operation::CopyOutput => {}
+    }
+  }
+  write_plan(&ops, manifest)?;
+  manifest.flush()?;
+  payloads.flush()?;
+
+  Ok(())
+}
+
+/// Applies the `manifest` from `split_diff` with its `payloads` to the `source` like `apply`
+/// applies the diff it was split from.
+pub(crate) fn apply_from_manifest<P, S, W>(
+  manifest: &[u8],
+  payloads: &mut P,
+  source: &mut S,
+  dest: &mut W,
+) -> Result<ApplyReport, Box<dyn Error>>
+where
+  P: Read + Seek,
+  S: Read + Seek,
+  W: Read + Write + Seek + Preallocate,
+{
+  let ops = load_plan(manifest)?;
+  let target_size = ops
+    .iter()
+    .try_fold(0u64, |total, &(_, _, size)| total.checked_add(size))
+    .ok_or(TargetTooLarge)?;
+
+  // Recombined into a diff, so it's checked and applied like any other.
+  let mut diff = BufWriter::new(tempfile::tempfile()?);
+  serialize_header_with_flags(target_size, 0, &mut diff)?;
+  let mut buf = Vec::new();
+  for (op, offset, size) in ops {
+    match op {
+      Operation::Insert => serialize_insert(offset, size, payloads, &mut diff, &mut buf)?,
+      op => serialize_copy(op, offset, size, &mut diff)?,
+    }
+  }
+  let mut diff = diff.into_inner().map_err(|err| err.into_error())?;
+
+  apply::apply(&mut diff, source, dest)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::apply;
+  use crate::diff::write_diff_between;
+  use crate::signature::Signature;
+  use crate::DiffOptions;
+
+  use std::io::Cursor;
+
+  #[test]
+  fn test_split_diff() {
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let source = random(200000);
+    let novel = random(5000);
+    let target = [&source[100000..], &novel, &source[..100000], &novel].concat();
+    let sig1 = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let sig2 = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+
+    for compress in [false, true] {
+      let options = DiffOptions {
+        compress: Some(compress),
+        insert_checksums: Some(true),
+        ..Default::default()
+      };
+      let mut diff = Vec::new();
+      write_diff_between(&sig1, &sig2, &mut Cursor::new(&target), &mut diff, &options).unwrap();
+
+      let (mut manifest, mut payloads) = (Vec::new(), Vec::new());
+      super::split_diff(&mut Cursor::new(&diff), &mut manifest, &mut payloads, None).unwrap();
+      assert!(payloads.len() >= novel.len());
+      assert!(payloads.len() < target.len() / 2);
+
+      let mut expected = Cursor::new(Vec::new());
+      apply::apply(
+        &mut Cursor::new(&diff),
+        &mut Cursor::new(&source),
+        &mut expected,
+      )
+      .unwrap();
+      let mut dest = Cursor::new(Vec::new());
+      super::apply_from_manifest(
+        &manifest,
+        &mut Cursor::new(&payloads),
+        &mut Cursor::new(&source),
+        &mut dest,
+      )
+      .unwrap();
+      assert_eq!(dest.into_inner(), expected.into_inner());
+
+      // Payloads missing their end can't be recombined.
+      payloads.pop();
+      let mut dest = Cursor::new(Vec::new());
+      assert!(super::apply_from_manifest(
+        &manifest,
+        &mut Cursor::new(&payloads),
+        &mut Cursor::new(&source),
+        &mut dest,
+      )
+      .is_err());
+    }
+  }
+}
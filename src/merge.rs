@@ -0,0 +1,437 @@
+//! Merging two diffs made from the same base, e.g. for concurrent edits, into one diff applying
+//! both of them. Every diff is turned into hunks, the regions of the base it replaces, which lie
+//! between the copies of the base keeping their order. The hunks of the two diffs may not
+//! overlap, the merged diff then copies the base between them and takes every hunk from its diff.
+
+use crate::apply;
+use crate::diff::{
+  serialize_copy, serialize_header_with_flags, serialize_insert, DiffOps, Operation,
+};
+use crate::signature::Signature;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Seek, Write};
+use std::ops::Range;
+
+#[derive(Debug)]
+struct Conflict {
+  start: u64,
+  end: u64,
+}
+
+impl fmt::Display for Conflict {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "both diffs change the base at bytes {} to {}",
+      self.start, self.end
+    )
+  }
+}
+
+impl Error for Conflict {}
+
+#[derive(Debug)]
+struct NotFromBase {
+  offset: u64,
+  size: u64,
+  base_size: u64,
+}
+
+impl fmt::Display for NotFromBase {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "diff copies {} bytes at offset {} past the end of the base ({} bytes), it wasn't made \
+       from it",
+      self.size, self.offset, self.base_size
+    )
+  }
+}
+
+impl Error for NotFromBase {}
+
+#[derive(Debug)]
+struct Pooled;
+
+impl fmt::Display for Pooled {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "diffs copying from an insert pool can't be merged")
+  }
+}
+
+impl Error for Pooled {}
+
+/// Merges the diffs `a` and `b` of the file with the signature `base` and writes the diff applying
+/// both to `dest`. Diffs don't record their source, so only that their copies fit in `base` is
+/// checked. Changes of the same region conflict even when they're equal, as do changes starting
+/// at the same offset, since their order is ambiguous.
+pub(crate) fn merge_diffs<R, W>(
+  base: &Signature,
+  a: &mut R,
+  b: &mut R,
+  dest: &mut W,
+) -> Result<(), Box<dyn Error>>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  let base_size = base.file_size();
+  let (_, a_ops, mut a_data) = apply::read_operations(a, None)?;
+  let (_, b_ops, mut b_data) = apply::read_operations(b, None)?;
+  let ops = [resolve(a_ops, base_size)?, resolve(b_ops, base_size)?];
+
+  let mut hunks: Vec<(Hunk, usize)> = ops
+    .iter()
+    .enumerate()
+    .flat_map(|(diff, ops)| {
+      hunks(ops, base_size)
+        .into_iter()
+        .map(move |hunk| (hunk, diff))
+    })
+    .collect();
+  hunks.sort_by_key(|(hunk, _)| (hunk.start, hunk.end));
+
+  // Hunks of the same diff never overlap, so any overlap is between the two diffs.
+  let mut covered: Option<(u64, u64)> = None;
+  for (hunk, _) in hunks.iter() {
+    if let Some((start, end)) = covered {
+      if hunk.start < end || hunk.start == start {
+        return Err(Box::new(Conflict {
+          start: hunk.start,
+          end: end.max(hunk.end),
+        }));
+      }
+    }
+    covered = Some((hunk.start, hunk.end));
+  }
+
+  // The base between the hunks is kept by both diffs.
+  let mut merged: Vec<(Operation, u64, u64, usize)> = Vec::new();
+  let mut base_pos = 0;
+  for (hunk, diff) in hunks {
+    if hunk.start > base_pos {
+      merged.push((Operation::Copy, base_pos, hunk.start - base_pos, diff));
+    }
+    for &(op, offset, size) in &ops[diff][hunk.ops] {
+      merged.push((op, offset, size, diff));
+    }
+    base_pos = hunk.end;
+  }
+  if base_size > base_pos {
+    merged.push((Operation::Copy, base_pos, base_size - base_pos, 0));
+  }
+
+  let target_size = merged.iter().map(|&(_, _, size, _)| size).sum();
+  serialize_header_with_flags(target_size, 0, dest)?;
+  let mut buf = Vec::new();
+  let mut copy: Option<(u64, u64)> = None;
+  // The data offsets, sizes and merged positions of the inserts of both diffs, in order. Resolved
+  // copies of the output insert data inserted earlier, which is copied from the output again.
+  let mut inserted: [Vec<(u64, u64, u64)>; 2] = [Vec::new(), Vec::new()];
+  let mut pos = 0;
+  for (op, offset, size, diff) in merged {
+    pos += size;
+    if op == Operation::Copy {
+      copy = match copy {
+        Some((start, len)) if start + len == offset => Some((start, len + size)),
+        Some((start, len)) => {
+          serialize_copy(Operation::Copy, start, len, dest)?;
+          Some((offset, size))
+        }
+        None => Some((offset, size)),
+      };
+      continue;
+    }
+
+    if let Some((start, len)) = copy.take() {
+      serialize_copy(Operation::Copy, start, len, dest)?;
+    }
+    let inserted = &mut inserted[diff];
+    let index = inserted.partition_point(|&(data, _, _)| data <= offset);
+    if let Some(&(data, len, at)) = index.checked_sub(1).map(|index| &inserted[index]) {
+      if offset + size <= data + len {
+        serialize_copy(Operation::CopyOutput, at + offset - data, size, dest)?;
+        continue;
+      }
+    }
+    match diff {
+      0 => serialize_insert(offset, size, &mut a_data, dest, &mut buf)?,
+      _ => serialize_insert(offset, size, &mut b_data, dest, &mut buf)?,
+    }
+    inserted.push((offset, size, pos - size));
+  }
+  if let Some((start, len)) = copy {
+    serialize_copy(Operation::Copy, start, len, dest)?;
+  }
+  dest.flush()?;
+
+  Ok(())
+}
+
+/// Replaces the copies of the output with the copies and inserts which wrote the data they copy,
+/// so every operation refers to the base or to the data of the diff.
+fn resolve(ops: DiffOps, base_size: u64) -> Result<DiffOps, Box<dyn Error>> {
+  // The target offsets of the resolved operations.
+  let mut starts = Vec::with_capacity(ops.len());
+  let mut resolved: DiffOps = Vec::with_capacity(ops.len());
+  let mut written = 0;
+
+  for (op, offset, size) in ops {
+    match op {
+      Operation::Copy if offset.checked_add(size).is_none_or(|end| end > base_size) => {
+        return Err(Box::new(NotFromBase {
+          offset,
+          size,
+          base_size,
+        }));
+      }
+      Operation::Copy | Operation::Insert => {
+        starts.push(written);
+        resolved.push((op, offset, size));
+      }
+      // The copied data was written already, `read_operations` checks it.
+      Operation::CopyOutput => {
+        let end = offset + size;
+        let mut index = starts.partition_point(|&start| start <= offset) - 1;
+        let mut pos = offset;
+        while pos < end {
+          let (from_op, from, len) = resolved[index];
+          let skip = pos - starts[index];
+          let take = (len - skip).min(end - pos);
+          starts.push(written + pos - offset);
+          resolved.push((from_op, from + skip, take));
+          pos += take;
+          index += 1;
+        }
+      }
+      Operation::CopyPool => return Err(Box::new(Pooled)),
+    }
+    written += size;
+  }
+
+  Ok(resolved)
+}
+
+/// A region of the base replaced by the operations `ops` of a diff.
+#[derive(Debug, PartialEq)]
+struct Hunk {
+  start: u64,
+  end: u64,
+  ops: Range<usize>,
+}
+
+/// Returns the hunks of the resolved `ops` in order, the regions of the base which aren't copied by
+/// `kept_copies`.
+fn hunks(ops: &DiffOps, base_size: u64) -> Vec<Hunk> {
+  let mut hunks = Vec::new();
+  let mut base_pos = 0;
+  let mut next = 0;
+
+  for index in kept_copies(ops).into_iter().chain([ops.len()]) {
+    let start = ops.get(index).map_or(base_size, |&(_, offset, _)| offset);
+    if start > base_pos || index > next {
+      hunks.push(Hunk {
+        start: base_pos,
+        end: start,
+        ops: next..index,
+      });
+    }
+    if let Some(&(_, offset, size)) = ops.get(index) {
+      base_pos = offset + size;
+      next = index + 1;
+    }
+  }
+
+  hunks
+}
+
+/// Returns the indices of the copies in `ops` which keep the order of the base with the most
+/// bytes, the heaviest increasing subsequence. A moved region is copied out of order, so it's a
+/// change instead of making everything before or after it one.
+fn kept_copies(ops: &DiffOps) -> Vec<usize> {
+  let mut ends: Vec<u64> = ops
+    .iter()
+    .filter(|&&(op, _, _)| op == Operation::Copy)
+    .map(|&(_, offset, size)| offset + size)
+    .collect();
+  ends.sort_unstable();
+  ends.dedup();
+
+  // A Fenwick tree of the heaviest chains by the index of their end, with their last copies.
+  let mut tree: Vec<(u64, Option<usize>)> = vec![(0, None); ends.len() + 1];
+  let mut previous = vec![None; ops.len()];
+  let mut best: (u64, Option<usize>) = (0, None);
+
+  for (index, &(op, offset, size)) in ops.iter().enumerate() {
+    if op != Operation::Copy {
+      continue;
+    }
+
+    let mut chain = (0, None);
+    let mut i = ends.partition_point(|&end| end <= offset);
+    while i > 0 {
+      chain = chain.max(tree[i]);
+      i &= i - 1;
+    }
+    previous[index] = chain.1;
+    let weight = (chain.0 + size, Some(index));
+    best = best.max(weight);
+
+    let mut i = ends.partition_point(|&end| end < offset + size) + 1;
+    while i < tree.len() {
+      tree[i] = tree[i].max(weight);
+      i += i & i.wrapping_neg();
+    }
+  }
+
+  let mut kept = Vec::new();
+  let mut index = best.1;
+  while let Some(i) = index {
+    kept.push(i);
+    index = previous[i];
+  }
+  kept.reverse();
+
+  kept
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::apply;
+  use crate::diff::{write_diff_between, Operation};
+  use crate::signature::Signature;
+  use crate::DiffOptions;
+
+  use std::io::Cursor;
+
+  fn diff(source: &Signature, target: &[u8]) -> Vec<u8> {
+    let signature = Signature::calculate(&mut Cursor::new(target), 1024, 4096, 16384).unwrap();
+    let mut diff = Vec::new();
+    write_diff_between(
+      source,
+      &signature,
+      &mut Cursor::new(target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+    diff
+  }
+
+  #[test]
+  fn test_merge_diffs() {
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let base = random(200000);
+    let base_sig = Signature::calculate(&mut Cursor::new(&base), 1024, 4096, 16384).unwrap();
+    let inserted = random(30000);
+
+    // A replaces a region near the start with data repeated twice, copied from its output.
+    let a_edit = [&inserted[..], &inserted[..]].concat();
+    let a = [&base[..20000], &a_edit[..], &base[25000..]].concat();
+    // B replaces a region near the end and appends.
+    let b_edit = random(3000);
+    let b_tail = random(10000);
+    let b = [&base[..150000], &b_edit[..], &base[150500..], &b_tail[..]].concat();
+    let expected = [
+      &base[..20000],
+      &a_edit[..],
+      &base[25000..150000],
+      &b_edit[..],
+      &base[150500..],
+      &b_tail[..],
+    ]
+    .concat();
+
+    let diff_a = diff(&base_sig, &a);
+    let diff_b = diff(&base_sig, &b);
+    let (_, a_ops, _) = apply::read_operations(&mut Cursor::new(&diff_a), None).unwrap();
+    assert!(a_ops.iter().any(|&(op, _, _)| op == Operation::CopyOutput));
+
+    for (first, second) in [(&diff_a, &diff_b), (&diff_b, &diff_a)] {
+      let mut merged = Vec::new();
+      super::merge_diffs(
+        &base_sig,
+        &mut Cursor::new(first),
+        &mut Cursor::new(second),
+        &mut merged,
+      )
+      .unwrap();
+
+      let mut dest = Cursor::new(Vec::new());
+      apply::apply(
+        &mut Cursor::new(&merged),
+        &mut Cursor::new(&base),
+        &mut dest,
+      )
+      .unwrap();
+      assert_eq!(dest.into_inner(), expected);
+      // The data inserted twice by A is still copied from the output.
+      assert!(merged.len() <= diff_a.len() + diff_b.len());
+    }
+  }
+
+  #[test]
+  fn test_merge_conflicting_diffs() {
+    let random = |len| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let base = random(100000);
+    let base_sig = Signature::calculate(&mut Cursor::new(&base), 1024, 4096, 16384).unwrap();
+    let a = [&base[..50000], &random(100)[..], &base[50100..]].concat();
+    let b = [&base[..50050], &random(100)[..], &base[50150..]].concat();
+
+    let mut merged = Vec::new();
+    let err = super::merge_diffs(
+      &base_sig,
+      &mut Cursor::new(diff(&base_sig, &a)),
+      &mut Cursor::new(diff(&base_sig, &b)),
+      &mut merged,
+    )
+    .unwrap_err();
+    assert!(err.to_string().starts_with("both diffs change the base at"));
+
+    // A diff of a larger file copies past the end of the base.
+    let other = random(300000);
+    let other_sig = Signature::calculate(&mut Cursor::new(&other), 1024, 4096, 16384).unwrap();
+    let err = super::merge_diffs(
+      &base_sig,
+      &mut Cursor::new(diff(&other_sig, &other)),
+      &mut Cursor::new(diff(&base_sig, &a)),
+      &mut merged,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("it wasn't made from it"));
+  }
+
+  #[test]
+  fn test_kept_copies() {
+    // The moved copy at the start is out of order with the heavier rest.
+    let ops = vec![
+      (Operation::Copy, 900, 100),
+      (Operation::Copy, 0, 400),
+      (Operation::Insert, 0, 50),
+      (Operation::Copy, 400, 500),
+    ];
+    assert_eq!(super::kept_copies(&ops), vec![1, 3]);
+    assert_eq!(
+      super::hunks(&ops, 1000),
+      vec![
+        super::Hunk {
+          start: 0,
+          end: 0,
+          ops: 0..1,
+        },
+        super::Hunk {
+          start: 400,
+          end: 400,
+          ops: 2..3,
+        },
+        super::Hunk {
+          start: 900,
+          end: 1000,
+          ops: 4..4,
+        },
+      ]
+    );
+  }
+}
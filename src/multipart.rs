@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub(crate) struct MalformedMultipart;
+
+impl fmt::Display for MalformedMultipart {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "malformed multipart/byteranges response")
+  }
+}
+
+impl Error for MalformedMultipart {}
+
+/// Returns the boundary of a `multipart/byteranges` content type.
+pub(crate) fn boundary(content_type: &str) -> Option<&str> {
+  let (mime, params) = content_type.split_once(';')?;
+  if !mime.trim().eq_ignore_ascii_case("multipart/byteranges") {
+    return None;
+  }
+
+  params.split(';').find_map(|param| {
+    let (name, value) = param.split_once('=')?;
+    name
+      .trim()
+      .eq_ignore_ascii_case("boundary")
+      .then(|| value.trim().trim_matches('"'))
+  })
+}
+
+/// Splits a `multipart/byteranges` body into the parts with the offsets of their data.
+///
+/// The data of a part is taken by the length from its `Content-Range` instead of looking for the
+/// next boundary, which may appear inside binary data.
+pub(crate) fn parts<'a>(
+  body: &'a [u8],
+  boundary: &str,
+) -> Result<Vec<(u64, &'a [u8])>, MalformedMultipart> {
+  let delimiter = format!("--{}", boundary);
+  let mut parts = Vec::new();
+  let mut rest = body;
+
+  loop {
+    let start = find(rest, delimiter.as_bytes()).ok_or(MalformedMultipart)?;
+    rest = &rest[start + delimiter.len()..];
+    if rest.starts_with(b"--") {
+      return Ok(parts);
+    }
+    rest = rest.strip_prefix(b"\r\n").ok_or(MalformedMultipart)?;
+
+    let mut range = None;
+    loop {
+      let end = find(rest, b"\r\n").ok_or(MalformedMultipart)?;
+      let line = std::str::from_utf8(&rest[..end]).map_err(|_| MalformedMultipart)?;
+      rest = &rest[end + 2..];
+      if line.is_empty() {
+        break;
+      }
+      if let Some((name, value)) = line.split_once(':') {
+        if name.trim().eq_ignore_ascii_case("content-range") {
+          range = parse_content_range(value);
+        }
+      }
+    }
+
+    let (start, end) = range.ok_or(MalformedMultipart)?;
+    let len = end
+      .checked_sub(start)
+      .and_then(|len| usize::try_from(len).ok()?.checked_add(1))
+      .ok_or(MalformedMultipart)?;
+    if rest.len() < len {
+      return Err(MalformedMultipart);
+    }
+    parts.push((start, &rest[..len]));
+    rest = &rest[len..];
+  }
+}
+
+/// Parses the first and the last offsets out of a `bytes <start>-<end>/<size>` content range.
+pub(crate) fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+  let (range, _) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+  let (start, end) = range.split_once('-')?;
+  Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack
+    .windows(needle.len())
+    .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_parts() {
+    let body = b"\r\n--XYZ\r\nContent-Type: application/octet-stream\r\n\
+                 Content-Range: bytes 2-5/10\r\n\r\n--XY\r\n\
+                 --XYZ\r\nContent-Range: bytes 8-9/10\r\n\r\nab\r\n--XYZ--\r\n";
+
+    assert_eq!(
+      super::boundary("multipart/byteranges; boundary=\"XYZ\""),
+      Some("XYZ")
+    );
+    assert_eq!(super::boundary("application/octet-stream"), None);
+    assert_eq!(
+      super::parts(body, "XYZ").unwrap(),
+      vec![(2, &b"--XY"[..]), (8, &b"ab"[..])]
+    );
+    assert!(super::parts(&body[..60], "XYZ").is_err());
+  }
+}
@@ -0,0 +1,81 @@
+use std::sync::{Condvar, Mutex};
+
+/// A limit of the files open at once shared by all threads of a batch, so batches of many files
+/// don't run out of file descriptors.
+pub(crate) struct OpenFileLimit {
+  max: usize,
+  open: Mutex<usize>,
+  closed: Condvar,
+}
+
+/// Files counted as open until dropped.
+pub(crate) struct OpenFiles<'a> {
+  limit: &'a OpenFileLimit,
+  count: usize,
+}
+
+impl OpenFileLimit {
+  pub(crate) fn new(max: usize) -> Self {
+    Self {
+      max: max.max(1),
+      open: Mutex::new(0),
+      closed: Condvar::new(),
+    }
+  }
+
+  /// Waits until `count` more files may be open. Counts above the limit are capped to it, so they
+  /// wait for all other files to be closed instead of forever.
+  pub(crate) fn acquire(&self, count: usize) -> OpenFiles<'_> {
+    let count = count.min(self.max);
+    let mut open = self.open.lock().unwrap();
+    while *open + count > self.max {
+      open = self.closed.wait(open).unwrap();
+    }
+    *open += count;
+
+    OpenFiles { limit: self, count }
+  }
+}
+
+impl Drop for OpenFiles<'_> {
+  fn drop(&mut self) {
+    *self.limit.open.lock().unwrap() -= self.count;
+    self.limit.closed.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::OpenFileLimit;
+
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn test_open_file_limit() {
+    let limit = OpenFileLimit::new(3);
+    let open = AtomicUsize::new(0);
+    let most_open = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+      for i in 0..16 {
+        let (limit, open, most_open) = (&limit, &open, &most_open);
+        scope.spawn(move || {
+          let count = i % 2 + 1;
+          let _files = limit.acquire(count);
+          let now_open = open.fetch_add(count, Ordering::SeqCst) + count;
+          most_open.fetch_max(now_open, Ordering::SeqCst);
+          thread::sleep(Duration::from_millis(5));
+          open.fetch_sub(count, Ordering::SeqCst);
+        });
+      }
+    });
+
+    let most_open = most_open.load(Ordering::SeqCst);
+    assert!((2..=3).contains(&most_open));
+    assert_eq!(*limit.open.lock().unwrap(), 0);
+    // More files than the limit wait for the others instead of forever.
+    drop(limit.acquire(5));
+  }
+}
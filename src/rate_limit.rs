@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket shared by all download tasks of a pull.
+///
+/// The bucket holds up to one second worth of bytes. Consumers may take more tokens than
+/// available, going into debt, and then sleep until the debt is paid off.
+pub(crate) struct RateLimiter {
+  bytes_per_sec: f64,
+  bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+impl RateLimiter {
+  pub(crate) fn new(bytes_per_sec: u64) -> Self {
+    let bytes_per_sec = bytes_per_sec as f64;
+
+    Self {
+      bytes_per_sec,
+      bucket: Mutex::new(Bucket {
+        tokens: bytes_per_sec,
+        updated_at: Instant::now(),
+      }),
+    }
+  }
+
+  /// Takes `bytes` tokens from the bucket, waiting if the bucket is empty.
+  pub(crate) async fn acquire(&self, bytes: usize) {
+    let wait = {
+      let mut bucket = self.bucket.lock().unwrap();
+      let now = Instant::now();
+      let refill = now.duration_since(bucket.updated_at).as_secs_f64() * self.bytes_per_sec;
+      bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec) - bytes as f64;
+      bucket.updated_at = now;
+
+      if bucket.tokens < 0.0 {
+        Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+      } else {
+        Duration::ZERO
+      }
+    };
+
+    if !wait.is_zero() {
+      napi::tokio::time::sleep(wait).await;
+    }
+  }
+}
@@ -0,0 +1,96 @@
+//! Copy-on-write clones of files, with `FICLONE` on Linux filesystems such as btrfs and XFS and
+//! with `clonefile` on APFS. A clone shares all blocks with its source until either is written.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+#[cfg(target_os = "macos")]
+use std::ffi::CString;
+#[cfg(target_os = "macos")]
+use std::os::unix::ffi::OsStrExt;
+
+/// Clones `from` to `to`, replacing it, and returns `to` open for reading and writing.
+#[cfg(target_os = "linux")]
+pub(crate) fn clone_file(from: &Path, to: &Path) -> Result<File, io::Error> {
+  let source = File::open(from)?;
+  let dest = File::options()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .open(to)?;
+
+  if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } < 0 {
+    let err = io::Error::last_os_error();
+    let _ = fs::remove_file(to);
+    return Err(err);
+  }
+
+  Ok(dest)
+}
+
+/// Clones `from` to `to`, replacing it, and returns `to` open for reading and writing.
+#[cfg(target_os = "macos")]
+pub(crate) fn clone_file(from: &Path, to: &Path) -> Result<File, io::Error> {
+  // `clonefile` doesn't replace existing files.
+  match fs::remove_file(to) {
+    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+    _ => {}
+  }
+  let c_from = CString::new(from.as_os_str().as_bytes())?;
+  let c_to = CString::new(to.as_os_str().as_bytes())?;
+  if unsafe { libc::clonefile(c_from.as_ptr(), c_to.as_ptr(), 0) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  File::options().read(true).write(true).open(to)
+}
+
+/// Clones `from` to `to`, replacing it, and returns `to` open for reading and writing.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn clone_file(_from: &Path, _to: &Path) -> Result<File, io::Error> {
+  Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Whether the clone failed only because the filesystem can't clone, or `from` and `to` are on
+/// different filesystems, so the file can be copied instead.
+pub(crate) fn is_unsupported(err: &io::Error) -> bool {
+  #[cfg(any(target_os = "linux", target_os = "macos"))]
+  if let Some(code) = err.raw_os_error() {
+    return [
+      libc::EOPNOTSUPP,
+      libc::ENOTSUP,
+      libc::EXDEV,
+      libc::EINVAL,
+      libc::ENOTTY,
+    ]
+    .contains(&code);
+  }
+
+  err.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  #[test]
+  fn test_clone_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    fs::write(&from, &data).unwrap();
+    fs::write(&to, b"replaced").unwrap();
+
+    // Filesystems of test machines rarely support clones.
+    match super::clone_file(&from, &to) {
+      Ok(_) => assert_eq!(fs::read(&to).unwrap(), data),
+      Err(err) => assert!(super::is_unsupported(&err), "{}", err),
+    }
+    assert_eq!(fs::read(&from).unwrap(), data);
+  }
+}
@@ -0,0 +1,84 @@
+use crate::PullOptions;
+
+use rand::Rng;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INITIAL_DELAY_MS: u32 = 100;
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+const DEFAULT_MAX_DELAY_MS: u32 = 10_000;
+
+#[derive(Debug)]
+pub(crate) struct RetryBudgetExceeded {
+  budget: Duration,
+}
+
+impl fmt::Display for RetryBudgetExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "retries exceeded the budget of {} ms",
+      self.budget.as_millis()
+    )
+  }
+}
+
+impl Error for RetryBudgetExceeded {}
+
+/// Exponential backoff with full jitter shared by all download tasks of a pull.
+///
+/// A request failing for the `n`th time is retried after a random delay between zero and
+/// `initial_delay * multiplier^(n - 1)`, capped by `max_delay`. The randomness keeps concurrent
+/// tasks from retrying all at once. No retry may end after the budget counted from the start of
+/// the pull.
+pub(crate) struct Backoff {
+  initial_delay: Duration,
+  multiplier: f64,
+  max_delay: Duration,
+  max_attempts: u32,
+  budget: Option<(Duration, Instant)>,
+}
+
+impl Backoff {
+  pub(crate) fn new(options: &PullOptions) -> Self {
+    let millis = |ms: u32| Duration::from_millis(ms.into());
+
+    Self {
+      initial_delay: millis(
+        options
+          .retry_initial_delay_ms
+          .unwrap_or(DEFAULT_INITIAL_DELAY_MS),
+      ),
+      multiplier: options.retry_multiplier.unwrap_or(DEFAULT_MULTIPLIER),
+      max_delay: millis(options.retry_max_delay_ms.unwrap_or(DEFAULT_MAX_DELAY_MS)),
+      max_attempts: options.max_attempts.unwrap_or(1),
+      budget: options
+        .retry_budget_ms
+        .map(|budget| (millis(budget), Instant::now())),
+    }
+  }
+
+  /// Returns the delay before retrying a request which failed `attempts` times, or `None` when
+  /// it has no attempts left.
+  pub(crate) fn delay(&self, attempts: u32) -> Result<Option<Duration>, RetryBudgetExceeded> {
+    if attempts >= self.max_attempts {
+      return Ok(None);
+    }
+
+    let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let cap = self
+      .initial_delay
+      .mul_f64(self.multiplier.max(0.0).powi(exponent).min(u32::MAX.into()))
+      .min(self.max_delay);
+    let delay = cap.mul_f64(rand::thread_rng().gen_range(0.0..=1.0));
+
+    if let Some((budget, started_at)) = self.budget {
+      if started_at.elapsed() + delay > budget {
+        return Err(RetryBudgetExceeded { budget });
+      }
+    }
+
+    Ok(Some(delay))
+  }
+}
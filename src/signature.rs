@@ -1,28 +1,106 @@
+use crate::weak_match;
+use crate::{Bucket, ChunkSizeHistogram, HashAlgorithm, VerifyResult};
+
 use arrayref::array_ref;
-use fastcdc::v2020::StreamCDC;
-use std::io::{self, Read, Write};
+use fastcdc::v2020::{
+  Normalization, StreamCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX,
+  MINIMUM_MIN,
+};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
-pub const VERSION: u8 = 0;
+pub const VERSION: u8 = 7;
+/// The first version storing the hash algorithm in signatures, older ones always use Blake3.
+const HASH_ALGORITHM_VERSION: u8 = 3;
+/// The first version storing the normalization level in signatures, older ones always use
+/// `DEFAULT_NORMALIZATION`.
+const NORMALIZATION_VERSION: u8 = 4;
+/// The first version storing the number of bytes of every chunk hash, older ones store all 32.
+const HASH_BYTES_VERSION: u8 = 5;
+/// The first version storing the fixed block size and the weak checksums of chunks, older ones
+/// are always content-defined.
+const BLOCKING_VERSION: u8 = 6;
+/// The first version storing the offsets archive members start at after the chunks, older ones
+/// have no members.
+const MEMBERS_VERSION: u8 = 7;
 pub const DEFAULT_MIN_SIZE: u32 = 4096;
 pub const DEFAULT_AVG_SIZE: u32 = 16384;
 pub const DEFAULT_MAX_SIZE: u32 = 65535;
+/// The FastCDC normalization level, from 0 to 3, used by default.
+pub const DEFAULT_NORMALIZATION: u8 = 1;
+/// Chunk hashes are stored whole by default.
+pub const DEFAULT_HASH_BYTES: u8 = 32;
+pub const HISTOGRAM_BUCKETS: u32 = 16;
+pub const DEFAULT_MAX_CLAMPED_FRACTION: f64 = 0.5;
+/// The chunk count up to which a whole input is warned about as too coarsely chunked.
+const FEW_CHUNKS: usize = 2;
 
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Signature {
   pub version: u8,
   pub min_size: u32,
   pub avg_size: u32,
   pub max_size: u32,
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub hash_algorithm: HashAlgorithm,
+  #[cfg_attr(feature = "serde", serde(default = "default_normalization"))]
+  pub normalization: u8,
+  /// The number of leading bytes of the chunk hashes kept, the rest are zeroed.
+  #[cfg_attr(feature = "serde", serde(default = "default_hash_bytes"))]
+  pub hash_bytes: u8,
+  /// The size of every chunk but the last when the source was cut into fixed-size blocks instead
+  /// of with FastCDC, the chunk sizes are all equal to it then.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub fixed_block_size: Option<u32>,
+  /// The rsync rolling checksums of the chunks, in the same order, for protocols matching blocks
+  /// by them before comparing strong hashes.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub weak_checksums: Option<Vec<u32>>,
+  /// The offsets where members of an archive start, in ascending order. Chunks are cut at them,
+  /// so a changed member doesn't shift the chunks of the following ones.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub boundaries: Vec<u64>,
   pub chunks: Vec<Chunk>,
 }
 
+#[cfg(feature = "serde")]
+fn default_normalization() -> u8 {
+  DEFAULT_NORMALIZATION
+}
+
+#[cfg(feature = "serde")]
+fn default_hash_bytes() -> u8 {
+  DEFAULT_HASH_BYTES
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Chunk {
+  #[cfg_attr(feature = "serde", serde(with = "hex_hash"))]
   pub hash: blake3::Hash,
   pub offset: u64,
   pub length: usize,
 }
 
+/// Serializes hashes as hex strings. The binary `Signature::write` format stays the canonical
+/// on-disk form.
+#[cfg(feature = "serde")]
+mod hex_hash {
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(hash: &blake3::Hash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(hash.to_hex().as_str())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<blake3::Hash, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    blake3::Hash::from_hex(hex).map_err(serde::de::Error::custom)
+  }
+}
+
 impl PartialEq for Chunk {
   // Chunks are equal when they have similar data hashes. Blake3 strong hashing guarantees this.
   fn eq(&self, other: &Self) -> bool {
@@ -38,27 +116,630 @@ impl PartialOrd for Chunk {
   }
 }
 
+#[derive(Debug)]
+struct InvalidChunkSize {
+  parameter: &'static str,
+  value: u32,
+  min: u32,
+  max: u32,
+}
+
+impl fmt::Display for InvalidChunkSize {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} is {}, but must be between {} and {}",
+      self.parameter, self.value, self.min, self.max
+    )
+  }
+}
+
+impl Error for InvalidChunkSize {}
+
+#[derive(Debug)]
+struct UnorderedChunkSizes {
+  min_size: u32,
+  avg_size: u32,
+  max_size: u32,
+}
+
+impl fmt::Display for UnorderedChunkSizes {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "chunk sizes must satisfy min_size <= avg_size <= max_size, got {}, {} and {}",
+      self.min_size, self.avg_size, self.max_size
+    )
+  }
+}
+
+impl Error for UnorderedChunkSizes {}
+
+#[derive(Debug)]
+struct InvalidHashBytes(u8);
+
+impl fmt::Display for InvalidHashBytes {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "hash_bytes is {}, but must be 16, 24 or 32", self.0)
+  }
+}
+
+impl Error for InvalidHashBytes {}
+
+#[derive(Debug)]
+struct ChunkingMismatch {
+  chunked: u64,
+  read: u64,
+}
+
+impl fmt::Display for ChunkingMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "chunks cover {} bytes, but {} bytes were read",
+      self.chunked, self.read
+    )
+  }
+}
+
+impl Error for ChunkingMismatch {}
+
+#[derive(Debug)]
+struct TooLarge(u64);
+
+impl fmt::Display for TooLarge {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} doesn't fit in usize on this target", self.0)
+  }
+}
+
+impl Error for TooLarge {}
+
+#[derive(Debug)]
+struct UnknownHashAlgorithm(u8);
+
+impl fmt::Display for UnknownHashAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "unknown hash algorithm {}", self.0)
+  }
+}
+
+impl Error for UnknownHashAlgorithm {}
+
+#[derive(Debug)]
+struct TruncatedSignature {
+  numchunks: u64,
+  available: u64,
+}
+
+impl fmt::Display for TruncatedSignature {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signature is truncated, it has room for {} of its {} chunks",
+      self.available, self.numchunks
+    )
+  }
+}
+
+impl Error for TruncatedSignature {}
+
+#[derive(Debug)]
+struct ChunkPastEnd {
+  offset: u64,
+  length: u64,
+}
+
+impl fmt::Display for ChunkPastEnd {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "chunk of {} bytes at offset {} ends past the largest possible file",
+      self.length, self.offset
+    )
+  }
+}
+
+impl Error for ChunkPastEnd {}
+
+#[derive(Debug)]
+enum InvalidBoundary {
+  Unsorted(u64),
+  PastEnd { boundary: u64, size: u64 },
+}
+
+impl fmt::Display for InvalidBoundary {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      InvalidBoundary::Unsorted(boundary) => write!(
+        f,
+        "member boundaries must be in ascending order, {} isn't",
+        boundary
+      ),
+      InvalidBoundary::PastEnd { boundary, size } => write!(
+        f,
+        "member boundary {} is past the end of the source of {} bytes",
+        boundary, size
+      ),
+    }
+  }
+}
+
+impl Error for InvalidBoundary {}
+
+#[derive(Debug)]
+enum LimitExceeded {
+  Chunks(usize),
+  Bytes(u64),
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LimitExceeded::Chunks(max) => write!(f, "the source has more than {} chunks", max),
+      LimitExceeded::Bytes(max) => write!(f, "the source is larger than {} bytes", max),
+    }
+  }
+}
+
+impl Error for LimitExceeded {}
+
+#[derive(Debug)]
+struct UpdatedMembers;
+
+impl fmt::Display for UpdatedMembers {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signatures of archive members can't be updated, edits move the member boundaries"
+    )
+  }
+}
+
+impl Error for UpdatedMembers {}
+
+#[derive(Debug)]
+struct Shrunk {
+  signed: u64,
+  actual: u64,
+}
+
+impl fmt::Display for Shrunk {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "file of {} bytes is smaller than the {} bytes of its signature, it wasn't only appended to",
+      self.actual, self.signed
+    )
+  }
+}
+
+impl Error for Shrunk {}
+
+#[derive(Debug)]
+struct NotContiguous {
+  offset: u64,
+  expected: u64,
+}
+
+impl fmt::Display for NotContiguous {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signature chunks aren't contiguous, a chunk starts at offset {} instead of {}",
+      self.offset, self.expected
+    )
+  }
+}
+
+impl Error for NotContiguous {}
+
+#[derive(Debug)]
+enum Unconvertible {
+  UnknownVersion(u32),
+  Lossy { version: u8, lost: &'static str },
+}
+
+impl fmt::Display for Unconvertible {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Unconvertible::UnknownVersion(version) => write!(
+        f,
+        "can't convert the signature to version {}, the latest one is {}",
+        version, VERSION
+      ),
+      Unconvertible::Lossy { version, lost } => write!(
+        f,
+        "can't convert the signature to version {}, which doesn't store {}",
+        version, lost
+      ),
+    }
+  }
+}
+
+impl Error for Unconvertible {}
+
+#[derive(Debug)]
+enum Uncombinable {
+  Empty,
+  Members,
+  OptionsMismatch(u64),
+  Gap { offset: u64, end: u64 },
+  Unordered(u64),
+  NoCommonBoundary { offset: u64, end: u64 },
+}
+
+impl fmt::Display for Uncombinable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Uncombinable::Empty => write!(f, "there are no range signatures to combine"),
+      Uncombinable::Members => write!(f, "signatures of archive members can't be combined"),
+      Uncombinable::OptionsMismatch(offset) => write!(
+        f,
+        "the signature of the range at offset {} has other options than the first one",
+        offset
+      ),
+      Uncombinable::Gap { offset, end } => write!(
+        f,
+        "the range at offset {} starts after the ranges before it end at {}",
+        offset, end
+      ),
+      Uncombinable::Unordered(offset) => write!(
+        f,
+        "the range at offset {} ends before the ranges before it",
+        offset
+      ),
+      Uncombinable::NoCommonBoundary { offset, end } => write!(
+        f,
+        "the range at offset {} has no chunk boundary in common with the ranges before it, which \
+         end at {}, they must overlap more",
+        offset, end
+      ),
+    }
+  }
+}
+
+impl Error for Uncombinable {}
+
+#[derive(Debug)]
+struct WeakChecksumCount {
+  checksums: usize,
+  chunks: usize,
+}
+
+impl fmt::Display for WeakChecksumCount {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "signature has {} weak checksums for its {} chunks",
+      self.checksums, self.chunks
+    )
+  }
+}
+
+impl Error for WeakChecksumCount {}
+
+/// Converts a size from the signature or diff formats, which are always `u64`, to `usize`
+/// instead of truncating it on 32-bit targets.
+pub(crate) fn usize_from(value: u64) -> Result<usize, io::Error> {
+  usize::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, TooLarge(value)))
+}
+
+/// Checks the chunk sizes against the bounds of FastCDC, which panics outside of them.
+pub(crate) fn check_chunk_sizes(
+  min_size: u32,
+  avg_size: u32,
+  max_size: u32,
+) -> Result<(), io::Error> {
+  let bounds = [
+    ("min_size", min_size, MINIMUM_MIN, MINIMUM_MAX),
+    ("avg_size", avg_size, AVERAGE_MIN, AVERAGE_MAX),
+    ("max_size", max_size, MAXIMUM_MIN, MAXIMUM_MAX),
+  ];
+  for (parameter, value, min, max) in bounds {
+    if !(min..=max).contains(&value) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        InvalidChunkSize {
+          parameter,
+          value,
+          min,
+          max,
+        },
+      ));
+    }
+  }
+
+  Ok(())
+}
+
+/// Checks that the chunk sizes grow from `min_size` to `max_size`. FastCDC accepts any order, but
+/// then cuts chunks outside of the expected range.
+pub(crate) fn check_chunk_size_order(
+  min_size: u32,
+  avg_size: u32,
+  max_size: u32,
+) -> Result<(), io::Error> {
+  if min_size <= avg_size && avg_size <= max_size {
+    return Ok(());
+  }
+
+  Err(io::Error::new(
+    io::ErrorKind::InvalidInput,
+    UnorderedChunkSizes {
+      min_size,
+      avg_size,
+      max_size,
+    },
+  ))
+}
+
+/// Returns the FastCDC normalization of the `level`.
+pub(crate) fn normalization(level: u8) -> Result<Normalization, io::Error> {
+  match level {
+    0 => Ok(Normalization::Level0),
+    1 => Ok(Normalization::Level1),
+    2 => Ok(Normalization::Level2),
+    3 => Ok(Normalization::Level3),
+    _ => Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      InvalidChunkSize {
+        parameter: "normalization",
+        value: level.into(),
+        min: 0,
+        max: 3,
+      },
+    )),
+  }
+}
+
+/// Checks the size of fixed blocks against the bounds of the FastCDC chunk sizes, since such
+/// blocks are an alternative to them.
+pub(crate) fn check_block_size(block_size: u32, kind: io::ErrorKind) -> Result<(), io::Error> {
+  if (MINIMUM_MIN..=MAXIMUM_MAX).contains(&block_size) {
+    return Ok(());
+  }
+
+  Err(io::Error::new(
+    kind,
+    InvalidChunkSize {
+      parameter: "fixed_block_size",
+      value: block_size,
+      min: MINIMUM_MIN,
+      max: MAXIMUM_MAX,
+    },
+  ))
+}
+
+/// Bounds of the sources chunked for a signature, so sources which never end, such as pipes or
+/// devices, fail instead of growing the chunk list until the memory runs out.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Limits {
+  pub max_chunks: Option<usize>,
+  pub max_bytes: Option<u64>,
+}
+
+impl Limits {
+  /// Checks `chunks` chunks covering `chunked` bytes are within the limits.
+  fn check(&self, chunks: usize, chunked: u64) -> Result<(), io::Error> {
+    let exceeded = match (self.max_chunks, self.max_bytes) {
+      (Some(max), _) if chunks > max => LimitExceeded::Chunks(max),
+      (_, Some(max)) if chunked > max => LimitExceeded::Bytes(max),
+      _ => return Ok(()),
+    };
+
+    Err(io::Error::new(io::ErrorKind::InvalidInput, exceeded))
+  }
+}
+
+/// Counts the bytes read from the `source`.
+struct Counter<'a, R> {
+  source: &'a mut R,
+  read: u64,
+}
+
+impl<R: Read> Read for Counter<'_, R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.source.read(buf)?;
+    self.read += read as u64;
+    Ok(read)
+  }
+}
+
+/// Reports the progress of reading a source of `total` bytes to chunk it, calling `progress` with
+/// the bytes read so far and their percentage of `total` once for every whole percent. Progress
+/// stops at 99% until `finish`, so 100% is reported once and only after the last chunk.
+pub(crate) struct ProgressReader<'a, R> {
+  source: R,
+  read: u64,
+  total: u64,
+  reported: Option<u32>,
+  progress: &'a mut dyn FnMut(u64, u32),
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+  pub(crate) fn new(source: R, total: u64, progress: &'a mut dyn FnMut(u64, u32)) -> Self {
+    Self {
+      source,
+      read: 0,
+      total,
+      reported: None,
+      progress,
+    }
+  }
+
+  pub(crate) fn finish(self) {
+    (self.progress)(self.read, 100);
+  }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.source.read(buf)?;
+    self.read += read as u64;
+
+    let percent = (self.read as u128 * 100 / u128::from(self.total.max(1))).min(99) as u32;
+    if self.reported.is_none_or(|reported| percent > reported) {
+      self.reported = Some(percent);
+      (self.progress)(self.read, percent);
+    }
+
+    Ok(read)
+  }
+}
+
+/// Checks the number of bytes of truncated chunk hashes. Shorter hashes make collisions likely in
+/// files with billions of chunks.
+pub(crate) fn check_hash_bytes(hash_bytes: u8, kind: io::ErrorKind) -> Result<(), io::Error> {
+  match hash_bytes {
+    16 | 24 | 32 => Ok(()),
+    _ => Err(io::Error::new(kind, InvalidHashBytes(hash_bytes))),
+  }
+}
+
+/// Checks that member boundaries are ascending, so every member has a start and an end.
+fn check_boundaries(boundaries: &[u64], kind: io::ErrorKind) -> Result<(), io::Error> {
+  match boundaries.windows(2).find(|pair| pair[0] >= pair[1]) {
+    Some(pair) => Err(io::Error::new(kind, InvalidBoundary::Unsorted(pair[1]))),
+    None => Ok(()),
+  }
+}
+
+/// Zeroes all but the first `hash_bytes` bytes of the `hash`, so truncated hashes are compared
+/// like whole ones.
+fn truncate(hash: blake3::Hash, hash_bytes: u8) -> blake3::Hash {
+  let mut bytes = *hash.as_bytes();
+  bytes[hash_bytes as usize..].fill(0);
+  bytes.into()
+}
+
+impl HashAlgorithm {
+  /// Returns the strong hash of `data`. SHA-256 digests are stored in the same 32 bytes as the
+  /// Blake3 ones.
+  pub fn hash(self, data: &[u8]) -> blake3::Hash {
+    match self {
+      HashAlgorithm::Blake3 => blake3::hash(data),
+      HashAlgorithm::Sha256 => <[u8; 32]>::from(Sha256::digest(data)).into(),
+    }
+  }
+}
+
+impl From<HashAlgorithm> for u8 {
+  fn from(algorithm: HashAlgorithm) -> Self {
+    match algorithm {
+      HashAlgorithm::Blake3 => 0,
+      HashAlgorithm::Sha256 => 1,
+    }
+  }
+}
+
+impl TryFrom<u8> for HashAlgorithm {
+  type Error = io::Error;
+
+  fn try_from(num: u8) -> Result<Self, Self::Error> {
+    match num {
+      0 => Ok(HashAlgorithm::Blake3),
+      1 => Ok(HashAlgorithm::Sha256),
+      _ => Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        UnknownHashAlgorithm(num),
+      )),
+    }
+  }
+}
+
 impl Signature {
   /// Calculates a signature using FastCDC to determine the data chunks and Blake3 to calculate
   /// strong hashes.
+  #[cfg(test)]
   pub fn calculate(
     source: &mut impl Read,
     min_size: u32,
     avg_size: u32,
     max_size: u32,
   ) -> Result<Self, io::Error> {
-    let chunker = StreamCDC::new(source, min_size, avg_size, max_size);
-    let mut chunks: Vec<Chunk> = Vec::new();
+    Self::calculate_with(
+      source,
+      min_size,
+      avg_size,
+      max_size,
+      HashAlgorithm::Blake3,
+      DEFAULT_NORMALIZATION,
+      DEFAULT_HASH_BYTES,
+    )
+  }
 
-    for result in chunker {
-      let chunk = result?;
-      let hash = blake3::hash(&chunk.data);
+  /// Calculates a signature like `calculate`, but with `hash_algorithm` for the strong hashes
+  /// truncated to `hash_bytes` bytes and the FastCDC `normalization` level.
+  #[cfg(test)]
+  pub fn calculate_with(
+    source: &mut impl Read,
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+    hash_algorithm: HashAlgorithm,
+    normalization: u8,
+    hash_bytes: u8,
+  ) -> Result<Self, io::Error> {
+    let (signature, _) = Self::calculate_from(
+      source,
+      min_size,
+      avg_size,
+      max_size,
+      hash_algorithm,
+      normalization,
+      hash_bytes,
+      Limits::default(),
+    )?;
 
-      chunks.push(Chunk {
-        hash,
-        offset: chunk.offset,
-        length: chunk.length,
-      });
+    Ok(signature)
+  }
+
+  /// Calculates a signature like `calculate_with` of an archive whose members start at the
+  /// ascending `boundaries`, cutting every member on its own. Changes of a member then only change
+  /// its chunks, instead of the chunking drifting into the members after it.
+  #[allow(clippy::too_many_arguments)]
+  pub fn calculate_members(
+    source: &mut impl Read,
+    boundaries: &[u64],
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+    hash_algorithm: HashAlgorithm,
+    normalization: u8,
+    hash_bytes: u8,
+  ) -> Result<Self, io::Error> {
+    check_boundaries(boundaries, io::ErrorKind::InvalidInput)?;
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for end in boundaries.iter().copied().map(Some).chain([None]) {
+      let len = end.map_or(u64::MAX, |end| end - start);
+      let (member, read) = Self::calculate_from(
+        &mut source.take(len),
+        min_size,
+        avg_size,
+        max_size,
+        hash_algorithm,
+        normalization,
+        hash_bytes,
+        Limits::default(),
+      )?;
+      if let Some(end) = end.filter(|_| read < len) {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          InvalidBoundary::PastEnd {
+            boundary: end,
+            size: start + read,
+          },
+        ));
+      }
+
+      chunks.extend(member.chunks.into_iter().map(|chunk| Chunk {
+        offset: start + chunk.offset,
+        ..chunk
+      }));
+      start += read;
     }
 
     Ok(Self {
@@ -66,58 +747,1010 @@ impl Signature {
       min_size,
       avg_size,
       max_size,
+      hash_algorithm,
+      normalization,
+      hash_bytes,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: boundaries.to_vec(),
       chunks,
     })
   }
 
-  /// Loads signature from raw data.
-  pub fn load(vec: &[u8]) -> Self {
-    let version = vec[0];
-    let min_size = u32::from_be_bytes(*array_ref![vec, 1, 4]);
-    let avg_size = u32::from_be_bytes(*array_ref![vec, 5, 4]);
-    let max_size = u32::from_be_bytes(*array_ref![vec, 9, 4]);
-    let numchunks = usize::from_be_bytes(*array_ref![vec, 13, 8]);
-    let mut offset = 21;
-    let mut chunks = Vec::with_capacity(numchunks);
-    for _i in 0..numchunks {
+  /// Calculates a signature like `calculate_with` and returns it along with the number of bytes
+  /// read from the `source`, which doesn't need to be a file with a known size. The chunks are
+  /// checked to follow each other and to cover every byte read, and to stay within the `limits`.
+  #[allow(clippy::too_many_arguments)]
+  pub(crate) fn calculate_from<R: Read>(
+    source: &mut R,
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+    hash_algorithm: HashAlgorithm,
+    normalization: u8,
+    hash_bytes: u8,
+    limits: Limits,
+  ) -> Result<(Self, u64), io::Error> {
+    check_chunk_sizes(min_size, avg_size, max_size)?;
+    check_hash_bytes(hash_bytes, io::ErrorKind::InvalidInput)?;
+    let level = self::normalization(normalization)?;
+    let mut counter = Counter { source, read: 0 };
+    let chunker = StreamCDC::with_level(&mut counter, min_size, avg_size, max_size, level);
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut chunked = 0;
+
+    for result in chunker {
+      let chunk = result?;
+      let hash = truncate(hash_algorithm.hash(&chunk.data), hash_bytes);
+
+      if chunk.offset != chunked {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          ChunkingMismatch {
+            chunked,
+            read: chunk.offset,
+          },
+        ));
+      }
+      chunked += chunk.length as u64;
+
       chunks.push(Chunk {
-        hash: (*array_ref![vec, offset, 32]).into(),
-        offset: u64::from_be_bytes(*array_ref![vec, offset + 32, 8]),
-        length: usize::from_be_bytes(*array_ref![vec, offset + 40, 8]),
+        hash,
+        offset: chunk.offset,
+        length: chunk.length,
       });
+      limits.check(chunks.len(), chunked)?;
+    }
 
-      offset += 48;
+    let read = counter.read;
+    if chunked != read {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        ChunkingMismatch { chunked, read },
+      ));
     }
 
-    Self {
-      version,
+    let signature = Self {
+      version: VERSION,
       min_size,
       avg_size,
       max_size,
+      hash_algorithm,
+      normalization,
+      hash_bytes,
+      fixed_block_size: None,
+      weak_checksums: None,
+      boundaries: Vec::new(),
       chunks,
-    }
+    };
+
+    Ok((signature, read))
   }
 
-  pub fn write<W: Write>(&self, dest: &mut W) -> Result<(), io::Error> {
-    dest.write_all(&[self.version])?;
-    dest.write_all(self.min_size.to_be_bytes().as_ref())?;
-    dest.write_all(self.avg_size.to_be_bytes().as_ref())?;
-    dest.write_all(self.max_size.to_be_bytes().as_ref())?;
-    dest.write_all(self.chunks.len().to_be_bytes().as_ref())?;
+  /// Calculates a signature of the `source` cut into blocks of `block_size` bytes, the last one
+  /// shorter, instead of with FastCDC, for protocols expecting rsync-style blocks. Such blocks
+  /// don't survive inserts or deletes, diffs only copy the blocks which stayed at their offsets.
+  /// With `weak_checksums` the rsync rolling checksums of the blocks are kept too.
+  #[cfg(test)]
+  pub fn calculate_fixed(
+    source: &mut impl Read,
+    block_size: u32,
+    hash_algorithm: HashAlgorithm,
+    hash_bytes: u8,
+    weak_checksums: bool,
+  ) -> Result<Self, io::Error> {
+    let (signature, _) = Self::calculate_blocks_from(
+      source,
+      block_size,
+      hash_algorithm,
+      hash_bytes,
+      weak_checksums,
+      Limits::default(),
+    )?;
 
-    for chunk in self.chunks.iter() {
-      dest.write_all(chunk.hash.as_bytes().as_ref())?;
-      dest.write_all(chunk.offset.to_be_bytes().as_ref())?;
-      dest.write_all(chunk.length.to_be_bytes().as_ref())?;
+    Ok(signature)
+  }
+
+  /// Calculates a signature like `calculate_fixed` and returns it along with the number of bytes
+  /// read from the `source`, failing once it's past the `limits`.
+  pub(crate) fn calculate_blocks_from<R: Read>(
+    source: &mut R,
+    block_size: u32,
+    hash_algorithm: HashAlgorithm,
+    hash_bytes: u8,
+    weak_checksums: bool,
+    limits: Limits,
+  ) -> Result<(Self, u64), io::Error> {
+    check_block_size(block_size, io::ErrorKind::InvalidInput)?;
+    check_hash_bytes(hash_bytes, io::ErrorKind::InvalidInput)?;
+    let mut block = Vec::with_capacity(block_size as usize);
+    let mut chunks = Vec::new();
+    let mut checksums = Vec::new();
+    let mut read = 0;
+
+    loop {
+      block.clear();
+      let length = source
+        .by_ref()
+        .take(block_size.into())
+        .read_to_end(&mut block)?;
+      if length == 0 {
+        break;
+      }
+
+      chunks.push(Chunk {
+        hash: truncate(hash_algorithm.hash(&block), hash_bytes),
+        offset: read,
+        length,
+      });
+      if weak_checksums {
+        checksums.push(weak_match::checksum(&block));
+      }
+      read += length as u64;
+      limits.check(chunks.len(), read)?;
+      if length < block_size as usize {
+        break;
+      }
     }
 
-    dest.flush()?;
+    let signature = Self {
+      version: VERSION,
+      min_size: block_size,
+      avg_size: block_size,
+      max_size: block_size,
+      hash_algorithm,
+      normalization: DEFAULT_NORMALIZATION,
+      hash_bytes,
+      fixed_block_size: Some(block_size),
+      weak_checksums: weak_checksums.then_some(checksums),
+      boundaries: Vec::new(),
+      chunks,
+    };
 
-    Ok(())
+    Ok((signature, read))
   }
-}
 
-#[cfg(test)]
+  /// Calculates a signature of the `source` the same way as this one, content-defined or in fixed
+  /// blocks, so their chunks can be compared.
+  pub fn calculate_like(&self, source: &mut impl Read) -> Result<Self, io::Error> {
+    let (signature, _) = match self.fixed_block_size {
+      Some(block_size) => Self::calculate_blocks_from(
+        source,
+        block_size,
+        self.hash_algorithm,
+        self.hash_bytes,
+        self.weak_checksums.is_some(),
+        Limits::default(),
+      )?,
+      None => Self::calculate_from(
+        source,
+        self.min_size,
+        self.avg_size,
+        self.max_size,
+        self.hash_algorithm,
+        self.normalization,
+        self.hash_bytes,
+        Limits::default(),
+      )?,
+    };
+
+    Ok(signature)
+  }
+
+  /// Returns the signature with the chunks of the `source` at the same offsets hashed with
+  /// `hash_algorithm` and truncated to `hash_bytes`, e.g. to compare it with signatures hashed
+  /// that way. The chunks aren't cut again, so all of them must be within the `source`.
+  pub fn rehash<R: Read + Seek>(
+    &self,
+    source: &mut R,
+    hash_algorithm: HashAlgorithm,
+    hash_bytes: u8,
+  ) -> Result<Self, io::Error> {
+    check_hash_bytes(hash_bytes, io::ErrorKind::InvalidInput)?;
+
+    let mut data = Vec::new();
+    let mut chunks = Vec::with_capacity(self.chunks.len());
+    for chunk in self.chunks.iter() {
+      data.resize(chunk.length, 0);
+      source.seek(SeekFrom::Start(chunk.offset))?;
+      source.read_exact(&mut data)?;
+      chunks.push(Chunk {
+        hash: truncate(hash_algorithm.hash(&data), hash_bytes),
+        ..*chunk
+      });
+    }
+
+    Ok(Self {
+      version: self.version.max(HASH_BYTES_VERSION),
+      min_size: self.min_size,
+      avg_size: self.avg_size,
+      max_size: self.max_size,
+      hash_algorithm,
+      normalization: self.normalization,
+      hash_bytes,
+      fixed_block_size: self.fixed_block_size,
+      weak_checksums: self.weak_checksums.clone(),
+      boundaries: self.boundaries.clone(),
+      chunks,
+    })
+  }
+
+  /// Returns the size of the file the signature was calculated for.
+  pub fn file_size(&self) -> u64 {
+    self.chunks.iter().map(|chunk| chunk.length as u64).sum()
+  }
+
+  /// Returns whether every chunk starts where the previous one ends, the first at 0, like in all
+  /// calculated signatures. Loaded ones may have been edited elsewhere, but `file_size`, `extend`
+  /// and `SignatureUpdater` rely on it.
+  pub fn is_contiguous(&self) -> bool {
+    self.gap().is_none()
+  }
+
+  /// Returns the offset of the first chunk which doesn't start where the previous one ends, along
+  /// with that end.
+  fn gap(&self) -> Option<(u64, u64)> {
+    let mut end = 0u64;
+    for chunk in self.chunks.iter() {
+      if chunk.offset != end {
+        return Some((chunk.offset, end));
+      }
+      end = chunk.offset.saturating_add(chunk.length as u64);
+    }
+
+    None
+  }
+
+  pub(crate) fn check_contiguous(&self) -> Result<(), io::Error> {
+    match self.gap() {
+      Some((offset, expected)) => Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        NotContiguous { offset, expected },
+      )),
+      None => Ok(()),
+    }
+  }
+
+  /// Changes the format version the signature is written in. Older versions imply the defaults of
+  /// the fields they don't store, so converting to them fails unless the signature uses these
+  /// defaults, converting to newer ones never loses anything.
+  pub fn convert(&mut self, version: u32) -> Result<(), io::Error> {
+    let version = match u8::try_from(version) {
+      Ok(version) if version <= VERSION => version,
+      _ => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          Unconvertible::UnknownVersion(version),
+        ))
+      }
+    };
+    let lost = if version < HASH_ALGORITHM_VERSION && self.hash_algorithm != HashAlgorithm::Blake3 {
+      Some("hash algorithms other than Blake3")
+    } else if version < NORMALIZATION_VERSION && self.normalization != DEFAULT_NORMALIZATION {
+      Some("normalization levels other than the default")
+    } else if version < HASH_BYTES_VERSION && self.hash_bytes != DEFAULT_HASH_BYTES {
+      Some("truncated chunk hashes")
+    } else if version < BLOCKING_VERSION
+      && (self.fixed_block_size.is_some() || self.weak_checksums.is_some())
+    {
+      Some("fixed-size blocks and weak checksums")
+    } else if version < MEMBERS_VERSION && !self.boundaries.is_empty() {
+      Some("archive member boundaries")
+    } else {
+      None
+    };
+    if let Some(lost) = lost {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        Unconvertible::Lossy { version, lost },
+      ));
+    }
+
+    self.version = version;
+    Ok(())
+  }
+
+  /// Extends the signature of the `file` which has only been appended to since, e.g. a log. The
+  /// last chunk was cut by the old end of the file, so only it and the appended data are chunked,
+  /// the chunks before it are kept as they are.
+  pub fn extend<R: Read + Seek>(&mut self, file: &mut R) -> Result<(), io::Error> {
+    self.check_contiguous()?;
+    let signed = self.file_size();
+    let actual = file.seek(SeekFrom::End(0))?;
+    if actual < signed {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        Shrunk { signed, actual },
+      ));
+    }
+
+    let start = self.chunks.pop().map_or(0, |chunk| chunk.offset);
+    file.seek(SeekFrom::Start(start))?;
+    let tail = self.calculate_like(file)?;
+    if let (Some(checksums), Some(tail_checksums)) = (&mut self.weak_checksums, tail.weak_checksums)
+    {
+      checksums.truncate(self.chunks.len());
+      checksums.extend(tail_checksums);
+    }
+    self
+      .chunks
+      .extend(tail.chunks.into_iter().map(|chunk| Chunk {
+        offset: start + chunk.offset,
+        ..chunk
+      }));
+
+    Ok(())
+  }
+
+  /// Combines the signatures of ranges of a file into the signature of the whole file, e.g. when
+  /// the ranges of a huge file are signed by separate processes. Every signature is calculated as
+  /// if its range were a whole file and comes with the offset of the range.
+  ///
+  /// Chunks are cut where the previous one ends, so the chunks of a range are only cut like the
+  /// ones of the whole file from a boundary they have in common, and the last one is cut by the
+  /// end of the range. The first range must start at 0 and every other one must overlap the ones
+  /// before it until they share a boundary: the chunks before it are kept and the chunks of the
+  /// range replace the ones after it. An overlap of a few maximum chunk sizes is usually enough,
+  /// shorter ones may fail. Fixed-size blocks only share boundaries when the ranges start at
+  /// multiples of the block size, but then ranges needn't overlap. All signatures must have the
+  /// same options.
+  pub fn combine_ranges(parts: Vec<(u64, Signature)>) -> Result<Self, io::Error> {
+    let invalid = |err: Uncombinable| io::Error::new(io::ErrorKind::InvalidInput, err);
+    let options = |sig: &Signature| {
+      (
+        sig.min_size,
+        sig.avg_size,
+        sig.max_size,
+        sig.hash_algorithm,
+        sig.normalization,
+        sig.hash_bytes,
+        sig.fixed_block_size,
+        sig.weak_checksums.is_some(),
+      )
+    };
+
+    let mut parts = parts.into_iter();
+    let Some((offset, mut combined)) = parts.next() else {
+      return Err(invalid(Uncombinable::Empty));
+    };
+    if offset != 0 {
+      return Err(invalid(Uncombinable::Gap { offset, end: 0 }));
+    }
+    if !combined.boundaries.is_empty() {
+      return Err(invalid(Uncombinable::Members));
+    }
+    combined.check_contiguous()?;
+
+    for (offset, part) in parts {
+      if options(&part) != options(&combined) {
+        return Err(invalid(Uncombinable::OptionsMismatch(offset)));
+      }
+      if !part.boundaries.is_empty() {
+        return Err(invalid(Uncombinable::Members));
+      }
+      part.check_contiguous()?;
+
+      let end = combined.file_size();
+      if offset > end {
+        return Err(invalid(Uncombinable::Gap { offset, end }));
+      }
+      if offset.saturating_add(part.file_size()) < end {
+        return Err(invalid(Uncombinable::Unordered(offset)));
+      }
+      if part.chunks.is_empty() {
+        continue;
+      }
+
+      // The end of a range is only a boundary of the whole file when it ends a whole block.
+      let ends_block = combined
+        .fixed_block_size
+        .is_some_and(|block_size| end % u64::from(block_size) == 0);
+      let is_boundary = |at: u64| {
+        (at == end && ends_block)
+          || combined
+            .chunks
+            .binary_search_by_key(&at, |chunk| chunk.offset)
+            .is_ok()
+      };
+      let Some(seam) = part
+        .chunks
+        .iter()
+        .position(|chunk| is_boundary(offset + chunk.offset))
+      else {
+        return Err(invalid(Uncombinable::NoCommonBoundary { offset, end }));
+      };
+
+      let kept = combined
+        .chunks
+        .partition_point(|chunk| chunk.offset < offset + part.chunks[seam].offset);
+      combined.chunks.truncate(kept);
+      combined
+        .chunks
+        .extend(part.chunks[seam..].iter().map(|chunk| Chunk {
+          offset: offset + chunk.offset,
+          ..chunk.clone()
+        }));
+      if let (Some(checksums), Some(part_checksums)) =
+        (&mut combined.weak_checksums, &part.weak_checksums)
+      {
+        checksums.truncate(kept);
+        checksums.extend_from_slice(&part_checksums[seam..]);
+      }
+    }
+
+    Ok(combined)
+  }
+
+  /// Groups the chunks into `HISTOGRAM_BUCKETS` buckets by their length and warns when more than
+  /// `max_clamped_fraction` of them are cut at the minimum or the maximum size, or when the whole
+  /// input is only one or two chunks. Fixed-size blocks are cut regardless of the data, so they're
+  /// never warned about.
+  pub fn chunk_size_histogram(&self, max_clamped_fraction: f64) -> ChunkSizeHistogram {
+    let mut lengths: Vec<u32> = self
+      .chunks
+      .iter()
+      .map(|chunk| chunk.length as u32)
+      .collect();
+    lengths.sort_unstable();
+
+    let (min, max) = match (lengths.first(), lengths.last()) {
+      (Some(&min), Some(&max)) => (min, max),
+      _ => {
+        return ChunkSizeHistogram {
+          buckets: Vec::new(),
+          chunk_count: 0,
+          min: 0,
+          max: 0,
+          mean: 0.0,
+          median: 0.0,
+          at_min_size: 0,
+          at_max_size: 0,
+          warning: None,
+        }
+      }
+    };
+
+    let width = (max - min) / HISTOGRAM_BUCKETS + 1;
+    let mut buckets: Vec<Bucket> = (0..HISTOGRAM_BUCKETS)
+      .map(|i| Bucket {
+        start: min + i * width,
+        end: min + (i + 1) * width - 1,
+        count: 0,
+      })
+      .take_while(|bucket| bucket.start <= max)
+      .collect();
+    for length in lengths.iter() {
+      buckets[((length - min) / width) as usize].count += 1;
+    }
+
+    let count = lengths.len();
+    let median = if count.is_multiple_of(2) {
+      (lengths[count / 2 - 1] as f64 + lengths[count / 2] as f64) / 2.0
+    } else {
+      lengths[count / 2] as f64
+    };
+
+    let at_min_size = lengths
+      .iter()
+      .filter(|&&length| length == self.min_size)
+      .count();
+    let at_max_size = lengths
+      .iter()
+      .filter(|&&length| length == self.max_size)
+      .count();
+    let clamped = |at_size| at_size as f64 / count as f64 > max_clamped_fraction;
+    let warning = if self.fixed_block_size.is_some() {
+      None
+    } else if count <= FEW_CHUNKS {
+      Some(format!(
+        "the whole input of {} bytes is {} chunk{} with min_size {} and avg_size {}, so diffs of \
+         it copy all or nothing, consider a smaller min_size and avg_size",
+        self.file_size(),
+        count,
+        if count == 1 { "" } else { "s" },
+        self.min_size,
+        self.avg_size
+      ))
+    } else if clamped(at_max_size) {
+      Some(format!(
+        "{} of {} chunks are cut at max_size {}, the data has too few cut points for avg_size {}, \
+         consider a larger max_size or a smaller avg_size",
+        at_max_size, count, self.max_size, self.avg_size
+      ))
+    } else if clamped(at_min_size) {
+      Some(format!(
+        "{} of {} chunks are cut at min_size {}, consider a smaller min_size for avg_size {}",
+        at_min_size, count, self.min_size, self.avg_size
+      ))
+    } else {
+      None
+    };
+
+    ChunkSizeHistogram {
+      buckets,
+      chunk_count: count as u32,
+      min,
+      max,
+      mean: lengths.iter().map(|&length| length as f64).sum::<f64>() / count as f64,
+      median,
+      at_min_size: at_min_size as u32,
+      at_max_size: at_max_size as u32,
+      warning,
+    }
+  }
+
+  /// Compares the signature to the `actual` signature of the same file calculated with the same
+  /// options. Chunks are compared at their offsets, so a resized region only mismatches the
+  /// chunks around it.
+  pub fn verify(&self, actual: &Signature) -> VerifyResult {
+    let mut actual_chunks = actual.chunks.iter().peekable();
+    let mut first_mismatch_offset = None;
+    let mut mismatched_chunks = 0;
+
+    for chunk in self.chunks.iter() {
+      while actual_chunks
+        .next_if(|other| other.offset < chunk.offset)
+        .is_some()
+      {}
+      if actual_chunks
+        .next_if(|other| other.offset == chunk.offset && *other == chunk)
+        .is_none()
+      {
+        first_mismatch_offset.get_or_insert(chunk.offset);
+        mismatched_chunks += 1;
+      }
+    }
+
+    // The chunks appended to the file.
+    let (expected_size, actual_size) = (self.file_size(), actual.file_size());
+    if actual_size > expected_size {
+      first_mismatch_offset.get_or_insert(expected_size);
+    }
+
+    VerifyResult {
+      matches: first_mismatch_offset.is_none(),
+      first_mismatch_offset: first_mismatch_offset.map(|offset| offset as i64),
+      mismatched_chunks,
+    }
+  }
+
+  /// Loads signature from raw data.
+  ///
+  /// Signatures may come from untrusted peers, so every field is checked before it's used: the
+  /// chunk count against the bytes that follow and the chunk sizes against the bounds of
+  /// FastCDC. Malformed data is an error, never a panic or a huge allocation.
+  pub fn load(vec: &[u8]) -> Result<Self, io::Error> {
+    let mut reader = SignatureReader::new(vec)?;
+    let mut chunks = Vec::with_capacity(reader.len());
+    let mut checksums = Vec::with_capacity(if reader.weak { reader.len() } else { 0 });
+    while let Some(entry) = reader.next_entry() {
+      let (chunk, checksum) = entry?;
+      chunks.push(chunk);
+      checksums.extend(checksum);
+    }
+
+    Ok(Self {
+      weak_checksums: reader.weak.then_some(checksums),
+      chunks,
+      ..reader.header
+    })
+  }
+
+  /// Writes the signature in its binary format. The chunks are written in the order chunking
+  /// produced them, by offset, and nothing else varies between runs, so identical data and
+  /// options give byte-identical signatures, e.g. for reproducible builds.
+  pub fn write<W: Write>(&self, dest: &mut W) -> Result<(), io::Error> {
+    dest.write_all(&[self.version])?;
+    if self.version >= HASH_ALGORITHM_VERSION {
+      dest.write_all(&[self.hash_algorithm.into()])?;
+    }
+    if self.version >= NORMALIZATION_VERSION {
+      dest.write_all(&[self.normalization])?;
+    }
+    let hash_len = if self.version >= HASH_BYTES_VERSION {
+      check_hash_bytes(self.hash_bytes, io::ErrorKind::InvalidInput)?;
+      dest.write_all(&[self.hash_bytes])?;
+      self.hash_bytes as usize
+    } else {
+      32
+    };
+    if self.version >= BLOCKING_VERSION {
+      if let Some(checksums) = &self.weak_checksums {
+        if checksums.len() != self.chunks.len() {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            WeakChecksumCount {
+              checksums: checksums.len(),
+              chunks: self.chunks.len(),
+            },
+          ));
+        }
+      }
+      dest.write_all(self.fixed_block_size.unwrap_or(0).to_be_bytes().as_ref())?;
+      dest.write_all(&[self.weak_checksums.is_some().into()])?;
+    }
+    dest.write_all(self.min_size.to_be_bytes().as_ref())?;
+    dest.write_all(self.avg_size.to_be_bytes().as_ref())?;
+    dest.write_all(self.max_size.to_be_bytes().as_ref())?;
+    dest.write_all((self.chunks.len() as u64).to_be_bytes().as_ref())?;
+
+    let checksums = self
+      .weak_checksums
+      .as_ref()
+      .filter(|_| self.version >= BLOCKING_VERSION);
+    for (i, chunk) in self.chunks.iter().enumerate() {
+      dest.write_all(&chunk.hash.as_bytes()[..hash_len])?;
+      dest.write_all(chunk.offset.to_be_bytes().as_ref())?;
+      dest.write_all((chunk.length as u64).to_be_bytes().as_ref())?;
+      if let Some(checksums) = checksums {
+        dest.write_all(checksums[i].to_be_bytes().as_ref())?;
+      }
+    }
+    if self.version >= MEMBERS_VERSION {
+      check_boundaries(&self.boundaries, io::ErrorKind::InvalidInput)?;
+      dest.write_all((self.boundaries.len() as u64).to_be_bytes().as_ref())?;
+      for boundary in self.boundaries.iter() {
+        dest.write_all(boundary.to_be_bytes().as_ref())?;
+      }
+    }
+
+    dest.flush()?;
+
+    Ok(())
+  }
+
+  /// Writes one JSON object per chunk and line, `{"offset", "length", "hash"}` with the stored
+  /// bytes of the hash in hex, for tools which don't parse the binary format.
+  pub fn write_ndjson<W: Write>(&self, dest: &mut W) -> Result<(), io::Error> {
+    check_hash_bytes(self.hash_bytes, io::ErrorKind::InvalidInput)?;
+    let hex_len = 2 * self.hash_bytes as usize;
+    for chunk in self.chunks.iter() {
+      let record = serde_json::json!({
+        "offset": chunk.offset,
+        "length": chunk.length,
+        "hash": &chunk.hash.to_hex()[..hex_len],
+      });
+      serde_json::to_writer(&mut *dest, &record)?;
+      dest.write_all(b"\n")?;
+    }
+
+    dest.flush()?;
+
+    Ok(())
+  }
+}
+
+/// Reads the chunks of a serialized signature one at a time instead of loading all of them, so
+/// scans of huge signatures take little memory. The header is checked like `Signature::load`
+/// checks it, and every chunk once it's read.
+pub struct SignatureReader<'a> {
+  header: Signature,
+  weak: bool,
+  hash_len: usize,
+  entries: std::slice::ChunksExact<'a, u8>,
+}
+
+impl<'a> SignatureReader<'a> {
+  pub fn new(vec: &'a [u8]) -> Result<Self, io::Error> {
+    let truncated = || io::Error::from(io::ErrorKind::UnexpectedEof);
+    let (&version, _) = vec.split_first().ok_or_else(truncated)?;
+    let field = |vec: &[u8]| vec.get(1).copied().ok_or_else(truncated);
+    let (hash_algorithm, vec) = if version >= HASH_ALGORITHM_VERSION {
+      (field(vec)?.try_into()?, &vec[1..])
+    } else {
+      (HashAlgorithm::Blake3, vec)
+    };
+    let (normalization, vec) = if version >= NORMALIZATION_VERSION {
+      (field(vec)?, &vec[1..])
+    } else {
+      (DEFAULT_NORMALIZATION, vec)
+    };
+    let (hash_bytes, vec) = if version >= HASH_BYTES_VERSION {
+      (field(vec)?, &vec[1..])
+    } else {
+      (DEFAULT_HASH_BYTES, vec)
+    };
+    check_hash_bytes(hash_bytes, io::ErrorKind::InvalidData)?;
+    let (fixed_block_size, weak, vec) = if version >= BLOCKING_VERSION {
+      let fields = vec.get(1..6).ok_or_else(truncated)?;
+      let block_size = u32::from_be_bytes(*array_ref![fields, 0, 4]);
+      (
+        (block_size != 0).then_some(block_size),
+        fields[4] != 0,
+        &vec[5..],
+      )
+    } else {
+      (None, false, vec)
+    };
+    let hash_len = hash_bytes as usize;
+    let entry_len = hash_len + 16 + if weak { 4 } else { 0 };
+    if vec.len() < 21 {
+      return Err(truncated());
+    }
+
+    let min_size = u32::from_be_bytes(*array_ref![vec, 1, 4]);
+    let avg_size = u32::from_be_bytes(*array_ref![vec, 5, 4]);
+    let max_size = u32::from_be_bytes(*array_ref![vec, 9, 4]);
+    let invalid_data = |err: io::Error| io::Error::new(io::ErrorKind::InvalidData, err);
+    match fixed_block_size {
+      Some(block_size) => check_block_size(block_size, io::ErrorKind::InvalidData)?,
+      None => {
+        check_chunk_sizes(min_size, avg_size, max_size).map_err(invalid_data)?;
+        check_chunk_size_order(min_size, avg_size, max_size).map_err(invalid_data)?;
+      }
+    }
+    self::normalization(normalization).map_err(invalid_data)?;
+
+    let numchunks = u64::from_be_bytes(*array_ref![vec, 13, 8]);
+    let available = ((vec.len() - 21) / entry_len) as u64;
+    if numchunks > available {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        TruncatedSignature {
+          numchunks,
+          available,
+        },
+      ));
+    }
+
+    let (entries, rest) = vec[21..].split_at(numchunks as usize * entry_len);
+    let boundaries = if version >= MEMBERS_VERSION {
+      let count = rest.get(..8).ok_or_else(truncated)?;
+      let count = u64::from_be_bytes(*array_ref![count, 0, 8]);
+      if count > (rest.len() as u64 - 8) / 8 {
+        return Err(truncated());
+      }
+      let boundaries: Vec<u64> = rest[8..][..count as usize * 8]
+        .chunks_exact(8)
+        .map(|boundary| u64::from_be_bytes(*array_ref![boundary, 0, 8]))
+        .collect();
+      check_boundaries(&boundaries, io::ErrorKind::InvalidData)?;
+      boundaries
+    } else {
+      Vec::new()
+    };
+
+    Ok(Self {
+      header: Signature {
+        version,
+        min_size,
+        avg_size,
+        max_size,
+        hash_algorithm,
+        normalization,
+        hash_bytes,
+        fixed_block_size,
+        weak_checksums: None,
+        boundaries,
+        chunks: Vec::new(),
+      },
+      weak,
+      hash_len,
+      entries: entries.chunks_exact(entry_len),
+    })
+  }
+
+  /// Returns the options of the signature, which has no chunks.
+  pub fn header(&self) -> &Signature {
+    &self.header
+  }
+
+  /// Returns the size of the file the signature was calculated for, which is where its last chunk
+  /// ends, reading only that chunk. Like `Signature::file_size` it relies on contiguous chunks.
+  pub fn file_size(&self) -> Result<u64, io::Error> {
+    match self.entries.clone().next_back() {
+      Some(entry) => {
+        let (chunk, _) = read_entry(entry, self.hash_len, self.weak)?;
+        Ok(chunk.offset + chunk.length as u64)
+      }
+      None => Ok(0),
+    }
+  }
+
+  /// Reads the chunks like iterating the reader, without advancing it, but fails at the first chunk
+  /// which doesn't start where the previous one ends, the first at 0, see
+  /// `Signature::is_contiguous`. It's meant for readers which haven't read any chunks yet.
+  pub fn contiguous(&self) -> impl Iterator<Item = Result<Chunk, io::Error>> + 'a {
+    let (hash_len, weak) = (self.hash_len, self.weak);
+    let mut end = 0u64;
+    self.entries.clone().map(move |entry| {
+      let (chunk, _) = read_entry(entry, hash_len, weak)?;
+      if chunk.offset != end {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          NotContiguous {
+            offset: chunk.offset,
+            expected: end,
+          },
+        ));
+      }
+      end = chunk.offset + chunk.length as u64;
+      Ok(chunk)
+    })
+  }
+
+  /// Reads the next chunk along with its weak checksum.
+  fn next_entry(&mut self) -> Option<Result<(Chunk, Option<u32>), io::Error>> {
+    let entry = self.entries.next()?;
+    Some(read_entry(entry, self.hash_len, self.weak))
+  }
+}
+
+/// Parses a serialized chunk along with its weak checksum.
+fn read_entry(
+  entry: &[u8],
+  hash_len: usize,
+  weak: bool,
+) -> Result<(Chunk, Option<u32>), io::Error> {
+  let mut hash = [0u8; 32];
+  hash[..hash_len].copy_from_slice(&entry[..hash_len]);
+  let offset = u64::from_be_bytes(*array_ref![entry, hash_len, 8]);
+  let length = u64::from_be_bytes(*array_ref![entry, hash_len + 8, 8]);
+  if offset.checked_add(length).is_none() {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      ChunkPastEnd { offset, length },
+    ));
+  }
+  let length = usize_from(length)?;
+  let checksum = weak.then(|| u32::from_be_bytes(*array_ref![entry, hash_len + 16, 4]));
+
+  Ok((
+    Chunk {
+      hash: hash.into(),
+      offset,
+      length,
+    },
+    checksum,
+  ))
+}
+
+impl Iterator for SignatureReader<'_> {
+  type Item = Result<Chunk, io::Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    Some(self.next_entry()?.map(|(chunk, _)| chunk))
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.entries.size_hint()
+  }
+}
+
+impl ExactSizeIterator for SignatureReader<'_> {}
+
+/// Updates a signature after edits of its file without chunking the whole file again.
+///
+/// FastCDC starts every chunk with a fresh gear hash, so a cut point only depends on the data
+/// since the previous one. Chunking restarts at the last boundary before an edit and stops as soon
+/// as a new boundary after the edit meets an old one, the old chunks from there are reused.
+pub struct SignatureUpdater {
+  signature: Signature,
+}
+
+impl SignatureUpdater {
+  pub fn new(signature: Signature) -> Self {
+    Self { signature }
+  }
+
+  /// Accounts for `old_length` bytes at `offset` replaced with `new_length` bytes. The `file` must
+  /// already contain the edited data.
+  pub fn update<R: Read + Seek>(
+    &mut self,
+    file: &mut R,
+    offset: u64,
+    old_length: u64,
+    new_length: u64,
+  ) -> Result<(), io::Error> {
+    self.signature.check_contiguous()?;
+    if !self.signature.boundaries.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, UpdatedMembers));
+    }
+    if let Some(block_size) = self.signature.fixed_block_size {
+      return self.update_blocks(file, offset, block_size);
+    }
+
+    let old_size = self.signature.file_size();
+    let delta = new_length as i64 - old_length as i64;
+    let hash_algorithm = self.signature.hash_algorithm;
+    let chunks = &mut self.signature.chunks;
+
+    // The last chunk is cut by the end of the file, so it's always chunked again.
+    let keep = chunks
+      .iter()
+      .take_while(|chunk| {
+        let end = chunk.offset + chunk.length as u64;
+        end <= offset && end < old_size
+      })
+      .count();
+    let tail = chunks.split_off(keep);
+    let start = chunks
+      .last()
+      .map_or(0, |chunk| chunk.offset + chunk.length as u64);
+
+    check_chunk_sizes(
+      self.signature.min_size,
+      self.signature.avg_size,
+      self.signature.max_size,
+    )?;
+    let level = normalization(self.signature.normalization)?;
+    check_hash_bytes(self.signature.hash_bytes, io::ErrorKind::InvalidInput)?;
+    file.seek(SeekFrom::Start(start))?;
+    let chunker = StreamCDC::with_level(
+      file,
+      self.signature.min_size,
+      self.signature.avg_size,
+      self.signature.max_size,
+      level,
+    );
+    let mut reused = None;
+
+    for result in chunker {
+      let chunk = result?;
+      let chunk_offset = start + chunk.offset;
+      chunks.push(Chunk {
+        hash: truncate(hash_algorithm.hash(&chunk.data), self.signature.hash_bytes),
+        offset: chunk_offset,
+        length: chunk.length,
+      });
+
+      let end = chunk_offset + chunk.length as u64;
+      if end >= offset + new_length {
+        let old_end = (end as i64 - delta) as u64;
+        if let Ok(index) = tail.binary_search_by_key(&old_end, |chunk| chunk.offset) {
+          reused = Some(index);
+          break;
+        }
+      }
+    }
+
+    if let Some(index) = reused {
+      chunks.extend(tail[index..].iter().map(|chunk| Chunk {
+        offset: (chunk.offset as i64 + delta) as u64,
+        ..chunk.clone()
+      }));
+    }
+
+    Ok(())
+  }
+
+  /// Blocks after an edit changing the size of the file are all shifted, so fixed-size blocks are
+  /// calculated again from the edited one to the end of the file.
+  fn update_blocks<R: Read + Seek>(
+    &mut self,
+    file: &mut R,
+    offset: u64,
+    block_size: u32,
+  ) -> Result<(), io::Error> {
+    let signature = &mut self.signature;
+    let keep = usize_from(offset / u64::from(block_size))?.min(signature.chunks.len());
+    let start = keep as u64 * u64::from(block_size);
+    signature.chunks.truncate(keep);
+
+    file.seek(SeekFrom::Start(start))?;
+    let tail = signature.calculate_like(file)?;
+    signature
+      .chunks
+      .extend(tail.chunks.into_iter().map(|chunk| Chunk {
+        offset: start + chunk.offset,
+        ..chunk
+      }));
+    if let (Some(checksums), Some(tail_checksums)) =
+      (&mut signature.weak_checksums, tail.weak_checksums)
+    {
+      checksums.truncate(keep);
+      checksums.extend(tail_checksums);
+    }
+
+    Ok(())
+  }
+
+  pub fn finish(self) -> Signature {
+    self.signature
+  }
+}
+
+#[cfg(test)]
 mod test {
   use super::Signature;
   use super::DEFAULT_AVG_SIZE;
@@ -141,7 +1774,869 @@ mod test {
       .write(&mut serialized_data)
       .expect("can't serialize the signature");
 
-    let sig_re = Signature::load(&serialized_data);
+    let sig_re = Signature::load(&serialized_data).unwrap();
+    assert_eq!(sig, sig_re);
+  }
+
+  #[test]
+  fn test_chunk_size_histogram() {
+    use std::io::Cursor;
+    let data: Vec<u8> = (0..1005000).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+
+    let histogram = sig.chunk_size_histogram(super::DEFAULT_MAX_CLAMPED_FRACTION);
+    assert_eq!(histogram.chunk_count as usize, sig.chunks.len());
+    assert_eq!(
+      histogram
+        .buckets
+        .iter()
+        .map(|bucket| bucket.count)
+        .sum::<u32>(),
+      histogram.chunk_count
+    );
+
+    // Only the last chunk may be smaller than `min_size`.
+    for chunk in sig.chunks[..sig.chunks.len() - 1].iter() {
+      assert!((1024..=16384).contains(&chunk.length));
+    }
+    assert!(histogram.max <= 16384);
+    assert!(histogram.buckets.last().unwrap().end >= histogram.max);
+    assert!(histogram.mean >= histogram.min as f64 && histogram.mean <= histogram.max as f64);
+    assert!(histogram.median >= histogram.min as f64 && histogram.median <= histogram.max as f64);
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn test_signature_serde() {
+    use std::io::Cursor;
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(
+      &mut Cursor::new(&data),
+      DEFAULT_MIN_SIZE,
+      DEFAULT_AVG_SIZE,
+      DEFAULT_MAX_SIZE,
+    )
+    .unwrap();
+
+    let json = serde_json::to_string(&sig).unwrap();
+    let sig_re: Signature = serde_json::from_str(&json).unwrap();
     assert_eq!(sig, sig_re);
+
+    let mut written = Vec::new();
+    sig.write(&mut written).unwrap();
+    let mut written_re = Vec::new();
+    sig_re.write(&mut written_re).unwrap();
+    assert_eq!(written, written_re);
+  }
+
+  #[test]
+  fn test_signature_updater() {
+    use super::SignatureUpdater;
+    use std::io::Cursor;
+
+    let mut data: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let calculate =
+      |data: &[u8]| Signature::calculate(&mut Cursor::new(data), 1024, 4096, 16384).unwrap();
+    let mut updater = SignatureUpdater::new(calculate(&data));
+
+    // Replace 100 bytes with 300 bytes in the middle of the file.
+    let insert: Vec<u8> = (0..300).map(|_| rand::random::<u8>()).collect();
+    data.splice(150000..150100, insert);
+    updater
+      .update(&mut Cursor::new(&data), 150000, 100, 300)
+      .unwrap();
+
+    // Remove 5000 bytes at the start and append 700 bytes at the end.
+    data.drain(10..5010);
+    updater
+      .update(&mut Cursor::new(&data), 10, 5000, 0)
+      .unwrap();
+    let len = data.len() as u64;
+    data.extend((0..700).map(|_| rand::random::<u8>()));
+    updater
+      .update(&mut Cursor::new(&data), len, 0, 700)
+      .unwrap();
+
+    let updated = updater.finish();
+    let expected = calculate(&data);
+    assert_eq!(updated, expected);
+    let offsets = |sig: &Signature| sig.chunks.iter().map(|c| c.offset).collect::<Vec<_>>();
+    assert_eq!(offsets(&updated), offsets(&expected));
+  }
+
+  #[test]
+  fn test_signature_hash_algorithms() {
+    use crate::HashAlgorithm;
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let calculate = |algorithm| {
+      Signature::calculate_with(
+        &mut Cursor::new(&data),
+        1024,
+        4096,
+        16384,
+        algorithm,
+        super::DEFAULT_NORMALIZATION,
+        super::DEFAULT_HASH_BYTES,
+      )
+      .unwrap()
+    };
+    let blake3 = calculate(HashAlgorithm::Blake3);
+    let sha256 = calculate(HashAlgorithm::Sha256);
+
+    assert_eq!(blake3.chunks.len(), sha256.chunks.len());
+    for (a, b) in blake3.chunks.iter().zip(sha256.chunks.iter()) {
+      assert_eq!((a.offset, a.length), (b.offset, b.length));
+      assert_ne!(a.hash, b.hash);
+    }
+
+    let mut serialized = Vec::new();
+    sha256.write(&mut serialized).unwrap();
+    let loaded = Signature::load(&serialized).unwrap();
+    assert_eq!(loaded.hash_algorithm, HashAlgorithm::Sha256);
+    assert_eq!(loaded, sha256);
+
+    let err = crate::diff::check_chunking(&blake3, &sha256).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "signatures are calculated with different hash algorithms: Blake3 and Sha256"
+    );
+  }
+
+  #[test]
+  fn test_signature_invalid_chunk_sizes() {
+    use std::io::Cursor;
+    let data = vec![0u8; 1000];
+
+    let err = Signature::calculate(&mut Cursor::new(&data), 16, 4096, 16384).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(
+      err.to_string(),
+      "min_size is 16, but must be between 64 and 1048576"
+    );
+
+    let err = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 1 << 25).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "max_size is 33554432, but must be between 1024 and 16777216"
+    );
+  }
+
+  #[test]
+  fn test_signature_verify() {
+    use std::io::Cursor;
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let calculate =
+      |data: &[u8]| Signature::calculate(&mut Cursor::new(data), 1024, 4096, 16384).unwrap();
+    let expected = calculate(&data);
+
+    let result = expected.verify(&calculate(&data));
+    assert!(result.matches);
+    assert_eq!(result.first_mismatch_offset, None);
+    assert_eq!(result.mismatched_chunks, 0);
+
+    let mut changed = data.clone();
+    changed[50000] ^= 1;
+    let result = expected.verify(&calculate(&changed));
+    let chunk = expected
+      .chunks
+      .iter()
+      .find(|chunk| chunk.offset + chunk.length as u64 > 50000)
+      .unwrap();
+    assert!(!result.matches);
+    assert_eq!(result.first_mismatch_offset, Some(chunk.offset as i64));
+    assert!(result.mismatched_chunks >= 1 && result.mismatched_chunks <= 2);
+
+    let result = expected.verify(&calculate(&data[..60000]));
+    assert!(!result.matches);
+    assert!(result.first_mismatch_offset.unwrap() <= 60000);
+    assert!(result.mismatched_chunks >= 1);
+
+    let mut appended = data.clone();
+    appended.extend_from_slice(&[0; 1000]);
+    let result = expected.verify(&calculate(&appended));
+    assert!(!result.matches);
+  }
+
+  #[test]
+  fn test_signature_normalization() {
+    use super::SignatureUpdater;
+    use crate::HashAlgorithm;
+    use std::io::Cursor;
+
+    let mut data: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let calculate = |data: &[u8], normalization| {
+      Signature::calculate_with(
+        &mut Cursor::new(data),
+        1024,
+        4096,
+        16384,
+        HashAlgorithm::Blake3,
+        normalization,
+        super::DEFAULT_HASH_BYTES,
+      )
+    };
+    let sig = calculate(&data, 3).unwrap();
+    assert_ne!(sig.chunks, calculate(&data, 0).unwrap().chunks);
+    assert!(calculate(&data, 4).is_err());
+
+    let mut serialized = Vec::new();
+    sig.write(&mut serialized).unwrap();
+    assert_eq!(Signature::load(&serialized).unwrap().normalization, 3);
+
+    // Updates chunk with the stored level.
+    let mut updater = SignatureUpdater::new(sig);
+    data.splice(150000..150100, [0u8; 300]);
+    updater
+      .update(&mut Cursor::new(&data), 150000, 100, 300)
+      .unwrap();
+    assert_eq!(updater.finish(), calculate(&data, 3).unwrap());
+
+    // Older signatures don't store the level.
+    let mut old = calculate(&data, 1).unwrap();
+    old.version = 3;
+    let mut serialized = Vec::new();
+    old.write(&mut serialized).unwrap();
+    assert_eq!(Signature::load(&serialized).unwrap(), old);
+  }
+
+  #[test]
+  fn test_signature_calculate_from() {
+    use crate::HashAlgorithm;
+    use std::io::{Cursor, Read};
+
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let calculate = |mut source: &mut dyn Read| {
+      Signature::calculate_from(
+        &mut source,
+        1024,
+        4096,
+        16384,
+        HashAlgorithm::Blake3,
+        super::DEFAULT_NORMALIZATION,
+        super::DEFAULT_HASH_BYTES,
+        super::Limits::default(),
+      )
+      .unwrap()
+    };
+
+    let mut cursor = Cursor::new(&data);
+    let (sig, read) = calculate(&mut cursor);
+    assert_eq!(read, cursor.get_ref().len() as u64);
+    assert_eq!(read, sig.file_size());
+
+    // A reader without a known size, returning the data in small pieces.
+    let (piped, read) = calculate(&mut data.chunks(1000).map(Cursor::new).fold(
+      Box::new(std::io::empty()) as Box<dyn Read>,
+      |reader, chunk| Box::new(reader.chain(chunk)),
+    ));
+    assert_eq!(read, data.len() as u64);
+    assert_eq!(piped, sig);
+  }
+
+  #[test]
+  fn test_deterministic_serialization() {
+    use crate::HashAlgorithm;
+    use std::io::{Cursor, Read};
+    use std::thread;
+
+    let data: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let serialize = |mut source: &mut dyn Read, hash_algorithm| {
+      let (sig, _) = Signature::calculate_from(
+        &mut source,
+        1024,
+        4096,
+        16384,
+        hash_algorithm,
+        super::DEFAULT_NORMALIZATION,
+        16,
+        super::Limits::default(),
+      )
+      .unwrap();
+      let mut serialized = Vec::new();
+      sig.write(&mut serialized).unwrap();
+      serialized
+    };
+
+    for hash_algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+      // Concurrent runs reading the data in different pieces.
+      let (whole, piped) = thread::scope(|scope| {
+        let whole = scope.spawn(|| serialize(&mut Cursor::new(&data), hash_algorithm));
+        let piped = scope.spawn(|| {
+          let mut reader = data.chunks(777).map(Cursor::new).fold(
+            Box::new(std::io::empty()) as Box<dyn Read>,
+            |reader, chunk| Box::new(reader.chain(chunk)),
+          );
+          serialize(&mut reader, hash_algorithm)
+        });
+        (whole.join().unwrap(), piped.join().unwrap())
+      });
+      assert!(whole == piped);
+      assert!(whole == serialize(&mut Cursor::new(&data), hash_algorithm));
+    }
+  }
+
+  #[test]
+  fn test_chunk_size_histogram_clamping() {
+    use super::{Chunk, DEFAULT_MAX_CLAMPED_FRACTION};
+    use std::io::Cursor;
+
+    let random: Vec<u8> = (0..1005000).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&random), 1024, 4096, 16384).unwrap();
+    assert_eq!(
+      sig
+        .chunk_size_histogram(DEFAULT_MAX_CLAMPED_FRACTION)
+        .warning,
+      None
+    );
+
+    // Zeros never match the cut mask, so every chunk is cut at max_size.
+    let zeros = vec![0u8; 1005000];
+    let sig = Signature::calculate(&mut Cursor::new(&zeros), 1024, 4096, 16384).unwrap();
+    let histogram = sig.chunk_size_histogram(DEFAULT_MAX_CLAMPED_FRACTION);
+    assert_eq!(histogram.at_max_size, histogram.chunk_count - 1);
+    assert!(histogram.warning.unwrap().contains("cut at max_size 16384"));
+
+    let mut sig = sig;
+    // Six of ten chunks are cut at min_size.
+    sig.chunks.clear();
+    for length in [1024, 1024, 4096, 1024, 1024, 4096, 1024, 1024, 4096, 4096] {
+      sig.chunks.push(Chunk {
+        hash: [0u8; 32].into(),
+        offset: sig.file_size(),
+        length,
+      });
+    }
+    let histogram = sig.chunk_size_histogram(DEFAULT_MAX_CLAMPED_FRACTION);
+    assert_eq!(histogram.at_min_size, 6);
+    assert!(histogram.warning.unwrap().contains("cut at min_size 1024"));
+    assert_eq!(sig.chunk_size_histogram(0.7).warning, None);
+  }
+
+  #[test]
+  fn test_load_malformed_signatures() {
+    use std::io::Cursor;
+    let data: Vec<u8> = (0..50000).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+    let mut serialized = Vec::new();
+    sig.write(&mut serialized).unwrap();
+
+    // Every truncation is rejected.
+    for len in 0..serialized.len() {
+      assert!(Signature::load(&serialized[..len]).is_err(), "{}", len);
+    }
+
+    // So are random bytes, no matter the version.
+    for _ in 0..10000 {
+      let len = rand::random::<usize>() % 200;
+      let random: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+      let _ = Signature::load(&random);
+    }
+
+    // VERSION, HASH ALGORITHM, NORMALIZATION, HASH BYTES, FIXED BLOCK SIZE, WEAK CHECKSUMS, MIN,
+    // AVG and MAX SIZE, NUMCHUNKS.
+    let with = |start: usize, bytes: &[u8]| {
+      let mut malformed = serialized.clone();
+      malformed[start..start + bytes.len()].copy_from_slice(bytes);
+      Signature::load(&malformed).unwrap_err().to_string()
+    };
+    assert_eq!(with(1, &[7]), "unknown hash algorithm 7");
+    assert!(with(2, &[9]).contains("normalization"));
+    assert!(with(4, &1u32.to_be_bytes()).contains("fixed_block_size"));
+    assert!(with(17, &0u32.to_be_bytes()).contains("max_size"));
+    assert!(with(9, &16384u32.to_be_bytes()).contains("min_size"));
+    assert_eq!(
+      with(21, &u64::MAX.to_be_bytes()),
+      format!(
+        "signature is truncated, it has room for {} of its {} chunks",
+        sig.chunks.len(),
+        u64::MAX
+      )
+    );
+    assert!(with(29 + 32, &u64::MAX.to_be_bytes()).contains("ends past"));
+  }
+
+  #[test]
+  fn test_chunk_size_histogram_few_chunks() {
+    use super::DEFAULT_MAX_CLAMPED_FRACTION;
+    use std::io::Cursor;
+
+    let small: Vec<u8> = (0..2048).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&small), 65536, 131072, 262144).unwrap();
+    let histogram = sig.chunk_size_histogram(DEFAULT_MAX_CLAMPED_FRACTION);
+    assert_eq!(histogram.chunk_count, 1);
+    assert_eq!(
+      histogram.warning.unwrap(),
+      "the whole input of 2048 bytes is 1 chunk with min_size 65536 and avg_size 131072, so diffs \
+       of it copy all or nothing, consider a smaller min_size and avg_size"
+    );
+
+    let sig = Signature::calculate(&mut Cursor::new(&small), 64, 256, 1024).unwrap();
+    assert_eq!(
+      sig
+        .chunk_size_histogram(DEFAULT_MAX_CLAMPED_FRACTION)
+        .warning,
+      None
+    );
+  }
+
+  #[test]
+  fn test_extend() {
+    use std::io::Cursor;
+
+    let calculate =
+      |data: &[u8]| Signature::calculate(&mut Cursor::new(data), 1024, 4096, 16384).unwrap();
+    let mut data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut sig = calculate(&data);
+
+    for appended in [30000, 1, 0, 70000] {
+      data.extend((0..appended).map(|_| rand::random::<u8>()));
+      sig.extend(&mut Cursor::new(&data)).unwrap();
+      let expected = calculate(&data);
+      assert_eq!(sig, expected);
+      let offsets = |sig: &Signature| {
+        sig
+          .chunks
+          .iter()
+          .map(|chunk| chunk.offset)
+          .collect::<Vec<_>>()
+      };
+      assert_eq!(offsets(&sig), offsets(&expected));
+    }
+
+    let mut empty = calculate(&[]);
+    empty.extend(&mut Cursor::new(&data)).unwrap();
+    assert_eq!(empty, calculate(&data));
+
+    let err = sig.extend(&mut Cursor::new(&data[..1000])).unwrap_err();
+    assert!(err.to_string().contains("wasn't only appended to"));
+  }
+
+  #[test]
+  fn test_fixed_blocks() {
+    use crate::apply;
+    use crate::diff::{check_chunking, write_diff_between};
+    use crate::{DiffOptions, HashAlgorithm};
+    use std::io::Cursor;
+
+    let calculate = |data: &[u8]| {
+      Signature::calculate_fixed(
+        &mut Cursor::new(data),
+        1000,
+        HashAlgorithm::Blake3,
+        super::DEFAULT_HASH_BYTES,
+        true,
+      )
+      .unwrap()
+    };
+    let source: Vec<u8> = (0..10500).map(|_| rand::random::<u8>()).collect();
+    let sig1 = calculate(&source);
+
+    assert_eq!(sig1.fixed_block_size, Some(1000));
+    assert_eq!(sig1.chunks.len(), 11);
+    for (i, chunk) in sig1.chunks.iter().enumerate() {
+      assert_eq!(chunk.offset, i as u64 * 1000);
+      assert_eq!(chunk.length, if i == 10 { 500 } else { 1000 });
+      let data = &source[chunk.offset as usize..chunk.offset as usize + chunk.length];
+      assert_eq!(chunk.hash, blake3::hash(data));
+      assert_eq!(
+        sig1.weak_checksums.as_ref().unwrap()[i],
+        crate::weak_match::checksum(data)
+      );
+    }
+
+    let mut serialized = Vec::new();
+    sig1.write(&mut serialized).unwrap();
+    assert_eq!(Signature::load(&serialized).unwrap(), sig1);
+
+    // Only the overwritten block and the appended data are inserted.
+    let mut target = source.clone();
+    target[2500..2600].fill(7);
+    target.extend_from_slice(&[9; 700]);
+    let sig2 = calculate(&target);
+    let mut diff = Vec::new();
+    write_diff_between(
+      &sig1,
+      &sig2,
+      &mut Cursor::new(&target),
+      &mut diff,
+      &DiffOptions::default(),
+    )
+    .unwrap();
+    let mut dest = Cursor::new(Vec::new());
+    let report = apply::apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut dest,
+    )
+    .unwrap();
+    assert_eq!(dest.into_inner(), target);
+    assert_eq!(report.insert_ops, 2);
+
+    let cdc = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let err = check_chunking(&sig1, &cdc).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "signatures are calculated with different blocking: fixed 1000-byte blocks and \
+       content-defined chunks"
+    );
+
+    let mut updater = super::SignatureUpdater::new(sig1);
+    updater
+      .update(&mut Cursor::new(&target), 2500, 100, 100)
+      .unwrap();
+    updater
+      .update(&mut Cursor::new(&target), 10500, 0, 700)
+      .unwrap();
+    let updated = updater.finish();
+    assert_eq!(updated, sig2);
+  }
+
+  #[test]
+  fn test_progress() {
+    use super::ProgressReader;
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    for data in [&data[..], &data[..1000], &[]] {
+      let mut reports = Vec::new();
+      let mut progress = |read, percent| reports.push((read, percent));
+      let mut reader = ProgressReader::new(Cursor::new(data), data.len() as u64, &mut progress);
+      let sig = Signature::calculate(&mut reader, 1024, 4096, 16384).unwrap();
+      reader.finish();
+
+      assert_eq!(
+        sig,
+        Signature::calculate(&mut Cursor::new(data), 1024, 4096, 16384).unwrap()
+      );
+      assert_eq!(reports.last(), Some(&(data.len() as u64, 100)));
+      assert!(reports
+        .windows(2)
+        .all(|pair| pair[0].0 <= pair[1].0 && pair[0].1 < pair[1].1));
+      assert!(reports.len() <= 101);
+    }
+  }
+
+  #[test]
+  fn test_is_contiguous() {
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut sig = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+    assert!(sig.is_contiguous());
+    assert!(
+      Signature::calculate(&mut Cursor::new(&[]), 1024, 4096, 16384)
+        .unwrap()
+        .is_contiguous()
+    );
+
+    // A chunk spliced out leaves a gap.
+    let removed = sig.chunks.remove(2);
+    assert!(!sig.is_contiguous());
+    let err = sig.extend(&mut Cursor::new(&data)).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      format!(
+        "signature chunks aren't contiguous, a chunk starts at offset {} instead of {}",
+        removed.offset + removed.length as u64,
+        removed.offset
+      )
+    );
+    let mut updater = super::SignatureUpdater::new(sig);
+    assert!(updater.update(&mut Cursor::new(&data), 0, 0, 0).is_err());
+  }
+
+  #[test]
+  fn test_convert() {
+    use super::{DEFAULT_NORMALIZATION, VERSION};
+    use crate::HashAlgorithm;
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+    let write = |sig: &Signature| {
+      let mut dest = Vec::new();
+      sig.write(&mut dest).unwrap();
+      dest
+    };
+
+    let mut old = Signature::load(&write(&sig)).unwrap();
+    old.convert(0).unwrap();
+    let serialized = write(&old);
+    // VERSION, MIN, AVG, MAX, NUMCHUNKS, then all chunks with whole hashes.
+    assert_eq!(serialized[0], 0);
+    assert_eq!(serialized.len(), 21 + sig.chunks.len() * 48);
+    assert!(serialized.len() < write(&sig).len());
+
+    let mut new = Signature::load(&serialized).unwrap();
+    new.convert(VERSION.into()).unwrap();
+    assert_eq!(new, sig);
+    assert_eq!(write(&new), write(&sig));
+
+    let truncated = Signature::calculate_with(
+      &mut Cursor::new(&data),
+      1024,
+      4096,
+      16384,
+      HashAlgorithm::Blake3,
+      DEFAULT_NORMALIZATION,
+      16,
+    )
+    .unwrap();
+    let mut old = Signature::load(&write(&truncated)).unwrap();
+    let err = old.convert(4).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "can't convert the signature to version 4, which doesn't store truncated chunk hashes"
+    );
+    assert_eq!(old, truncated);
+    assert!(old.convert(u32::from(VERSION) + 1).is_err());
+    assert!(old.convert(1000).is_err());
+  }
+
+  #[test]
+  fn test_signature_reader() {
+    use super::SignatureReader;
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..4_000_000).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&data), 256, 1024, 4096).unwrap();
+    let mut serialized = Vec::new();
+    sig.write(&mut serialized).unwrap();
+
+    let reader = SignatureReader::new(&serialized).unwrap();
+    assert_eq!(reader.len(), sig.chunks.len());
+    assert_eq!(reader.header().avg_size, 1024);
+    assert!(reader.header().chunks.is_empty());
+    let chunks: Vec<_> = reader.map(Result::unwrap).collect();
+    assert_eq!(chunks, Signature::load(&serialized).unwrap().chunks);
+
+    // Chunks are only checked once they're read. The last chunk ends before the member count.
+    let last = serialized.len() - 16;
+    serialized[last..last + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+    let mut reader = SignatureReader::new(&serialized).unwrap();
+    assert!(reader
+      .by_ref()
+      .take(sig.chunks.len() - 1)
+      .all(|chunk| chunk.is_ok()));
+    assert!(reader.next().unwrap().is_err());
+    assert!(reader.next().is_none());
+    assert!(Signature::load(&serialized).is_err());
+  }
+
+  #[test]
+  fn test_archive_members() {
+    use super::{SignatureUpdater, DEFAULT_HASH_BYTES, DEFAULT_NORMALIZATION};
+    use crate::diff::{diff_signatures, Operation};
+    use crate::HashAlgorithm;
+    use std::io::Cursor;
+
+    let random = |len: usize| -> Vec<u8> { (0..len).map(|_| rand::random::<u8>()).collect() };
+    let members = |data: &[u8], boundaries: &[u64]| {
+      Signature::calculate_members(
+        &mut Cursor::new(data),
+        boundaries,
+        1024,
+        4096,
+        16384,
+        HashAlgorithm::default(),
+        DEFAULT_NORMALIZATION,
+        DEFAULT_HASH_BYTES,
+      )
+    };
+    let (first, last) = (random(60000), random(60000));
+    let source = [first.clone(), random(60000), last.clone()].concat();
+    let target = [first, random(70000), last].concat();
+
+    let a = members(&source, &[60000, 120000]).unwrap();
+    let b = members(&target, &[60000, 130000]).unwrap();
+    assert!(b.chunks.iter().any(|chunk| chunk.offset == 130000));
+    assert!(b.is_contiguous());
+
+    // Only the changed member is inserted, the chunks of the others still match.
+    let ops = diff_signatures(&a, &b);
+    for (op, offset, size) in &ops {
+      if *op == Operation::Insert {
+        assert!(
+          *offset >= 60000 && offset + size <= 130000,
+          "{} {}",
+          offset,
+          size
+        );
+      }
+    }
+    let copied: u64 = ops
+      .iter()
+      .filter(|(op, _, _)| *op == Operation::Copy)
+      .map(|(_, _, size)| size)
+      .sum();
+    assert_eq!(copied, 120000);
+
+    let mut serialized = Vec::new();
+    b.write(&mut serialized).unwrap();
+    let loaded = Signature::load(&serialized).unwrap();
+    assert_eq!(loaded.boundaries, vec![60000, 130000]);
+    assert_eq!(loaded, b);
+
+    assert!(members(&source, &[120000, 60000]).is_err());
+    assert!(members(&source, &[60000, 60000]).is_err());
+    let err = members(&source, &[60000, 200000]).unwrap_err();
+    assert!(err.to_string().contains("200000"), "{}", err);
+
+    let err = SignatureUpdater::new(b)
+      .update(&mut Cursor::new(&target), 0, 10, 10)
+      .unwrap_err();
+    assert!(err.to_string().contains("archive members"), "{}", err);
+  }
+
+  #[test]
+  fn test_write_ndjson() {
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let sig = Signature::calculate(&mut Cursor::new(&data), 1024, 4096, 16384).unwrap();
+    let mut dest = Vec::new();
+    sig.write_ndjson(&mut dest).unwrap();
+
+    let lines: Vec<&str> = std::str::from_utf8(&dest).unwrap().lines().collect();
+    assert_eq!(lines.len(), sig.chunks.len());
+    let mut offset = 0;
+    for (line, chunk) in lines.iter().zip(sig.chunks.iter()) {
+      let record: serde_json::Value = serde_json::from_str(line).unwrap();
+      assert_eq!(record["offset"], offset);
+      assert_eq!(record["length"], chunk.length);
+      assert_eq!(record["hash"], chunk.hash.to_hex().as_str());
+      offset += record["length"].as_u64().unwrap();
+    }
+    assert_eq!(offset, data.len() as u64);
+  }
+
+  #[test]
+  fn test_calculate_limits() {
+    use super::{Limits, DEFAULT_HASH_BYTES, DEFAULT_NORMALIZATION};
+    use crate::HashAlgorithm;
+    use std::io::{self, Read};
+
+    let calculate = |source: &mut dyn Read, limits| {
+      Signature::calculate_from(
+        &mut &mut *source,
+        1024,
+        4096,
+        16384,
+        HashAlgorithm::Blake3,
+        DEFAULT_NORMALIZATION,
+        DEFAULT_HASH_BYTES,
+        limits,
+      )
+    };
+    // Never ends, like a pipe or a device.
+    let endless = || io::repeat(0);
+    let err = calculate(
+      &mut endless(),
+      Limits {
+        max_chunks: Some(5),
+        ..Default::default()
+      },
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "the source has more than 5 chunks");
+    let err = calculate(
+      &mut endless(),
+      Limits {
+        max_bytes: Some(100000),
+        ..Default::default()
+      },
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "the source is larger than 100000 bytes");
+
+    let err = Signature::calculate_blocks_from(
+      &mut endless(),
+      1024,
+      HashAlgorithm::Blake3,
+      DEFAULT_HASH_BYTES,
+      false,
+      Limits {
+        max_chunks: Some(5),
+        ..Default::default()
+      },
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "the source has more than 5 chunks");
+
+    // Sources within the limits are chunked as without them.
+    let data: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    let (sig, _) = calculate(
+      &mut &data[..],
+      Limits {
+        max_bytes: Some(100000),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    let limits = Limits {
+      max_chunks: Some(sig.chunks.len()),
+      ..Default::default()
+    };
+    assert_eq!(calculate(&mut &data[..], limits).unwrap().0, sig);
+  }
+
+  #[test]
+  fn test_combine_ranges() {
+    use crate::HashAlgorithm;
+    use std::io::Cursor;
+
+    let data: Vec<u8> = (0..500000).map(|_| rand::random::<u8>()).collect();
+    let calculate = |data: &[u8]| Signature::calculate(&mut Cursor::new(data), 1024, 4096, 16384);
+    let whole = calculate(&data).unwrap();
+
+    // Ranges overlapping by a few maximum chunk sizes are cut like the whole file after the seam.
+    let ranges = [(0, 200000), (150000, 400000), (350000, 500000)];
+    let parts = |ranges: &[(usize, usize)]| {
+      ranges
+        .iter()
+        .map(|&(start, end)| (start as u64, calculate(&data[start..end]).unwrap()))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(Signature::combine_ranges(parts(&ranges)).unwrap(), whole);
+
+    let blocks = |data: &[u8]| {
+      Signature::calculate_fixed(
+        &mut Cursor::new(data),
+        4096,
+        HashAlgorithm::Blake3,
+        32,
+        true,
+      )
+      .unwrap()
+    };
+    let combined = Signature::combine_ranges(vec![
+      (0, blocks(&data[..204800])),
+      (204800, blocks(&data[204800..])),
+    ])
+    .unwrap();
+    assert_eq!(combined, blocks(&data));
+
+    let err = Signature::combine_ranges(parts(&[(0, 200000), (200000, 500000)])).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the range at offset 200000 has no chunk boundary in common with the ranges before it, \
+       which end at 200000, they must overlap more"
+    );
+    let err = Signature::combine_ranges(parts(&[(0, 200000), (250000, 500000)])).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the range at offset 250000 starts after the ranges before it end at 200000"
+    );
+    let err = Signature::combine_ranges(vec![
+      (0, calculate(&data[..200000]).unwrap()),
+      (150000, blocks(&data[150000..])),
+    ])
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "the signature of the range at offset 150000 has other options than the first one"
+    );
   }
 }
@@ -0,0 +1,393 @@
+//! Diff and apply over arbitrary readers and writers, e.g. to send a diff as an HTTP response
+//! body without a temporary file.
+
+use crate::apply::{self, Preallocate};
+use crate::diff;
+use crate::signature::Signature;
+use crate::DiffOptions;
+
+use futures::io::AsyncRead;
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Writes a diff transforming the file with the serialized signature `source_sig` to the
+/// `target_data` with the serialized signature `target_sig` to the `writer`.
+///
+/// `target_data` must be `Read + Seek` because the inserted chunks are read from it by their
+/// offsets. The `writer` is only written to sequentially.
+pub fn stream_diff<R, W>(
+  source_sig: &[u8],
+  target_sig: &[u8],
+  target_data: &mut R,
+  writer: &mut W,
+) -> Result<(), Box<dyn Error>>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  let source_signature = Signature::load(source_sig)?;
+  let target_signature = Signature::load(target_sig)?;
+  diff::check_chunking(&source_signature, &target_signature)?;
+
+  diff::write_diff_between(
+    &source_signature,
+    &target_signature,
+    target_data,
+    writer,
+    &DiffOptions::default(),
+  )?;
+
+  Ok(())
+}
+
+/// Applies the diff read from `diff_reader` to the `source` and writes the result to the `writer`.
+///
+/// The `writer` must be `Read + Seek` as well, because diffs may copy data already written to the
+/// output.
+pub fn stream_apply<D, S, W>(
+  diff_reader: &mut D,
+  source: &mut S,
+  writer: &mut W,
+) -> Result<(), Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  W: Read + Write + Seek,
+{
+  apply::apply(diff_reader, source, &mut Unallocated(writer))?;
+
+  Ok(())
+}
+
+/// Applies the diff read from the `diff_reader` stream to the `source` as it arrives, e.g. while
+/// it's being downloaded, and writes the result to the `writer`.
+///
+/// Unlike `stream_apply`, the `diff_reader` is only read sequentially, so compressed and signed
+/// diffs aren't supported. The `writer` must be `Read + Seek` for the same reason as there.
+pub async fn apply_streaming<D, S, W>(
+  diff_reader: &mut D,
+  source: &mut S,
+  writer: &mut W,
+) -> Result<(), Box<dyn Error>>
+where
+  D: AsyncRead + Unpin,
+  S: Read + Seek,
+  W: Read + Write + Seek,
+{
+  apply::apply_streaming(diff_reader, source, &mut Unallocated(writer)).await?;
+
+  Ok(())
+}
+
+/// Applies the diff read from `diff_reader` to the `source` and writes the result to the `writer`
+/// front to back, e.g. to an HTTP response body, which can't be read back.
+///
+/// The diff is read twice: first to find the parts of the result it copies from, which are kept in
+/// memory as they're written, then to apply it. Compressed diffs are decompressed both times.
+pub fn apply_to_writer<D, S, W>(
+  diff_reader: &mut D,
+  source: &mut S,
+  writer: &mut W,
+) -> Result<(), Box<dyn Error>>
+where
+  D: Read + Seek,
+  S: Read + Seek,
+  W: Write,
+{
+  let reads = apply::output_reads(diff_reader, None)?;
+  let mut dest = Sequential {
+    writer,
+    len: 0,
+    position: 0,
+    kept: reads
+      .into_iter()
+      .map(|(start, size)| (start, size, Vec::new()))
+      .collect(),
+  };
+  apply::apply(diff_reader, source, &mut dest)?;
+  dest.flush()?;
+
+  Ok(())
+}
+
+/// A destination passing the data written to it on to a writer which is only written to
+/// sequentially. Reads are served from the `kept` regions.
+struct Sequential<'a, W> {
+  writer: &'a mut W,
+  /// The number of bytes written.
+  len: u64,
+  position: u64,
+  /// The start, size and the data written so far of every region of the output which is read
+  /// later, in order.
+  kept: Vec<(u64, u64, Vec<u8>)>,
+}
+
+impl<W> Preallocate for Sequential<'_, W> {
+  fn preallocate(&mut self, _size: u64) -> Result<(), io::Error> {
+    Ok(())
+  }
+}
+
+impl<W> Read for Sequential<'_, W> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let position = self.position;
+    let index = self
+      .kept
+      .partition_point(|(start, size, _)| start + size <= position);
+    let data = match self.kept.get(index) {
+      Some((start, _, data)) if *start <= position => &data[(position - start) as usize..],
+      _ => &[][..],
+    };
+    if data.is_empty() && !buf.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("the output at {} isn't kept to be read back", position),
+      ));
+    }
+
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    self.position += len as u64;
+
+    Ok(len)
+  }
+}
+
+impl<W: Write> Write for Sequential<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.position != self.len {
+      return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the output can only be written sequentially",
+      ));
+    }
+
+    let written = self.writer.write(buf)?;
+    let (from, to) = (self.len, self.len + written as u64);
+    let first = self
+      .kept
+      .partition_point(|(start, size, _)| start + size <= from);
+    for (start, size, data) in self.kept[first..].iter_mut() {
+      if *start >= to {
+        break;
+      }
+      let kept_from = from.max(*start);
+      let kept_to = to.min(*start + *size);
+      data.extend_from_slice(&buf[(kept_from - from) as usize..(kept_to - from) as usize]);
+    }
+    self.len = to;
+    self.position = to;
+
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.writer.flush()
+  }
+}
+
+impl<W> Seek for Sequential<'_, W> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let position = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+    };
+    self.position = position.ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+      )
+    })?;
+
+    Ok(self.position)
+  }
+}
+
+/// A writer without a known way to preallocate it.
+struct Unallocated<'a, W>(&'a mut W);
+
+impl<W> Preallocate for Unallocated<'_, W> {
+  fn preallocate(&mut self, _size: u64) -> Result<(), io::Error> {
+    Ok(())
+  }
+}
+
+impl<W: Read> Read for Unallocated<'_, W> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.0.read(buf)
+  }
+}
+
+impl<W: Write> Write for Unallocated<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.flush()
+  }
+}
+
+impl<W: Seek> Seek for Unallocated<'_, W> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.0.seek(pos)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::signature::Signature;
+
+  use std::io::Cursor;
+
+  #[test]
+  fn test_stream_diff_into_stream_apply() {
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..300).map(|_| rand::random::<u8>()));
+
+    let signature = |data: &[u8]| {
+      let mut sig = Vec::new();
+      Signature::calculate(&mut Cursor::new(data), 1024, 4096, 16384)
+        .unwrap()
+        .write(&mut sig)
+        .unwrap();
+      sig
+    };
+
+    let mut diff = Vec::new();
+    super::stream_diff(
+      &signature(&source),
+      &signature(&target),
+      &mut Cursor::new(&target),
+      &mut diff,
+    )
+    .unwrap();
+
+    let mut result = Cursor::new(Vec::new());
+    super::stream_apply(
+      &mut Cursor::new(&diff),
+      &mut Cursor::new(&source),
+      &mut result,
+    )
+    .unwrap();
+    assert_eq!(result.into_inner(), target);
+  }
+
+  #[test]
+  fn test_apply_streaming() {
+    use crate::diff::write_diff_between;
+    use crate::DiffOptions;
+    use futures::TryStreamExt;
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..30000).map(|_| rand::random::<u8>()));
+    // Repeats inserted data, so the diff copies from the output.
+    target.extend_from_within(55000..75000);
+
+    let source_sig = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let target_sig = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    let diff = |options| {
+      let mut diff = Vec::new();
+      write_diff_between(
+        &source_sig,
+        &target_sig,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &options,
+      )
+      .unwrap();
+      diff
+    };
+    // The diff arrives in small pieces splitting the operations.
+    let apply_streaming = |diff: Vec<u8>| {
+      let pieces: Vec<std::io::Result<Vec<u8>>> = diff.chunks(7).map(|p| Ok(p.to_vec())).collect();
+      let mut result = Cursor::new(Vec::new());
+      futures::executor::block_on(super::apply_streaming(
+        &mut futures::stream::iter(pieces).into_async_read(),
+        &mut Cursor::new(&source),
+        &mut result,
+      ))
+      .map(|_| result.into_inner())
+    };
+
+    let plain = diff(DiffOptions::default());
+    let mut expected = Cursor::new(Vec::new());
+    super::stream_apply(
+      &mut Cursor::new(&plain),
+      &mut Cursor::new(&source),
+      &mut expected,
+    )
+    .unwrap();
+    assert_eq!(expected.get_ref(), &target);
+    assert_eq!(apply_streaming(plain).unwrap(), expected.into_inner());
+
+    let compressed = diff(DiffOptions {
+      compress: Some(true),
+      ..Default::default()
+    });
+    assert!(apply_streaming(compressed).is_err());
+  }
+
+  #[test]
+  fn test_apply_to_writer() {
+    use crate::diff::write_diff_between;
+    use crate::DiffOptions;
+    use std::io::{self, Write};
+    use std::time::Duration;
+
+    /// Takes a few bytes at a time, slowly, like a stream waiting to drain.
+    struct Slow(Vec<u8>);
+
+    impl Write for Slow {
+      fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        std::thread::sleep(Duration::from_micros(50));
+        let len = buf.len().min(1000);
+        self.0.extend_from_slice(&buf[..len]);
+        Ok(len)
+      }
+
+      fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+      }
+    }
+
+    let source: Vec<u8> = (0..100500).map(|_| rand::random::<u8>()).collect();
+    let mut target = source.clone();
+    target.splice(50000..50100, (0..30000).map(|_| rand::random::<u8>()));
+    // Repeats inserted data, so the diff copies from the output.
+    target.extend_from_within(55000..75000);
+    target.extend_from_within(60000..70000);
+
+    let source_sig = Signature::calculate(&mut Cursor::new(&source), 1024, 4096, 16384).unwrap();
+    let target_sig = Signature::calculate(&mut Cursor::new(&target), 1024, 4096, 16384).unwrap();
+    for compress in [false, true] {
+      let mut diff = Vec::new();
+      write_diff_between(
+        &source_sig,
+        &target_sig,
+        &mut Cursor::new(&target),
+        &mut diff,
+        &DiffOptions {
+          compress: Some(compress),
+          ..Default::default()
+        },
+      )
+      .unwrap();
+      assert!(!crate::apply::output_reads(&mut Cursor::new(&diff), None)
+        .unwrap()
+        .is_empty());
+
+      let mut writer = Slow(Vec::new());
+      super::apply_to_writer(
+        &mut Cursor::new(&diff),
+        &mut Cursor::new(&source),
+        &mut writer,
+      )
+      .unwrap();
+      assert_eq!(writer.0, target);
+    }
+  }
+}
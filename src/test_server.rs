@@ -0,0 +1,288 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub(crate) struct TestServer {
+  pub url: String,
+  /// Heads of all received requests: the request line followed by the headers.
+  pub requests: Arc<Mutex<Vec<String>>>,
+  /// The number of accepted connections.
+  pub connections: Arc<AtomicUsize>,
+}
+
+pub(crate) struct Request {
+  /// The requested range when exactly one is requested.
+  pub range: Option<(u64, u64)>,
+  /// All requested ranges.
+  pub ranges: Vec<(u64, u64)>,
+  /// The value of the Authorization header.
+  pub authorization: Option<String>,
+}
+
+impl Request {
+  fn new(range_header: Option<&str>, authorization: Option<String>) -> Self {
+    let ranges: Vec<(u64, u64)> = range_header
+      .map(|value| {
+        value
+          .trim()
+          .strip_prefix("bytes=")
+          .unwrap()
+          .split(',')
+          .map(|range| {
+            let (start, end) = range.trim().split_once('-').unwrap();
+            (start.parse::<u64>().unwrap(), end.parse::<u64>().unwrap())
+          })
+          .collect()
+      })
+      .unwrap_or_default();
+
+    Self {
+      range: match ranges[..] {
+        [range] => Some(range),
+        _ => None,
+      },
+      ranges,
+      authorization,
+    }
+  }
+}
+
+pub(crate) struct Response {
+  pub status: &'static str,
+  pub headers: Vec<(&'static str, String)>,
+  pub body: Vec<u8>,
+}
+
+/// Answers with the requested range of `data`, or with the whole `data` when no range is
+/// requested.
+pub(crate) fn range_response(data: &[u8], request: &Request) -> Response {
+  match request.range {
+    Some((start, end)) => Response {
+      status: "206 Partial Content",
+      headers: vec![(
+        "Content-Range",
+        format!("bytes {}-{}/{}", start, end, data.len()),
+      )],
+      body: data[start as usize..=end as usize].to_vec(),
+    },
+    None => Response {
+      status: "200 OK",
+      headers: vec![("Accept-Ranges", "bytes".to_string())],
+      body: data.to_vec(),
+    },
+  }
+}
+
+/// Answers with the `ranges` of `data` as `multipart/byteranges`.
+pub(crate) fn multipart_response(data: &[u8], ranges: &[(u64, u64)]) -> Response {
+  let mut body = Vec::new();
+  for &(start, end) in ranges {
+    body.extend_from_slice(
+      format!(
+        "\r\n--BOUNDARY\r\nContent-Type: application/octet-stream\r\n\
+         Content-Range: bytes {}-{}/{}\r\n\r\n",
+        start,
+        end,
+        data.len()
+      )
+      .as_bytes(),
+    );
+    body.extend_from_slice(&data[start as usize..=end as usize]);
+  }
+  body.extend_from_slice(b"\r\n--BOUNDARY--\r\n");
+
+  Response {
+    status: "206 Partial Content",
+    headers: vec![(
+      "Content-Type",
+      "multipart/byteranges; boundary=BOUNDARY".to_string(),
+    )],
+    body,
+  }
+}
+
+/// Serves `data` on a random local port. Every response for a range starting at `start` is
+/// delayed by `delay(start)`.
+pub(crate) fn serve<F>(data: Vec<u8>, delay: F) -> TestServer
+where
+  F: Fn(u64) -> Duration + Send + Sync + 'static,
+{
+  serve_with(move |request| {
+    if let Some((start, _)) = request.range {
+      thread::sleep(delay(start));
+    }
+
+    range_response(&data, request)
+  })
+}
+
+/// Serves responses built by `handler` on a random local port.
+pub(crate) fn serve_with<H>(handler: H) -> TestServer
+where
+  H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+  listen("http", move |stream, requests| {
+    handle(stream, &handler, requests)
+  })
+}
+
+/// Serves `data` over TLS on a random local port using `identity` as the server certificate.
+pub(crate) fn serve_tls(data: Vec<u8>, identity: native_tls::Identity) -> TestServer {
+  let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+  let handler = move |request: &Request| range_response(&data, request);
+
+  listen("https", move |stream, requests| {
+    if let Ok(stream) = acceptor.accept(stream) {
+      handle(stream, &handler, requests);
+    }
+  })
+}
+
+/// Serves `data` over HTTP/2 with prior knowledge on a random local port.
+pub(crate) fn serve_h2(data: Vec<u8>) -> TestServer {
+  let listener = TcpListener::bind("127.0.0.1:0").expect("can't bind the test server");
+  listener.set_nonblocking(true).unwrap();
+  let addr = listener.local_addr().unwrap();
+  let data = Arc::new(data);
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let connections = Arc::new(AtomicUsize::new(0));
+  let server_requests = requests.clone();
+  let server_connections = connections.clone();
+
+  thread::spawn(move || {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+      .enable_io()
+      .build()
+      .unwrap();
+    runtime.block_on(async move {
+      let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+      while let Ok((stream, _)) = listener.accept().await {
+        server_connections.fetch_add(1, Ordering::SeqCst);
+        let data = data.clone();
+        let requests = server_requests.clone();
+
+        tokio::spawn(async move {
+          let Ok(mut connection) = h2::server::handshake(stream).await else {
+            return;
+          };
+          while let Some(Ok((request, mut respond))) = connection.accept().await {
+            let range_header = request
+              .headers()
+              .get("range")
+              .map(|value| value.to_str().unwrap().to_string());
+            let authorization = request
+              .headers()
+              .get("authorization")
+              .map(|value| value.to_str().unwrap().to_string());
+            requests
+              .lock()
+              .unwrap()
+              .push(format!("{} {}", request.method(), request.uri()));
+
+            let request = Request::new(range_header.as_deref(), authorization);
+            let response = range_response(&data, &request);
+            let mut builder = http::Response::builder().status(&response.status[..3]);
+            for (name, value) in response.headers {
+              builder = builder.header(name, value);
+            }
+            let Ok(mut body) = respond.send_response(builder.body(()).unwrap(), false) else {
+              continue;
+            };
+            let _ = body.send_data(response.body.into(), true);
+          }
+        });
+      }
+    });
+  });
+
+  TestServer {
+    url: format!("http://{}", addr),
+    requests,
+    connections,
+  }
+}
+
+/// Accepts connections in a background thread and handles each of them in its own thread.
+fn listen<C>(scheme: &str, connection: C) -> TestServer
+where
+  C: Fn(TcpStream, &Mutex<Vec<String>>) + Send + Sync + 'static,
+{
+  let listener = TcpListener::bind("127.0.0.1:0").expect("can't bind the test server");
+  let addr = listener.local_addr().unwrap();
+  let connection = Arc::new(connection);
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let connections = Arc::new(AtomicUsize::new(0));
+  let server_requests = requests.clone();
+  let server_connections = connections.clone();
+
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(_) => continue,
+      };
+      server_connections.fetch_add(1, Ordering::SeqCst);
+      let connection = connection.clone();
+      let requests = server_requests.clone();
+
+      thread::spawn(move || connection(stream, &requests));
+    }
+  });
+
+  TestServer {
+    url: format!("{}://{}", scheme, addr),
+    requests,
+    connections,
+  }
+}
+
+fn handle<S, H>(mut stream: S, handler: &H, requests: &Mutex<Vec<String>>)
+where
+  S: Read + Write,
+  H: Fn(&Request) -> Response,
+{
+  let mut reader = BufReader::new(&mut stream);
+  let mut range_header = None;
+  let mut authorization = None;
+  let mut head = String::new();
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+      break;
+    }
+    head.push_str(&line);
+    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: ") {
+      range_header = Some(value.to_string());
+    }
+    if let Some((name, value)) = line.split_once(": ") {
+      if name.eq_ignore_ascii_case("authorization") {
+        authorization = Some(value.trim_end().to_string());
+      }
+    }
+  }
+  drop(reader);
+
+  requests.lock().unwrap().push(head);
+
+  let response = handler(&Request::new(range_header.as_deref(), authorization));
+  let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", response.status);
+  // Handlers may announce a longer body than they send, which breaks off the body.
+  let announced = response
+    .headers
+    .iter()
+    .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"));
+  if !announced {
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+  }
+  for (name, value) in response.headers {
+    head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  head.push_str("\r\n");
+
+  let _ = stream.write_all(head.as_bytes());
+  let _ = stream.write_all(&response.body);
+  let _ = stream.flush();
+}
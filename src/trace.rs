@@ -0,0 +1,10 @@
+//! Instrumentation with `tracing`, compiled in with the `tracing` feature only. Without it the
+//! macros expand to nothing, so the instrumentation costs nothing.
+
+/// Emits a `tracing` event at `$level`, e.g. `debug` or `trace`, with the `tracing` feature.
+macro_rules! event {
+  ($level:ident, $($arg:tt)+) => {
+    #[cfg(feature = "tracing")]
+    tracing::$level!($($arg)+);
+  };
+}
@@ -0,0 +1,203 @@
+use crate::diff::Operation;
+use crate::signature::usize_from;
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+/// The rsync rolling checksum: `a` is the sum of the window bytes and `b` is the sum of the
+/// prefix sums, both kept modulo 2^16.
+struct Rolling {
+  a: u32,
+  b: u32,
+  len: u32,
+}
+
+impl Rolling {
+  fn new(window: &[u8]) -> Self {
+    let len = window.len() as u32;
+    let mut a: u32 = 0;
+    let mut b: u32 = 0;
+    for (i, &byte) in window.iter().enumerate() {
+      a = a.wrapping_add(byte as u32);
+      b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+    }
+
+    Self { a, b, len }
+  }
+
+  /// Moves the window one byte forward.
+  fn roll(&mut self, out: u8, into: u8) {
+    self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+    self.b = self
+      .b
+      .wrapping_sub(self.len.wrapping_mul(out as u32))
+      .wrapping_add(self.a);
+  }
+
+  fn digest(&self) -> u32 {
+    (self.b << 16) | (self.a & 0xffff)
+  }
+}
+
+/// Returns the rsync rolling checksum of the whole `block`.
+pub(crate) fn checksum(block: &[u8]) -> u32 {
+  Rolling::new(block).digest()
+}
+
+/// Weak and strong hashes of the source blocks of `block_size` bytes.
+struct BlockIndex {
+  block_size: usize,
+  blocks: HashMap<u32, Vec<(u64, blake3::Hash)>>,
+}
+
+impl BlockIndex {
+  fn build<R: Read + Seek>(source: &mut R, block_size: usize) -> Result<Self, io::Error> {
+    source.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(source);
+    let mut blocks: HashMap<u32, Vec<(u64, blake3::Hash)>> = HashMap::new();
+    let mut block = vec![0; block_size];
+    let mut offset = 0;
+
+    // The last block is only indexed when it's complete.
+    while read_block(&mut reader, &mut block)? {
+      blocks
+        .entry(Rolling::new(&block).digest())
+        .or_default()
+        .push((offset, blake3::hash(&block)));
+      offset += block_size as u64;
+    }
+
+    Ok(Self { block_size, blocks })
+  }
+
+  /// Returns the source offset of a block equal to the `window`.
+  fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+    let candidates = self.blocks.get(&weak)?;
+    let strong = blake3::hash(window);
+    candidates
+      .iter()
+      .find(|(_, hash)| *hash == strong)
+      .map(|(offset, _)| *offset)
+  }
+}
+
+fn read_block<R: Read>(reader: &mut R, block: &mut [u8]) -> Result<bool, io::Error> {
+  let mut filled = 0;
+  while filled < block.len() {
+    match reader.read(&mut block[filled..])? {
+      0 => return Ok(false),
+      n => filled += n,
+    }
+  }
+
+  Ok(true)
+}
+
+/// Looks for source blocks of `block_size` bytes inside the inserted regions of `diff`, so data
+/// that is only shifted inside a chunk, e.g. after a small edit near a chunk boundary, is copied
+/// instead of inserted. Candidate blocks are found by a rolling weak hash and confirmed by Blake3.
+pub(crate) fn refine<R: Read + Seek>(
+  diff: Vec<(Operation, u64, u64)>,
+  source: &mut R,
+  target: &mut R,
+  block_size: usize,
+) -> Result<Vec<(Operation, u64, u64)>, io::Error> {
+  let index = BlockIndex::build(source, block_size)?;
+  let mut refined = Vec::with_capacity(diff.len());
+
+  for (op, offset, size) in diff {
+    if op != Operation::Insert || size < block_size as u64 {
+      push_op(&mut refined, (op, offset, size));
+      continue;
+    }
+
+    let mut data = Vec::with_capacity(usize_from(size)?);
+    target.seek(SeekFrom::Start(offset))?;
+    target.take(size).read_to_end(&mut data)?;
+
+    for matched in match_blocks(&index, &data) {
+      let op = match matched {
+        (Operation::Insert, start, len) => (Operation::Insert, offset + start, len),
+        other => other,
+      };
+      push_op(&mut refined, op);
+    }
+  }
+
+  Ok(refined)
+}
+
+/// Splits `data` into copies of source blocks and inserts of the rest, insert offsets are relative
+/// to the `data`.
+fn match_blocks(index: &BlockIndex, data: &[u8]) -> Vec<(Operation, u64, u64)> {
+  let block_size = index.block_size;
+  let mut ops = Vec::new();
+  let mut literal_start = 0;
+  let mut pos = 0;
+  let mut rolling = Rolling::new(&data[..block_size]);
+
+  loop {
+    if let Some(source_offset) = index.find(rolling.digest(), &data[pos..pos + block_size]) {
+      if literal_start < pos {
+        ops.push((
+          Operation::Insert,
+          literal_start as u64,
+          (pos - literal_start) as u64,
+        ));
+      }
+      ops.push((Operation::Copy, source_offset, block_size as u64));
+      pos += block_size;
+      literal_start = pos;
+
+      if pos + block_size > data.len() {
+        break;
+      }
+      rolling = Rolling::new(&data[pos..pos + block_size]);
+      continue;
+    }
+
+    if pos + block_size >= data.len() {
+      break;
+    }
+    rolling.roll(data[pos], data[pos + block_size]);
+    pos += 1;
+  }
+
+  if literal_start < data.len() {
+    ops.push((
+      Operation::Insert,
+      literal_start as u64,
+      (data.len() - literal_start) as u64,
+    ));
+  }
+
+  ops
+}
+
+/// Appends `op` to `ops`, merging it into the last operation when they are contiguous.
+fn push_op(ops: &mut Vec<(Operation, u64, u64)>, op: (Operation, u64, u64)) {
+  if let Some(last) = ops.last_mut() {
+    if last.0 == op.0 && last.1 + last.2 == op.1 {
+      last.2 += op.2;
+      return;
+    }
+  }
+
+  ops.push(op);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Rolling;
+
+  #[test]
+  fn test_rolling_hash() {
+    let data: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+    let mut rolling = Rolling::new(&data[..100]);
+
+    for i in 1..900 {
+      rolling.roll(data[i - 1], data[i + 99]);
+      assert_eq!(rolling.digest(), Rolling::new(&data[i..i + 100]).digest());
+    }
+  }
+}
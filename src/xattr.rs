@@ -0,0 +1,147 @@
+//! Extended attributes, such as SELinux labels and capabilities on Linux or quarantine flags and
+//! resource forks on macOS. Other platforms have none to copy.
+
+use std::io;
+use std::path::Path;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::ffi::{CStr, CString};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::os::unix::ffi::OsStrExt;
+
+/// Copies the extended attributes of `from` to `to`. Nothing is copied when the filesystem of
+/// `from` doesn't support them.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn copy_xattrs<P: AsRef<Path>>(from: P, to: P) -> Result<(), io::Error> {
+  let from = c_path(from.as_ref())?;
+  let to = c_path(to.as_ref())?;
+
+  let names = match read_all(|buf, size| unsafe { list(&from, buf, size) }) {
+    Ok(names) => names,
+    Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+    Err(err) => return Err(err),
+  };
+  for name in names
+    .split(|&byte| byte == 0)
+    .filter(|name| !name.is_empty())
+  {
+    let name = CString::new(name)?;
+    let value = read_all(|buf, size| unsafe { get(&from, &name, buf, size) })?;
+    set(&to, &name, &value)?;
+  }
+
+  Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn copy_xattrs<P: AsRef<Path>>(_from: P, _to: P) -> Result<(), io::Error> {
+  Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn c_path(path: &Path) -> Result<CString, io::Error> {
+  Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Reads a list or a value with `read`, which is called with an empty buffer first to get the
+/// size. Attributes can change in between, so it's retried when the buffer turns out too small.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_all<F>(mut read: F) -> Result<Vec<u8>, io::Error>
+where
+  F: FnMut(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+  loop {
+    let size = read(std::ptr::null_mut(), 0);
+    if size < 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0u8; size as usize];
+    let read = read(buf.as_mut_ptr().cast(), buf.len());
+    if read >= 0 {
+      buf.truncate(read as usize);
+      return Ok(buf);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::ERANGE) {
+      return Err(err);
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn list(path: &CStr, buf: *mut libc::c_void, size: usize) -> libc::ssize_t {
+  libc::listxattr(path.as_ptr(), buf.cast(), size)
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn list(path: &CStr, buf: *mut libc::c_void, size: usize) -> libc::ssize_t {
+  libc::listxattr(path.as_ptr(), buf.cast(), size, 0)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn get(path: &CStr, name: &CStr, buf: *mut libc::c_void, size: usize) -> libc::ssize_t {
+  libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size)
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn get(path: &CStr, name: &CStr, buf: *mut libc::c_void, size: usize) -> libc::ssize_t {
+  libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size, 0, 0)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set(path: &CStr, name: &CStr, value: &[u8]) -> Result<(), io::Error> {
+  #[cfg(target_os = "linux")]
+  let result = unsafe {
+    libc::setxattr(
+      path.as_ptr(),
+      name.as_ptr(),
+      value.as_ptr().cast(),
+      value.len(),
+      0,
+    )
+  };
+  #[cfg(target_os = "macos")]
+  let result = unsafe {
+    libc::setxattr(
+      path.as_ptr(),
+      name.as_ptr(),
+      value.as_ptr().cast(),
+      value.len(),
+      0,
+      0,
+    )
+  };
+
+  if result != 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+  use std::ffi::CString;
+
+  #[test]
+  fn test_copy_xattrs() {
+    let source = tempfile::NamedTempFile::new().unwrap();
+    let dest = tempfile::NamedTempFile::new().unwrap();
+    let source_path = super::c_path(source.path()).unwrap();
+    let name = CString::new("user.fastcdc-diff").unwrap();
+
+    match super::set(&source_path, &name, b"value") {
+      Ok(()) => (),
+      // The filesystem of the temporary directory has no user attributes.
+      Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return,
+      Err(err) => panic!("{}", err),
+    }
+    super::copy_xattrs(source.path(), dest.path()).unwrap();
+
+    let dest_path = super::c_path(dest.path()).unwrap();
+    let value = super::read_all(|buf, size| unsafe { super::get(&dest_path, &name, buf, size) });
+    assert_eq!(value.unwrap(), b"value");
+  }
+}